@@ -0,0 +1,34 @@
+//! Normalization of internationalized mail addresses (RFC 6531).
+//!
+//! Local parts are compared in Unicode NFC and lowercased, as the rest of the
+//! server has always lowercased them. Domains are converted to their ASCII
+//! (punycode) form so `bücher.example` and `xn--bcher-kva.example` match.
+
+use unicode_normalization::UnicodeNormalization;
+
+/// The form mailbox names are stored and looked up in.
+pub fn normalize_local(local: &str) -> String {
+    local.nfc().collect::<String>().to_lowercase()
+}
+
+/// The lowercase ASCII form of a domain, or `None` if it is not a valid IDN.
+pub fn normalize_domain(domain: &str) -> Option<String> {
+    let domain = domain.trim_end_matches('.');
+    if domain.is_ascii() {
+        return Some(domain.to_ascii_lowercase());
+    }
+    idna::domain_to_ascii(domain).ok()
+}
+
+/// `local@domain` with both halves normalized; the split is at the last `@`
+/// because a quoted local part may contain one.
+pub fn normalize(address: &str) -> Option<String> {
+    match address.rsplit_once('@') {
+        Some((local, domain)) => Some(format!(
+            "{}@{}",
+            normalize_local(local),
+            normalize_domain(domain)?
+        )),
+        None => Some(normalize_local(address)),
+    }
+}
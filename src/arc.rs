@@ -0,0 +1,447 @@
+//! ARC chain validation (RFC 8617).
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::dkim::{self, Canonicalization, Field, KeyError};
+use crate::dns::Resolver;
+
+/// Highest instance number allowed in a chain (§4.2.1).
+const MAX_INSTANCES: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArcResult {
+    None,
+    Pass,
+    Fail,
+}
+
+impl ArcResult {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ArcResult::None => "none",
+            ArcResult::Pass => "pass",
+            ArcResult::Fail => "fail",
+        }
+    }
+}
+
+/// One hop of the chain as recorded in its ARC-Seal.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArcHop {
+    pub instance: usize,
+    /// Sealing domain (`d=` of the ARC-Seal).
+    pub domain: Option<String>,
+    pub selector: Option<String>,
+    /// Chain validation status the hop claimed (`cv=`).
+    pub cv: Option<String>,
+    /// Authentication results the hop recorded (ARC-Authentication-Results, without `i=`).
+    pub results: Option<String>,
+}
+
+/// Outcome of validating a message's ARC chain.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArcCheck {
+    pub result: ArcResult,
+    /// Number of ARC sets on the message.
+    pub instances: usize,
+    /// Oldest instance whose ARC-Message-Signature still verifies.
+    pub oldest_pass: Option<usize>,
+    pub hops: Vec<ArcHop>,
+    pub reason: Option<String>,
+}
+
+#[derive(Default)]
+struct ArcSet<'a> {
+    results: Option<&'a Field<'a>>,
+    signature: Option<&'a Field<'a>>,
+    seal: Option<&'a Field<'a>>,
+}
+
+/// Validate the ARC chain of a message as received.
+pub async fn verify(resolver: &dyn Resolver, raw: &[u8]) -> ArcCheck {
+    let message = dkim::to_crlf(raw);
+    let (headers, body) = dkim::split_message(&message);
+    let fields = dkim::parse_fields(headers);
+
+    let mut check = ArcCheck {
+        result: ArcResult::None,
+        instances: 0,
+        oldest_pass: None,
+        hops: Vec::new(),
+        reason: None,
+    };
+
+    let sets = match collect_sets(&fields) {
+        Ok(sets) => sets,
+        Err(e) => {
+            check.result = ArcResult::Fail;
+            check.reason = Some(e);
+            return check;
+        }
+    };
+    if sets.is_empty() {
+        return check;
+    }
+    check.instances = sets.len();
+    check.hops = sets.iter().enumerate().map(|(i, set)| hop(i + 1, set)).collect();
+
+    match validate(resolver, &sets, &fields, body, &mut check.oldest_pass).await {
+        Ok(()) => check.result = ArcResult::Pass,
+        Err(e) => {
+            check.result = ArcResult::Fail;
+            check.reason = Some(e);
+        }
+    }
+    check
+}
+
+/// Group the ARC headers by instance; sets must run 1..=N with one header of each kind.
+fn collect_sets<'a>(fields: &'a [Field<'a>]) -> Result<Vec<ArcSet<'a>>, String> {
+    let mut sets: Vec<ArcSet> = Vec::new();
+
+    for field in fields {
+        let kind = field.name.to_ascii_lowercase();
+        if !matches!(
+            kind.as_str(),
+            "arc-authentication-results" | "arc-message-signature" | "arc-seal"
+        ) {
+            continue;
+        }
+
+        let instance = instance_of(field)
+            .filter(|i| (1..=MAX_INSTANCES).contains(i))
+            .ok_or_else(|| format!("{} has no valid i= tag", field.name))?;
+        if sets.len() < instance {
+            sets.resize_with(instance, ArcSet::default);
+        }
+        let set = &mut sets[instance - 1];
+        let slot = match kind.as_str() {
+            "arc-authentication-results" => &mut set.results,
+            "arc-message-signature" => &mut set.signature,
+            _ => &mut set.seal,
+        };
+        if slot.is_some() {
+            return Err(format!("duplicate {} for i={}", field.name, instance));
+        }
+        *slot = Some(field);
+    }
+
+    for (i, set) in sets.iter().enumerate() {
+        if set.results.is_none() || set.signature.is_none() || set.seal.is_none() {
+            return Err(format!("incomplete ARC set i={}", i + 1));
+        }
+    }
+    Ok(sets)
+}
+
+fn instance_of(field: &Field) -> Option<usize> {
+    let value = field.value().replace("\r\n", "");
+    value
+        .split(';')
+        .filter_map(|spec| spec.split_once('='))
+        .find(|(name, _)| name.trim() == "i")
+        .and_then(|(_, v)| v.trim().parse().ok())
+}
+
+fn hop(instance: usize, set: &ArcSet) -> ArcHop {
+    let tags = set
+        .seal
+        .and_then(|f| dkim::parse_tags(f.value()).ok())
+        .unwrap_or_default();
+    ArcHop {
+        instance,
+        domain: dkim::tag(&tags, "d").map(|d| d.to_ascii_lowercase()),
+        selector: dkim::tag(&tags, "s").map(str::to_string),
+        cv: dkim::tag(&tags, "cv").map(|cv| cv.to_ascii_lowercase()),
+        results: set.results.map(|f| {
+            let value = f.value().replace("\r\n", "");
+            let value = value.trim();
+            // drop the leading "i=N;"
+            value
+                .split_once(';')
+                .map(|(_, rest)| rest.trim().to_string())
+                .unwrap_or_else(|| value.to_string())
+        }),
+    }
+}
+
+/// The validation steps of §5.2: seal statuses, newest message signature, then every seal.
+async fn validate(
+    resolver: &dyn Resolver,
+    sets: &[ArcSet<'_>],
+    fields: &[Field<'_>],
+    body: &[u8],
+    oldest_pass: &mut Option<usize>,
+) -> Result<(), String> {
+    for (i, set) in sets.iter().enumerate() {
+        let tags = dkim::parse_tags(set.seal.unwrap().value())
+            .map_err(|e| format!("ARC-Seal i={}: {}", i + 1, e))?;
+        let cv = dkim::tag(&tags, "cv").unwrap_or("").to_ascii_lowercase();
+        let expected = if i == 0 { "none" } else { "pass" };
+        if cv != expected {
+            return Err(format!("ARC-Seal i={} has cv={}", i + 1, cv));
+        }
+    }
+
+    let newest = sets.len();
+    verify_message_signature(resolver, sets[newest - 1].signature.unwrap(), fields, body)
+        .await
+        .map_err(|e| format!("ARC-Message-Signature i={}: {}", newest, e))?;
+    *oldest_pass = Some(newest);
+
+    // older message signatures only show how far back the content is unchanged
+    for instance in (1..newest).rev() {
+        let signature = sets[instance - 1].signature.unwrap();
+        if verify_message_signature(resolver, signature, fields, body).await.is_err() {
+            break;
+        }
+        *oldest_pass = Some(instance);
+    }
+
+    for instance in (1..=newest).rev() {
+        verify_seal(resolver, &sets[..instance])
+            .await
+            .map_err(|e| format!("ARC-Seal i={}: {}", instance, e))?;
+    }
+
+    Ok(())
+}
+
+fn key_type(algorithm: &str) -> Result<&'static str, String> {
+    match algorithm.to_ascii_lowercase().as_str() {
+        "rsa-sha256" => Ok("rsa"),
+        "ed25519-sha256" => Ok("ed25519"),
+        other => Err(format!("unsupported algorithm {}", other)),
+    }
+}
+
+async fn verify_with_key(
+    resolver: &dyn Resolver,
+    tags: &[(String, String)],
+    hash: &[u8],
+) -> Result<(), String> {
+    let domain = dkim::tag(tags, "d").ok_or("missing d= tag")?;
+    let selector = dkim::tag(tags, "s").ok_or("missing s= tag")?;
+    let key_type = key_type(dkim::tag(tags, "a").ok_or("missing a= tag")?)?;
+    let signature = BASE64
+        .decode(dkim::strip_whitespace(dkim::tag(tags, "b").unwrap_or("")).as_bytes())
+        .map_err(|_| "b= is not valid base64".to_string())?;
+
+    let (key, _) = dkim::fetch_key(resolver, selector, &domain.to_ascii_lowercase(), key_type)
+        .await
+        .map_err(|e| match e {
+            KeyError::Temp(e) | KeyError::Perm(e) => e,
+        })?;
+    if key.verify(hash, &signature) {
+        Ok(())
+    } else {
+        Err("signature did not verify".to_string())
+    }
+}
+
+async fn verify_message_signature(
+    resolver: &dyn Resolver,
+    signature: &Field<'_>,
+    fields: &[Field<'_>],
+    body: &[u8],
+) -> Result<(), String> {
+    let tags = dkim::parse_tags(signature.value())?;
+    let (header_c, body_c) = dkim::parse_canonicalization(dkim::tag(&tags, "c"))
+        .ok_or("unknown canonicalization")?;
+    let signed: Vec<&str> = dkim::tag(&tags, "h").ok_or("missing h= tag")?.split(':').collect();
+    if signed.iter().any(|h| h.trim().eq_ignore_ascii_case("arc-seal")) {
+        return Err("h= must not include ARC-Seal".to_string());
+    }
+
+    let body_hash = BASE64.encode(Sha256::digest(dkim::canonical_body(body, body_c)));
+    if body_hash != dkim::strip_whitespace(dkim::tag(&tags, "bh").unwrap_or("")) {
+        return Err("body hash did not verify".to_string());
+    }
+
+    let hash = dkim::header_hash(fields, &signed, signature, header_c);
+    verify_with_key(resolver, &tags, &hash).await
+}
+
+/// A seal covers every ARC set up to its own, always with relaxed canonicalization (§5.1.1).
+async fn verify_seal(resolver: &dyn Resolver, sets: &[ArcSet<'_>]) -> Result<(), String> {
+    let seal = sets.last().and_then(|s| s.seal).ok_or("missing seal")?;
+    let tags = dkim::parse_tags(seal.value())?;
+    if dkim::tag(&tags, "h").is_some() {
+        return Err("h= is not allowed in ARC-Seal".to_string());
+    }
+
+    let relaxed = Canonicalization::Relaxed;
+    let mut hasher = Sha256::new();
+    for (i, set) in sets.iter().enumerate() {
+        hasher.update(dkim::canonical_header(set.results.unwrap().text, relaxed));
+        hasher.update(dkim::canonical_header(set.signature.unwrap().text, relaxed));
+        if i + 1 < sets.len() {
+            hasher.update(dkim::canonical_header(set.seal.unwrap().text, relaxed));
+        }
+    }
+    let own = dkim::canonical_header(&dkim::strip_b_value(seal.text), relaxed);
+    hasher.update(own.strip_suffix(b"\r\n").unwrap_or(&own));
+
+    verify_with_key(resolver, &tags, &hasher.finalize()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::ZoneResolver;
+
+    const ZONE: &str = r#"
+ed._domainkey.pass.test.    TXT "v=DKIM1; k=ed25519; p=fqgmdGQ8Azt9BEInycg5A6hi0OHwD6qQ4iidUuBL/UM="
+ed._domainkey.list.test.    TXT "v=DKIM1; k=ed25519; p=fqgmdGQ8Azt9BEInycg5A6hi0OHwD6qQ4iidUuBL/UM="
+"#;
+
+    /// Sealed by pass.test, then by list.test after it added a footer to the body.
+    const CHAIN: &[u8] = b"ARC-Seal: i=2; a=ed25519-sha256; cv=pass; d=list.test; s=ed;\r\n\
+        \tb=jOGwseCswtp+//OqJyevQlZq9Vr4sEc5eIbseyAXafrkQez0krrduuYr728jJy0C22Uxi3kLqJ2DAhIx1Z/bDg==\r\n\
+        ARC-Message-Signature: i=2; a=ed25519-sha256; c=relaxed/relaxed; d=list.test; s=ed;\r\n\
+        \th=from:to:subject; bh=H2EZnPtna2JJCtrxSNGMOvFDKE9bv5zuN2+cD0agsCo=;\r\n\
+        \tb=dHnVt8KGkzbcpbyoQOBvLziRm/5sRn71ILldGkzc4smKzeksyTY19fQZHFnUVEOQigSIHX8Cn6BDK6ae5YuqBA==\r\n\
+        ARC-Authentication-Results: i=2; list.test; spf=pass smtp.mailfrom=alice@pass.test\r\n\
+        ARC-Seal: i=1; a=ed25519-sha256; cv=none; d=pass.test; s=ed;\r\n\
+        \tb=/3d+5LYrAAJP/V+bm0SS9Kv7jnGacvkR7zHAacap7RpO+1tYkspp/GK0eZVligeHEhf6MTgmOPPXk3QQA5mBCQ==\r\n\
+        ARC-Message-Signature: i=1; a=ed25519-sha256; c=relaxed/relaxed; d=pass.test; s=ed;\r\n\
+        \th=from:to:subject; bh=mCTzU67nt0YOwC48GoJlR0myI8h+W5XBddYu4LPUpds=;\r\n\
+        \tb=TaAm5QD6nx4ckWgzQRfuZlaG8o3qAerxcFF7LL9QRDpJGao1oGLrwsXwJfxFJF4pqT1A88GlwxIw6f76KJR3BA==\r\n\
+        ARC-Authentication-Results: i=1; pass.test; spf=pass smtp.mailfrom=alice@pass.test\r\n\
+        From: alice@pass.test\r\n\
+        To: list@list.test\r\n\
+        Subject: hello\r\n\
+        \r\n\
+        Hello list\r\n\
+        -- footer\r\n";
+
+    async fn chain_check(message: &[u8]) -> ArcCheck {
+        let zone = ZoneResolver::parse(ZONE).unwrap();
+        verify(&zone, message).await
+    }
+
+    fn replace(message: &[u8], from: &str, to: &str) -> Vec<u8> {
+        let text = std::str::from_utf8(message).unwrap();
+        assert!(text.contains(from), "{:?} not in message", from);
+        text.replacen(from, to, 1).into_bytes()
+    }
+
+    /// The message without the header line starting with `prefix`.
+    fn without(message: &[u8], prefix: &str) -> Vec<u8> {
+        let text = std::str::from_utf8(message).unwrap();
+        let start = text.find(prefix).unwrap();
+        let end = start + text[start..].find("\r\nARC-").unwrap() + 2;
+        [&text[..start], &text[end..]].concat().into_bytes()
+    }
+
+    #[tokio::test]
+    async fn validates_a_two_hop_chain() {
+        let check = chain_check(CHAIN).await;
+        assert_eq!(check.result, ArcResult::Pass, "{:?}", check.reason);
+        assert_eq!(check.instances, 2);
+        // the footer broke the first hop's message signature
+        assert_eq!(check.oldest_pass, Some(2));
+        let hops: Vec<_> = check
+            .hops
+            .iter()
+            .map(|h| (h.instance, h.domain.as_deref(), h.cv.as_deref()))
+            .collect();
+        assert_eq!(
+            hops,
+            [
+                (1, Some("pass.test"), Some("none")),
+                (2, Some("list.test"), Some("pass")),
+            ]
+        );
+        assert_eq!(
+            check.hops[1].results.as_deref(),
+            Some("list.test; spf=pass smtp.mailfrom=alice@pass.test")
+        );
+    }
+
+    #[tokio::test]
+    async fn first_hop_alone_passes_on_the_original_message() {
+        let at = CHAIN.windows(14).position(|w| w == b"ARC-Seal: i=1;").unwrap();
+        let first = &CHAIN[at..];
+        let original = replace(first, "Hello list\r\n-- footer\r\n", "Hello list\r\n");
+        let check = chain_check(&original).await;
+        assert_eq!(check.result, ArcResult::Pass, "{:?}", check.reason);
+        assert_eq!(check.instances, 1);
+        assert_eq!(check.oldest_pass, Some(1));
+
+        // with the footer the only message signature fails
+        let check = chain_check(first).await;
+        assert_eq!(check.result, ArcResult::Fail);
+        assert_eq!(check.oldest_pass, None);
+    }
+
+    #[tokio::test]
+    async fn tampered_message_fails() {
+        let tampered = replace(CHAIN, "-- footer", "-- FOOTER");
+        let check = chain_check(&tampered).await;
+        assert_eq!(check.result, ArcResult::Fail);
+        assert!(check
+            .reason
+            .unwrap()
+            .starts_with("ARC-Message-Signature i=2: body hash"));
+
+        // the seals cover the authentication results of every hop
+        let tampered = replace(
+            CHAIN,
+            "i=1; pass.test; spf=pass",
+            "i=1; pass.test; spf=fail",
+        );
+        let check = chain_check(&tampered).await;
+        assert_eq!(check.result, ArcResult::Fail);
+        assert_eq!(
+            check.reason.as_deref(),
+            Some("ARC-Seal i=2: signature did not verify")
+        );
+    }
+
+    #[tokio::test]
+    async fn broken_cv_sequence_fails() {
+        let check = chain_check(&replace(CHAIN, "cv=pass", "cv=fail")).await;
+        assert_eq!(check.result, ArcResult::Fail);
+        assert_eq!(check.reason.as_deref(), Some("ARC-Seal i=2 has cv=fail"));
+
+        let check = chain_check(&replace(CHAIN, "cv=none", "cv=pass")).await;
+        assert_eq!(check.reason.as_deref(), Some("ARC-Seal i=1 has cv=pass"));
+    }
+
+    #[tokio::test]
+    async fn incomplete_and_duplicate_sets_fail() {
+        let check = chain_check(&without(CHAIN, "ARC-Seal: i=1;")).await;
+        assert_eq!(check.result, ArcResult::Fail);
+        assert_eq!(check.reason.as_deref(), Some("incomplete ARC set i=1"));
+
+        let duplicate = [
+            b"ARC-Authentication-Results: i=2; list.test; none\r\n".as_slice(),
+            CHAIN,
+        ]
+        .concat();
+        let check = chain_check(&duplicate).await;
+        assert_eq!(
+            check.reason.as_deref(),
+            Some("duplicate ARC-Authentication-Results for i=2")
+        );
+
+        let check = chain_check(&replace(CHAIN, "i=2; list.test;", "i=51; list.test;")).await;
+        assert_eq!(
+            check.reason.as_deref(),
+            Some("ARC-Authentication-Results has no valid i= tag")
+        );
+    }
+
+    #[tokio::test]
+    async fn message_without_arc_headers_has_no_result() {
+        let check = chain_check(b"From: alice@pass.test\r\n\r\nHi\r\n").await;
+        assert_eq!(check.result, ArcResult::None);
+        assert_eq!(check.instances, 0);
+        assert!(check.hops.is_empty());
+    }
+}
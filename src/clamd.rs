@@ -0,0 +1,249 @@
+//! Virus scanning through a clamd-compatible daemon using the INSTREAM command.
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::config::env_or;
+
+/// Data is streamed to clamd in chunks of this size.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Where clamd listens (`CLAMD_ADDRESS`): `host:port`, `tcp:host:port`, or
+/// `unix:/path` / an absolute socket path.
+#[derive(Debug, Clone)]
+pub enum ClamdAddress {
+    Tcp(String),
+    Unix(String),
+}
+
+impl ClamdAddress {
+    fn parse(value: &str) -> Self {
+        if let Some(path) = value.strip_prefix("unix:") {
+            ClamdAddress::Unix(path.to_string())
+        } else if value.starts_with('/') {
+            ClamdAddress::Unix(value.to_string())
+        } else {
+            ClamdAddress::Tcp(value.strip_prefix("tcp:").unwrap_or(value).to_string())
+        }
+    }
+}
+
+/// What happens to infected messages (`CLAMD_ACTION`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InfectedAction {
+    /// Refuse the message at end of DATA.
+    Reject,
+    /// Store the message but keep it out of the inbox listing.
+    Quarantine,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClamdConfig {
+    /// Scanning is off when unset.
+    pub address: Option<ClamdAddress>,
+    pub action: InfectedAction,
+    /// Accept unscanned mail when clamd fails instead of answering 451 (`CLAMD_ON_ERROR=accept`).
+    pub accept_on_error: bool,
+    /// Limit for the whole exchange with clamd (`CLAMD_TIMEOUT_SECS`).
+    pub timeout: Duration,
+}
+
+impl ClamdConfig {
+    pub fn from_env() -> Self {
+        let address = std::env::var("CLAMD_ADDRESS")
+            .ok()
+            .map(|a| a.trim().to_string())
+            .filter(|a| !a.is_empty())
+            .map(|a| ClamdAddress::parse(&a));
+        let action = match env_or("CLAMD_ACTION", String::from("reject")).to_lowercase().as_str() {
+            "quarantine" => InfectedAction::Quarantine,
+            "reject" => InfectedAction::Reject,
+            other => {
+                tracing::warn!("Unknown CLAMD_ACTION {:?}, rejecting infected mail", other);
+                InfectedAction::Reject
+            }
+        };
+        Self {
+            address,
+            action,
+            accept_on_error: env_or("CLAMD_ON_ERROR", String::from("tempfail"))
+                .eq_ignore_ascii_case("accept"),
+            timeout: Duration::from_secs(env_or("CLAMD_TIMEOUT_SECS", 30)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanResult {
+    Clean,
+    Infected,
+    Error,
+}
+
+impl ScanResult {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScanResult::Clean => "clean",
+            ScanResult::Infected => "infected",
+            ScanResult::Error => "error",
+        }
+    }
+}
+
+/// Verdict stored with a scanned message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScanVerdict {
+    pub result: ScanResult,
+    /// Signature name reported for infected mail.
+    pub signature: Option<String>,
+    pub reason: Option<String>,
+}
+
+/// Scan a message; failures are reported as `ScanResult::Error` with the reason.
+pub async fn scan(address: &ClamdAddress, timeout: Duration, data: &[u8]) -> ScanVerdict {
+    let reply = tokio::time::timeout(timeout, async {
+        match address {
+            ClamdAddress::Tcp(addr) => instream(TcpStream::connect(addr).await?, data).await,
+            #[cfg(unix)]
+            ClamdAddress::Unix(path) => {
+                instream(tokio::net::UnixStream::connect(path).await?, data).await
+            }
+            #[cfg(not(unix))]
+            ClamdAddress::Unix(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "unix sockets are not supported on this platform",
+            )),
+        }
+    })
+    .await;
+
+    match reply {
+        Ok(Ok(reply)) => verdict(&reply),
+        Ok(Err(e)) => error_verdict(format!("clamd: {}", e)),
+        Err(_) => error_verdict("clamd: timed out".to_string()),
+    }
+}
+
+/// Send `zINSTREAM`, the message as length-prefixed chunks, a zero-length
+/// terminator, and read the NUL-terminated reply.
+async fn instream<S>(mut stream: S, data: &[u8]) -> std::io::Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(b"zINSTREAM\0").await?;
+    for chunk in data.chunks(CHUNK_SIZE) {
+        stream.write_all(&(chunk.len() as u32).to_be_bytes()).await?;
+        stream.write_all(chunk).await?;
+    }
+    stream.write_all(&0u32.to_be_bytes()).await?;
+    stream.flush().await?;
+
+    let mut reply = Vec::new();
+    let mut byte = [0u8; 1];
+    while stream.read(&mut byte).await? == 1 && byte[0] != 0 {
+        reply.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&reply).trim().to_string())
+}
+
+fn error_verdict(reason: String) -> ScanVerdict {
+    ScanVerdict {
+        result: ScanResult::Error,
+        signature: None,
+        reason: Some(reason),
+    }
+}
+
+/// Interpret `stream: OK`, `stream: <name> FOUND` or `... ERROR`.
+fn verdict(reply: &str) -> ScanVerdict {
+    let status = reply.strip_prefix("stream:").unwrap_or(reply).trim();
+    if status == "OK" {
+        ScanVerdict {
+            result: ScanResult::Clean,
+            signature: None,
+            reason: None,
+        }
+    } else if let Some(signature) = status.strip_suffix(" FOUND") {
+        ScanVerdict {
+            result: ScanResult::Infected,
+            signature: Some(signature.trim().to_string()),
+            reason: None,
+        }
+    } else {
+        error_verdict(format!("clamd: {}", reply))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn parses_replies() {
+        assert_eq!(verdict("stream: OK").result, ScanResult::Clean);
+
+        let infected = verdict("stream: Eicar-Test-Signature FOUND");
+        assert_eq!(infected.result, ScanResult::Infected);
+        assert_eq!(infected.signature.as_deref(), Some("Eicar-Test-Signature"));
+
+        let error = verdict("INSTREAM size limit exceeded. ERROR");
+        assert_eq!(error.result, ScanResult::Error);
+    }
+
+    /// A clamd stub that answers one INSTREAM request, reporting infected data
+    /// when it contains `EICAR`. Returns its address and the bytes it received.
+    async fn stub() -> (String, tokio::task::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut command = [0u8; 10];
+            stream.read_exact(&mut command).await.unwrap();
+            assert_eq!(&command, b"zINSTREAM\0");
+            let mut data = Vec::new();
+            loop {
+                let len = stream.read_u32().await.unwrap() as usize;
+                if len == 0 {
+                    break;
+                }
+                let mut chunk = vec![0u8; len];
+                stream.read_exact(&mut chunk).await.unwrap();
+                data.extend_from_slice(&chunk);
+            }
+            let reply: &[u8] = if data.windows(5).any(|w| w == b"EICAR") {
+                b"stream: Eicar-Test-Signature FOUND\0"
+            } else {
+                b"stream: OK\0"
+            };
+            stream.write_all(reply).await.unwrap();
+            data
+        });
+        (address, handle)
+    }
+
+    #[tokio::test]
+    async fn scans_through_a_stub() {
+        let message = vec![b'x'; CHUNK_SIZE * 2 + 10];
+        let (address, handle) = stub().await;
+        let verdict = scan(&ClamdAddress::Tcp(address), Duration::from_secs(5), &message).await;
+        assert_eq!(verdict.result, ScanResult::Clean);
+        assert_eq!(handle.await.unwrap(), message);
+
+        let (address, _) = stub().await;
+        let verdict = scan(&ClamdAddress::Tcp(address), Duration::from_secs(5), b"EICAR test").await;
+        assert_eq!(verdict.result, ScanResult::Infected);
+    }
+
+    #[tokio::test]
+    async fn unreachable_daemon_is_an_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let verdict = scan(&ClamdAddress::Tcp(address), Duration::from_secs(5), b"data").await;
+        assert_eq!(verdict.result, ScanResult::Error);
+    }
+}
@@ -0,0 +1,661 @@
+//! SMTP command parsing following the RFC 5321 §4.1 grammar, with the ESMTP
+//! parameters of the extensions we advertise.
+
+use std::fmt;
+
+use crate::address;
+
+/// Longest ENVID accepted (RFC 3461 §4.4).
+const MAX_ENVID_LEN: usize = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Helo(String),
+    Ehlo(String),
+    Mail {
+        /// Normalized sender; empty for the null reverse-path `<>`.
+        from: String,
+        params: MailParams,
+    },
+    Rcpt {
+        /// Normalized recipient.
+        to: String,
+        params: RcptParams,
+    },
+    Data,
+    Bdat {
+        size: usize,
+        last: bool,
+    },
+    Rset,
+    Noop,
+    Quit,
+    Vrfy(String),
+    Expn(String),
+    Help(Option<String>),
+}
+
+/// `BODY=` values (RFC 6152, RFC 3030).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Body {
+    SevenBit,
+    EightBitMime,
+    BinaryMime,
+}
+
+/// `RET=` values (RFC 3461 §4.3).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ret {
+    Full,
+    Hdrs,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MailParams {
+    pub size: Option<usize>,
+    pub body: Option<Body>,
+    pub smtputf8: bool,
+    pub ret: Option<Ret>,
+    pub envid: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RcptParams {
+    /// `NOTIFY=` conditions, or `NEVER`.
+    pub notify: Vec<String>,
+    /// `ORCPT=` as `addr-type;xtext`.
+    pub orcpt: Option<String>,
+}
+
+/// A command that could not be accepted, with the reply to send.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandError {
+    pub code: u16,
+    pub status: &'static str,
+    pub text: String,
+}
+
+impl CommandError {
+    fn new(code: u16, status: &'static str, text: impl Into<String>) -> Self {
+        Self {
+            code,
+            status,
+            text: text.into(),
+        }
+    }
+
+    /// 500: the verb is not one we know.
+    fn unrecognized() -> Self {
+        Self::new(500, "5.5.2", "Syntax error, command unrecognized")
+    }
+
+    /// 501: the arguments do not follow the grammar.
+    fn syntax(text: impl Into<String>) -> Self {
+        Self::new(501, "5.5.4", text)
+    }
+
+    /// 504: a known parameter with a value we do not implement.
+    fn not_implemented(text: impl Into<String>) -> Self {
+        Self::new(504, "5.5.4", text)
+    }
+
+    /// 555: a MAIL or RCPT parameter that is unknown or not advertised.
+    fn unknown_parameter(text: impl Into<String>) -> Self {
+        Self::new(555, "5.5.4", text)
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.code, self.status, self.text)
+    }
+}
+
+/// Parse one command line (without CRLF). `esmtp` is whether the client greeted
+/// with EHLO; MAIL and RCPT parameters are only accepted after EHLO.
+pub fn parse(line: &str, esmtp: bool) -> Result<Command, CommandError> {
+    let line = line.trim_end_matches(['\r', '\n']);
+    let (verb, rest) = match line.split_once(' ') {
+        Some((verb, rest)) => (verb, rest),
+        None => (line, ""),
+    };
+
+    match verb.to_ascii_uppercase().as_str() {
+        "HELO" => domain_argument(rest, "HELO").map(Command::Helo),
+        "EHLO" => domain_argument(rest, "EHLO").map(Command::Ehlo),
+        "MAIL" => {
+            let rest = prefix(rest, "FROM:")
+                .ok_or_else(|| CommandError::syntax("Syntax: MAIL FROM:<address> [parameters]"))?;
+            let (path, params) = split_path(rest)?;
+            let from = if path == "<>" {
+                String::new()
+            } else {
+                parse_path(path)?
+            };
+            Ok(Command::Mail {
+                from,
+                params: mail_params(params, esmtp)?,
+            })
+        }
+        "RCPT" => {
+            let rest = prefix(rest, "TO:")
+                .ok_or_else(|| CommandError::syntax("Syntax: RCPT TO:<address> [parameters]"))?;
+            let (path, params) = split_path(rest)?;
+            let to = if path.eq_ignore_ascii_case("<postmaster>") {
+                "postmaster".to_string()
+            } else {
+                parse_path(path)?
+            };
+            Ok(Command::Rcpt {
+                to,
+                params: rcpt_params(params, esmtp)?,
+            })
+        }
+        "DATA" => no_argument(rest, Command::Data, "DATA"),
+        "RSET" => no_argument(rest, Command::Rset, "RSET"),
+        "QUIT" => no_argument(rest, Command::Quit, "QUIT"),
+        // NOOP may carry a string that is ignored (§4.1.1.9)
+        "NOOP" => Ok(Command::Noop),
+        "BDAT" => {
+            let args: Vec<&str> = rest.split(' ').filter(|a| !a.is_empty()).collect();
+            let size = args
+                .first()
+                .filter(|s| s.bytes().all(|b| b.is_ascii_digit()))
+                .and_then(|s| s.parse().ok());
+            let last = match args.get(1) {
+                None => Some(false),
+                Some(arg) if args.len() == 2 && arg.eq_ignore_ascii_case("LAST") => Some(true),
+                Some(_) => None,
+            };
+            match (size, last) {
+                (Some(size), Some(last)) => Ok(Command::Bdat { size, last }),
+                _ => Err(CommandError::syntax("Syntax: BDAT <size> [LAST]")),
+            }
+        }
+        "VRFY" | "EXPN" if rest.trim().is_empty() => Err(CommandError::syntax(format!(
+            "Syntax: {} <string>",
+            verb.to_ascii_uppercase()
+        ))),
+        "VRFY" => Ok(Command::Vrfy(rest.trim().to_string())),
+        "EXPN" => Ok(Command::Expn(rest.trim().to_string())),
+        "HELP" => Ok(Command::Help(
+            Some(rest.trim().to_string()).filter(|t| !t.is_empty()),
+        )),
+        _ => Err(CommandError::unrecognized()),
+    }
+}
+
+/// `rest` without a case-insensitive `keyword`; a space after the colon is tolerated
+/// because many clients send one.
+fn prefix<'a>(rest: &'a str, keyword: &str) -> Option<&'a str> {
+    let head = rest.get(..keyword.len())?;
+    head.eq_ignore_ascii_case(keyword)
+        .then(|| rest[keyword.len()..].trim_start_matches(' '))
+}
+
+fn no_argument(rest: &str, command: Command, verb: &str) -> Result<Command, CommandError> {
+    if rest.trim().is_empty() {
+        Ok(command)
+    } else {
+        Err(CommandError::syntax(format!(
+            "Syntax: {} takes no arguments",
+            verb
+        )))
+    }
+}
+
+/// The domain or address literal of HELO/EHLO. Plenty of clients announce names
+/// that are not valid domains (underscores, bare host names), so only presence is checked.
+fn domain_argument(rest: &str, verb: &str) -> Result<String, CommandError> {
+    match rest.split(' ').find(|a| !a.is_empty()) {
+        Some(domain) if !domain.chars().any(char::is_control) => Ok(domain.to_string()),
+        _ => Err(CommandError::syntax(format!("Syntax: {} hostname", verb))),
+    }
+}
+
+/// Split `<path> params` at the `>` closing the path, skipping quoted strings.
+fn split_path(rest: &str) -> Result<(&str, &str), CommandError> {
+    if !rest.starts_with('<') {
+        return Err(CommandError::syntax("Address must be enclosed in <>"));
+    }
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in rest.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '>' if !quoted => {
+                let params = &rest[i + 1..];
+                if !params.is_empty() && !params.starts_with(' ') {
+                    return Err(CommandError::syntax(
+                        "Parameters must follow the address after a space",
+                    ));
+                }
+                return Ok((&rest[..=i], params));
+            }
+            _ => {}
+        }
+    }
+    Err(CommandError::syntax("Address must be enclosed in <>"))
+}
+
+/// `<[@route,@route:]local@domain>` to a normalized address.
+fn parse_path(path: &str) -> Result<String, CommandError> {
+    let inner = &path[1..path.len() - 1];
+    // source routes are obsolete and ignored (§4.1.2, appendix C)
+    let mailbox = match inner.strip_prefix('@') {
+        Some(route) => route
+            .split_once(':')
+            .map(|(_, mailbox)| mailbox)
+            .ok_or_else(|| CommandError::syntax("Invalid source route"))?,
+        None => inner,
+    };
+
+    let (local, domain) = mailbox
+        .rsplit_once('@')
+        .ok_or_else(|| CommandError::syntax("Address must be local@domain"))?;
+    let local = local_part(local)?;
+    if !is_domain(domain) && !is_address_literal(domain) {
+        return Err(CommandError::syntax(format!("Invalid domain {:?}", domain)));
+    }
+    address::normalize(&format!("{}@{}", local, domain))
+        .ok_or_else(|| CommandError::syntax(format!("Invalid domain {:?}", domain)))
+}
+
+/// A Dot-string as is, or a Quoted-string; quoted parts that are valid dot-strings
+/// are unquoted so both spellings reach the same mailbox.
+fn local_part(local: &str) -> Result<String, CommandError> {
+    if let Some(quoted) = local.strip_prefix('"').and_then(|l| l.strip_suffix('"')) {
+        let mut content = String::new();
+        let mut chars = quoted.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some(c) if c == ' ' || c.is_ascii_graphic() || !c.is_ascii() => content.push(c),
+                    _ => return Err(CommandError::syntax("Invalid quoted local part")),
+                },
+                '"' => return Err(CommandError::syntax("Invalid quoted local part")),
+                c if c == ' ' || c.is_ascii_graphic() || !c.is_ascii() => content.push(c),
+                _ => return Err(CommandError::syntax("Invalid quoted local part")),
+            }
+        }
+        if is_dot_string(&content) {
+            return Ok(content);
+        }
+        let escaped = content.replace('\\', "\\\\").replace('"', "\\\"");
+        return Ok(format!("\"{}\"", escaped));
+    }
+    if is_dot_string(local) {
+        Ok(local.to_string())
+    } else {
+        Err(CommandError::syntax(format!(
+            "Invalid local part {:?}",
+            local
+        )))
+    }
+}
+
+/// RFC 5322 atext, plus any non-ASCII character (RFC 6531 §3.3).
+fn is_atext(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c) || !c.is_ascii()
+}
+
+fn is_dot_string(s: &str) -> bool {
+    !s.is_empty()
+        && s.split('.')
+            .all(|atom| !atom.is_empty() && atom.chars().all(is_atext))
+}
+
+/// Dot-separated labels of letters, digits and hyphens; U-labels are allowed and
+/// checked when the domain is converted to ASCII.
+fn is_domain(s: &str) -> bool {
+    let s = s.strip_suffix('.').unwrap_or(s);
+    !s.is_empty()
+        && s.len() <= 255
+        && s.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_alphanumeric() || c == '-')
+        })
+}
+
+/// `[192.0.2.1]`, `[IPv6:2001:db8::1]`.
+fn is_address_literal(s: &str) -> bool {
+    let Some(inner) = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')) else {
+        return false;
+    };
+    match inner.get(..5) {
+        Some(tag) if tag.eq_ignore_ascii_case("IPv6:") => {
+            inner[5..].parse::<std::net::Ipv6Addr>().is_ok()
+        }
+        _ => inner.parse::<std::net::Ipv4Addr>().is_ok(),
+    }
+}
+
+/// `keyword[=value]` pairs after the path.
+fn esmtp_params(params: &str, esmtp: bool) -> Result<Vec<(String, Option<&str>)>, CommandError> {
+    let params: Vec<&str> = params.split(' ').filter(|p| !p.is_empty()).collect();
+    if !params.is_empty() && !esmtp {
+        return Err(CommandError::unknown_parameter(
+            "Parameters are only allowed after EHLO",
+        ));
+    }
+    params
+        .into_iter()
+        .map(|param| {
+            let (keyword, value) = match param.split_once('=') {
+                Some((keyword, value)) => (keyword, Some(value)),
+                None => (param, None),
+            };
+            let valid_keyword = keyword
+                .chars()
+                .next()
+                .is_some_and(|c| c.is_ascii_alphanumeric())
+                && keyword
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-');
+            let valid_value = value.is_none_or(|v| {
+                !v.is_empty()
+                    && v.chars()
+                        .all(|c| c != '=' && (c.is_ascii_graphic() || !c.is_ascii()))
+            });
+            if valid_keyword && valid_value {
+                Ok((keyword.to_ascii_uppercase(), value))
+            } else {
+                Err(CommandError::syntax(format!(
+                    "Invalid parameter {:?}",
+                    param
+                )))
+            }
+        })
+        .collect()
+}
+
+fn require_value<'a>(keyword: &str, value: Option<&'a str>) -> Result<&'a str, CommandError> {
+    value.ok_or_else(|| CommandError::syntax(format!("{} requires a value", keyword)))
+}
+
+fn mail_params(params: &str, esmtp: bool) -> Result<MailParams, CommandError> {
+    let mut parsed = MailParams::default();
+    for (keyword, value) in esmtp_params(params, esmtp)? {
+        match keyword.as_str() {
+            "SIZE" => {
+                let value = require_value(&keyword, value)?;
+                parsed.size = Some(
+                    value
+                        .parse()
+                        .ok()
+                        .filter(|_| value.bytes().all(|b| b.is_ascii_digit()))
+                        .ok_or_else(|| CommandError::syntax("SIZE must be a number"))?,
+                );
+            }
+            "BODY" => {
+                let value = require_value(&keyword, value)?;
+                parsed.body = Some(match value.to_ascii_uppercase().as_str() {
+                    "7BIT" => Body::SevenBit,
+                    "8BITMIME" => Body::EightBitMime,
+                    "BINARYMIME" => Body::BinaryMime,
+                    _ => {
+                        return Err(CommandError::not_implemented(format!(
+                            "BODY={} is not supported",
+                            value
+                        )))
+                    }
+                });
+            }
+            "SMTPUTF8" if value.is_none() => parsed.smtputf8 = true,
+            "SMTPUTF8" => return Err(CommandError::syntax("SMTPUTF8 takes no value")),
+            "RET" => {
+                let value = require_value(&keyword, value)?;
+                parsed.ret = Some(match value.to_ascii_uppercase().as_str() {
+                    "FULL" => Ret::Full,
+                    "HDRS" => Ret::Hdrs,
+                    _ => {
+                        return Err(CommandError::not_implemented(format!(
+                            "RET={} is not supported",
+                            value
+                        )))
+                    }
+                });
+            }
+            "ENVID" => {
+                let value = require_value(&keyword, value)?;
+                if value.len() > MAX_ENVID_LEN || !is_xtext(value) {
+                    return Err(CommandError::syntax(
+                        "ENVID must be xtext of at most 100 characters",
+                    ));
+                }
+                parsed.envid = Some(value.to_string());
+            }
+            _ => {
+                return Err(CommandError::unknown_parameter(format!(
+                    "MAIL parameter {} is not supported",
+                    keyword
+                )))
+            }
+        }
+    }
+    Ok(parsed)
+}
+
+fn rcpt_params(params: &str, esmtp: bool) -> Result<RcptParams, CommandError> {
+    let mut parsed = RcptParams::default();
+    for (keyword, value) in esmtp_params(params, esmtp)? {
+        match keyword.as_str() {
+            "NOTIFY" => {
+                let value = require_value(&keyword, value)?.to_ascii_uppercase();
+                let conditions: Vec<String> = value.split(',').map(str::to_string).collect();
+                let valid = if conditions.iter().any(|c| c == "NEVER") {
+                    conditions.len() == 1
+                } else {
+                    conditions
+                        .iter()
+                        .all(|c| matches!(c.as_str(), "SUCCESS" | "FAILURE" | "DELAY"))
+                };
+                if !valid {
+                    return Err(CommandError::syntax(format!("Invalid NOTIFY={}", value)));
+                }
+                parsed.notify = conditions;
+            }
+            "ORCPT" => {
+                let value = require_value(&keyword, value)?;
+                match value.split_once(';') {
+                    Some((kind, addr)) if !kind.is_empty() && is_xtext(addr) => {
+                        parsed.orcpt = Some(value.to_string())
+                    }
+                    _ => return Err(CommandError::syntax("ORCPT must be addr-type;xtext")),
+                }
+            }
+            _ => {
+                return Err(CommandError::unknown_parameter(format!(
+                    "RCPT parameter {} is not supported",
+                    keyword
+                )))
+            }
+        }
+    }
+    Ok(parsed)
+}
+
+/// RFC 3461 §4: printable ASCII except `+` and `=`, with `+XX` hex escapes.
+fn is_xtext(value: &str) -> bool {
+    let bytes = value.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => {
+                let hex = bytes.get(i + 1..i + 3);
+                if !hex.is_some_and(|h| {
+                    h.iter()
+                        .all(|b| b.is_ascii_digit() || (b'A'..=b'F').contains(b))
+                }) {
+                    return false;
+                }
+                i += 3;
+            }
+            b'!'..=b'~' if bytes[i] != b'=' => i += 1,
+            _ => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mail_from(line: &str) -> String {
+        match parse(line, true) {
+            Ok(Command::Mail { from, .. }) => from,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    fn rcpt_to(line: &str) -> String {
+        match parse(line, true) {
+            Ok(Command::Rcpt { to, .. }) => to,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    fn code(line: &str, esmtp: bool) -> u16 {
+        parse(line, esmtp).unwrap_err().code
+    }
+
+    #[test]
+    fn simple_commands() {
+        assert_eq!(
+            parse("ehlo client.test", false),
+            Ok(Command::Ehlo("client.test".into()))
+        );
+        assert_eq!(
+            parse("HELO my_host\r\n", false),
+            Ok(Command::Helo("my_host".into()))
+        );
+        assert_eq!(parse("DATA", true), Ok(Command::Data));
+        assert_eq!(parse("NOOP anything", true), Ok(Command::Noop));
+        assert_eq!(parse("HELP", true), Ok(Command::Help(None)));
+        assert_eq!(
+            parse("HELP rcpt", true),
+            Ok(Command::Help(Some("rcpt".into())))
+        );
+        assert_eq!(
+            parse("BDAT 100 LAST", true),
+            Ok(Command::Bdat {
+                size: 100,
+                last: true
+            })
+        );
+        assert_eq!(
+            parse("BDAT 0", true),
+            Ok(Command::Bdat {
+                size: 0,
+                last: false
+            })
+        );
+        assert_eq!(parse("VRFY bob", true), Ok(Command::Vrfy("bob".into())));
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(mail_from("MAIL FROM:<>"), "");
+        assert_eq!(
+            mail_from("MAIL FROM: <Alice@Example.TEST>"),
+            "alice@example.test"
+        );
+        assert_eq!(
+            mail_from("mail from:<@relay.test,@b.test:bob@example.test>"),
+            "bob@example.test"
+        );
+        assert_eq!(mail_from("MAIL FROM:<bob@[192.0.2.1]>"), "bob@[192.0.2.1]");
+        assert_eq!(
+            mail_from("MAIL FROM:<bob@[IPv6:2001:db8::1]>"),
+            "bob@[ipv6:2001:db8::1]"
+        );
+        assert_eq!(rcpt_to("RCPT TO:<Postmaster>"), "postmaster");
+        assert_eq!(
+            rcpt_to("RCPT TO:<\"bob\"@example.test>"),
+            "bob@example.test"
+        );
+        assert_eq!(
+            rcpt_to("RCPT TO:<\"bob smith\\\"\"@example.test>"),
+            "\"bob smith\\\"\"@example.test"
+        );
+        assert_eq!(
+            rcpt_to("RCPT TO:<\"a>b\"@example.test>"),
+            "\"a>b\"@example.test"
+        );
+        assert_eq!(
+            rcpt_to("RCPT TO:<bob@bücher.example>"),
+            "bob@xn--bcher-kva.example"
+        );
+    }
+
+    #[test]
+    fn parameters() {
+        let Ok(Command::Mail { params, .. }) = parse(
+            "MAIL FROM:<a@example.test> SIZE=1000 BODY=8bitmime SMTPUTF8 RET=HDRS ENVID=id+2B1",
+            true,
+        ) else {
+            panic!();
+        };
+        assert_eq!(
+            params,
+            MailParams {
+                size: Some(1000),
+                body: Some(Body::EightBitMime),
+                smtputf8: true,
+                ret: Some(Ret::Hdrs),
+                envid: Some("id+2B1".into()),
+            }
+        );
+
+        let Ok(Command::Rcpt { params, .. }) = parse(
+            "RCPT TO:<b@example.test> NOTIFY=success,delay ORCPT=rfc822;b@example.test",
+            true,
+        ) else {
+            panic!();
+        };
+        assert_eq!(params.notify, ["SUCCESS", "DELAY"]);
+        assert_eq!(params.orcpt.as_deref(), Some("rfc822;b@example.test"));
+    }
+
+    #[test]
+    fn reply_codes() {
+        assert_eq!(code("STARTTLS", true), 500);
+        assert_eq!(code("", true), 500);
+
+        for line in [
+            "HELO",
+            "MAIL bob@example.test",
+            "MAIL FROM:bob@example.test",
+            "MAIL FROM:<bob>",
+            "MAIL FROM:<bob@exa mple.test>",
+            "MAIL FROM:<bo b@example.test>",
+            "MAIL FROM:<@relay.test>",
+            "MAIL FROM:<a@example.test>SIZE=1",
+            "MAIL FROM:<a@example.test> SIZE=ten",
+            "MAIL FROM:<a@example.test> SIZE",
+            "MAIL FROM:<a@example.test> SMTPUTF8=yes",
+            "MAIL FROM:<a@example.test> ENVID=a=b",
+            "RCPT TO:<b@example.test> NOTIFY=NEVER,SUCCESS",
+            "RCPT TO:<b@example.test> ORCPT=b@example.test",
+            "DATA now",
+            "BDAT",
+            "BDAT -1",
+            "BDAT 10 LAST more",
+            "VRFY",
+        ] {
+            assert_eq!(code(line, true), 501, "{}", line);
+        }
+
+        assert_eq!(code("MAIL FROM:<a@example.test> BODY=9BIT", true), 504);
+        assert_eq!(code("MAIL FROM:<a@example.test> RET=ALL", true), 504);
+
+        assert_eq!(code("MAIL FROM:<a@example.test> SIZE=1", false), 555);
+        assert_eq!(code("MAIL FROM:<a@example.test> AUTH=<>", true), 555);
+        assert_eq!(code("RCPT TO:<b@example.test> SIZE=1", true), 555);
+    }
+}
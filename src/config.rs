@@ -0,0 +1,26 @@
+//! Helpers for reading optional settings from the environment.
+
+use std::str::FromStr;
+
+/// Parse `name` from the environment, falling back to `default` when unset or invalid.
+pub fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(v) if !v.trim().is_empty() => v.trim().parse().unwrap_or_else(|_| {
+            tracing::warn!("Ignoring invalid value for {}: {:?}", name, v);
+            default
+        }),
+        _ => default,
+    }
+}
+
+/// A comma-separated list from the environment, lowercased; empty when unset.
+pub fn env_list(name: &str) -> Vec<String> {
+    std::env::var(name)
+        .map(|v| {
+            v.split(',')
+                .map(|s| s.trim().to_lowercase())
+                .filter(|s| !s.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
@@ -1,9 +1,18 @@
 use anyhow::Result;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::{PgPool, Row};
+use sqlx::{postgres::PgRow, types::Json, PgPool, Row};
 use uuid::Uuid;
 
+use crate::address::normalize_local;
+use crate::arc::ArcCheck;
+use crate::clamd::ScanVerdict;
+use crate::dkim::DkimCheck;
+use crate::dmarc::DmarcCheck;
+use crate::dnsbl::DnsblListing;
+use crate::spam::SpamReport;
+use crate::spf::SpfCheck;
+
 #[derive(Clone)]
 pub struct Db {
     pool: PgPool,
@@ -15,6 +24,8 @@ pub struct Mailbox {
     pub local: String,
     pub created_at: DateTime<Utc>,
     pub expires_at: Option<DateTime<Utc>>, // <-- Added field for TTL logic
+    /// Keep SMTP session transcripts for mail addressed to this mailbox.
+    pub record_transcripts: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -22,15 +33,392 @@ pub struct Message {
     pub id: Uuid,
     pub mailbox_id: Uuid,
     // FIX E0599 (unwrap_or_else): from_addr must be Option<String> for unwrap_or_else to work
+    /// Envelope sender (SMTP `MAIL FROM`).
     pub from_addr: Option<String>,
+    /// Envelope recipient this copy was delivered to.
     pub to_addr: String,
+    /// Every envelope recipient of the SMTP transaction.
+    pub envelope_to: Vec<String>,
+    pub header_from: Option<MailAddress>,
+    pub header_to: Vec<MailAddress>,
+    pub header_cc: Vec<MailAddress>,
+    pub header_reply_to: Vec<MailAddress>,
+    /// The recipient appears in neither the `To` nor the `Cc` header.
+    pub bcc: bool,
     pub subject: String,
     pub body_text: String,
     pub body_html: Option<String>,
     pub raw: String, // raw_email was renamed to raw for simplicity
+    pub headers: Vec<MessageHeader>,
+    /// Where the message came from; absent for messages stored before this was recorded.
+    pub connection: Option<ConnectionInfo>,
+    pub auth: AuthResults,
+    pub spam: SpamReport,
+    /// Antivirus verdict; absent when scanning is not configured.
+    pub scan: Option<ScanVerdict>,
+    /// Held back from the inbox listing because the scanner flagged it.
+    pub quarantined: bool,
     pub received_at: DateTime<Utc>,
 }
 
+/// Details of the SMTP connection a message arrived on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+    pub session_id: String,
+    pub client_ip: String,
+    pub client_port: u16,
+    /// Forward-confirmed reverse DNS name of the client.
+    pub reverse_dns: Option<String>,
+    /// Name the client gave in HELO/EHLO.
+    pub helo: Option<String>,
+    pub tls: Option<TlsInfo>,
+    /// Blocklists that list the client.
+    #[serde(default)]
+    pub dnsbl: Vec<DnsblListing>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsInfo {
+    pub protocol: String,
+    pub cipher: String,
+}
+
+/// Sender authentication checks run when the message was received.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AuthResults {
+    pub spf: Option<SpfCheck>,
+    /// One entry per DKIM-Signature header, in header order.
+    #[serde(default)]
+    pub dkim: Vec<DkimCheck>,
+    pub dmarc: Option<DmarcCheck>,
+    pub arc: Option<ArcCheck>,
+}
+
+/// One line of an SMTP session transcript.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TranscriptLine {
+    /// Milliseconds since the connection was accepted.
+    pub at_ms: u64,
+    /// `C` for client, `S` for server, `-` for notes.
+    pub direction: String,
+    pub text: String,
+}
+
+/// A recorded SMTP session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionTranscript {
+    pub id: Uuid,
+    pub session_id: String,
+    pub client_ip: String,
+    pub helo: Option<String>,
+    /// `delivered` when at least one message was stored, otherwise `failed`.
+    pub outcome: String,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    pub lines: Vec<TranscriptLine>,
+}
+
+/// A display name / address pair from an address header such as `From` or `Cc`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MailAddress {
+    pub name: Option<String>,
+    pub address: Option<String>,
+}
+
+/// A single top-level header as it appeared in the message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageHeader {
+    pub name: String,
+    pub value: String,
+}
+
+/// Narrows a mailbox listing; unset fields do not filter.
+#[derive(Debug, Default)]
+pub struct MessageFilter<'a> {
+    /// Case-insensitive substring of the subject, sender or text body.
+    pub search: Option<&'a str>,
+    /// Only messages carrying this header (name compared case-insensitively).
+    pub header_name: Option<&'a str>,
+    /// Exact value the `header_name` header must have.
+    pub header_value: Option<&'a str>,
+    /// List quarantined messages instead of the regular inbox.
+    pub quarantined: bool,
+}
+
+/// Fields of a message about to be stored; ids and timestamps are assigned by the database.
+pub struct NewMessage<'a> {
+    pub mailbox_id: Uuid,
+    pub from_addr: Option<&'a str>,
+    pub to_addr: &'a str,
+    pub envelope_to: &'a [String],
+    pub header_from: Option<&'a MailAddress>,
+    pub header_to: &'a [MailAddress],
+    pub header_cc: &'a [MailAddress],
+    pub header_reply_to: &'a [MailAddress],
+    pub bcc: bool,
+    pub subject: &'a str,
+    pub body_text: &'a str,
+    pub body_html: Option<&'a str>,
+    pub raw: &'a str,
+    /// The message as received, when `raw` could not hold it unchanged (8-bit or
+    /// binary content that is not UTF-8, NUL bytes).
+    pub raw_bytes: Option<&'a [u8]>,
+    pub headers: &'a [MessageHeader],
+    pub connection: &'a ConnectionInfo,
+    pub auth: &'a AuthResults,
+    pub spam: &'a SpamReport,
+    pub scan: Option<&'a ScanVerdict>,
+    pub quarantined: bool,
+}
+
+/// A decoded non-body MIME part (attachment or inline resource) of a stored message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub id: Uuid,
+    pub message_id: Uuid,
+    pub content_id: Option<String>,
+    pub filename: Option<String>,
+    pub content_type: String,
+    pub disposition: Option<String>,
+    pub size: i64,
+    #[serde(skip)]
+    pub data: Vec<u8>,
+}
+
+pub struct NewAttachment {
+    pub content_id: Option<String>,
+    pub filename: Option<String>,
+    pub content_type: String,
+    pub disposition: Option<String>,
+    pub data: Vec<u8>,
+}
+
+/// A one-time code or action link found in a message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Extraction {
+    pub id: Uuid,
+    pub message_id: Uuid,
+    pub kind: String,
+    pub value: String,
+    pub label: Option<String>,
+    pub rule: String,
+    pub created_at: DateTime<Utc>,
+}
+
+pub struct NewExtraction {
+    pub kind: String,
+    pub value: String,
+    pub label: Option<String>,
+    pub rule: String,
+}
+
+/// A DMARC aggregate report (RFC 7489 appendix C) found in a message.
+pub struct NewDmarcReport {
+    pub org_name: String,
+    pub report_id: String,
+    pub email: Option<String>,
+    /// Domain the report is about (`policy_published/domain`).
+    pub domain: String,
+    pub policy: Option<String>,
+    pub date_begin: DateTime<Utc>,
+    pub date_end: DateTime<Utc>,
+    pub records: Vec<NewDmarcRecord>,
+}
+
+/// One `<record>` row: messages from a source IP and how they were evaluated.
+pub struct NewDmarcRecord {
+    pub source_ip: String,
+    pub count: i64,
+    pub disposition: Option<String>,
+    pub dkim: Option<String>,
+    pub spf: Option<String>,
+    pub header_from: Option<String>,
+    pub envelope_from: Option<String>,
+}
+
+/// An SMTP TLS report (RFC 8460) found in a message.
+pub struct NewTlsReport {
+    pub org_name: String,
+    pub report_id: String,
+    pub contact: Option<String>,
+    pub date_begin: DateTime<Utc>,
+    pub date_end: DateTime<Utc>,
+    pub policies: Vec<NewTlsPolicy>,
+}
+
+pub struct NewTlsPolicy {
+    /// `sts`, `tlsa` or `no-policy-found`.
+    pub policy_type: String,
+    pub policy_domain: String,
+    pub successful: i64,
+    pub failed: i64,
+    /// The report's `failure-details` array as sent.
+    pub failure_details: serde_json::Value,
+}
+
+/// Aggregate DMARC results for one reported domain.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DmarcDomainSummary {
+    pub domain: String,
+    pub reports: i64,
+    pub messages: i64,
+    /// Messages that passed DKIM or SPF as evaluated by the reporter.
+    pub passed: i64,
+    pub failed: i64,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub sources: Vec<DmarcSourceSummary>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DmarcSourceSummary {
+    pub source_ip: String,
+    pub messages: i64,
+    pub passed: i64,
+    pub failed: i64,
+}
+
+/// Aggregate TLS-RPT results for one policy domain.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsDomainSummary {
+    pub policy_domain: String,
+    pub reports: i64,
+    pub successful: i64,
+    pub failed: i64,
+    pub failures: Vec<TlsFailureSummary>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsFailureSummary {
+    pub result_type: String,
+    pub sessions: i64,
+}
+
+/// A report listed on the dashboard.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReportSummary {
+    pub id: Uuid,
+    pub message_id: Uuid,
+    /// `dmarc` or `tls`.
+    pub kind: String,
+    pub org_name: String,
+    pub report_id: String,
+    pub domain: Option<String>,
+    pub date_begin: DateTime<Utc>,
+    pub date_end: DateTime<Utc>,
+}
+
+/// State of one greylisting triplet.
+#[derive(Debug, Clone)]
+pub struct GreylistEntry {
+    pub first_seen: DateTime<Utc>,
+    /// Last time the triplet was let through.
+    pub passed_at: Option<DateTime<Utc>>,
+    pub attempts: i32,
+}
+
+/// A mailbox-specific regex used to extract codes or links in addition to the built-in heuristics.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExtractionRule {
+    pub id: Uuid,
+    pub mailbox_id: Uuid,
+    pub kind: String,
+    pub name: String,
+    pub pattern: String,
+    pub created_at: DateTime<Utc>,
+}
+
+const MESSAGE_COLUMNS: &str = "id, mailbox_id, from_addr, to_addr, envelope_to, header_from, \
+    header_to, header_cc, header_reply_to, bcc, subject, body_text, body_html, raw, headers, connection, \
+    auth, spam, scan, quarantined, received_at";
+
+fn message_from_row(r: &PgRow) -> Message {
+    Message {
+        id: r.get("id"),
+        mailbox_id: r.get("mailbox_id"),
+        from_addr: r.get("from_addr"),
+        to_addr: r.get("to_addr"),
+        envelope_to: r.get("envelope_to"),
+        header_from: r
+            .get::<Option<Json<MailAddress>>, _>("header_from")
+            .map(|j| j.0),
+        header_to: r.get::<Json<Vec<MailAddress>>, _>("header_to").0,
+        header_cc: r.get::<Json<Vec<MailAddress>>, _>("header_cc").0,
+        header_reply_to: r.get::<Json<Vec<MailAddress>>, _>("header_reply_to").0,
+        bcc: r.get("bcc"),
+        subject: r.get("subject"),
+        body_text: r.get("body_text"),
+        body_html: r.get("body_html"),
+        raw: r.get("raw"),
+        headers: r.get::<Json<Vec<MessageHeader>>, _>("headers").0,
+        connection: r
+            .get::<Option<Json<ConnectionInfo>>, _>("connection")
+            .map(|j| j.0),
+        auth: r.get::<Json<AuthResults>, _>("auth").0,
+        spam: r.get::<Json<SpamReport>, _>("spam").0,
+        scan: r.get::<Option<Json<ScanVerdict>>, _>("scan").map(|j| j.0),
+        quarantined: r.get("quarantined"),
+        received_at: r.get("received_at"),
+    }
+}
+
+const ATTACHMENT_COLUMNS: &str =
+    "id, message_id, content_id, filename, content_type, disposition, size, data";
+
+fn attachment_from_row(r: &PgRow) -> Attachment {
+    Attachment {
+        id: r.get("id"),
+        message_id: r.get("message_id"),
+        content_id: r.get("content_id"),
+        filename: r.get("filename"),
+        content_type: r.get("content_type"),
+        disposition: r.get("disposition"),
+        size: r.get("size"),
+        data: r.get("data"),
+    }
+}
+
+const EXTRACTION_COLUMNS: &str = "id, message_id, kind, value, label, rule, created_at";
+
+fn extraction_from_row(r: &PgRow) -> Extraction {
+    Extraction {
+        id: r.get("id"),
+        message_id: r.get("message_id"),
+        kind: r.get("kind"),
+        value: r.get("value"),
+        label: r.get("label"),
+        rule: r.get("rule"),
+        created_at: r.get("created_at"),
+    }
+}
+
+const TRANSCRIPT_COLUMNS: &str =
+    "t.id, t.session_id, t.client_ip, t.helo, t.outcome, t.started_at, t.ended_at, t.lines";
+
+fn transcript_from_row(r: &PgRow) -> SessionTranscript {
+    SessionTranscript {
+        id: r.get("id"),
+        session_id: r.get("session_id"),
+        client_ip: r.get("client_ip"),
+        helo: r.get("helo"),
+        outcome: r.get("outcome"),
+        started_at: r.get("started_at"),
+        ended_at: r.get("ended_at"),
+        lines: r.get::<Json<Vec<TranscriptLine>>, _>("lines").0,
+    }
+}
+
+fn rule_from_row(r: &PgRow) -> ExtractionRule {
+    ExtractionRule {
+        id: r.get("id"),
+        mailbox_id: r.get("mailbox_id"),
+        kind: r.get("kind"),
+        name: r.get("name"),
+        pattern: r.get("pattern"),
+        created_at: r.get("created_at"),
+    }
+}
+
 impl Db {
     pub async fn new(database_url: &str) -> Result<Self> {
         let pool = PgPool::connect(database_url).await?;
@@ -66,6 +454,12 @@ impl Db {
         .execute(&self.pool)
         .await?;
 
+        sqlx::query(
+            "ALTER TABLE mailboxes ADD COLUMN IF NOT EXISTS record_transcripts BOOLEAN NOT NULL DEFAULT FALSE",
+        )
+        .execute(&self.pool)
+        .await?;
+
         sqlx::query(
             r#"
         CREATE TABLE IF NOT EXISTS messages (
@@ -88,29 +482,305 @@ impl Db {
             .execute(&self.pool)
             .await?;
 
+        sqlx::query("ALTER TABLE messages ADD COLUMN IF NOT EXISTS headers JSONB NOT NULL DEFAULT '[]'")
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query(
+            r#"
+        ALTER TABLE messages
+            ADD COLUMN IF NOT EXISTS envelope_to TEXT[] NOT NULL DEFAULT '{}',
+            ADD COLUMN IF NOT EXISTS header_from JSONB,
+            ADD COLUMN IF NOT EXISTS header_to JSONB NOT NULL DEFAULT '[]',
+            ADD COLUMN IF NOT EXISTS header_cc JSONB NOT NULL DEFAULT '[]',
+            ADD COLUMN IF NOT EXISTS header_reply_to JSONB NOT NULL DEFAULT '[]',
+            ADD COLUMN IF NOT EXISTS bcc BOOLEAN NOT NULL DEFAULT FALSE,
+            ADD COLUMN IF NOT EXISTS connection JSONB,
+            ADD COLUMN IF NOT EXISTS auth JSONB NOT NULL DEFAULT '{}',
+            ADD COLUMN IF NOT EXISTS spam JSONB NOT NULL DEFAULT '{}',
+            ADD COLUMN IF NOT EXISTS scan JSONB,
+            ADD COLUMN IF NOT EXISTS quarantined BOOLEAN NOT NULL DEFAULT FALSE,
+            ADD COLUMN IF NOT EXISTS raw_bytes BYTEA
+        "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
         sqlx::query(
             "CREATE INDEX IF NOT EXISTS idx_messages_received_at ON messages(received_at DESC);",
         )
         .execute(&self.pool)
         .await?;
 
+        sqlx::query(
+            r#"
+        CREATE TABLE IF NOT EXISTS attachments (
+            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+            message_id UUID NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+            content_id TEXT,
+            filename TEXT,
+            content_type TEXT NOT NULL,
+            disposition TEXT,
+            size BIGINT NOT NULL,
+            data BYTEA NOT NULL
+        )
+        "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS idx_attachments_message_id ON attachments(message_id);",
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+        CREATE TABLE IF NOT EXISTS extraction_rules (
+            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+            mailbox_id UUID NOT NULL REFERENCES mailboxes(id) ON DELETE CASCADE,
+            kind TEXT NOT NULL,
+            name TEXT NOT NULL,
+            pattern TEXT NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+        )
+        "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+        CREATE TABLE IF NOT EXISTS extractions (
+            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+            message_id UUID NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+            kind TEXT NOT NULL,
+            value TEXT NOT NULL,
+            label TEXT,
+            rule TEXT NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+        )
+        "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS idx_extractions_message_id ON extractions(message_id, kind);",
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+        CREATE TABLE IF NOT EXISTS transcripts (
+            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+            session_id TEXT NOT NULL UNIQUE,
+            client_ip TEXT NOT NULL,
+            helo TEXT,
+            outcome TEXT NOT NULL,
+            started_at TIMESTAMPTZ NOT NULL,
+            ended_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+            lines JSONB NOT NULL
+        )
+        "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+        CREATE TABLE IF NOT EXISTS transcript_mailboxes (
+            transcript_id UUID NOT NULL REFERENCES transcripts(id) ON DELETE CASCADE,
+            mailbox_id UUID NOT NULL REFERENCES mailboxes(id) ON DELETE CASCADE,
+            PRIMARY KEY (transcript_id, mailbox_id)
+        )
+        "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS idx_transcript_mailboxes_mailbox_id ON transcript_mailboxes(mailbox_id);",
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Reports are kept once per mailbox even when several copies arrive
+        sqlx::query(
+            r#"
+        CREATE TABLE IF NOT EXISTS dmarc_reports (
+            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+            message_id UUID NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+            mailbox_id UUID NOT NULL REFERENCES mailboxes(id) ON DELETE CASCADE,
+            org_name TEXT NOT NULL,
+            report_id TEXT NOT NULL,
+            email TEXT,
+            domain TEXT NOT NULL,
+            policy TEXT,
+            date_begin TIMESTAMPTZ NOT NULL,
+            date_end TIMESTAMPTZ NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+            UNIQUE (mailbox_id, org_name, report_id)
+        )
+        "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+        CREATE TABLE IF NOT EXISTS dmarc_report_records (
+            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+            report_id UUID NOT NULL REFERENCES dmarc_reports(id) ON DELETE CASCADE,
+            source_ip TEXT NOT NULL,
+            count BIGINT NOT NULL,
+            disposition TEXT,
+            dkim TEXT,
+            spf TEXT,
+            header_from TEXT,
+            envelope_from TEXT
+        )
+        "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+        CREATE TABLE IF NOT EXISTS tls_reports (
+            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+            message_id UUID NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+            mailbox_id UUID NOT NULL REFERENCES mailboxes(id) ON DELETE CASCADE,
+            org_name TEXT NOT NULL,
+            report_id TEXT NOT NULL,
+            contact TEXT,
+            date_begin TIMESTAMPTZ NOT NULL,
+            date_end TIMESTAMPTZ NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+            UNIQUE (mailbox_id, org_name, report_id)
+        )
+        "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+        CREATE TABLE IF NOT EXISTS tls_report_policies (
+            id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+            report_id UUID NOT NULL REFERENCES tls_reports(id) ON DELETE CASCADE,
+            policy_type TEXT NOT NULL,
+            policy_domain TEXT NOT NULL,
+            successful BIGINT NOT NULL,
+            failed BIGINT NOT NULL,
+            failure_details JSONB NOT NULL DEFAULT '[]'
+        )
+        "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        for statement in [
+            "CREATE INDEX IF NOT EXISTS idx_dmarc_report_records_report_id ON dmarc_report_records(report_id);",
+            "CREATE INDEX IF NOT EXISTS idx_tls_report_policies_report_id ON tls_report_policies(report_id);",
+        ] {
+            sqlx::query(statement).execute(&self.pool).await?;
+        }
+
+        sqlx::query(
+            r#"
+        CREATE TABLE IF NOT EXISTS greylist (
+            network TEXT NOT NULL,
+            sender TEXT NOT NULL,
+            recipient TEXT NOT NULL,
+            first_seen TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+            passed_at TIMESTAMPTZ,
+            attempts INTEGER NOT NULL DEFAULT 1,
+            PRIMARY KEY (network, sender, recipient)
+        )
+        "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        self.fold_mailbox_case().await?;
+
+        Ok(())
+    }
+
+    /// Lowercase mailboxes created before local parts were normalized. Mailboxes
+    /// that only differ in case are merged into the lowercase one, or the oldest.
+    async fn fold_mailbox_case(&self) -> Result<()> {
+        let mixed: bool =
+            sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM mailboxes WHERE local <> lower(local))")
+                .fetch_one(&self.pool)
+                .await?;
+        if !mixed {
+            return Ok(());
+        }
+
+        let mut tx = self.pool.begin().await?;
+        for statement in [
+            r#"
+            CREATE TEMP TABLE mailbox_merges ON COMMIT DROP AS
+            SELECT id, keeper FROM (
+                SELECT id, first_value(id) OVER (
+                    PARTITION BY lower(local)
+                    ORDER BY local = lower(local) DESC, created_at, id
+                ) AS keeper
+                FROM mailboxes
+            ) ranked
+            WHERE id <> keeper
+            "#,
+            "UPDATE messages t SET mailbox_id = g.keeper FROM mailbox_merges g WHERE t.mailbox_id = g.id",
+            "UPDATE extraction_rules t SET mailbox_id = g.keeper FROM mailbox_merges g WHERE t.mailbox_id = g.id",
+            r#"
+            INSERT INTO transcript_mailboxes (transcript_id, mailbox_id)
+            SELECT tm.transcript_id, g.keeper FROM transcript_mailboxes tm
+            JOIN mailbox_merges g ON g.id = tm.mailbox_id
+            ON CONFLICT DO NOTHING
+            "#,
+            r#"
+            UPDATE dmarc_reports t SET mailbox_id = g.keeper FROM mailbox_merges g
+            WHERE t.mailbox_id = g.id AND NOT EXISTS (
+                SELECT 1 FROM dmarc_reports k
+                WHERE k.mailbox_id = g.keeper AND k.org_name = t.org_name AND k.report_id = t.report_id
+            )
+            "#,
+            r#"
+            UPDATE tls_reports t SET mailbox_id = g.keeper FROM mailbox_merges g
+            WHERE t.mailbox_id = g.id AND NOT EXISTS (
+                SELECT 1 FROM tls_reports k
+                WHERE k.mailbox_id = g.keeper AND k.org_name = t.org_name AND k.report_id = t.report_id
+            )
+            "#,
+            // anything left on a merged mailbox is a duplicate and goes with it
+            "DELETE FROM mailboxes WHERE id IN (SELECT id FROM mailbox_merges)",
+            "UPDATE mailboxes SET local = lower(local) WHERE local <> lower(local)",
+        ] {
+            sqlx::query(statement).execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
         Ok(())
     }
 
     // FIX E0061: Updated signature to accept ttl_seconds
     pub async fn create_mailbox(&self, local: &str, ttl_seconds: Option<i64>) -> Result<Mailbox> {
+        let local = normalize_local(local);
         let query = if ttl_seconds.is_some() {
             // Use an SQL expression to calculate expires_at
             sqlx::query(
-                "INSERT INTO mailboxes (local, expires_at) VALUES ($1, NOW() + INTERVAL '1 second' * $2) RETURNING id, local, created_at, expires_at"
+                "INSERT INTO mailboxes (local, expires_at) VALUES ($1, NOW() + INTERVAL '1 second' * $2) RETURNING id, local, created_at, expires_at, record_transcripts"
             )
-            .bind(local)
+            .bind(&local)
             .bind(ttl_seconds.unwrap_or(0))
         } else {
             sqlx::query(
-                "INSERT INTO mailboxes (local) VALUES ($1) RETURNING id, local, created_at, expires_at"
+                "INSERT INTO mailboxes (local) VALUES ($1) RETURNING id, local, created_at, expires_at, record_transcripts"
             )
-            .bind(local)
+            .bind(&local)
         };
 
         let row = query.fetch_one(&self.pool).await?;
@@ -120,14 +790,16 @@ impl Db {
             local: row.get("local"),
             created_at: row.get("created_at"),
             expires_at: row.get("expires_at"),
+            record_transcripts: row.get("record_transcripts"),
         })
     }
 
     // Helper to get Mailbox ID
     pub async fn get_mailbox_by_local(&self, local: &str) -> Result<Option<Mailbox>> {
+        let local = normalize_local(local);
         let row =
-            sqlx::query("SELECT id, local, created_at, expires_at FROM mailboxes WHERE local = $1")
-                .bind(local)
+            sqlx::query("SELECT id, local, created_at, expires_at, record_transcripts FROM mailboxes WHERE local = $1")
+                .bind(&local)
                 .fetch_optional(&self.pool)
                 .await?;
 
@@ -136,6 +808,7 @@ impl Db {
             local: r.get("local"),
             created_at: r.get("created_at"),
             expires_at: r.get("expires_at"),
+            record_transcripts: r.get("record_transcripts"),
         }))
     }
 
@@ -146,38 +819,44 @@ impl Db {
     }
 
     // FIX E0599: Implementation of list_messages
-    pub async fn list_messages(&self, local: &str) -> Result<Vec<Message>> {
+    pub async fn list_messages(&self, local: &str, filter: &MessageFilter<'_>) -> Result<Vec<Message>> {
         let mailbox = match self.get_mailbox_by_local(local).await? {
             Some(m) => m,
             None => return Ok(vec![]),
         };
 
-        let rows = sqlx::query(
+        let pattern = filter.search.map(|q| {
+            format!(
+                "%{}%",
+                q.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+            )
+        });
+
+        let rows = sqlx::query(&format!(
             r#"
-            SELECT id, mailbox_id, from_addr, to_addr, subject, body_text, body_html, raw, received_at
-            FROM messages
+            SELECT {} FROM messages
             WHERE mailbox_id = $1
+              AND ($2::TEXT IS NULL OR subject ILIKE $2 OR body_text ILIKE $2 OR from_addr ILIKE $2
+                   OR header_from->>'address' ILIKE $2 OR header_from->>'name' ILIKE $2)
+              AND ($3::TEXT IS NULL OR EXISTS (
+                  SELECT 1 FROM jsonb_array_elements(headers) h
+                  WHERE lower(h->>'name') = lower($3)
+                    AND ($4::TEXT IS NULL OR h->>'value' = $4)
+              ))
+              AND quarantined = $5
             ORDER BY received_at DESC
-            "#
-        )
+            "#,
+            MESSAGE_COLUMNS
+        ))
         .bind(mailbox.id)
+        .bind(pattern)
+        .bind(filter.header_name)
+        .bind(filter.header_value)
+        .bind(filter.quarantined)
         .fetch_all(&self.pool)
         .await?;
 
-        Ok(rows
-            .into_iter()
-            .map(|r| Message {
-                id: r.get("id"),
-                mailbox_id: r.get("mailbox_id"),
-                from_addr: r.get("from_addr"),
-                to_addr: r.get("to_addr"),
-                subject: r.get("subject"),
-                body_text: r.get("body_text"),
-                body_html: r.get("body_html"),
-                raw: r.get("raw"), // Changed from raw_email
-                received_at: r.get("received_at"),
-            })
-            .collect())
+        Ok(rows.iter().map(message_from_row).collect())
     }
 
     // FIX E0599: Implementation of get_message (renamed from get_message_by_id)
@@ -188,75 +867,761 @@ impl Db {
             None => return Ok(None),
         };
 
-        let row = sqlx::query(
-            r#"
-            SELECT id, mailbox_id, from_addr, to_addr, subject, body_text, body_html, raw, received_at
-            FROM messages
-            WHERE id = $1 AND mailbox_id = $2
-            "#
-        )
+        let row = sqlx::query(&format!(
+            "SELECT {} FROM messages WHERE id = $1 AND mailbox_id = $2",
+            MESSAGE_COLUMNS
+        ))
         .bind(id)
         .bind(mailbox.id)
         .fetch_optional(&self.pool)
         .await?;
 
-        Ok(row.map(|r| Message {
-            id: r.get("id"),
-            mailbox_id: r.get("mailbox_id"),
-            from_addr: r.get("from_addr"),
-            to_addr: r.get("to_addr"),
-            subject: r.get("subject"),
-            body_text: r.get("body_text"),
-            body_html: r.get("body_html"),
-            raw: r.get("raw"),
-            received_at: r.get("received_at"),
-        }))
+        Ok(row.as_ref().map(message_from_row))
     }
 
-    // ... (other functions from db.rs, like create_message, delete_old_messages, etc.)
-    // Note: I've updated create_message to use raw instead of raw_email and from_addr as Option<String>
+    pub async fn create_message(&self, msg: &NewMessage<'_>) -> Result<Message> {
+        let row = sqlx::query(&format!(
+            r#"
+            INSERT INTO messages (
+                mailbox_id, from_addr, to_addr, envelope_to, header_from, header_to, header_cc,
+                header_reply_to, bcc, subject, body_text, body_html, raw, headers, connection, auth,
+                spam, scan, quarantined, raw_bytes
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20)
+            RETURNING {}
+            "#,
+            MESSAGE_COLUMNS
+        ))
+        .bind(msg.mailbox_id)
+        .bind(msg.from_addr)
+        .bind(msg.to_addr)
+        .bind(msg.envelope_to)
+        .bind(msg.header_from.map(Json))
+        .bind(Json(msg.header_to))
+        .bind(Json(msg.header_cc))
+        .bind(Json(msg.header_reply_to))
+        .bind(msg.bcc)
+        .bind(msg.subject)
+        .bind(msg.body_text)
+        .bind(msg.body_html)
+        .bind(msg.raw)
+        .bind(Json(msg.headers))
+        .bind(Json(msg.connection))
+        .bind(Json(msg.auth))
+        .bind(Json(msg.spam))
+        .bind(msg.scan.map(Json))
+        .bind(msg.quarantined)
+        .bind(msg.raw_bytes)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(message_from_row(&row))
+    }
 
-    pub async fn create_message(
+    pub async fn create_attachment(
         &self,
-        mailbox_id: Uuid,
-        from_addr: Option<&str>, // Changed to Option
-        to_addr: &str,
-        subject: &str,
-        body_text: &str,
-        body_html: Option<&str>,
-        raw_email: &str, // Renaming this to 'raw' in usage
-    ) -> Result<Message> {
-        let row = sqlx::query(
+        message_id: Uuid,
+        attachment: &NewAttachment,
+    ) -> Result<Attachment> {
+        let row = sqlx::query(&format!(
             r#"
-            INSERT INTO messages (mailbox_id, from_addr, to_addr, subject, body_text, body_html, raw)
+            INSERT INTO attachments (message_id, content_id, filename, content_type, disposition, size, data)
             VALUES ($1, $2, $3, $4, $5, $6, $7)
-            RETURNING id, mailbox_id, from_addr, to_addr, subject, body_text, body_html, raw, received_at
-            "#
-        )
-        .bind(mailbox_id)
-        .bind(from_addr)
-        .bind(to_addr)
-        .bind(subject)
-        .bind(body_text)
-        .bind(body_html)
-        .bind(raw_email)
+            RETURNING {}
+            "#,
+            ATTACHMENT_COLUMNS
+        ))
+        .bind(message_id)
+        .bind(&attachment.content_id)
+        .bind(&attachment.filename)
+        .bind(&attachment.content_type)
+        .bind(&attachment.disposition)
+        .bind(attachment.data.len() as i64)
+        .bind(&attachment.data)
         .fetch_one(&self.pool)
         .await?;
 
-        Ok(Message {
-            id: row.get("id"),
-            mailbox_id: row.get("mailbox_id"),
-            from_addr: row.get("from_addr"),
-            to_addr: row.get("to_addr"),
-            subject: row.get("subject"),
-            body_text: row.get("body_text"),
-            body_html: row.get("body_html"),
-            raw: row.get("raw"), // Changed from raw_email
-            received_at: row.get("received_at"),
+        Ok(attachment_from_row(&row))
+    }
+
+    pub async fn list_attachments(&self, message_id: Uuid) -> Result<Vec<Attachment>> {
+        let rows = sqlx::query(&format!(
+            "SELECT {} FROM attachments WHERE message_id = $1 ORDER BY filename NULLS LAST",
+            ATTACHMENT_COLUMNS
+        ))
+        .bind(message_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.iter().map(attachment_from_row).collect())
+    }
+
+    /// Fetch an attachment, checking that it belongs to the given message in the given mailbox.
+    pub async fn get_attachment(
+        &self,
+        local: &str,
+        message_id: Uuid,
+        attachment_id: Uuid,
+    ) -> Result<Option<Attachment>> {
+        let local = normalize_local(local);
+        let row = sqlx::query(&format!(
+            r#"
+            SELECT {}
+            FROM attachments
+            WHERE id = $1 AND message_id = $2 AND message_id IN (
+                SELECT m.id FROM messages m
+                JOIN mailboxes mb ON mb.id = m.mailbox_id
+                WHERE mb.local = $3
+            )
+            "#,
+            ATTACHMENT_COLUMNS
+        ))
+        .bind(attachment_id)
+        .bind(message_id)
+        .bind(&local)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.as_ref().map(attachment_from_row))
+    }
+
+    /// The message exactly as received, for re-parsing its MIME structure. Falls back to
+    /// the `raw` text, which is lossless for UTF-8 messages and all that older rows have.
+    pub async fn message_source(&self, message_id: Uuid) -> Result<Option<Vec<u8>>> {
+        let source: Option<Vec<u8>> = sqlx::query_scalar(
+            "SELECT COALESCE(raw_bytes, convert_to(raw, 'UTF8')) FROM messages WHERE id = $1",
+        )
+        .bind(message_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(source)
+    }
+
+    pub async fn message_quarantined(&self, message_id: Uuid) -> Result<bool> {
+        let quarantined: Option<bool> =
+            sqlx::query_scalar("SELECT quarantined FROM messages WHERE id = $1")
+                .bind(message_id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(quarantined.unwrap_or(false))
+    }
+
+    pub async fn create_extraction(
+        &self,
+        message_id: Uuid,
+        extraction: &NewExtraction,
+    ) -> Result<Extraction> {
+        let row = sqlx::query(&format!(
+            r#"
+            INSERT INTO extractions (message_id, kind, value, label, rule)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING {}
+            "#,
+            EXTRACTION_COLUMNS
+        ))
+        .bind(message_id)
+        .bind(&extraction.kind)
+        .bind(&extraction.value)
+        .bind(&extraction.label)
+        .bind(&extraction.rule)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(extraction_from_row(&row))
+    }
+
+    pub async fn list_extractions(
+        &self,
+        local: &str,
+        message_id: Uuid,
+        kind: &str,
+    ) -> Result<Vec<Extraction>> {
+        let local = normalize_local(local);
+        let rows = sqlx::query(&format!(
+            r#"
+            SELECT {}
+            FROM extractions
+            WHERE message_id = $1 AND kind = $2 AND message_id IN (
+                SELECT m.id FROM messages m
+                JOIN mailboxes mb ON mb.id = m.mailbox_id
+                WHERE mb.local = $3
+            )
+            ORDER BY created_at, id
+            "#,
+            EXTRACTION_COLUMNS
+        ))
+        .bind(message_id)
+        .bind(kind)
+        .bind(&local)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.iter().map(extraction_from_row).collect())
+    }
+
+    /// Most recent extraction of `kind` across the mailbox, by message arrival time.
+    /// Quarantined messages are left out, like they are from the inbox.
+    pub async fn latest_extraction(&self, local: &str, kind: &str) -> Result<Option<Extraction>> {
+        let local = normalize_local(local);
+        let row = sqlx::query(
+            r#"
+            SELECT e.id, e.message_id, e.kind, e.value, e.label, e.rule, e.created_at
+            FROM extractions e
+            JOIN messages m ON m.id = e.message_id
+            JOIN mailboxes mb ON mb.id = m.mailbox_id
+            WHERE mb.local = $1 AND e.kind = $2 AND NOT m.quarantined
+            ORDER BY m.received_at DESC, e.created_at
+            LIMIT 1
+            "#,
+        )
+        .bind(&local)
+        .bind(kind)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.as_ref().map(extraction_from_row))
+    }
+
+    pub async fn create_extraction_rule(
+        &self,
+        mailbox_id: Uuid,
+        kind: &str,
+        name: &str,
+        pattern: &str,
+    ) -> Result<ExtractionRule> {
+        let row = sqlx::query(
+            r#"
+            INSERT INTO extraction_rules (mailbox_id, kind, name, pattern)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id, mailbox_id, kind, name, pattern, created_at
+            "#,
+        )
+        .bind(mailbox_id)
+        .bind(kind)
+        .bind(name)
+        .bind(pattern)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(rule_from_row(&row))
+    }
+
+    pub async fn list_extraction_rules(&self, mailbox_id: Uuid) -> Result<Vec<ExtractionRule>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, mailbox_id, kind, name, pattern, created_at
+            FROM extraction_rules
+            WHERE mailbox_id = $1
+            ORDER BY created_at
+            "#,
+        )
+        .bind(mailbox_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.iter().map(rule_from_row).collect())
+    }
+
+    pub async fn delete_extraction_rule(&self, mailbox_id: Uuid, id: Uuid) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM extraction_rules WHERE id = $1 AND mailbox_id = $2")
+            .bind(id)
+            .bind(mailbox_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn set_record_transcripts(&self, local: &str, enabled: bool) -> Result<bool> {
+        let local = normalize_local(local);
+        let result = sqlx::query("UPDATE mailboxes SET record_transcripts = $2 WHERE local = $1")
+            .bind(&local)
+            .bind(enabled)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Store a session transcript and attach it to the given mailboxes.
+    pub async fn create_transcript(
+        &self,
+        transcript: &SessionTranscript,
+        mailbox_ids: &[Uuid],
+    ) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        let id: Uuid = sqlx::query_scalar(
+            r#"
+            INSERT INTO transcripts (session_id, client_ip, helo, outcome, started_at, ended_at, lines)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            RETURNING id
+            "#,
+        )
+        .bind(&transcript.session_id)
+        .bind(&transcript.client_ip)
+        .bind(&transcript.helo)
+        .bind(&transcript.outcome)
+        .bind(transcript.started_at)
+        .bind(transcript.ended_at)
+        .bind(Json(&transcript.lines))
+        .fetch_one(&mut *tx)
+        .await?;
+
+        for mailbox_id in mailbox_ids {
+            sqlx::query("INSERT INTO transcript_mailboxes (transcript_id, mailbox_id) VALUES ($1, $2)")
+                .bind(id)
+                .bind(mailbox_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Apply transcript retention: drop anything older than `days`, then all but
+    /// the newest `max_per_mailbox` transcripts of each mailbox.
+    pub async fn prune_transcripts(&self, days: i64, max_per_mailbox: i64) -> Result<u64> {
+        let expired = sqlx::query("DELETE FROM transcripts WHERE ended_at < NOW() - INTERVAL '1 day' * $1")
+            .bind(days)
+            .execute(&self.pool)
+            .await?;
+
+        let excess = sqlx::query(
+            r#"
+            DELETE FROM transcripts WHERE id IN (
+                SELECT transcript_id FROM (
+                    SELECT tm.transcript_id,
+                           ROW_NUMBER() OVER (PARTITION BY tm.mailbox_id ORDER BY t.ended_at DESC) AS n
+                    FROM transcript_mailboxes tm
+                    JOIN transcripts t ON t.id = tm.transcript_id
+                ) ranked
+                WHERE n > $1
+            )
+            "#,
+        )
+        .bind(max_per_mailbox)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(expired.rows_affected() + excess.rows_affected())
+    }
+
+    pub async fn list_transcripts(&self, local: &str) -> Result<Vec<SessionTranscript>> {
+        let local = normalize_local(local);
+        let rows = sqlx::query(&format!(
+            r#"
+            SELECT {}
+            FROM transcripts t
+            JOIN transcript_mailboxes tm ON tm.transcript_id = t.id
+            JOIN mailboxes mb ON mb.id = tm.mailbox_id
+            WHERE mb.local = $1
+            ORDER BY t.started_at DESC
+            "#,
+            TRANSCRIPT_COLUMNS
+        ))
+        .bind(&local)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.iter().map(transcript_from_row).collect())
+    }
+
+    /// Fetch a transcript of the mailbox by its id or by the SMTP session id.
+    pub async fn get_transcript(&self, local: &str, id_or_session: &str) -> Result<Option<SessionTranscript>> {
+        let local = normalize_local(local);
+        let row = sqlx::query(&format!(
+            r#"
+            SELECT {}
+            FROM transcripts t
+            JOIN transcript_mailboxes tm ON tm.transcript_id = t.id
+            JOIN mailboxes mb ON mb.id = tm.mailbox_id
+            WHERE mb.local = $1 AND (t.id::TEXT = $2 OR t.session_id = $2)
+            "#,
+            TRANSCRIPT_COLUMNS
+        ))
+        .bind(&local)
+        .bind(id_or_session)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.as_ref().map(transcript_from_row))
+    }
+
+    /// Ids of the mailbox's messages that were received in the given SMTP session.
+    pub async fn session_message_ids(&self, local: &str, session_id: &str) -> Result<Vec<Uuid>> {
+        let local = normalize_local(local);
+        let rows = sqlx::query(
+            r#"
+            SELECT m.id
+            FROM messages m
+            JOIN mailboxes mb ON mb.id = m.mailbox_id
+            WHERE mb.local = $1 AND m.connection->>'session_id' = $2
+            ORDER BY m.received_at
+            "#,
+        )
+        .bind(&local)
+        .bind(session_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.iter().map(|r| r.get("id")).collect())
+    }
+
+    /// Store a DMARC aggregate report; returns false if the mailbox already has it.
+    pub async fn create_dmarc_report(
+        &self,
+        message_id: Uuid,
+        mailbox_id: Uuid,
+        report: &NewDmarcReport,
+    ) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+
+        let id: Option<Uuid> = sqlx::query_scalar(
+            r#"
+            INSERT INTO dmarc_reports (message_id, mailbox_id, org_name, report_id, email, domain, policy, date_begin, date_end)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            ON CONFLICT (mailbox_id, org_name, report_id) DO NOTHING
+            RETURNING id
+            "#,
+        )
+        .bind(message_id)
+        .bind(mailbox_id)
+        .bind(&report.org_name)
+        .bind(&report.report_id)
+        .bind(&report.email)
+        .bind(&report.domain)
+        .bind(&report.policy)
+        .bind(report.date_begin)
+        .bind(report.date_end)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some(id) = id else {
+            return Ok(false);
+        };
+
+        for record in &report.records {
+            sqlx::query(
+                r#"
+                INSERT INTO dmarc_report_records (report_id, source_ip, count, disposition, dkim, spf, header_from, envelope_from)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                "#,
+            )
+            .bind(id)
+            .bind(&record.source_ip)
+            .bind(record.count)
+            .bind(&record.disposition)
+            .bind(&record.dkim)
+            .bind(&record.spf)
+            .bind(&record.header_from)
+            .bind(&record.envelope_from)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    /// Store a TLS-RPT report; returns false if the mailbox already has it.
+    pub async fn create_tls_report(
+        &self,
+        message_id: Uuid,
+        mailbox_id: Uuid,
+        report: &NewTlsReport,
+    ) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+
+        let id: Option<Uuid> = sqlx::query_scalar(
+            r#"
+            INSERT INTO tls_reports (message_id, mailbox_id, org_name, report_id, contact, date_begin, date_end)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (mailbox_id, org_name, report_id) DO NOTHING
+            RETURNING id
+            "#,
+        )
+        .bind(message_id)
+        .bind(mailbox_id)
+        .bind(&report.org_name)
+        .bind(&report.report_id)
+        .bind(&report.contact)
+        .bind(report.date_begin)
+        .bind(report.date_end)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some(id) = id else {
+            return Ok(false);
+        };
+
+        for policy in &report.policies {
+            sqlx::query(
+                r#"
+                INSERT INTO tls_report_policies (report_id, policy_type, policy_domain, successful, failed, failure_details)
+                VALUES ($1, $2, $3, $4, $5, $6)
+                "#,
+            )
+            .bind(id)
+            .bind(&policy.policy_type)
+            .bind(&policy.policy_domain)
+            .bind(policy.successful)
+            .bind(policy.failed)
+            .bind(Json(&policy.failure_details))
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    /// Per-domain DMARC pass/fail totals with a breakdown by source IP.
+    pub async fn dmarc_summary(&self, local: &str) -> Result<Vec<DmarcDomainSummary>> {
+        let local = normalize_local(local);
+        let rows = sqlx::query(
+            r#"
+            SELECT r.domain,
+                   COUNT(DISTINCT r.id) AS reports,
+                   COALESCE(SUM(rec.count), 0)::BIGINT AS messages,
+                   COALESCE(SUM(rec.count) FILTER (WHERE rec.dkim = 'pass' OR rec.spf = 'pass'), 0)::BIGINT AS passed,
+                   MIN(r.date_begin) AS first_seen,
+                   MAX(r.date_end) AS last_seen
+            FROM dmarc_reports r
+            JOIN mailboxes mb ON mb.id = r.mailbox_id
+            LEFT JOIN dmarc_report_records rec ON rec.report_id = r.id
+            WHERE mb.local = $1
+            GROUP BY r.domain
+            ORDER BY messages DESC
+            "#,
+        )
+        .bind(&local)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let source_rows = sqlx::query(
+            r#"
+            SELECT r.domain,
+                   rec.source_ip,
+                   SUM(rec.count)::BIGINT AS messages,
+                   COALESCE(SUM(rec.count) FILTER (WHERE rec.dkim = 'pass' OR rec.spf = 'pass'), 0)::BIGINT AS passed
+            FROM dmarc_reports r
+            JOIN mailboxes mb ON mb.id = r.mailbox_id
+            JOIN dmarc_report_records rec ON rec.report_id = r.id
+            WHERE mb.local = $1
+            GROUP BY r.domain, rec.source_ip
+            ORDER BY messages DESC
+            "#,
+        )
+        .bind(&local)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .iter()
+            .map(|r| {
+                let domain: String = r.get("domain");
+                let messages: i64 = r.get("messages");
+                let passed: i64 = r.get("passed");
+                let sources = source_rows
+                    .iter()
+                    .filter(|s| s.get::<String, _>("domain") == domain)
+                    .map(|s| {
+                        let messages: i64 = s.get("messages");
+                        let passed: i64 = s.get("passed");
+                        DmarcSourceSummary {
+                            source_ip: s.get("source_ip"),
+                            messages,
+                            passed,
+                            failed: messages - passed,
+                        }
+                    })
+                    .collect();
+                DmarcDomainSummary {
+                    domain,
+                    reports: r.get("reports"),
+                    messages,
+                    passed,
+                    failed: messages - passed,
+                    first_seen: r.get("first_seen"),
+                    last_seen: r.get("last_seen"),
+                    sources,
+                }
+            })
+            .collect())
+    }
+
+    /// Per-policy-domain TLS session totals with failures grouped by result type.
+    pub async fn tls_summary(&self, local: &str) -> Result<Vec<TlsDomainSummary>> {
+        let local = normalize_local(local);
+        let rows = sqlx::query(
+            r#"
+            SELECT p.policy_domain,
+                   COUNT(DISTINCT r.id) AS reports,
+                   SUM(p.successful)::BIGINT AS successful,
+                   SUM(p.failed)::BIGINT AS failed
+            FROM tls_reports r
+            JOIN mailboxes mb ON mb.id = r.mailbox_id
+            JOIN tls_report_policies p ON p.report_id = r.id
+            WHERE mb.local = $1
+            GROUP BY p.policy_domain
+            ORDER BY p.policy_domain
+            "#,
+        )
+        .bind(&local)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let failure_rows = sqlx::query(
+            r#"
+            SELECT p.policy_domain,
+                   d->>'result-type' AS result_type,
+                   SUM(COALESCE((d->>'failed-session-count')::BIGINT, 0))::BIGINT AS sessions
+            FROM tls_reports r
+            JOIN mailboxes mb ON mb.id = r.mailbox_id
+            JOIN tls_report_policies p ON p.report_id = r.id
+            CROSS JOIN LATERAL jsonb_array_elements(p.failure_details) d
+            WHERE mb.local = $1
+            GROUP BY p.policy_domain, d->>'result-type'
+            ORDER BY sessions DESC
+            "#,
+        )
+        .bind(&local)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .iter()
+            .map(|r| {
+                let policy_domain: String = r.get("policy_domain");
+                let failures = failure_rows
+                    .iter()
+                    .filter(|f| f.get::<String, _>("policy_domain") == policy_domain)
+                    .map(|f| TlsFailureSummary {
+                        result_type: f
+                            .get::<Option<String>, _>("result_type")
+                            .unwrap_or_else(|| "unknown".into()),
+                        sessions: f.get("sessions"),
+                    })
+                    .collect();
+                TlsDomainSummary {
+                    policy_domain,
+                    reports: r.get("reports"),
+                    successful: r.get("successful"),
+                    failed: r.get("failed"),
+                    failures,
+                }
+            })
+            .collect())
+    }
+
+    /// Every DMARC and TLS report of the mailbox, newest period first.
+    pub async fn list_reports(&self, local: &str) -> Result<Vec<ReportSummary>> {
+        let local = normalize_local(local);
+        let rows = sqlx::query(
+            r#"
+            SELECT r.id, r.message_id, 'dmarc' AS kind, r.org_name, r.report_id, r.domain, r.date_begin, r.date_end
+            FROM dmarc_reports r
+            JOIN mailboxes mb ON mb.id = r.mailbox_id
+            WHERE mb.local = $1
+            UNION ALL
+            SELECT r.id, r.message_id, 'tls' AS kind, r.org_name, r.report_id, NULL AS domain, r.date_begin, r.date_end
+            FROM tls_reports r
+            JOIN mailboxes mb ON mb.id = r.mailbox_id
+            WHERE mb.local = $1
+            ORDER BY date_end DESC
+            "#,
+        )
+        .bind(&local)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .iter()
+            .map(|r| ReportSummary {
+                id: r.get("id"),
+                message_id: r.get("message_id"),
+                kind: r.get("kind"),
+                org_name: r.get("org_name"),
+                report_id: r.get("report_id"),
+                domain: r.get("domain"),
+                date_begin: r.get("date_begin"),
+                date_end: r.get("date_end"),
+            })
+            .collect())
+    }
+
+    /// Count a delivery attempt for a greylisting triplet, creating it on first sight.
+    pub async fn record_greylist_attempt(
+        &self,
+        network: &str,
+        sender: &str,
+        recipient: &str,
+    ) -> Result<GreylistEntry> {
+        let row = sqlx::query(
+            r#"
+            INSERT INTO greylist (network, sender, recipient)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (network, sender, recipient)
+            DO UPDATE SET attempts = greylist.attempts + 1
+            RETURNING first_seen, passed_at, attempts
+            "#,
+        )
+        .bind(network)
+        .bind(sender)
+        .bind(recipient)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(GreylistEntry {
+            first_seen: row.get("first_seen"),
+            passed_at: row.get("passed_at"),
+            attempts: row.get("attempts"),
         })
     }
 
+    pub async fn pass_greylist(&self, network: &str, sender: &str, recipient: &str) -> Result<()> {
+        sqlx::query(
+            "UPDATE greylist SET passed_at = NOW() WHERE network = $1 AND sender = $2 AND recipient = $3",
+        )
+        .bind(network)
+        .bind(sender)
+        .bind(recipient)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Start the delay over for a triplet that expired.
+    pub async fn restart_greylist(&self, network: &str, sender: &str, recipient: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE greylist SET first_seen = NOW(), passed_at = NULL, attempts = 1
+            WHERE network = $1 AND sender = $2 AND recipient = $3
+            "#,
+        )
+        .bind(network)
+        .bind(sender)
+        .bind(recipient)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Drop triplets that have not been seen or passed for `seconds`.
+    pub async fn prune_greylist(&self, seconds: i64) -> Result<u64> {
+        let result = sqlx::query(
+            "DELETE FROM greylist WHERE COALESCE(passed_at, first_seen) < NOW() - INTERVAL '1 second' * $1",
+        )
+        .bind(seconds)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
     // ... (rest of the Db impl unchanged)
+    #[allow(dead_code)] // cleanup is currently driven by cron (see README)
     pub async fn delete_old_messages(&self, days: i64) -> Result<u64> {
         let result =
             sqlx::query("DELETE FROM messages WHERE received_at < NOW() - INTERVAL '1 day' * $1")
@@ -267,6 +1632,7 @@ impl Db {
         Ok(result.rows_affected())
     }
 
+    #[allow(dead_code)]
     pub async fn delete_old_mailboxes(&self, days: i64) -> Result<u64> {
         let result =
             sqlx::query("DELETE FROM mailboxes WHERE created_at < NOW() - INTERVAL '1 day' * $1")
@@ -277,3 +1643,168 @@ impl Db {
         Ok(result.rows_affected())
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Tests that need Postgres run against `TEST_DATABASE_URL` and are skipped without it.
+    pub(crate) async fn test_db() -> Option<Db> {
+        let url = std::env::var("TEST_DATABASE_URL").ok()?;
+        let db = Db::new(&url).await.expect("connect to TEST_DATABASE_URL");
+        db.run_migrations().await.expect("run migrations");
+        Some(db)
+    }
+
+    /// Delete a mailbox a test created, with everything stored for it.
+    pub(crate) async fn remove_mailbox(db: &Db, id: Uuid) {
+        sqlx::query("DELETE FROM mailboxes WHERE id = $1")
+            .bind(id)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+    }
+
+    /// Store a bare message with the given subject and headers.
+    pub(crate) async fn insert_message(
+        db: &Db,
+        mailbox_id: Uuid,
+        subject: &str,
+        headers: &[(&str, &str)],
+    ) -> Uuid {
+        let headers: Vec<MessageHeader> = headers
+            .iter()
+            .map(|(name, value)| MessageHeader {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+            .collect();
+        sqlx::query_scalar(
+            "INSERT INTO messages (mailbox_id, to_addr, subject, body_text, raw, headers) \
+             VALUES ($1, '', $2, '', '', $3) RETURNING id",
+        )
+        .bind(mailbox_id)
+        .bind(subject)
+        .bind(Json(headers))
+        .fetch_one(&db.pool)
+        .await
+        .unwrap()
+    }
+
+    /// Move the greylisting triplets of `sender` back in time by `seconds`.
+    pub(crate) async fn age_greylist(db: &Db, sender: &str, seconds: i64) {
+        sqlx::query(
+            "UPDATE greylist SET first_seen = first_seen - INTERVAL '1 second' * $2, \
+             passed_at = passed_at - INTERVAL '1 second' * $2 WHERE sender = $1",
+        )
+        .bind(sender)
+        .bind(seconds)
+        .execute(&db.pool)
+        .await
+        .unwrap();
+    }
+
+    pub(crate) async fn remove_greylist(db: &Db, sender: &str) {
+        sqlx::query("DELETE FROM greylist WHERE sender = $1")
+            .bind(sender)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+    }
+
+    async fn insert_mailbox(db: &Db, local: &str) -> Uuid {
+        let id: Uuid = sqlx::query_scalar("INSERT INTO mailboxes (local) VALUES ($1) RETURNING id")
+            .bind(local)
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        sqlx::query(
+            "INSERT INTO messages (mailbox_id, to_addr, subject, body_text, raw) VALUES ($1, '', '', '', '')",
+        )
+        .bind(id)
+        .execute(&db.pool)
+        .await
+        .unwrap();
+        id
+    }
+
+    #[tokio::test]
+    async fn mixed_case_mailboxes_are_folded() {
+        let Some(db) = test_db().await else {
+            return;
+        };
+        let tag: String = Uuid::new_v4().simple().to_string()[..8].to_string();
+        let mixed = format!("aB3{}", tag);
+        let upper = format!("DuP{}", tag);
+        let lower = upper.to_lowercase();
+
+        let mixed_id = insert_mailbox(&db, &mixed).await;
+        let upper_id = insert_mailbox(&db, &upper).await;
+        let lower_id = insert_mailbox(&db, &lower).await;
+        db.fold_mailbox_case().await.unwrap();
+
+        let found = db.get_mailbox_by_local(&mixed).await.unwrap().unwrap();
+        assert_eq!(found.id, mixed_id);
+        assert_eq!(found.local, mixed.to_lowercase());
+
+        let merged = db.get_mailbox_by_local(&upper).await.unwrap().unwrap();
+        assert_eq!(merged.id, lower_id);
+        let messages: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM messages WHERE mailbox_id = $1")
+                .bind(lower_id)
+                .fetch_one(&db.pool)
+                .await
+                .unwrap();
+        assert_eq!(messages, 2);
+        let gone: bool =
+            sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM mailboxes WHERE id = $1)")
+                .bind(upper_id)
+                .fetch_one(&db.pool)
+                .await
+                .unwrap();
+        assert!(!gone);
+
+        sqlx::query("DELETE FROM mailboxes WHERE id = ANY($1)")
+            .bind(vec![mixed_id, lower_id])
+            .execute(&db.pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn message_source_prefers_the_received_bytes() {
+        let Some(db) = test_db().await else {
+            return;
+        };
+        let local = format!("src{}", &Uuid::new_v4().simple().to_string()[..8]);
+        let mailbox_id = insert_mailbox(&db, &local).await;
+        let source = |raw: &str, raw_bytes: Option<&[u8]>| {
+            sqlx::query_scalar::<_, Uuid>(
+                "INSERT INTO messages (mailbox_id, to_addr, subject, body_text, raw, raw_bytes) \
+                 VALUES ($1, '', '', '', $2, $3) RETURNING id",
+            )
+            .bind(mailbox_id)
+            .bind(raw.to_string())
+            .bind(raw_bytes.map(<[u8]>::to_vec))
+            .fetch_one(&db.pool)
+        };
+
+        let text = source("Subject: caf\u{e9}\r\n\r\nhi", None).await.unwrap();
+        assert_eq!(
+            db.message_source(text).await.unwrap().unwrap(),
+            "Subject: caf\u{e9}\r\n\r\nhi".as_bytes()
+        );
+        let binary = source("\r\n\u{fffd}", Some(b"\r\n\xff\x00")).await.unwrap();
+        assert_eq!(
+            db.message_source(binary).await.unwrap().unwrap(),
+            b"\r\n\xff\x00"
+        );
+        assert_eq!(db.message_source(Uuid::new_v4()).await.unwrap(), None);
+
+        sqlx::query("DELETE FROM mailboxes WHERE id = $1")
+            .bind(mailbox_id)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,650 @@
+//! DKIM signature verification (RFC 6376, Ed25519 keys per RFC 8463).
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ed25519_dalek::Verifier;
+use rsa::pkcs1::DecodeRsaPublicKey;
+use rsa::pkcs8::DecodePublicKey;
+use rsa::traits::PublicKeyParts;
+use rsa::{Pkcs1v15Sign, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::dns::{DnsError, Resolver};
+
+/// Signatures beyond this many are ignored so a message cannot force unbounded key lookups.
+const MAX_SIGNATURES: usize = 8;
+/// RSA keys shorter than this are rejected (RFC 8301 §3.2).
+const MIN_RSA_BITS: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DkimResult {
+    Pass,
+    Fail,
+    Neutral,
+    TempError,
+    PermError,
+}
+
+impl DkimResult {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DkimResult::Pass => "pass",
+            DkimResult::Fail => "fail",
+            DkimResult::Neutral => "neutral",
+            DkimResult::TempError => "temperror",
+            DkimResult::PermError => "permerror",
+        }
+    }
+}
+
+/// Outcome of verifying one `DKIM-Signature` header.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DkimCheck {
+    pub result: DkimResult,
+    /// Signing domain (`d=`).
+    pub domain: Option<String>,
+    /// Key selector (`s=`).
+    pub selector: Option<String>,
+    /// Signing algorithm (`a=`).
+    pub algorithm: Option<String>,
+    /// Agent or user identifier (`i=`).
+    pub identity: Option<String>,
+    /// Start of the signature value, to tell signatures of the same domain apart.
+    pub header_b: Option<String>,
+    pub reason: Option<String>,
+}
+
+/// Verify every DKIM signature of a message as received.
+pub async fn verify(resolver: &dyn Resolver, raw: &[u8]) -> Vec<DkimCheck> {
+    let message = to_crlf(raw);
+    let (headers, body) = split_message(&message);
+    let fields = parse_fields(headers);
+
+    let mut checks = Vec::new();
+    for field in fields
+        .iter()
+        .filter(|f| f.name.eq_ignore_ascii_case("DKIM-Signature"))
+        .take(MAX_SIGNATURES)
+    {
+        checks.push(verify_signature(resolver, field, &fields, body).await);
+    }
+    checks
+}
+
+/// A raw header field; `text` is the whole field, folding included, without the final CRLF.
+/// It is kept as bytes since a signed field may carry any 8-bit text.
+pub(crate) struct Field<'a> {
+    pub name: &'a str,
+    pub text: &'a [u8],
+}
+
+impl Field<'_> {
+    /// Everything after the colon, still folded; empty unless it is UTF-8, which
+    /// signature and seal fields always are.
+    pub fn value(&self) -> &str {
+        let value = match self.text.iter().position(|&b| b == b':') {
+            Some(colon) => &self.text[colon + 1..],
+            None => &[],
+        };
+        std::str::from_utf8(value).unwrap_or("")
+    }
+}
+
+/// Turn bare LF line endings into CRLF so canonicalization sees what was signed.
+pub(crate) fn to_crlf(raw: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(raw.len() + raw.len() / 40);
+    let mut prev = 0u8;
+    for &b in raw {
+        if b == b'\n' && prev != b'\r' {
+            out.push(b'\r');
+        }
+        out.push(b);
+        prev = b;
+    }
+    out
+}
+
+/// Header section and body of a CRLF message.
+pub(crate) fn split_message(message: &[u8]) -> (&[u8], &[u8]) {
+    match find(message, b"\r\n\r\n") {
+        Some(i) => (&message[..i + 2], &message[i + 4..]),
+        None => (message, &[][..]),
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+pub(crate) fn parse_fields(headers: &[u8]) -> Vec<Field<'_>> {
+    let mut fields = Vec::new();
+    let mut start: Option<usize> = None;
+    let mut pos = 0;
+
+    for line in headers.split_inclusive(|&b| b == b'\n') {
+        let continuation = line.starts_with(b" ") || line.starts_with(b"\t");
+        if !continuation {
+            if let Some(s) = start.take() {
+                push_field(&mut fields, &headers[s..pos]);
+            }
+            start = Some(pos);
+        }
+        pos += line.len();
+    }
+    if let Some(s) = start {
+        push_field(&mut fields, &headers[s..pos]);
+    }
+    fields
+}
+
+fn push_field<'a>(fields: &mut Vec<Field<'a>>, text: &'a [u8]) {
+    let text = text.strip_suffix(b"\r\n").unwrap_or(text);
+    let Some(colon) = text.iter().position(|&b| b == b':') else {
+        return;
+    };
+    // field names are printable ASCII (RFC 5322 §3.6.8)
+    if let Ok(name) = std::str::from_utf8(&text[..colon]) {
+        fields.push(Field {
+            name: name.trim_end(),
+            text,
+        });
+    }
+}
+
+/// Parse a `tag=value; ...` list (RFC 6376 §3.2); duplicate tags are an error.
+pub(crate) fn parse_tags(value: &str) -> Result<Vec<(String, String)>, String> {
+    let mut tags: Vec<(String, String)> = Vec::new();
+    for spec in value.split(';') {
+        if spec.trim().is_empty() {
+            continue;
+        }
+        let (name, value) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("malformed tag '{}'", spec.trim()))?;
+        let name = name.trim().to_string();
+        if tags.iter().any(|(n, _)| *n == name) {
+            return Err(format!("duplicate tag {}=", name));
+        }
+        tags.push((name, unfold_value(value)));
+    }
+    Ok(tags)
+}
+
+/// Drop folding line breaks and surrounding whitespace from a tag value.
+fn unfold_value(value: &str) -> String {
+    value.replace("\r\n", "").trim().to_string()
+}
+
+pub(crate) fn tag<'a>(tags: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    tags.iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, v)| v.as_str())
+}
+
+pub(crate) fn strip_whitespace(value: &str) -> String {
+    value.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Canonicalization {
+    Simple,
+    Relaxed,
+}
+
+/// Parse a `c=` value into header and body canonicalization.
+pub(crate) fn parse_canonicalization(
+    value: Option<&str>,
+) -> Option<(Canonicalization, Canonicalization)> {
+    let parse = |s: &str| match s.to_ascii_lowercase().as_str() {
+        "simple" => Some(Canonicalization::Simple),
+        "relaxed" => Some(Canonicalization::Relaxed),
+        _ => None,
+    };
+    match value {
+        None => Some((Canonicalization::Simple, Canonicalization::Simple)),
+        Some(v) => match v.split_once('/') {
+            Some((h, b)) => Some((parse(h)?, parse(b)?)),
+            None => Some((parse(v)?, Canonicalization::Simple)),
+        },
+    }
+}
+
+pub(crate) fn canonical_header(field: &[u8], c: Canonicalization) -> Vec<u8> {
+    let mut out = Vec::with_capacity(field.len() + 2);
+    match c {
+        Canonicalization::Simple => out.extend_from_slice(field),
+        Canonicalization::Relaxed => {
+            let colon = field.iter().position(|&b| b == b':').unwrap_or(field.len());
+            let name = field[..colon].trim_ascii();
+            out.extend(name.iter().map(u8::to_ascii_lowercase));
+            out.push(b':');
+            let mut in_space = false;
+            let mut started = false;
+            for &b in field.get(colon + 1..).unwrap_or_default() {
+                match b {
+                    // unfold
+                    b'\r' | b'\n' => {}
+                    b' ' | b'\t' => in_space = true,
+                    _ => {
+                        if in_space && started {
+                            out.push(b' ');
+                        }
+                        in_space = false;
+                        started = true;
+                        out.push(b);
+                    }
+                }
+            }
+        }
+    }
+    out.extend_from_slice(b"\r\n");
+    out
+}
+
+pub(crate) fn canonical_body(body: &[u8], c: Canonicalization) -> Vec<u8> {
+    let mut lines: Vec<Vec<u8>> = body
+        .split(|&b| b == b'\n')
+        .map(|l| l.strip_suffix(b"\r").unwrap_or(l).to_vec())
+        .collect();
+    // the split leaves an empty piece after the final CRLF
+    if body.ends_with(b"\n") {
+        lines.pop();
+    }
+
+    if c == Canonicalization::Relaxed {
+        for line in lines.iter_mut() {
+            let mut out = Vec::with_capacity(line.len());
+            let mut in_space = false;
+            for &b in line.iter() {
+                if b == b' ' || b == b'\t' {
+                    in_space = true;
+                    continue;
+                }
+                if in_space {
+                    out.push(b' ');
+                }
+                in_space = false;
+                out.push(b);
+            }
+            *line = out;
+        }
+    }
+
+    while lines.last().is_some_and(|l| l.is_empty()) {
+        lines.pop();
+    }
+
+    let mut out = Vec::with_capacity(body.len());
+    for line in &lines {
+        out.extend_from_slice(line);
+        out.extend_from_slice(b"\r\n");
+    }
+    if out.is_empty() && c == Canonicalization::Simple {
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+/// Hash the fields listed in `h=`, taking repeated names from the bottom up,
+/// followed by the signature field itself with its `b=` value removed.
+pub(crate) fn header_hash(
+    fields: &[Field],
+    signed: &[&str],
+    signature: &Field,
+    c: Canonicalization,
+) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    let mut used = vec![false; fields.len()];
+
+    for name in signed {
+        let found = fields
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(i, f)| !used[*i] && f.name.eq_ignore_ascii_case(name.trim()));
+        if let Some((i, field)) = found {
+            used[i] = true;
+            hasher.update(canonical_header(field.text, c));
+        }
+    }
+
+    let own = canonical_header(&strip_b_value(signature.text), c);
+    hasher.update(own.strip_suffix(b"\r\n").unwrap_or(&own));
+    hasher.finalize().to_vec()
+}
+
+/// The field with the value of its `b=` tag emptied, everything else untouched.
+pub(crate) fn strip_b_value(field: &[u8]) -> Vec<u8> {
+    let Some(colon) = field.iter().position(|&b| b == b':') else {
+        return field.to_vec();
+    };
+    let mut out = Vec::with_capacity(field.len());
+    out.extend_from_slice(&field[..=colon]);
+
+    let mut first = true;
+    for spec in field[colon + 1..].split(|&b| b == b';') {
+        if !first {
+            out.push(b';');
+        }
+        first = false;
+        match spec.iter().position(|&b| b == b'=') {
+            Some(eq) if spec[..eq].trim_ascii() == b"b" => out.extend_from_slice(&spec[..=eq]),
+            _ => out.extend_from_slice(spec),
+        }
+    }
+    out
+}
+
+pub(crate) enum PublicKey {
+    Rsa(RsaPublicKey),
+    Ed25519(ed25519_dalek::VerifyingKey),
+}
+
+impl PublicKey {
+    /// Check `signature` over the SHA-256 `hash` of the signed data.
+    pub fn verify(&self, hash: &[u8], signature: &[u8]) -> bool {
+        match self {
+            PublicKey::Rsa(key) => key
+                .verify(Pkcs1v15Sign::new::<Sha256>(), hash, signature)
+                .is_ok(),
+            PublicKey::Ed25519(key) => ed25519_dalek::Signature::from_slice(signature)
+                .is_ok_and(|sig| key.verify(hash, &sig).is_ok()),
+        }
+    }
+}
+
+pub(crate) enum KeyError {
+    Temp(String),
+    Perm(String),
+}
+
+/// Fetch and decode the key record at `<selector>._domainkey.<domain>` (§3.6.1).
+pub(crate) async fn fetch_key(
+    resolver: &dyn Resolver,
+    selector: &str,
+    domain: &str,
+    key_type: &str,
+) -> Result<(PublicKey, Vec<(String, String)>), KeyError> {
+    let name = format!("{}._domainkey.{}", selector, domain);
+    let records = match resolver.txt(&name).await {
+        Ok(records) => records,
+        Err(DnsError::NotFound) => return Err(KeyError::Perm(format!("no key at {}", name))),
+        Err(DnsError::Temporary(e)) => return Err(KeyError::Temp(e)),
+    };
+    let record = match records.as_slice() {
+        [record] => record,
+        _ => return Err(KeyError::Perm(format!("expected one key record at {}", name))),
+    };
+
+    let tags = parse_tags(record).map_err(|e| KeyError::Perm(format!("key record: {}", e)))?;
+    if tag(&tags, "v").is_some_and(|v| v != "DKIM1") {
+        return Err(KeyError::Perm("key record has wrong version".to_string()));
+    }
+    let k = tag(&tags, "k").unwrap_or("rsa");
+    if !k.eq_ignore_ascii_case(key_type) {
+        return Err(KeyError::Perm(format!("key type {} does not match signature", k)));
+    }
+    let p = strip_whitespace(tag(&tags, "p").unwrap_or(""));
+    if p.is_empty() {
+        return Err(KeyError::Perm("key revoked".to_string()));
+    }
+    let der = BASE64
+        .decode(p.as_bytes())
+        .map_err(|_| KeyError::Perm("key is not valid base64".to_string()))?;
+
+    let key = if key_type.eq_ignore_ascii_case("ed25519") {
+        let bytes: [u8; 32] = der
+            .as_slice()
+            .try_into()
+            .map_err(|_| KeyError::Perm("Ed25519 key must be 32 bytes".to_string()))?;
+        PublicKey::Ed25519(
+            ed25519_dalek::VerifyingKey::from_bytes(&bytes)
+                .map_err(|_| KeyError::Perm("invalid Ed25519 key".to_string()))?,
+        )
+    } else {
+        let key = RsaPublicKey::from_public_key_der(&der)
+            .or_else(|_| RsaPublicKey::from_pkcs1_der(&der))
+            .map_err(|_| KeyError::Perm("invalid RSA key".to_string()))?;
+        if key.size() * 8 < MIN_RSA_BITS {
+            return Err(KeyError::Perm(format!(
+                "RSA key shorter than {} bits",
+                MIN_RSA_BITS
+            )));
+        }
+        PublicKey::Rsa(key)
+    };
+
+    Ok((key, tags))
+}
+
+async fn verify_signature(
+    resolver: &dyn Resolver,
+    signature: &Field<'_>,
+    fields: &[Field<'_>],
+    body: &[u8],
+) -> DkimCheck {
+    let mut check = DkimCheck {
+        result: DkimResult::PermError,
+        domain: None,
+        selector: None,
+        algorithm: None,
+        identity: None,
+        header_b: None,
+        reason: None,
+    };
+
+    let tags = match parse_tags(signature.value()) {
+        Ok(tags) => tags,
+        Err(e) => {
+            check.reason = Some(e);
+            return check;
+        }
+    };
+    check.domain = tag(&tags, "d").map(|d| d.to_ascii_lowercase());
+    check.selector = tag(&tags, "s").map(str::to_string);
+    check.algorithm = tag(&tags, "a").map(|a| a.to_ascii_lowercase());
+    check.identity = tag(&tags, "i").map(str::to_string);
+    check.header_b = tag(&tags, "b").map(|b| strip_whitespace(b).chars().take(8).collect());
+
+    match evaluate(resolver, signature, &tags, fields, body).await {
+        Ok(()) => check.result = DkimResult::Pass,
+        Err((result, reason)) => {
+            check.result = result;
+            check.reason = Some(reason);
+        }
+    }
+    check
+}
+
+type Failure = (DkimResult, String);
+
+fn perm(reason: impl Into<String>) -> Failure {
+    (DkimResult::PermError, reason.into())
+}
+
+async fn evaluate(
+    resolver: &dyn Resolver,
+    signature: &Field<'_>,
+    tags: &[(String, String)],
+    fields: &[Field<'_>],
+    body: &[u8],
+) -> Result<(), Failure> {
+    for required in ["v", "a", "b", "bh", "d", "h", "s"] {
+        if tag(tags, required).is_none() {
+            return Err(perm(format!("missing {}= tag", required)));
+        }
+    }
+    if tag(tags, "v") != Some("1") {
+        return Err(perm("unsupported version"));
+    }
+
+    let domain = tag(tags, "d").unwrap_or("").to_ascii_lowercase();
+    let selector = tag(tags, "s").unwrap_or("");
+    let key_type = match tag(tags, "a").unwrap_or("").to_ascii_lowercase().as_str() {
+        "rsa-sha256" => "rsa",
+        "ed25519-sha256" => "ed25519",
+        "rsa-sha1" => return Err(perm("rsa-sha1 is no longer accepted (RFC 8301)")),
+        other => return Err(perm(format!("unknown algorithm {}", other))),
+    };
+    let (header_c, body_c) = parse_canonicalization(tag(tags, "c"))
+        .ok_or_else(|| perm("unknown canonicalization"))?;
+
+    let signed: Vec<&str> = tag(tags, "h").unwrap_or("").split(':').collect();
+    if !signed.iter().any(|h| h.trim().eq_ignore_ascii_case("from")) {
+        return Err(perm("From is not signed"));
+    }
+
+    let identity_domain = match tag(tags, "i") {
+        Some(i) => {
+            let d = i.rsplit_once('@').map(|(_, d)| d).unwrap_or("").to_ascii_lowercase();
+            if d != domain && !d.ends_with(&format!(".{}", domain)) {
+                return Err(perm("i= is not within d="));
+            }
+            Some(d)
+        }
+        None => None,
+    };
+
+    if let Some(expires) = tag(tags, "x") {
+        let expires: i64 = expires.parse().map_err(|_| perm("invalid x= tag"))?;
+        if expires < chrono::Utc::now().timestamp() {
+            return Err(perm("signature expired"));
+        }
+    }
+
+    let (key, key_tags) = match fetch_key(resolver, selector, &domain, key_type).await {
+        Ok(found) => found,
+        Err(KeyError::Temp(e)) => return Err((DkimResult::TempError, e)),
+        Err(KeyError::Perm(e)) => return Err(perm(e)),
+    };
+    if tag(&key_tags, "h").is_some_and(|h| !h.split(':').any(|a| a.trim() == "sha256")) {
+        return Err(perm("key does not allow sha256"));
+    }
+    let strict = tag(&key_tags, "t").is_some_and(|t| t.split(':').any(|f| f.trim() == "s"));
+    if strict && identity_domain.is_some_and(|d| d != domain) {
+        return Err(perm("key requires i= to match d= exactly"));
+    }
+
+    let mut canonical = canonical_body(body, body_c);
+    if let Some(length) = tag(tags, "l") {
+        let length: usize = length.parse().map_err(|_| perm("invalid l= tag"))?;
+        if length > canonical.len() {
+            return Err(perm("l= is longer than the body"));
+        }
+        canonical.truncate(length);
+    }
+    let body_hash = BASE64.encode(Sha256::digest(&canonical));
+    if body_hash != strip_whitespace(tag(tags, "bh").unwrap_or("")) {
+        return Err((DkimResult::Fail, "body hash did not verify".to_string()));
+    }
+
+    let signature_bytes = BASE64
+        .decode(strip_whitespace(tag(tags, "b").unwrap_or("")).as_bytes())
+        .map_err(|_| perm("b= is not valid base64"))?;
+    let hash = header_hash(fields, &signed, signature, header_c);
+    if !key.verify(&hash, &signature_bytes) {
+        return Err((DkimResult::Fail, "signature did not verify".to_string()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::ZoneResolver;
+
+    const ZONE: &str = r#"
+ed._domainkey.pass.test.        TXT "v=DKIM1; k=ed25519; p=fqgmdGQ8Azt9BEInycg5A6hi0OHwD6qQ4iidUuBL/UM="
+revoked._domainkey.pass.test.   TXT "v=DKIM1; p="
+"#;
+
+    /// Signed over From, To and a Latin-1 Subject; X-Legacy is unsigned and not UTF-8 either.
+    const SIGNED: &[u8] = b"DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed; d=pass.test; s=ed;\r\n\
+        \th=from:to:subject; bh=qhoJ+psZWWwGBFo47vMkZ+rqMzSRnateL1Q1CLX3aAM=; \
+        b=ZAUITm9bbyEyuPPyTEUKqZWxz9eYvp+/nwWIHby92lJiniBXmPoJoDMems58q3sra5oATN8uepBEV2dIzJ7eAQ==\r\n\
+        From: alice@pass.test\r\n\
+        To: bob@tempmail.local\r\n\
+        Subject: caf\xe9  au lait\r\n\
+        X-Legacy: \xff\xfe\r\n\
+        \r\n\
+        Hello  there\r\n\
+        \r\n";
+
+    async fn results(message: &[u8]) -> Vec<DkimCheck> {
+        let zone = ZoneResolver::parse(ZONE).unwrap();
+        verify(&zone, message).await
+    }
+
+    fn replace(message: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+        let at = find(message, from).unwrap();
+        [&message[..at], to, &message[at + from.len()..]].concat()
+    }
+
+    #[tokio::test]
+    async fn verifies_8bit_headers() {
+        let checks = results(SIGNED).await;
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].result, DkimResult::Pass, "{:?}", checks[0].reason);
+        assert_eq!(checks[0].domain.as_deref(), Some("pass.test"));
+        assert_eq!(checks[0].selector.as_deref(), Some("ed"));
+    }
+
+    #[tokio::test]
+    async fn relaxed_canonicalization_ignores_whitespace_and_lf() {
+        let refolded = replace(
+            SIGNED,
+            b"Subject: caf\xe9  au",
+            b"subject:   caf\xe9\r\n\tau",
+        );
+        assert_eq!(results(&refolded).await[0].result, DkimResult::Pass);
+
+        let bare_lf: Vec<u8> = SIGNED.iter().copied().filter(|&b| b != b'\r').collect();
+        assert_eq!(results(&bare_lf).await[0].result, DkimResult::Pass);
+    }
+
+    #[tokio::test]
+    async fn tampering_fails() {
+        let body = replace(SIGNED, b"Hello", b"Hullo");
+        let check = &results(&body).await[0];
+        assert_eq!(check.result, DkimResult::Fail);
+        assert_eq!(check.reason.as_deref(), Some("body hash did not verify"));
+
+        let header = replace(SIGNED, b"caf\xe9", b"caf\xe8");
+        let check = &results(&header).await[0];
+        assert_eq!(check.result, DkimResult::Fail);
+        assert_eq!(check.reason.as_deref(), Some("signature did not verify"));
+
+        // unsigned headers may change freely
+        let unsigned = replace(SIGNED, b"\xff\xfe", b"\xfd");
+        assert_eq!(results(&unsigned).await[0].result, DkimResult::Pass);
+    }
+
+    #[tokio::test]
+    async fn key_problems() {
+        let revoked = replace(SIGNED, b"s=ed;", b"s=revoked;");
+        assert_eq!(results(&revoked).await[0].result, DkimResult::PermError);
+
+        let missing = replace(SIGNED, b"s=ed;", b"s=gone;");
+        assert_eq!(results(&missing).await[0].result, DkimResult::PermError);
+    }
+
+    #[test]
+    fn canonical_headers() {
+        let field = b"Subject :  caf\xe9 \r\n\t au\t lait ";
+        assert_eq!(
+            canonical_header(field, Canonicalization::Relaxed),
+            b"subject:caf\xe9 au lait\r\n"
+        );
+        assert_eq!(
+            canonical_header(field, Canonicalization::Simple),
+            [&field[..], b"\r\n"].concat()
+        );
+        assert_eq!(
+            strip_b_value(b"DKIM-Signature: a=x; b=abc\r\n\tdef; bh=xyz"),
+            b"DKIM-Signature: a=x; b=; bh=xyz"
+        );
+    }
+}
@@ -0,0 +1,424 @@
+//! DMARC policy discovery and identifier alignment (RFC 7489).
+//!
+//! Organizational domains come from the Public Suffix List in
+//! `PUBLIC_SUFFIX_FILE` when set (the `public_suffix_list.dat` format);
+//! otherwise a short list of common multi-label suffixes is used.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::OnceLock;
+
+use crate::dkim::{self, DkimCheck, DkimResult};
+use crate::dns::{DnsError, Resolver};
+use crate::spf::{SpfCheck, SpfResult};
+
+/// Used when no suffix list file is configured.
+const FALLBACK_SUFFIXES: &[&str] = &[
+    "co.uk", "org.uk", "ac.uk", "gov.uk", "me.uk", "com.au", "net.au", "org.au", "co.nz",
+    "co.jp", "ne.jp", "or.jp", "com.br", "com.cn", "com.mx", "co.in", "co.za", "com.tr",
+    "com.sg", "com.hk", "co.kr",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DmarcResult {
+    None,
+    Pass,
+    Fail,
+    TempError,
+    PermError,
+}
+
+impl DmarcResult {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DmarcResult::None => "none",
+            DmarcResult::Pass => "pass",
+            DmarcResult::Fail => "fail",
+            DmarcResult::TempError => "temperror",
+            DmarcResult::PermError => "permerror",
+        }
+    }
+}
+
+/// Outcome of the DMARC check of a message's header From domain.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DmarcCheck {
+    pub result: DmarcResult,
+    pub from_domain: Option<String>,
+    /// Domain whose `_dmarc` record applied (the From domain or its organizational domain).
+    pub record_domain: Option<String>,
+    /// Policy requested for this message: `none`, `quarantine` or `reject`.
+    pub policy: Option<String>,
+    pub spf_aligned: bool,
+    pub dkim_aligned: bool,
+    pub reason: Option<String>,
+}
+
+/// Check the header From domain against the SPF and DKIM results of the message.
+pub async fn check(
+    resolver: &dyn Resolver,
+    from_domains: &[String],
+    spf: Option<&SpfCheck>,
+    dkim: &[DkimCheck],
+) -> DmarcCheck {
+    let mut check = DmarcCheck {
+        result: DmarcResult::None,
+        from_domain: None,
+        record_domain: None,
+        policy: None,
+        spf_aligned: false,
+        dkim_aligned: false,
+        reason: None,
+    };
+
+    let from_domain = match from_domains {
+        [] => {
+            check.reason = Some("no From domain".to_string());
+            return check;
+        }
+        [domain] => domain.trim_end_matches('.').to_ascii_lowercase(),
+        _ => {
+            check.result = DmarcResult::PermError;
+            check.reason = Some("multiple From domains".to_string());
+            return check;
+        }
+    };
+    check.from_domain = Some(from_domain.clone());
+
+    let org_domain = organizational_domain(&from_domain);
+    let (record_domain, tags) = match find_record(resolver, &from_domain, &org_domain).await {
+        Ok(Some(found)) => found,
+        Ok(None) => {
+            check.reason = Some(format!("no DMARC record for {}", from_domain));
+            return check;
+        }
+        Err(e) => {
+            check.result = DmarcResult::TempError;
+            check.reason = Some(e);
+            return check;
+        }
+    };
+
+    let policy_tag = if record_domain != from_domain {
+        dkim::tag(&tags, "sp").or_else(|| dkim::tag(&tags, "p"))
+    } else {
+        dkim::tag(&tags, "p")
+    };
+    check.policy = Some(match policy_tag.map(|p| p.to_ascii_lowercase()) {
+        Some(p) if p == "none" || p == "quarantine" || p == "reject" => p,
+        // a missing or invalid policy is treated as p=none (§6.6.3)
+        _ => "none".to_string(),
+    });
+    check.record_domain = Some(record_domain);
+
+    let strict_spf = dkim::tag(&tags, "aspf").is_some_and(|v| v.eq_ignore_ascii_case("s"));
+    let strict_dkim = dkim::tag(&tags, "adkim").is_some_and(|v| v.eq_ignore_ascii_case("s"));
+
+    check.spf_aligned = spf.is_some_and(|spf| {
+        spf.result == SpfResult::Pass && aligned(&spf.domain, &from_domain, strict_spf)
+    });
+    check.dkim_aligned = dkim.iter().any(|sig| {
+        sig.result == DkimResult::Pass
+            && sig
+                .domain
+                .as_deref()
+                .is_some_and(|d| aligned(d, &from_domain, strict_dkim))
+    });
+
+    if check.spf_aligned || check.dkim_aligned {
+        check.result = DmarcResult::Pass;
+    } else {
+        check.result = DmarcResult::Fail;
+        check.reason = Some("no aligned SPF or DKIM pass".to_string());
+    }
+    check
+}
+
+/// The DMARC record for the From domain, falling back to its organizational domain (§6.6.3).
+async fn find_record(
+    resolver: &dyn Resolver,
+    from_domain: &str,
+    org_domain: &str,
+) -> Result<Option<(String, Vec<(String, String)>)>, String> {
+    let mut candidates = vec![from_domain];
+    if org_domain != from_domain {
+        candidates.push(org_domain);
+    }
+
+    for domain in candidates {
+        let records = match resolver.txt(&format!("_dmarc.{}", domain)).await {
+            Ok(records) => records,
+            Err(DnsError::NotFound) => continue,
+            Err(DnsError::Temporary(e)) => return Err(e),
+        };
+        let mut dmarc = records.iter().filter_map(|r| {
+            let tags = dkim::parse_tags(r).ok()?;
+            (tags.first().is_some_and(|(n, v)| n == "v" && v == "DMARC1")).then_some(tags)
+        });
+        // more than one record means no policy is published there
+        if let (Some(tags), None) = (dmarc.next(), dmarc.next()) {
+            return Ok(Some((domain.to_string(), tags)));
+        }
+    }
+    Ok(None)
+}
+
+fn aligned(domain: &str, from_domain: &str, strict: bool) -> bool {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    if strict {
+        domain == from_domain
+    } else {
+        organizational_domain(&domain) == organizational_domain(from_domain)
+    }
+}
+
+struct SuffixList {
+    rules: HashSet<String>,
+    wildcards: HashSet<String>,
+    exceptions: HashSet<String>,
+}
+
+impl SuffixList {
+    /// Rules in the `public_suffix_list.dat` format; an empty list uses the fallback suffixes.
+    fn parse(contents: &str) -> Self {
+        let mut list = SuffixList {
+            rules: HashSet::new(),
+            wildcards: HashSet::new(),
+            exceptions: HashSet::new(),
+        };
+        for line in contents.lines() {
+            let rule = line.split_whitespace().next().unwrap_or("");
+            if rule.is_empty() || rule.starts_with("//") {
+                continue;
+            }
+            let rule = rule.to_lowercase();
+            if let Some(rest) = rule.strip_prefix('!') {
+                list.exceptions.insert(rest.to_string());
+            } else if let Some(rest) = rule.strip_prefix("*.") {
+                list.wildcards.insert(rest.to_string());
+            } else {
+                list.rules.insert(rule);
+            }
+        }
+        if list.rules.is_empty() {
+            list.rules
+                .extend(FALLBACK_SUFFIXES.iter().map(|s| s.to_string()));
+        }
+        list
+    }
+
+    /// The registered domain: one label below the longest matching public suffix.
+    fn organizational_domain(&self, domain: &str) -> String {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let labels: Vec<&str> = domain.split('.').collect();
+
+        // number of labels in the public suffix; a single label is the implicit "*" rule
+        let mut suffix_len = 1;
+        for i in (0..labels.len()).rev() {
+            let candidate = labels[i..].join(".");
+            let len = labels.len() - i;
+            if self.exceptions.contains(&candidate) {
+                suffix_len = len - 1;
+                break;
+            }
+            if self.rules.contains(&candidate) {
+                suffix_len = suffix_len.max(len);
+            }
+            if i > 0 && self.wildcards.contains(&candidate) {
+                suffix_len = suffix_len.max(len + 1);
+            }
+        }
+
+        if labels.len() <= suffix_len {
+            return domain;
+        }
+        labels[labels.len() - suffix_len - 1..].join(".")
+    }
+}
+
+fn suffix_list() -> &'static SuffixList {
+    static LIST: OnceLock<SuffixList> = OnceLock::new();
+    LIST.get_or_init(|| {
+        let contents = match std::env::var("PUBLIC_SUFFIX_FILE") {
+            Ok(path) if !path.is_empty() => match std::fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    tracing::warn!("Failed to read public suffix list {}: {}", path, e);
+                    String::new()
+                }
+            },
+            _ => String::new(),
+        };
+        SuffixList::parse(&contents)
+    })
+}
+
+/// The registered domain: one label below the longest matching public suffix.
+pub fn organizational_domain(domain: &str) -> String {
+    suffix_list().organizational_domain(domain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::ZoneResolver;
+
+    const SUFFIXES: &str = "
+// comments and blank lines are skipped
+
+com
+uk
+co.uk
+ck
+*.ck
+!www.ck
+";
+
+    const ZONE: &str = r#"
+_dmarc.example.com.     TXT "v=DMARC1; p=reject; sp=quarantine"
+_dmarc.nosp.test.       TXT "v=DMARC1; p=reject; adkim=s; aspf=s"
+_dmarc.own.nosp.test.   TXT "v=DMARC1; p=none"
+_dmarc.multi.test.      TXT "v=DMARC1; p=reject"
+_dmarc.multi.test.      TXT "v=DMARC1; p=none"
+_dmarc.other.test.      TXT "v=spf1 -all"
+_dmarc.other.test.      TXT "v=DMARC1; p=bogus"
+"#;
+
+    fn spf(result: SpfResult, domain: &str) -> SpfCheck {
+        SpfCheck {
+            result,
+            scope: "mailfrom".to_string(),
+            identity: format!("bounce@{}", domain),
+            domain: domain.to_string(),
+            mechanism: None,
+            reason: None,
+        }
+    }
+
+    fn dkim(result: DkimResult, domain: &str) -> DkimCheck {
+        DkimCheck {
+            result,
+            domain: Some(domain.to_string()),
+            selector: Some("s1".to_string()),
+            algorithm: Some("rsa-sha256".to_string()),
+            identity: None,
+            header_b: None,
+            reason: None,
+        }
+    }
+
+    async fn run(from: &str, spf: Option<&SpfCheck>, dkim: &[DkimCheck]) -> DmarcCheck {
+        let zone = ZoneResolver::parse(ZONE).unwrap();
+        check(&zone, &[from.to_string()], spf, dkim).await
+    }
+
+    #[test]
+    fn organizational_domain_follows_the_suffix_rules() {
+        let list = SuffixList::parse(SUFFIXES);
+        let org = |domain: &str| list.organizational_domain(domain);
+        assert_eq!(org("mail.Example.COM."), "example.com");
+        assert_eq!(org("a.b.example.co.uk"), "example.co.uk");
+        // *.ck makes every second-level name a suffix, except www.ck
+        assert_eq!(org("shop.foo.ck"), "shop.foo.ck");
+        assert_eq!(org("a.shop.foo.ck"), "shop.foo.ck");
+        assert_eq!(org("a.www.ck"), "www.ck");
+        // unlisted top-level domains are still a suffix
+        assert_eq!(org("host.example.internal"), "example.internal");
+        assert_eq!(org("com"), "com");
+        assert_eq!(org("co.uk"), "co.uk");
+    }
+
+    #[test]
+    fn empty_suffix_list_uses_the_fallback() {
+        let list = SuffixList::parse("// nothing but comments\n");
+        let org = |domain: &str| list.organizational_domain(domain);
+        assert_eq!(org("mail.example.co.uk"), "example.co.uk");
+        assert_eq!(org("mail.example.com.au"), "example.com.au");
+        assert_eq!(org("mail.example.com"), "example.com");
+    }
+
+    #[test]
+    fn relaxed_alignment_compares_organizational_domains() {
+        assert!(aligned("bounce.example.com", "example.com", false));
+        assert!(aligned("Example.com.", "mail.example.com", false));
+        assert!(!aligned("example.net", "example.com", false));
+        assert!(!aligned("other.co.uk", "example.co.uk", false));
+
+        assert!(aligned("EXAMPLE.com", "example.com", true));
+        assert!(!aligned("bounce.example.com", "example.com", true));
+    }
+
+    #[tokio::test]
+    async fn subdomains_use_the_sp_policy() {
+        let pass = dkim(DkimResult::Pass, "example.com");
+        let check = run("example.com", None, std::slice::from_ref(&pass)).await;
+        assert_eq!(check.result, DmarcResult::Pass);
+        assert_eq!(check.policy.as_deref(), Some("reject"));
+        assert!(check.dkim_aligned);
+
+        let check = run("news.example.com", None, std::slice::from_ref(&pass)).await;
+        assert_eq!(check.result, DmarcResult::Pass);
+        assert_eq!(check.record_domain.as_deref(), Some("example.com"));
+        assert_eq!(check.policy.as_deref(), Some("quarantine"));
+
+        // without sp= the organizational policy applies, a subdomain's own record wins
+        let check = run("news.nosp.test", None, &[]).await;
+        assert_eq!(check.result, DmarcResult::Fail);
+        assert_eq!(check.policy.as_deref(), Some("reject"));
+        let check = run("own.nosp.test", None, &[]).await;
+        assert_eq!(check.record_domain.as_deref(), Some("own.nosp.test"));
+        assert_eq!(check.policy.as_deref(), Some("none"));
+    }
+
+    #[tokio::test]
+    async fn strict_alignment_needs_the_exact_domain() {
+        let check = run(
+            "nosp.test",
+            Some(&spf(SpfResult::Pass, "bounce.nosp.test")),
+            &[dkim(DkimResult::Pass, "mail.nosp.test")],
+        )
+        .await;
+        assert_eq!(check.result, DmarcResult::Fail);
+        assert!(!check.spf_aligned && !check.dkim_aligned);
+
+        let check = run("nosp.test", Some(&spf(SpfResult::Pass, "nosp.test")), &[]).await;
+        assert_eq!(check.result, DmarcResult::Pass);
+        assert!(check.spf_aligned);
+
+        // a relaxed record accepts the subdomain, but not a failed result
+        let check = run(
+            "example.com",
+            Some(&spf(SpfResult::Pass, "bounce.example.com")),
+            &[dkim(DkimResult::Fail, "example.com")],
+        )
+        .await;
+        assert!(check.spf_aligned && !check.dkim_aligned);
+        let softfail = spf(SpfResult::SoftFail, "example.com");
+        let check = run("example.com", Some(&softfail), &[]).await;
+        assert_eq!(check.result, DmarcResult::Fail);
+    }
+
+    #[tokio::test]
+    async fn multiple_records_publish_no_policy() {
+        let check = run("multi.test", None, &[]).await;
+        assert_eq!(check.result, DmarcResult::None);
+        assert_eq!(check.policy, None);
+
+        // other TXT records do not count; an unknown policy is p=none
+        let check = run("other.test", None, &[]).await;
+        assert_eq!(check.result, DmarcResult::Fail);
+        assert_eq!(check.policy.as_deref(), Some("none"));
+    }
+
+    #[tokio::test]
+    async fn from_domains_must_be_unique() {
+        let zone = ZoneResolver::parse(ZONE).unwrap();
+        let none = check(&zone, &[], None, &[]).await;
+        assert_eq!(none.result, DmarcResult::None);
+
+        let from = ["example.com".to_string(), "other.test".to_string()];
+        let multiple = check(&zone, &from, None, &[]).await;
+        assert_eq!(multiple.result, DmarcResult::PermError);
+    }
+}
@@ -0,0 +1,363 @@
+//! DNS lookups behind a small trait so checks can run against a local stub zone.
+//!
+//! `DNS_ZONE_FILE` switches from the system resolver to [`ZoneResolver`], which
+//! answers only from a file of `name TYPE value` lines:
+//!
+//! ```text
+//! example.com.            TXT  "v=spf1 ip4:192.0.2.0/24 -all"
+//! mail.example.com.       A    192.0.2.10
+//! 10.2.0.192.in-addr.arpa PTR  mail.example.com.
+//! ```
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::proto::op::ResponseCode;
+use hickory_resolver::TokioAsyncResolver;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsError {
+    /// The name does not exist or has no records of the requested type.
+    NotFound,
+    /// The lookup failed for a reason that may go away on retry.
+    Temporary(String),
+}
+
+impl std::fmt::Display for DnsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DnsError::NotFound => write!(f, "no records found"),
+            DnsError::Temporary(e) => write!(f, "temporary DNS failure: {}", e),
+        }
+    }
+}
+
+pub type DnsResult<T> = std::result::Result<T, DnsError>;
+
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    async fn a(&self, name: &str) -> DnsResult<Vec<Ipv4Addr>>;
+    async fn aaaa(&self, name: &str) -> DnsResult<Vec<Ipv6Addr>>;
+    async fn ptr(&self, ip: IpAddr) -> DnsResult<Vec<String>>;
+    /// Each TXT record with its character-strings joined.
+    async fn txt(&self, name: &str) -> DnsResult<Vec<String>>;
+    /// Exchange names ordered by preference.
+    async fn mx(&self, name: &str) -> DnsResult<Vec<String>>;
+
+    /// A and AAAA records together.
+    async fn ips(&self, name: &str) -> DnsResult<Vec<IpAddr>> {
+        let v4 = self.a(name).await;
+        let v6 = self.aaaa(name).await;
+        match (v4, v6) {
+            (Err(DnsError::Temporary(e)), _) | (_, Err(DnsError::Temporary(e))) => {
+                Err(DnsError::Temporary(e))
+            }
+            (Err(_), Err(_)) => Err(DnsError::NotFound),
+            (v4, v6) => Ok(v4
+                .unwrap_or_default()
+                .into_iter()
+                .map(IpAddr::V4)
+                .chain(v6.unwrap_or_default().into_iter().map(IpAddr::V6))
+                .collect()),
+        }
+    }
+}
+
+/// Pick the resolver configured by the environment.
+pub fn from_env() -> Result<Arc<dyn Resolver>> {
+    match std::env::var("DNS_ZONE_FILE") {
+        Ok(path) if !path.is_empty() => {
+            tracing::info!("Using DNS stub zone from {}", path);
+            Ok(Arc::new(ZoneResolver::from_file(&path)?))
+        }
+        _ => Ok(Arc::new(SystemResolver::new()?)),
+    }
+}
+
+/// The PTR query name for an address (`4.3.2.1.in-addr.arpa.` style).
+pub fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => {
+            let o = v4.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa.", o[3], o[2], o[1], o[0])
+        }
+        IpAddr::V6(v6) => {
+            let mut name = String::with_capacity(73);
+            for byte in v6.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0x0f, byte >> 4));
+            }
+            name.push_str("ip6.arpa.");
+            name
+        }
+    }
+}
+
+/// The first PTR name for `ip` that resolves back to `ip` (forward-confirmed reverse DNS).
+pub async fn verified_reverse(resolver: &dyn Resolver, ip: IpAddr) -> Option<String> {
+    let names = resolver.ptr(ip).await.ok()?;
+    for name in names {
+        if let Ok(ips) = resolver.ips(&name).await {
+            if ips.contains(&ip) {
+                return Some(name.trim_end_matches('.').to_string());
+            }
+        }
+    }
+    None
+}
+
+fn fqdn(name: &str) -> String {
+    if name.ends_with('.') {
+        name.to_string()
+    } else {
+        format!("{}.", name)
+    }
+}
+
+/// Resolver backed by the host's resolv.conf.
+pub struct SystemResolver {
+    inner: TokioAsyncResolver,
+}
+
+impl SystemResolver {
+    pub fn new() -> Result<Self> {
+        let inner = TokioAsyncResolver::tokio_from_system_conf()
+            .context("Failed to read system DNS configuration")?;
+        Ok(Self { inner })
+    }
+}
+
+fn map_error(e: hickory_resolver::error::ResolveError) -> DnsError {
+    match e.kind() {
+        ResolveErrorKind::NoRecordsFound { response_code, .. }
+            if *response_code == ResponseCode::NXDomain
+                || *response_code == ResponseCode::NoError =>
+        {
+            DnsError::NotFound
+        }
+        _ => DnsError::Temporary(e.to_string()),
+    }
+}
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn a(&self, name: &str) -> DnsResult<Vec<Ipv4Addr>> {
+        let lookup = self.inner.ipv4_lookup(fqdn(name)).await.map_err(map_error)?;
+        Ok(lookup.iter().map(|a| a.0).collect())
+    }
+
+    async fn aaaa(&self, name: &str) -> DnsResult<Vec<Ipv6Addr>> {
+        let lookup = self.inner.ipv6_lookup(fqdn(name)).await.map_err(map_error)?;
+        Ok(lookup.iter().map(|a| a.0).collect())
+    }
+
+    async fn ptr(&self, ip: IpAddr) -> DnsResult<Vec<String>> {
+        let lookup = self.inner.reverse_lookup(ip).await.map_err(map_error)?;
+        Ok(lookup.iter().map(|name| name.to_utf8()).collect())
+    }
+
+    async fn txt(&self, name: &str) -> DnsResult<Vec<String>> {
+        let lookup = self.inner.txt_lookup(fqdn(name)).await.map_err(map_error)?;
+        Ok(lookup
+            .iter()
+            .map(|txt| {
+                txt.txt_data()
+                    .iter()
+                    .map(|part| String::from_utf8_lossy(part))
+                    .collect::<String>()
+            })
+            .collect())
+    }
+
+    async fn mx(&self, name: &str) -> DnsResult<Vec<String>> {
+        let lookup = self.inner.mx_lookup(fqdn(name)).await.map_err(map_error)?;
+        let mut records: Vec<_> = lookup
+            .iter()
+            .map(|mx| (mx.preference(), mx.exchange().to_utf8()))
+            .collect();
+        records.sort();
+        Ok(records.into_iter().map(|(_, name)| name).collect())
+    }
+}
+
+/// Resolver answering from a static zone file; anything not listed is NXDOMAIN.
+#[derive(Default)]
+pub struct ZoneResolver {
+    records: HashMap<(String, String), Vec<String>>,
+}
+
+impl ZoneResolver {
+    pub fn from_file(path: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read DNS zone file {}", path))?;
+        Self::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut zone = Self::default();
+
+        for (n, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+                continue;
+            }
+
+            // columns may be aligned with any amount of whitespace
+            let fields = line
+                .split_once(char::is_whitespace)
+                .and_then(|(name, rest)| {
+                    let (kind, value) = rest.trim_start().split_once(char::is_whitespace)?;
+                    Some((name, kind, value))
+                });
+            let Some((name, kind, value)) = fields else {
+                anyhow::bail!("zone line {}: expected `name TYPE value`", n + 1);
+            };
+
+            let kind = kind.to_ascii_uppercase();
+            let value = value.trim();
+            let value = if kind == "TXT" {
+                unquote_txt(value)
+            } else {
+                value.to_string()
+            };
+            zone.records
+                .entry((normalize(name), kind))
+                .or_default()
+                .push(value);
+        }
+
+        Ok(zone)
+    }
+
+    fn lookup(&self, name: &str, kind: &str) -> DnsResult<&[String]> {
+        self.records
+            .get(&(normalize(name), kind.to_string()))
+            .map(|v| v.as_slice())
+            .ok_or(DnsError::NotFound)
+    }
+}
+
+fn normalize(name: &str) -> String {
+    fqdn(&name.to_ascii_lowercase())
+}
+
+/// Join one or more `"quoted"` character-strings; unquoted values are taken as-is.
+fn unquote_txt(value: &str) -> String {
+    if !value.starts_with('"') {
+        return value.to_string();
+    }
+
+    let mut out = String::new();
+    let mut in_quotes = false;
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match (c, in_quotes) {
+            ('"', _) => in_quotes = !in_quotes,
+            ('\\', true) => {
+                if let Some(next) = chars.next() {
+                    out.push(next);
+                }
+            }
+            (c, true) => out.push(c),
+            _ => {}
+        }
+    }
+    out
+}
+
+#[async_trait]
+impl Resolver for ZoneResolver {
+    async fn a(&self, name: &str) -> DnsResult<Vec<Ipv4Addr>> {
+        Ok(self
+            .lookup(name, "A")?
+            .iter()
+            .filter_map(|v| v.parse().ok())
+            .collect())
+    }
+
+    async fn aaaa(&self, name: &str) -> DnsResult<Vec<Ipv6Addr>> {
+        Ok(self
+            .lookup(name, "AAAA")?
+            .iter()
+            .filter_map(|v| v.parse().ok())
+            .collect())
+    }
+
+    async fn ptr(&self, ip: IpAddr) -> DnsResult<Vec<String>> {
+        Ok(self
+            .lookup(&reverse_name(ip), "PTR")?
+            .iter()
+            .map(|v| fqdn(v))
+            .collect())
+    }
+
+    async fn txt(&self, name: &str) -> DnsResult<Vec<String>> {
+        Ok(self.lookup(name, "TXT")?.to_vec())
+    }
+
+    /// MX values are written as `preference exchange`.
+    async fn mx(&self, name: &str) -> DnsResult<Vec<String>> {
+        let mut records: Vec<(u16, String)> = self
+            .lookup(name, "MX")?
+            .iter()
+            .filter_map(|v| {
+                let (pref, exchange) = v.split_once(char::is_whitespace)?;
+                Some((pref.parse().ok()?, fqdn(exchange.trim())))
+            })
+            .collect();
+        records.sort();
+        Ok(records.into_iter().map(|(_, name)| name).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZONE: &str = r#"
+; stub zone
+example.test.                 TXT  "v=spf1 " "-all"
+Example.test.                 MX   20 backup.example.test.
+example.test.                 MX   10 mail.example.test.
+mail.example.test.            A    192.0.2.10
+mail.example.test.            AAAA 2001:db8::10
+10.2.0.192.in-addr.arpa.      PTR  mail.example.test.
+11.2.0.192.in-addr.arpa.      PTR  mail.example.test.
+"#;
+
+    #[tokio::test]
+    async fn answers_from_the_zone() {
+        let zone = ZoneResolver::parse(ZONE).unwrap();
+        assert_eq!(zone.txt("EXAMPLE.test").await.unwrap(), ["v=spf1 -all"]);
+        assert_eq!(
+            zone.mx("example.test.").await.unwrap(),
+            ["mail.example.test.", "backup.example.test."]
+        );
+        assert_eq!(zone.ips("mail.example.test").await.unwrap().len(), 2);
+        assert_eq!(zone.a("missing.example.test").await, Err(DnsError::NotFound));
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert!(ZoneResolver::parse("example.test. TXT").is_err());
+    }
+
+    #[test]
+    fn reverse_names() {
+        assert_eq!(reverse_name("192.0.2.10".parse().unwrap()), "10.2.0.192.in-addr.arpa.");
+        let v6 = reverse_name("2001:db8::1".parse().unwrap());
+        assert!(v6.starts_with("1.0.0.0.0.0.0.0."));
+        assert!(v6.ends_with(".8.b.d.0.1.0.0.2.ip6.arpa."));
+    }
+
+    #[tokio::test]
+    async fn reverse_dns_must_resolve_back() {
+        let zone = ZoneResolver::parse(ZONE).unwrap();
+        let confirmed = verified_reverse(&zone, "192.0.2.10".parse().unwrap()).await;
+        assert_eq!(confirmed.as_deref(), Some("mail.example.test"));
+        assert_eq!(verified_reverse(&zone, "192.0.2.11".parse().unwrap()).await, None);
+        assert_eq!(verified_reverse(&zone, "192.0.2.12".parse().unwrap()).await, None);
+    }
+}
@@ -0,0 +1,217 @@
+//! DNS blocklist (DNSBL) lookups for the connecting client (RFC 5782).
+
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+use crate::config::{env_list, env_or};
+use crate::dns::{self, DnsError, Resolver};
+
+/// What a listing on a zone does to the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsblAction {
+    /// Refuse the connection with 554 before the greeting.
+    Reject,
+    /// Accept mail but record the listing so it counts towards the spam score.
+    Tag,
+}
+
+impl DnsblAction {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "reject" => Some(DnsblAction::Reject),
+            "tag" => Some(DnsblAction::Tag),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DnsblZone {
+    pub zone: String,
+    pub action: DnsblAction,
+}
+
+#[derive(Debug, Clone)]
+pub struct DnsblConfig {
+    /// `DNSBL_ZONES`: comma-separated zones, each optionally suffixed with
+    /// `=reject` or `=tag` to override `DNSBL_ACTION`.
+    pub zones: Vec<DnsblZone>,
+}
+
+impl DnsblConfig {
+    pub fn from_env() -> Self {
+        let default_action = match env_or("DNSBL_ACTION", String::from("tag"))
+            .to_lowercase()
+            .as_str()
+        {
+            "reject" => DnsblAction::Reject,
+            "tag" => DnsblAction::Tag,
+            other => {
+                tracing::warn!("Unknown DNSBL_ACTION {:?}, tagging listed clients", other);
+                DnsblAction::Tag
+            }
+        };
+        let zones = env_list("DNSBL_ZONES")
+            .into_iter()
+            .filter_map(|entry| {
+                let (zone, action) = match entry.split_once('=') {
+                    Some((zone, action)) => match DnsblAction::parse(action.trim()) {
+                        Some(action) => (zone.trim(), action),
+                        None => {
+                            tracing::warn!("Ignoring DNSBL zone with unknown action {:?}", entry);
+                            return None;
+                        }
+                    },
+                    None => (entry.as_str(), default_action),
+                };
+                Some(DnsblZone {
+                    zone: zone.trim_matches('.').to_string(),
+                    action,
+                })
+            })
+            .collect();
+        Self { zones }
+    }
+}
+
+/// A zone that lists the client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DnsblListing {
+    pub zone: String,
+    pub action: DnsblAction,
+    /// The 127.0.0.x return codes.
+    pub codes: Vec<String>,
+    /// The zone's TXT explanation, if it publishes one.
+    pub reason: Option<String>,
+}
+
+/// The query name for `ip` in `zone`: reversed octets or nibbles followed by the zone.
+fn query_name(ip: IpAddr, zone: &str) -> String {
+    let reversed = dns::reverse_name(ip);
+    let reversed = reversed
+        .strip_suffix("in-addr.arpa.")
+        .or_else(|| reversed.strip_suffix("ip6.arpa."))
+        .unwrap_or(&reversed);
+    format!("{}{}.", reversed, zone)
+}
+
+/// Look the client up in every configured zone. Lookup failures are logged and
+/// treated as not listed.
+pub async fn lookup(
+    resolver: &dyn Resolver,
+    config: &DnsblConfig,
+    ip: IpAddr,
+) -> Vec<DnsblListing> {
+    let mut listings = Vec::new();
+    for zone in &config.zones {
+        let name = query_name(ip, &zone.zone);
+        let codes: Vec<_> = match resolver.a(&name).await {
+            // 127.255.255.0/24 are zone error codes (quota exceeded, public resolver
+            // refused), not listings
+            Ok(addresses) => addresses
+                .into_iter()
+                .filter(|a| a.octets()[0] == 127 && a.octets()[..3] != [127, 255, 255])
+                .collect(),
+            Err(DnsError::NotFound) => continue,
+            Err(e) => {
+                tracing::warn!("DNSBL lookup {} failed: {}", name, e);
+                continue;
+            }
+        };
+        if codes.is_empty() {
+            continue;
+        }
+        let reason = resolver
+            .txt(&name)
+            .await
+            .ok()
+            .and_then(|txt| txt.into_iter().next());
+        listings.push(DnsblListing {
+            zone: zone.zone.clone(),
+            action: zone.action,
+            codes: codes.iter().map(|c| c.to_string()).collect(),
+            reason,
+        });
+    }
+    listings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::ZoneResolver;
+
+    const ZONE: &str = r#"
+1.0.0.127.bl.test.      A   127.0.0.2
+1.0.0.127.bl.test.      A   127.0.0.10
+1.0.0.127.bl.test.      TXT "Listed for testing"
+1.0.0.127.tag.test.     A   127.0.0.4
+1.0.0.127.err.test.     A   127.255.255.254
+1.0.0.127.odd.test.     A   10.0.0.1
+1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.bl.test. A 127.0.0.3
+"#;
+
+    fn config(zones: &[(&str, DnsblAction)]) -> DnsblConfig {
+        DnsblConfig {
+            zones: zones
+                .iter()
+                .map(|&(zone, action)| DnsblZone {
+                    zone: zone.to_string(),
+                    action,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn query_names() {
+        let v4: IpAddr = "192.0.2.99".parse().unwrap();
+        assert_eq!(query_name(v4, "bl.test"), "99.2.0.192.bl.test.");
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(
+            query_name(v6, "bl.test"),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.bl.test."
+        );
+    }
+
+    #[tokio::test]
+    async fn listings_carry_codes_reason_and_action() {
+        let zone = ZoneResolver::parse(ZONE).unwrap();
+        let config = config(&[
+            ("bl.test", DnsblAction::Reject),
+            ("tag.test", DnsblAction::Tag),
+        ]);
+        let listings = lookup(&zone, &config, "127.0.0.1".parse().unwrap()).await;
+
+        assert_eq!(listings.len(), 2);
+        assert_eq!(listings[0].zone, "bl.test");
+        assert_eq!(listings[0].action, DnsblAction::Reject);
+        assert_eq!(listings[0].codes, ["127.0.0.2", "127.0.0.10"]);
+        assert_eq!(listings[0].reason.as_deref(), Some("Listed for testing"));
+        assert_eq!(listings[1].zone, "tag.test");
+        assert_eq!(listings[1].action, DnsblAction::Tag);
+        assert_eq!(listings[1].reason, None);
+
+        let v6 = lookup(&zone, &config, "2001:db8::1".parse().unwrap()).await;
+        assert_eq!(v6.len(), 1);
+        assert_eq!(v6[0].codes, ["127.0.0.3"]);
+    }
+
+    #[tokio::test]
+    async fn ignores_error_codes_and_unlisted_clients() {
+        let zone = ZoneResolver::parse(ZONE).unwrap();
+        let config = config(&[
+            ("err.test", DnsblAction::Reject),
+            ("odd.test", DnsblAction::Reject),
+            ("bl.test", DnsblAction::Reject),
+        ]);
+        assert!(lookup(&zone, &config, "127.0.0.1".parse().unwrap())
+            .await
+            .iter()
+            .all(|l| l.zone == "bl.test"));
+        assert!(lookup(&zone, &config, "192.0.2.1".parse().unwrap())
+            .await
+            .is_empty());
+    }
+}
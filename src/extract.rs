@@ -0,0 +1,396 @@
+//! Detection of one-time codes and action links ("verify your email") in incoming mail.
+
+use regex::Regex;
+use std::sync::OnceLock;
+
+use crate::db::{ExtractionRule, NewExtraction};
+use crate::html;
+
+pub const KIND_CODE: &str = "code";
+pub const KIND_LINK: &str = "link";
+
+/// Name recorded for matches produced by the built-in heuristics.
+const DEFAULT_RULE: &str = "default";
+
+/// How far (in characters) around a candidate code we look for a keyword.
+const KEYWORD_WINDOW: usize = 80;
+
+const LINK_KEYWORDS: &[&str] = &[
+    "verify",
+    "verification",
+    "confirm",
+    "activate",
+    "activation",
+    "validate",
+    "reset",
+    "magic",
+    "login",
+    "log in",
+    "signin",
+    "sign in",
+    "sign-in",
+    "invite",
+    "invitation",
+    "accept",
+    "token=",
+];
+
+fn code_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"\b(\d{3}[ -]\d{3}|\d{4,8})\b").unwrap())
+}
+
+fn code_keyword_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(
+            r"(?i)\b(code|otp|pin|passcode|password|verification|verify|one[- ]time|security|confirm\w*|log ?in|sign ?in|2fa|token)\b",
+        )
+        .unwrap()
+    })
+}
+
+fn url_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r#"https?://[^\s<>"'\)\]]+"#).unwrap())
+}
+
+/// A link found in a message, with its anchor text when it came from HTML.
+#[derive(Debug, Clone)]
+pub struct FoundLink {
+    pub url: String,
+    pub text: Option<String>,
+}
+
+/// Run the built-in heuristics plus any mailbox rules over a message.
+pub fn extract(
+    subject: &str,
+    body_text: &str,
+    body_html: Option<&str>,
+    rules: &[ExtractionRule],
+) -> Vec<NewExtraction> {
+    let haystack = format!("{}\n{}", subject, body_text);
+    let links = find_links(body_text, body_html);
+    let mut found = Vec::new();
+
+    for rule in rules {
+        let re = match Regex::new(&rule.pattern) {
+            Ok(re) => re,
+            Err(e) => {
+                tracing::warn!("Skipping invalid extraction rule {}: {}", rule.id, e);
+                continue;
+            }
+        };
+        match rule.kind.as_str() {
+            KIND_CODE => {
+                for caps in re.captures_iter(&haystack) {
+                    let m = caps.get(1).or_else(|| caps.get(0)).unwrap();
+                    push_unique(&mut found, KIND_CODE, m.as_str(), None, &rule.name);
+                }
+            }
+            KIND_LINK => {
+                for link in links.iter().filter(|l| re.is_match(&l.url)) {
+                    push_unique(&mut found, KIND_LINK, &link.url, link.text.as_deref(), &rule.name);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for code in default_codes(&haystack) {
+        push_unique(&mut found, KIND_CODE, &code, None, DEFAULT_RULE);
+    }
+
+    for link in links.iter().filter(|l| is_action_link(l)) {
+        push_unique(&mut found, KIND_LINK, &link.url, link.text.as_deref(), DEFAULT_RULE);
+    }
+
+    found
+}
+
+/// Check that a user-supplied rule is usable before it is stored.
+pub fn validate_rule(kind: &str, pattern: &str) -> Result<(), String> {
+    if kind != KIND_CODE && kind != KIND_LINK {
+        return Err(format!("kind must be '{}' or '{}'", KIND_CODE, KIND_LINK));
+    }
+    Regex::new(pattern).map(|_| ()).map_err(|e| e.to_string())
+}
+
+fn push_unique(
+    found: &mut Vec<NewExtraction>,
+    kind: &str,
+    value: &str,
+    label: Option<&str>,
+    rule: &str,
+) {
+    if found.iter().any(|e| e.kind == kind && e.value == value) {
+        return;
+    }
+    found.push(NewExtraction {
+        kind: kind.to_string(),
+        value: value.to_string(),
+        label: label.map(|l| l.to_string()),
+        rule: rule.to_string(),
+    });
+}
+
+/// Numeric codes that appear close to a word like "code" or "verification".
+fn default_codes(text: &str) -> Vec<String> {
+    let mut codes = Vec::new();
+
+    for m in code_regex().find_iter(text) {
+        // skip amounts, decimals and parts of longer identifiers
+        let before = text[..m.start()].chars().next_back();
+        let after = text[m.end()..].chars().next();
+        if matches!(before, Some('$' | '€' | '£' | '.' | ',' | '/' | '#' | '-' | ':'))
+            || (matches!(after, Some('.' | ',' | '/' | '%' | ':' | '-'))
+                && text[m.end()..].chars().nth(1).is_some_and(|c| c.is_ascii_digit()))
+        {
+            continue;
+        }
+
+        let window_start = floor_char_boundary(text, m.start().saturating_sub(KEYWORD_WINDOW));
+        let window_end = floor_char_boundary(text, (m.end() + KEYWORD_WINDOW).min(text.len()));
+        if !code_keyword_regex().is_match(&text[window_start..window_end]) {
+            continue;
+        }
+
+        let code: String = m.as_str().chars().filter(|c| c.is_ascii_digit()).collect();
+        if !codes.contains(&code) {
+            codes.push(code);
+        }
+    }
+
+    codes
+}
+
+fn floor_char_boundary(s: &str, mut i: usize) -> usize {
+    while i > 0 && !s.is_char_boundary(i) {
+        i -= 1;
+    }
+    i
+}
+
+fn is_action_link(link: &FoundLink) -> bool {
+    let url = link.url.to_lowercase();
+    if url.contains("unsubscribe") {
+        return false;
+    }
+    let text = link.text.as_deref().unwrap_or("").to_lowercase();
+    LINK_KEYWORDS
+        .iter()
+        .any(|k| url.contains(k) || text.contains(k))
+}
+
+/// Collect anchors from the HTML body and bare URLs from the text body.
+pub fn find_links(body_text: &str, body_html: Option<&str>) -> Vec<FoundLink> {
+    let mut links: Vec<FoundLink> = Vec::new();
+
+    if let Some(body) = body_html {
+        let lower = body.to_ascii_lowercase();
+        let mut pos = 0;
+        while let Some(i) = lower[pos..].find("<a") {
+            let start = pos + i;
+            let after = lower.as_bytes().get(start + 2).copied();
+            if !matches!(after, Some(b' ' | b'\t' | b'\r' | b'\n')) {
+                pos = start + 2;
+                continue;
+            }
+            let Some(tag_end) = lower[start..].find('>').map(|e| start + e) else {
+                break;
+            };
+            let close = lower[tag_end..]
+                .find("</a")
+                .map(|e| tag_end + e)
+                .unwrap_or(lower.len());
+
+            if let Some(href) = html::attribute(&body[start + 1..tag_end], "href") {
+                let href = href.trim();
+                if href.starts_with("http://") || href.starts_with("https://") {
+                    let text = html::html_to_text(&body[tag_end + 1..close]);
+                    if !links.iter().any(|l| l.url == href) {
+                        links.push(FoundLink {
+                            url: href.to_string(),
+                            text: Some(text).filter(|t| !t.is_empty()),
+                        });
+                    }
+                }
+            }
+            pos = close;
+        }
+    }
+
+    for m in url_regex().find_iter(body_text) {
+        let url = m.as_str().trim_end_matches(['.', ',', ';', ':', '!', '?']);
+        if !links.iter().any(|l| l.url == url) {
+            links.push(FoundLink {
+                url: url.to_string(),
+                text: None,
+            });
+        }
+    }
+
+    links
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    fn rule(kind: &str, name: &str, pattern: &str) -> ExtractionRule {
+        ExtractionRule {
+            id: Uuid::new_v4(),
+            mailbox_id: Uuid::new_v4(),
+            kind: kind.to_string(),
+            name: name.to_string(),
+            pattern: pattern.to_string(),
+            created_at: Utc::now(),
+        }
+    }
+
+    fn values<'a>(found: &'a [NewExtraction], kind: &str) -> Vec<&'a str> {
+        found
+            .iter()
+            .filter(|e| e.kind == kind)
+            .map(|e| e.value.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn finds_codes_near_keywords() {
+        assert_eq!(
+            default_codes("Your verification code is 482913. It expires in 10 minutes."),
+            ["482913"]
+        );
+        assert_eq!(default_codes("Use 123 456 to sign in"), ["123456"]);
+        assert_eq!(default_codes("Your OTP: 4821"), ["4821"]);
+        // without a keyword nearby a number is just a number
+        assert!(default_codes("We shipped 48291 parcels last year").is_empty());
+    }
+
+    #[test]
+    fn ignores_dates_amounts_and_phone_numbers() {
+        // every number sits within reach of a keyword
+        for text in [
+            "Your security code was requested on 2024-05-17 at 10:45.",
+            "Order #58213 confirmed: total $1299.00, a 15.5% discount.",
+            "Questions about your code? Call +1 555-123-4567.",
+            "Login help: 020/7946-0958",
+        ] {
+            assert_eq!(default_codes(text), Vec::<String>::new(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn recognises_action_links() {
+        let link = |url: &str, text: Option<&str>| FoundLink {
+            url: url.to_string(),
+            text: text.map(str::to_string),
+        };
+        assert!(is_action_link(&link(
+            "https://example.com/verify?t=abc",
+            None
+        )));
+        assert!(is_action_link(&link(
+            "https://example.com/e/8f3a",
+            Some("Confirm your email")
+        )));
+        assert!(is_action_link(&link("https://example.com/r?token=x", None)));
+        assert!(!is_action_link(&link(
+            "https://example.com/blog",
+            Some("Read more")
+        )));
+        // an unsubscribe link mentioning "confirm" is not the action
+        assert!(!is_action_link(&link(
+            "https://example.com/unsubscribe?confirm=1",
+            Some("Unsubscribe")
+        )));
+    }
+
+    #[test]
+    fn collects_links_from_html_and_text() {
+        let html = r#"<p>Hi <A HREF="https://example.com/verify?t=1">Verify <b>email</b></a>
+            <a name="top">anchor</a> <a href="mailto:help@example.com">help</a>
+            <abbr>x</abbr> <a href="https://example.com/verify?t=1">again</a></p>"#;
+        let text = "Or open https://example.com/verify?t=1, or https://example.com/help.";
+        let links = find_links(text, Some(html));
+        let found: Vec<_> = links
+            .iter()
+            .map(|l| (l.url.as_str(), l.text.as_deref()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("https://example.com/verify?t=1", Some("Verify email")),
+                ("https://example.com/help", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn extracts_from_a_typical_otp_email() {
+        let found = extract(
+            "Your sign-in code",
+            "Hi,\n\nEnter 739104 to finish signing in.\n\n\
+             Not you? https://example.com/unsubscribe?u=9\n",
+            None,
+            &[],
+        );
+        assert_eq!(values(&found, KIND_CODE), ["739104"]);
+        assert!(values(&found, KIND_LINK).is_empty());
+        assert!(found.iter().all(|e| e.rule == DEFAULT_RULE));
+    }
+
+    #[test]
+    fn extracts_from_a_typical_verification_email() {
+        let html = r#"<p>Welcome!</p>
+            <p><a href="https://app.example.com/activate/5f2c9e">Activate account</a></p>
+            <p><a href="https://example.com/privacy">Privacy</a> |
+            <a href="https://example.com/unsubscribe?id=1">Unsubscribe</a></p>"#;
+        let found = extract("Welcome to Example", "", Some(html), &[]);
+        assert_eq!(
+            values(&found, KIND_LINK),
+            ["https://app.example.com/activate/5f2c9e"]
+        );
+        assert_eq!(found[0].label.as_deref(), Some("Activate account"));
+        assert!(values(&found, KIND_CODE).is_empty());
+    }
+
+    #[test]
+    fn mailbox_rules_run_first_and_are_not_duplicated() {
+        let rules = [
+            rule(KIND_CODE, "ticket", r"Ticket ([A-Z]{3}-\d+)"),
+            rule(KIND_CODE, "otp", r"code is (\d+)"),
+            rule(KIND_LINK, "portal", r"^https://portal\.example\.com/"),
+            rule(KIND_CODE, "broken", r"("),
+        ];
+        let found = extract(
+            "Ticket ABC-42 opened",
+            "Your code is 550123.\nTrack it at https://portal.example.com/t/42\n",
+            None,
+            &rules,
+        );
+        let summary: Vec<_> = found
+            .iter()
+            .map(|e| (e.kind.as_str(), e.value.as_str(), e.rule.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (KIND_CODE, "ABC-42", "ticket"),
+                (KIND_CODE, "550123", "otp"),
+                (KIND_LINK, "https://portal.example.com/t/42", "portal"),
+            ]
+        );
+    }
+
+    #[test]
+    fn validates_rules() {
+        assert!(validate_rule(KIND_CODE, r"\d{6}").is_ok());
+        assert!(validate_rule(KIND_LINK, r"^https://").is_ok());
+        assert!(validate_rule("phone", r"\d+").is_err());
+        assert!(validate_rule(KIND_CODE, r"(\d").is_err());
+    }
+}
@@ -0,0 +1,304 @@
+//! Greylisting at RCPT time (RFC 6647): the first delivery attempt of an unknown
+//! (client network, sender, recipient) triplet is deferred, and a retry after
+//! the delay passes the triplet for a while.
+
+use anyhow::Result;
+use chrono::{Duration, Utc};
+use std::net::IpAddr;
+
+use crate::config::{env_list, env_or};
+use crate::db::Db;
+use crate::spf;
+
+#[derive(Debug, Clone)]
+pub struct GreylistConfig {
+    /// Greylisting is off unless `GREYLIST_ENABLED` is set.
+    pub enabled: bool,
+    /// Retries sooner than this are deferred again (`GREYLIST_DELAY_SECS`).
+    pub delay: Duration,
+    /// A triplet must be retried within this time or it starts over (`GREYLIST_RETRY_HOURS`).
+    pub retry_window: Duration,
+    /// A passed triplet is accepted without delay for this long after its last accepted
+    /// delivery (`GREYLIST_PASS_DAYS`).
+    pub pass_window: Duration,
+    /// Never greylisted (`GREYLIST_ALLOWLIST`).
+    pub allowlist: Vec<AllowEntry>,
+}
+
+/// One `GREYLIST_ALLOWLIST` entry: an IP or CIDR network, a sender address, or a
+/// sender domain (`example.com` or `@example.com`).
+#[derive(Debug, Clone)]
+pub enum AllowEntry {
+    Network(IpAddr, u8),
+    Address(String),
+    Domain(String),
+}
+
+impl AllowEntry {
+    fn parse(value: &str) -> Option<Self> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+        if let Ok(ip) = addr.parse::<IpAddr>() {
+            let max = if ip.is_ipv4() { 32 } else { 128 };
+            let prefix = match prefix {
+                Some(p) => p.parse().ok().filter(|p| *p <= max)?,
+                None => max,
+            };
+            return Some(AllowEntry::Network(ip, prefix));
+        }
+        if let Some(domain) = value.strip_prefix('@') {
+            return Some(AllowEntry::Domain(domain.to_string()));
+        }
+        if value.contains('@') {
+            return Some(AllowEntry::Address(value.to_string()));
+        }
+        Some(AllowEntry::Domain(value.to_string()))
+    }
+
+    fn matches(&self, ip: IpAddr, sender: &str) -> bool {
+        match self {
+            AllowEntry::Network(network, prefix) => spf::in_network(ip, *network, *prefix),
+            AllowEntry::Address(address) => sender.eq_ignore_ascii_case(address),
+            AllowEntry::Domain(domain) => sender
+                .rsplit_once('@')
+                .is_some_and(|(_, d)| d.eq_ignore_ascii_case(domain)),
+        }
+    }
+}
+
+impl GreylistConfig {
+    pub fn from_env() -> Self {
+        Self {
+            enabled: env_or("GREYLIST_ENABLED", false),
+            delay: Duration::seconds(env_or("GREYLIST_DELAY_SECS", 300)),
+            retry_window: Duration::hours(env_or("GREYLIST_RETRY_HOURS", 48)),
+            pass_window: Duration::days(env_or("GREYLIST_PASS_DAYS", 35)),
+            allowlist: env_list("GREYLIST_ALLOWLIST")
+                .iter()
+                .filter_map(|entry| {
+                    let parsed = AllowEntry::parse(entry);
+                    if parsed.is_none() {
+                        tracing::warn!("Ignoring invalid GREYLIST_ALLOWLIST entry {:?}", entry);
+                    }
+                    parsed
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Pass,
+    /// Try again after this many seconds.
+    Defer(i64),
+}
+
+/// The client network a triplet is keyed on: /24 for IPv4, /64 for IPv6.
+fn network_key(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            format!("{}.{}.{}.0/24", a, b, c)
+        }
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            format!("{:x}:{:x}:{:x}:{:x}::/64", s[0], s[1], s[2], s[3])
+        }
+    }
+}
+
+/// Decide whether a recipient may be accepted now.
+pub async fn check(
+    db: &Db,
+    config: &GreylistConfig,
+    ip: IpAddr,
+    sender: &str,
+    recipient: &str,
+) -> Result<Decision> {
+    if !config.enabled || config.allowlist.iter().any(|e| e.matches(ip, sender)) {
+        return Ok(Decision::Pass);
+    }
+
+    let network = network_key(ip);
+    let sender = if sender.is_empty() {
+        "<>".to_string()
+    } else {
+        sender.to_lowercase()
+    };
+    let recipient = recipient.to_lowercase();
+    let entry = db
+        .record_greylist_attempt(&network, &sender, &recipient)
+        .await?;
+    let now = Utc::now();
+
+    if entry.attempts == 1 {
+        db.prune_greylist(config.retry_window.max(config.pass_window).num_seconds())
+            .await?;
+    }
+
+    let expired = match entry.passed_at {
+        // a passed triplet stays allowed while mail keeps arriving
+        Some(passed_at) => now - passed_at > config.pass_window,
+        None => now - entry.first_seen > config.retry_window,
+    };
+    if expired {
+        db.restart_greylist(&network, &sender, &recipient).await?;
+        return Ok(Decision::Defer(config.delay.num_seconds()));
+    }
+
+    let waited = now - entry.first_seen;
+    if entry.passed_at.is_some() || waited >= config.delay {
+        db.pass_greylist(&network, &sender, &recipient).await?;
+        Ok(Decision::Pass)
+    } else {
+        // round up so the client does not come back just too early
+        let remaining = (config.delay - waited).num_milliseconds();
+        Ok(Decision::Defer((remaining + 999) / 1000))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::{age_greylist, remove_greylist, test_db};
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_allowlist_entries() {
+        assert!(matches!(
+            AllowEntry::parse("192.0.2.0/24"),
+            Some(AllowEntry::Network(_, 24))
+        ));
+        assert!(matches!(
+            AllowEntry::parse("2001:db8::/32"),
+            Some(AllowEntry::Network(IpAddr::V6(_), 32))
+        ));
+        assert!(matches!(
+            AllowEntry::parse("192.0.2.1"),
+            Some(AllowEntry::Network(_, 32))
+        ));
+        assert!(AllowEntry::parse("192.0.2.0/33").is_none());
+        assert!(AllowEntry::parse("192.0.2.0/x").is_none());
+        assert!(matches!(
+            AllowEntry::parse("@example.com"),
+            Some(AllowEntry::Domain(d)) if d == "example.com"
+        ));
+        assert!(matches!(
+            AllowEntry::parse("example.com"),
+            Some(AllowEntry::Domain(d)) if d == "example.com"
+        ));
+        assert!(matches!(
+            AllowEntry::parse("alice@example.com"),
+            Some(AllowEntry::Address(a)) if a == "alice@example.com"
+        ));
+    }
+
+    #[test]
+    fn allowlist_entries_match_clients_and_senders() {
+        let entry = |value: &str| AllowEntry::parse(value).unwrap();
+        let client = ip("192.0.2.77");
+
+        assert!(entry("192.0.2.0/24").matches(client, "a@example.org"));
+        assert!(!entry("192.0.2.128/25").matches(client, "a@example.org"));
+        assert!(entry("2001:db8::/32").matches(ip("2001:db8::1"), ""));
+
+        assert!(entry("Alice@Example.com").matches(client, "alice@example.com"));
+        assert!(!entry("alice@example.com").matches(client, "bob@example.com"));
+
+        assert!(entry("example.com").matches(client, "bob@EXAMPLE.com"));
+        assert!(!entry("example.com").matches(client, "bob@mail.example.com"));
+        assert!(!entry("example.com").matches(client, ""));
+    }
+
+    #[test]
+    fn triplets_are_keyed_on_the_client_network() {
+        assert_eq!(network_key(ip("192.0.2.77")), "192.0.2.0/24");
+        assert_eq!(network_key(ip("2001:db8:1:2:3:4:5:6")), "2001:db8:1:2::/64");
+    }
+
+    #[tokio::test]
+    async fn triplet_lifecycle() {
+        let Some(db) = test_db().await else {
+            return;
+        };
+        let config = GreylistConfig {
+            enabled: true,
+            delay: Duration::seconds(300),
+            retry_window: Duration::hours(48),
+            pass_window: Duration::days(35),
+            allowlist: vec![AllowEntry::parse("198.51.100.0/24").unwrap()],
+        };
+        let sender = format!("{}@greylist.test", uuid::Uuid::new_v4().simple());
+        let client = ip("192.0.2.10");
+        let attempt = |client, recipient| check(&db, &config, client, &sender, recipient);
+
+        // the first attempt is deferred, and so is a retry before the delay
+        assert_eq!(
+            attempt(client, "a@tempmail.test").await.unwrap(),
+            Decision::Defer(300)
+        );
+        let Decision::Defer(remaining) = attempt(client, "a@tempmail.test").await.unwrap() else {
+            panic!("early retry passed");
+        };
+        assert!(remaining > 0 && remaining <= 300);
+
+        // after the delay, a retry from the same network passes
+        age_greylist(&db, &sender, 301).await;
+        assert_eq!(
+            attempt(ip("192.0.2.20"), "A@tempmail.test").await.unwrap(),
+            Decision::Pass
+        );
+        assert_eq!(
+            attempt(client, "b@tempmail.test").await.unwrap(),
+            Decision::Defer(300)
+        );
+        assert_eq!(
+            attempt(ip("192.0.3.10"), "a@tempmail.test").await.unwrap(),
+            Decision::Defer(300)
+        );
+
+        // a passed triplet stays passed while mail keeps coming within the pass window
+        age_greylist(&db, &sender, 34 * 86400).await;
+        assert_eq!(
+            attempt(client, "a@tempmail.test").await.unwrap(),
+            Decision::Pass
+        );
+        age_greylist(&db, &sender, 36 * 86400).await;
+        assert_eq!(
+            attempt(client, "a@tempmail.test").await.unwrap(),
+            Decision::Defer(300)
+        );
+
+        // a retry after the retry window starts over
+        assert_eq!(
+            attempt(client, "c@tempmail.test").await.unwrap(),
+            Decision::Defer(300)
+        );
+        age_greylist(&db, &sender, 49 * 3600).await;
+        assert_eq!(
+            attempt(client, "c@tempmail.test").await.unwrap(),
+            Decision::Defer(300)
+        );
+        age_greylist(&db, &sender, 301).await;
+        assert_eq!(
+            attempt(client, "c@tempmail.test").await.unwrap(),
+            Decision::Pass
+        );
+
+        // allowlisted clients are never deferred
+        assert_eq!(
+            attempt(ip("198.51.100.5"), "d@tempmail.test")
+                .await
+                .unwrap(),
+            Decision::Pass
+        );
+
+        remove_greylist(&db, &sender).await;
+    }
+}
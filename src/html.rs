@@ -0,0 +1,600 @@
+//! Conversions between HTML and plain-text message bodies.
+
+/// Render an HTML body as readable plain text.
+///
+/// Block elements become line breaks, list items are bulleted or numbered,
+/// table cells are separated by `|`, and links are turned into numbered
+/// footnotes listed after the text.
+pub fn html_to_text(html: &str) -> String {
+    let mut w = TextWriter::default();
+    let mut links: Vec<String> = Vec::new();
+    let mut anchors: Vec<Option<(String, usize)>> = Vec::new();
+    let mut lists: Vec<Option<usize>> = Vec::new();
+    let mut skip_depth = 0usize;
+    let mut pre_depth = 0usize;
+    let mut first_cell = true;
+    let mut pos = 0;
+
+    while pos < html.len() {
+        let rest = &html[pos..];
+
+        if !rest.starts_with('<') {
+            let end = rest.find('<').unwrap_or(rest.len());
+            if skip_depth == 0 {
+                w.push_text(&decode_entities(&rest[..end]), pre_depth > 0);
+            }
+            pos += end;
+            continue;
+        }
+
+        if rest.starts_with("<!--") {
+            pos += rest.find("-->").map(|i| i + 3).unwrap_or(rest.len());
+            continue;
+        }
+
+        let end = match find_tag_end(rest) {
+            Some(i) => i,
+            None => {
+                // a stray '<' that never closes is plain text
+                if skip_depth == 0 {
+                    w.push_text("<", pre_depth > 0);
+                }
+                pos += 1;
+                continue;
+            }
+        };
+        let tag = &rest[1..end];
+        pos += end + 1;
+
+        let closing = tag.starts_with('/');
+        let body = tag.trim_start_matches('/');
+        let name: String = body
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect::<String>()
+            .to_ascii_lowercase();
+        if name.is_empty() {
+            continue; // <!DOCTYPE>, <?xml?> and friends
+        }
+
+        if matches!(name.as_str(), "script" | "style" | "head" | "title") {
+            if closing {
+                skip_depth = skip_depth.saturating_sub(1);
+            } else if !body.ends_with('/') {
+                skip_depth += 1;
+            }
+            continue;
+        }
+        if skip_depth > 0 {
+            continue;
+        }
+
+        match (name.as_str(), closing) {
+            ("br", _) => w.newline(),
+            ("hr", false) => {
+                w.newline();
+                w.push_raw("----");
+                w.newline();
+            }
+            ("p" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "blockquote", _) => w.blank_line(),
+            ("div" | "section" | "article" | "header" | "footer" | "address" | "center", _) => {
+                w.newline()
+            }
+            ("pre", false) => {
+                w.blank_line();
+                pre_depth += 1;
+            }
+            ("pre", true) => {
+                pre_depth = pre_depth.saturating_sub(1);
+                w.blank_line();
+            }
+            ("ul", false) => {
+                w.newline();
+                lists.push(None);
+            }
+            ("ol", false) => {
+                w.newline();
+                lists.push(Some(0));
+            }
+            ("ul" | "ol", true) => {
+                lists.pop();
+                w.newline();
+            }
+            ("li", false) => {
+                w.newline();
+                let depth = lists.len().max(1);
+                w.push_raw(&"  ".repeat(depth - 1));
+                match lists.last_mut() {
+                    Some(Some(n)) => {
+                        *n += 1;
+                        let marker = format!("{}. ", n);
+                        w.push_raw(&marker);
+                    }
+                    _ => w.push_raw("* "),
+                }
+            }
+            ("li", true) => w.newline(),
+            ("table", _) => w.blank_line(),
+            ("tr", false) => {
+                w.newline();
+                first_cell = true;
+            }
+            ("tr", true) => w.newline(),
+            ("td" | "th", false) => {
+                if !first_cell {
+                    w.push_raw(" | ");
+                }
+                first_cell = false;
+            }
+            ("img", false) => {
+                if let Some(alt) = attribute(body, "alt").filter(|a| !a.trim().is_empty()) {
+                    w.push_text(&format!("[{}]", alt.trim()), false);
+                }
+            }
+            ("a", false) => {
+                let href = attribute(body, "href")
+                    .map(|h| h.trim().to_string())
+                    .filter(|h| !h.is_empty() && !h.starts_with('#') && !h.starts_with("javascript:"));
+                anchors.push(href.map(|h| (h, w.len())));
+            }
+            ("a", true) => {
+                if let Some(Some((href, start))) = anchors.pop() {
+                    let text = w.since(start).trim().to_string();
+                    let target = href.strip_prefix("mailto:").unwrap_or(&href);
+                    if text.is_empty() {
+                        w.push_text(target, false);
+                    } else if text != target {
+                        let n = match links.iter().position(|l| *l == href) {
+                            Some(i) => i + 1,
+                            None => {
+                                links.push(href);
+                                links.len()
+                            }
+                        };
+                        w.push_raw(&format!(" [{}]", n));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut text = w.finish();
+    if !links.is_empty() {
+        text.push_str("\n\nLinks:\n");
+        for (i, link) in links.iter().enumerate() {
+            text.push_str(&format!("[{}] {}\n", i + 1, link));
+        }
+        text.pop();
+    }
+    text
+}
+
+/// Render a plain-text body as HTML, escaping markup and turning URLs and
+/// email addresses into links.
+pub fn text_to_html(text: &str) -> String {
+    let mut out = String::from(
+        "<div style=\"white-space: pre-wrap; word-wrap: break-word; font-family: monospace;\">",
+    );
+
+    for (i, line) in text.lines().enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        for (j, word) in line.split(' ').enumerate() {
+            if j > 0 {
+                out.push(' ');
+            }
+            out.push_str(&linkify_word(word));
+        }
+    }
+
+    out.push_str("</div>");
+    out
+}
+
+/// A whitespace-collapsed excerpt of a text body, cut at `max_chars`.
+pub fn preview(text: &str, max_chars: usize) -> String {
+    let collapsed = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if collapsed.chars().count() <= max_chars {
+        return collapsed;
+    }
+    let mut cut: String = collapsed.chars().take(max_chars).collect();
+    cut.push('…');
+    cut
+}
+
+pub fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn linkify_word(word: &str) -> String {
+    let lower = word.to_ascii_lowercase();
+    let start = ["https://", "http://", "www."]
+        .iter()
+        .filter_map(|p| lower.find(p))
+        .min();
+
+    if let Some(start) = start {
+        let (prefix, candidate) = word.split_at(start);
+        let url = candidate.trim_end_matches(['.', ',', ';', ':', '!', '?', ')', ']', '>', '"', '\'']);
+        let suffix = &candidate[url.len()..];
+        let href = if url.to_ascii_lowercase().starts_with("www.") {
+            format!("http://{}", url)
+        } else {
+            url.to_string()
+        };
+        return format!(
+            "{}<a href=\"{}\" target=\"_blank\" rel=\"noopener noreferrer\">{}</a>{}",
+            escape(prefix),
+            escape(&href),
+            escape(url),
+            escape(suffix)
+        );
+    }
+
+    let trimmed = word
+        .trim_start_matches(['<', '(', '[', '"', '\''])
+        .trim_end_matches(['.', ',', ';', ':', '!', '?', ')', ']', '>', '"', '\'']);
+    if is_email(trimmed) {
+        let start = word.find(trimmed).unwrap_or(0);
+        return format!(
+            "{}<a href=\"mailto:{}\">{}</a>{}",
+            escape(&word[..start]),
+            escape(trimmed),
+            escape(trimmed),
+            escape(&word[start + trimmed.len()..])
+        );
+    }
+
+    escape(word)
+}
+
+fn is_email(s: &str) -> bool {
+    match s.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && s.chars()
+                    .all(|c| c.is_alphanumeric() || "@.+-_%".contains(c))
+        }
+        None => false,
+    }
+}
+
+/// Locate the `>` closing the tag that starts at the beginning of `s`, skipping quoted attribute values.
+fn find_tag_end(s: &str) -> Option<usize> {
+    let mut quote: Option<char> = None;
+    for (i, c) in s.char_indices().skip(1) {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '>') => return Some(i),
+            (None, '<') if i == 1 => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Extract an attribute value from the inside of a tag (`a href="..." class=x`).
+pub fn attribute(tag: &str, name: &str) -> Option<String> {
+    let bytes = tag.as_bytes();
+    let mut i = tag.find(|c: char| c.is_whitespace())?;
+
+    while i < bytes.len() {
+        while i < bytes.len() && (bytes[i].is_ascii_whitespace() || bytes[i] == b'/') {
+            i += 1;
+        }
+        let key_start = i;
+        while i < bytes.len() && !bytes[i].is_ascii_whitespace() && bytes[i] != b'=' && bytes[i] != b'/' {
+            i += 1;
+        }
+        let key = &tag[key_start..i];
+        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+
+        let mut value = String::new();
+        if i < bytes.len() && bytes[i] == b'=' {
+            i += 1;
+            while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            if i < bytes.len() && (bytes[i] == b'"' || bytes[i] == b'\'') {
+                let q = bytes[i];
+                let start = i + 1;
+                let end = tag[start..]
+                    .find(q as char)
+                    .map(|e| start + e)
+                    .unwrap_or(tag.len());
+                value = tag[start..end].to_string();
+                i = end + 1;
+            } else {
+                let start = i;
+                while i < bytes.len() && !bytes[i].is_ascii_whitespace() {
+                    i += 1;
+                }
+                value = tag[start..i].to_string();
+            }
+        }
+
+        if key.eq_ignore_ascii_case(name) {
+            return Some(decode_entities(&value));
+        }
+        if key.is_empty() {
+            break;
+        }
+    }
+    None
+}
+
+/// Decode named and numeric character references.
+pub fn decode_entities(s: &str) -> String {
+    if !s.contains('&') {
+        return s.to_string();
+    }
+
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+
+        let end = rest[1..]
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '#'))
+            .map(|i| i + 1)
+            .unwrap_or(rest.len());
+        let name = &rest[1..end];
+        let decoded = if let Some(num) = name.strip_prefix('#') {
+            numeric_reference(num).map(|c| c.to_string())
+        } else {
+            named_entity(name).map(|s| s.to_string())
+        };
+
+        match decoded {
+            Some(text) => {
+                out.push_str(&text);
+                rest = rest[end..].strip_prefix(';').unwrap_or(&rest[end..]);
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+/// The character of `&#num;`, or `None` if `num` is not a number. NUL, surrogates and
+/// values past U+10FFFF become U+FFFD as in HTML5, so no decoded text carries them.
+fn numeric_reference(num: &str) -> Option<char> {
+    let (digits, radix) = match num.strip_prefix(['x', 'X']) {
+        Some(hex) => (hex, 16),
+        None => (num, 10),
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    let code = u32::from_str_radix(digits, radix).unwrap_or(u32::MAX);
+    Some(
+        char::from_u32(code)
+            .filter(|&c| c != '\0')
+            .unwrap_or(char::REPLACEMENT_CHARACTER),
+    )
+}
+
+fn named_entity(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "amp" | "AMP" => "&",
+        "lt" | "LT" => "<",
+        "gt" | "GT" => ">",
+        "quot" | "QUOT" => "\"",
+        "apos" => "'",
+        "nbsp" => "\u{a0}",
+        "copy" => "©",
+        "reg" => "®",
+        "trade" => "™",
+        "mdash" => "—",
+        "ndash" => "–",
+        "hellip" => "…",
+        "lsquo" => "‘",
+        "rsquo" => "’",
+        "ldquo" => "“",
+        "rdquo" => "”",
+        "laquo" => "«",
+        "raquo" => "»",
+        "bull" => "•",
+        "middot" => "·",
+        "euro" => "€",
+        "pound" => "£",
+        "zwnj" | "zwj" | "shy" => "",
+        _ => return None,
+    })
+}
+
+/// Accumulates converted text while collapsing whitespace and redundant line breaks.
+#[derive(Default)]
+struct TextWriter {
+    out: String,
+}
+
+impl TextWriter {
+    fn len(&self) -> usize {
+        self.out.len()
+    }
+
+    /// Text written since `start`. Trailing spaces before `start` may have been
+    /// trimmed since, so it is clamped to the text that follows the trim.
+    fn since(&self, start: usize) -> &str {
+        let mut start = start.min(self.out.len());
+        while !self.out.is_char_boundary(start) {
+            start -= 1;
+        }
+        &self.out[start..]
+    }
+
+    fn push_text(&mut self, text: &str, preformatted: bool) {
+        if preformatted {
+            self.out.push_str(text);
+            return;
+        }
+        for c in text.chars() {
+            if c.is_ascii_whitespace() {
+                if !self.out.is_empty() && !self.out.ends_with([' ', '\n']) {
+                    self.out.push(' ');
+                }
+            } else {
+                self.out.push(c);
+            }
+        }
+    }
+
+    fn push_raw(&mut self, text: &str) {
+        self.out.push_str(text);
+    }
+
+    fn trim_trailing_spaces(&mut self) {
+        let trimmed = self.out.trim_end_matches(' ').len();
+        self.out.truncate(trimmed);
+    }
+
+    fn newline(&mut self) {
+        self.trim_trailing_spaces();
+        if !self.out.is_empty() && !self.out.ends_with('\n') {
+            self.out.push('\n');
+        }
+    }
+
+    fn blank_line(&mut self) {
+        self.newline();
+        if !self.out.is_empty() && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+    }
+
+    fn finish(self) -> String {
+        let text = self.out.replace('\u{a0}', " ");
+        let mut out = String::with_capacity(text.len());
+        let mut newlines = 0;
+        for line in text.lines() {
+            let line = line.trim_end();
+            if line.is_empty() {
+                newlines += 1;
+                if newlines > 1 {
+                    continue;
+                }
+            } else {
+                newlines = 0;
+            }
+            out.push_str(line);
+            out.push('\n');
+        }
+        out.trim().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn links_become_footnotes() {
+        let text = html_to_text(
+            "<p>Hello <a href=\"https://example.com/verify?t=1\">verify your email</a>.</p>\
+             <p>Or <a href=\"https://example.com/verify?t=1\">here</a>, \
+             <a href=\"mailto:x@y.z\">mail</a> or \
+             <a href=\"https://example.com/\">https://example.com/</a></p>",
+        );
+        assert_eq!(
+            text,
+            "Hello verify your email [1].\n\n\
+             Or here [1], mail [2] or https://example.com/\n\n\
+             Links:\n\
+             [1] https://example.com/verify?t=1\n\
+             [2] mailto:x@y.z"
+        );
+    }
+
+    #[test]
+    fn lists_are_numbered_and_bulleted() {
+        let text = html_to_text(
+            "<ol><li>One</li><li>Two<ul><li>Nested</li></ul></li><li>Three</li></ol>\
+             <ul><li>A</li></ul>",
+        );
+        assert_eq!(text, "1. One\n2. Two\n  * Nested\n3. Three\n* A");
+    }
+
+    #[test]
+    fn table_cells_are_separated() {
+        let text = html_to_text(
+            "<table><tr><th>Item</th><th>Qty</th></tr><tr><td>Apple</td><td>2</td></tr></table>",
+        );
+        assert_eq!(text, "Item | Qty\nApple | 2");
+    }
+
+    #[test]
+    fn scripts_are_dropped_and_entities_decoded() {
+        let text = html_to_text(
+            "<style>p{}</style><script>x</script><p>a&amp;b &lt;c&gt; &#65;&#x42; &#; &bogus;</p>",
+        );
+        assert_eq!(text, "a&b <c> AB &#; &bogus;");
+    }
+
+    #[test]
+    fn invalid_code_points_are_replaced() {
+        assert_eq!(decode_entities("a&#0;b&#x0;"), "a\u{fffd}b\u{fffd}");
+        assert_eq!(
+            decode_entities("&#xD800;&#x110000;&#99999999999;"),
+            "\u{fffd}".repeat(3)
+        );
+        assert_eq!(decode_entities("&#x1g;&#x;"), "&#x1g;&#x;");
+        assert!(!html_to_text("<p>&#0;</p>").contains('\0'));
+    }
+
+    #[test]
+    fn text_to_html_escapes_and_links() {
+        assert_eq!(
+            text_to_html(
+                "Visit https://example.com/a?b=1&c=<2> or mail bob@example.com.\n<script>"
+            ),
+            "<div style=\"white-space: pre-wrap; word-wrap: break-word; font-family: monospace;\">\
+             Visit <a href=\"https://example.com/a?b=1&amp;c=&lt;2\" target=\"_blank\" \
+             rel=\"noopener noreferrer\">https://example.com/a?b=1&amp;c=&lt;2</a>&gt; \
+             or mail <a href=\"mailto:bob@example.com\">bob@example.com</a>.\n\
+             &lt;script&gt;</div>"
+        );
+        assert!(text_to_html("see www.example.com,").contains("href=\"http://www.example.com\""));
+        assert!(!text_to_html("\"><img src=x onerror=alert(1)>").contains("<img"));
+    }
+
+    #[test]
+    fn link_after_trimmed_preformatted_spaces() {
+        let text = html_to_text("<pre>ab     <a href=\"http://x.com\"></pre>q</a>");
+        assert!(text.starts_with("ab"));
+        assert!(text.contains("http://x.com"));
+    }
+
+    #[test]
+    fn link_text_after_trim_is_not_split_inside_a_character() {
+        let text = html_to_text("<pre>ab   <a href=\"http://x.com\"></pre>é</a>");
+        assert!(text.contains('é'));
+    }
+}
@@ -1,17 +1,26 @@
 use axum::{
-    Router, extract::{Form, Path, State}, http::{HeaderValue, StatusCode}, response::{Html, IntoResponse, Redirect}, routing::{get, post}, serve
+    Router, extract::{Form, Path, Query, State}, http::{header, HeaderValue, StatusCode}, response::{Html, IntoResponse, Redirect, Response}, routing::{delete, get, post}, serve, Json
 };
+use mail_parser::MessageParser;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use serde::Deserialize;
 use std::{net::SocketAddr, sync::Arc};
 use tera::{Context, Tera};
 use tokio::net::TcpListener; // <-- New import for server binding
+use axum::extract::{ConnectInfo, Request};
+use axum::Extension;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
 use tower_http::cors::{Any, CorsLayer};
+use tower_http::trace::TraceLayer;
 use tower_http::services::ServeDir;
 use tracing::error;
 use uuid::Uuid; // <-- Added Uuid import for view_message Path
 
-use crate::db::{Db, Message};
+use crate::db::{Db, MailAddress, Message, MessageFilter, SessionTranscript};
+use crate::proxy::{self, ProxyConfig};
+use crate::{extract, html, mime};
 
 #[derive(Clone)]
 pub struct AppState {
@@ -20,17 +29,18 @@ pub struct AppState {
     pub templates: Arc<Tera>,
 }
 
+/// The page templates (templates/*). Tera escapes every `.html` value, which keeps
+/// anything a sender controls inert; a value may only skip that with `| safe`.
+fn load_templates() -> tera::Result<Tera> {
+    Tera::new("templates/**/*")
+}
+
 /// Start the HTTP server (called from main.rs)
 pub async fn start_server(listen: SocketAddr, domain: String, db: Db) -> anyhow::Result<()> {
-    // initialize tera: templates directory (templates/*)
-    let mut tera = Tera::new("templates/**/*")?;
-    // disable autoescape for now (we render raw text inside <pre>)
-    tera.autoescape_on(vec![]);
-
     let state = AppState {
         db,
         domain,
-        templates: Arc::new(tera),
+        templates: Arc::new(load_templates()?),
     };
 
     let cors = CorsLayer::new()
@@ -43,32 +53,118 @@ pub async fn start_server(listen: SocketAddr, domain: String, db: Db) -> anyhow:
     fn api_routes() -> Router<AppState> {
         Router::new()
             .route("/api/mailbox", post(create_mailbox)) // example API route
-            .route("/api/:local/messages", get(view_inbox))
+            .route("/api/:local/messages", get(list_messages))
             .route("/api/:local/messages/:id", get(view_message))
+            .route("/api/:local/messages/:id/html", get(view_message_html))
+            .route(
+                "/api/:local/messages/:id/attachments/:attachment_id",
+                get(download_attachment),
+            )
+            .route("/api/:local/messages/:id/headers", get(message_headers))
+            .route("/api/:local/messages/:id/addresses", get(message_addresses))
+            .route("/api/:local/messages/:id/connection", get(message_connection))
+            .route("/api/:local/messages/:id/auth", get(message_auth))
+            .route("/api/:local/messages/:id/spam", get(message_spam))
+            .route("/api/:local/messages/:id/mime", get(message_mime))
+            .route("/api/:local/messages/:id/parts/:path", get(download_part))
+            .route("/api/:local/messages/:id/codes", get(message_codes))
+            .route("/api/:local/messages/:id/links", get(message_links))
+            .route("/api/:local/codes/latest", get(latest_code))
+            .route("/api/:local/rules", get(list_rules).post(create_rule))
+            .route("/api/:local/rules/:rule_id", delete(delete_rule))
+            .route(
+                "/api/:local/transcripts",
+                get(list_transcripts).put(set_transcript_recording),
+            )
+            .route("/api/:local/transcripts/:transcript_id", get(get_transcript))
+            .route("/api/:local/reports", get(list_reports))
     }
 
     let app = Router::new()
         .merge(api_routes())
         .layer(cors)
         .route("/", get(index))
+        .route("/metrics", get(metrics))
         .route("/create", post(create_mailbox))
         .route("/inbox/:local", get(view_inbox))
+        .route("/inbox/:local/transcripts", get(view_transcripts))
+        .route("/inbox/:local/transcripts/:transcript_id", get(view_transcript))
+        .route("/inbox/:local/reports", get(view_reports))
         .route("/inbox/:local/:id", get(view_message))
+        .route("/inbox/:local/:id/html", get(view_message_html))
+        .route(
+            "/inbox/:local/:id/attachments/:attachment_id",
+            get(download_attachment),
+        )
+        .route("/inbox/:local/:id/parts/:path", get(download_part))
         // serve static files from ./static on /static/*
         .nest_service("/templates", ServeDir::new("static"))
         .with_state(state);
 
+    let app = app.layer(TraceLayer::new_for_http().make_span_with(|request: &Request<_>| {
+        let client = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string())
+            .unwrap_or_default();
+        tracing::debug_span!(
+            "request",
+            method = %request.method(),
+            uri = %request.uri(),
+            client = %client
+        )
+    }));
+
     tracing::info!("HTTP server listening on http://{}", listen);
 
     // FIX E0433: Use tokio::net::TcpListener and axum::serve
     let listener = TcpListener::bind(listen).await?;
-    serve(listener, app.into_make_service()).await?;
+    let proxy = ProxyConfig::from_env("HTTP");
+    if !proxy.enabled {
+        serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
+        return Ok(());
+    }
 
-    Ok(())
+    // behind a balancer every connection starts with a PROXY header, which axum::serve
+    // cannot consume, so connections are served one by one with the client address attached
+    loop {
+        let (mut stream, balancer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let proxy = proxy.clone();
+        let app = app.clone();
+        tokio::spawn(async move {
+            let client = match proxy::client_address(&proxy, &mut stream, balancer).await {
+                Ok(client) => client,
+                Err(e) => {
+                    tracing::warn!("Dropping HTTP connection from {}: {}", balancer, e);
+                    return;
+                }
+            };
+            let service = TowerToHyperService::new(app.layer(Extension(ConnectInfo(client))));
+            if let Err(e) = auto::Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::debug!("HTTP connection from {} ended: {}", client, e);
+            }
+        });
+    }
 }
 
 /* ---------- Handlers ---------- */
 
+async fn metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        crate::metrics::render(),
+    )
+}
+
 async fn index(State(state): State<AppState>) -> impl IntoResponse {
     let mut ctx = Context::new();
     ctx.insert("domain", &state.domain);
@@ -91,11 +187,11 @@ async fn create_mailbox(
     State(state): State<AppState>,
     Form(form): Form<CreateForm>,
 ) -> impl IntoResponse {
-    // generate a 10-char local part
+    // generate a 10-char local part; local parts are stored lowercase
     let local: String = thread_rng()
         .sample_iter(&Alphanumeric)
         .take(10)
-        .map(char::from)
+        .map(|c| char::from(c).to_ascii_lowercase())
         .collect();
 
     let ttl_seconds = form.ttl_hours.map(|h| h * 3600);
@@ -108,11 +204,40 @@ async fn create_mailbox(
     }
 
     // FIX E0308: Must call .into_response() on the bare Redirect to match return type
-    Redirect::to(&format!("/inbox/{}", local)).into_response()
+    Redirect::to(&format!("/inbox/{}", path_segment(&local))).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct InboxQuery {
+    pub q: Option<String>,
+    /// `Name` to require a header, or `Name:value` to also match its value exactly.
+    pub header: Option<String>,
+    /// Show messages held back by the virus scanner.
+    #[serde(default)]
+    pub quarantined: bool,
+}
+
+impl InboxQuery {
+    fn filter(&self) -> MessageFilter<'_> {
+        let (header_name, header_value) = match self.header.as_deref().map(str::trim) {
+            Some(h) if !h.is_empty() => match h.split_once(':') {
+                Some((name, value)) => (Some(name.trim()), Some(value.trim())),
+                None => (Some(h), None),
+            },
+            _ => (None, None),
+        };
+        MessageFilter {
+            search: self.q.as_deref().map(str::trim).filter(|q| !q.is_empty()),
+            header_name,
+            header_value,
+            quarantined: self.quarantined,
+        }
+    }
 }
 
 async fn view_inbox(
     Path(local): Path<String>,
+    Query(query): Query<InboxQuery>,
     State(state): State<AppState>,
 ) -> Result<Html<String>, Redirect> {
     // check mailbox exists (uses Db::mailbox_exists)
@@ -122,7 +247,8 @@ async fn view_inbox(
     }
 
     // List messages (uses Db::list_messages)
-    let messages = match state.db.list_messages(&local).await {
+    let filter = query.filter();
+    let messages = match state.db.list_messages(&local, &filter).await {
         Ok(v) => v,
         Err(e) => {
             error!("db list_messages error: {:?}", e);
@@ -134,6 +260,9 @@ async fn view_inbox(
     let mut ctx = Context::new();
     ctx.insert("domain", &state.domain);
     ctx.insert("local", &local);
+    ctx.insert("q", filter.search.unwrap_or(""));
+    ctx.insert("header", query.header.as_deref().unwrap_or(""));
+    ctx.insert("quarantined", &query.quarantined);
 
     // convert messages into simple serializable objects for Tera
     let msgs_for_template: Vec<_> = messages
@@ -142,19 +271,18 @@ async fn view_inbox(
             let id = m.id.to_string();
 
             // FIX E0599 (unwrap_or_else for String) - Message::from_addr must be Option<String> in db.rs
-            let from = m.from_addr.unwrap_or_else(|| "<unknown>".into());
-
-            let received = {
-                // FIX: Use .timestamp() which returns i64, not .timestamp_opt() which is not a method on DateTime<Utc>
-                let ts = m.received_at.timestamp();
-                chrono::NaiveDateTime::from_timestamp_opt(ts, 0)
-                    .map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string())
-                    .unwrap_or_else(|| "unknown".into())
+            let from = match &m.header_from {
+                Some(a) => display_address(a),
+                None => m.from_addr.clone().unwrap_or_else(|| "<unknown>".into()),
             };
 
+            let received = m.received_at.format("%Y-%m-%d %H:%M:%S").to_string();
+
             serde_json::json!({
                 "id": id,
                 "from": from,
+                "subject": m.subject,
+                "preview": html::preview(&m.body_text, 140),
                 "received": received
             })
         })
@@ -177,7 +305,7 @@ async fn view_message(
     // parse uuid
     let uuid = match Uuid::parse_str(&id) {
         Ok(u) => u,
-        Err(_) => return Err(Redirect::to(&format!("/inbox/{}", local))),
+        Err(_) => return Err(Redirect::to(&format!("/inbox/{}", path_segment(&local)))),
     };
 
     // Get message (uses Db::get_message)
@@ -185,36 +313,1102 @@ async fn view_message(
         Ok(o) => o,
         Err(e) => {
             error!("db get_message error: {:?}", e);
-            return Err(Redirect::to(&format!("/inbox/{}", local)));
+            return Err(Redirect::to(&format!("/inbox/{}", path_segment(&local))));
         }
     };
 
     let message = match opt {
         Some(m) => m,
-        None => return Err(Redirect::to(&format!("/inbox/{}", local))),
+        None => return Err(Redirect::to(&format!("/inbox/{}", path_segment(&local)))),
     };
 
     let mut ctx = Context::new();
     ctx.insert("domain", &state.domain);
     ctx.insert("local", &local);
-    ctx.insert(
-        "from",
-        &message.from_addr.unwrap_or_else(|| "<unknown>".into()),
-    );
+    let from = match &message.header_from {
+        Some(a) => display_address(a),
+        None => message.from_addr.clone().unwrap_or_else(|| "<unknown>".into()),
+    };
+    ctx.insert("from", &from);
+    ctx.insert("envelope_from", message.from_addr.as_deref().unwrap_or(""));
+    ctx.insert("envelope_to", &message.envelope_to);
+    ctx.insert("delivered_to", &message.to_addr);
+    ctx.insert("bcc", &message.bcc);
+    ctx.insert("connection", &message.connection);
+    let has_transcript = match &message.connection {
+        Some(c) => matches!(state.db.get_transcript(&local, &c.session_id).await, Ok(Some(_))),
+        None => false,
+    };
+    ctx.insert("has_transcript", &has_transcript);
+    ctx.insert("auth", &message.auth);
+    ctx.insert("spam", &message.spam);
+    ctx.insert("scan", &message.scan);
+    ctx.insert("quarantined", &message.quarantined);
+    for (key, list) in [
+        ("header_to", &message.header_to),
+        ("header_cc", &message.header_cc),
+        ("header_reply_to", &message.header_reply_to),
+    ] {
+        let shown: Vec<String> = list.iter().map(display_address).collect();
+        ctx.insert(key, &shown.join(", "));
+    }
     ctx.insert("raw", &message.raw);
+    ctx.insert("headers", &message.headers);
+    ctx.insert("id", &message.id.to_string());
 
-    // FIX: Use .timestamp() which returns i64, not .timestamp_opt() which is not a method on DateTime<Utc>
-    let ts = message.received_at.timestamp();
-    let received = chrono::NaiveDateTime::from_timestamp_opt(ts, 0)
-        .map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string())
-        .unwrap_or_else(|| "unknown".into());
-
+    let received = message.received_at.format("%Y-%m-%d %H:%M:%S").to_string();
     ctx.insert("received", &received);
 
+    let attachments = state.db.list_attachments(message.id).await.unwrap_or_else(|e| {
+        error!("db list_attachments error: {:?}", e);
+        vec![]
+    });
+    let attachments_for_template: Vec<_> = attachments
+        .into_iter()
+        .map(|a| {
+            serde_json::json!({
+                "id": a.id.to_string(),
+                "filename": a.filename.unwrap_or_else(|| "(unnamed)".into()),
+                "content_type": a.content_type,
+                "size": a.size,
+                "inline": a.content_id.is_some(),
+            })
+        })
+        .collect();
+    ctx.insert("attachments", &attachments_for_template);
+
+    let source = match state.db.message_source(message.id).await {
+        Ok(Some(source)) => source,
+        Ok(None) => message.raw.clone().into_bytes(),
+        Err(e) => {
+            error!("db message_source error: {:?}", e);
+            message.raw.clone().into_bytes()
+        }
+    };
+    if let Some(parsed) = MessageParser::default().parse(&source) {
+        let mut parts = Vec::new();
+        flatten_mime(&mime::mime_tree(&parsed), 0, &mut parts);
+        ctx.insert("mime_parts", &parts);
+    } else {
+        ctx.insert("mime_parts", &Vec::<serde_json::Value>::new());
+    }
+
+    for (key, kind) in [("codes", extract::KIND_CODE), ("links", extract::KIND_LINK)] {
+        let found = state
+            .db
+            .list_extractions(&local, message.id, kind)
+            .await
+            .unwrap_or_else(|e| {
+                error!("db list_extractions error: {:?}", e);
+                vec![]
+            });
+        ctx.insert(key, &found);
+    }
+
     let rendered = state.templates.render("message.html", &ctx).map_err(|e| {
         error!("render message template: {:?}", e);
-        Redirect::to(&format!("/inbox/{}", local))
+        Redirect::to(&format!("/inbox/{}", path_segment(&local)))
     })?;
 
     Ok(Html(rendered))
 }
+
+/// Serve the HTML body of a message with `cid:` references pointing at the stored inline parts.
+/// Text-only messages are rendered as escaped, linkified HTML instead.
+async fn view_message_html(
+    Path((local, id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Response {
+    let Ok(uuid) = Uuid::parse_str(&id) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let message = match state.db.get_message(&local, uuid).await {
+        Ok(Some(m)) => m,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            error!("db get_message error: {:?}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let Some(html) = message.body_html else {
+        return untrusted_html(html::text_to_html(&message.body_text));
+    };
+
+    let attachments = state.db.list_attachments(message.id).await.unwrap_or_else(|e| {
+        error!("db list_attachments error: {:?}", e);
+        vec![]
+    });
+
+    let rewritten = mime::rewrite_cid_references(&html, |cid| {
+        attachments
+            .iter()
+            .find(|a| a.content_id.as_deref() == Some(cid))
+            .map(|a| {
+                format!(
+                    "/inbox/{}/{}/attachments/{}",
+                    path_segment(&local),
+                    message.id,
+                    a.id
+                )
+            })
+    });
+
+    untrusted_html(rewritten)
+}
+
+/// Serve sender-controlled HTML from our origin. The iframe `sandbox` attribute does
+/// not apply when the URL is opened directly, so the response sandboxes itself.
+fn untrusted_html(html: String) -> Response {
+    (
+        [
+            (header::CONTENT_SECURITY_POLICY, "sandbox"),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+        ],
+        Html(html),
+    )
+        .into_response()
+}
+
+/// Image types browsers only ever render as pictures, which are safe to show inline.
+fn is_raster_image(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or("").trim();
+    ["image/png", "image/jpeg", "image/jpg", "image/gif", "image/webp", "image/bmp", "image/avif"]
+        .iter()
+        .any(|t| essence.eq_ignore_ascii_case(t))
+}
+
+async fn download_attachment(
+    Path((local, id, attachment_id)): Path<(String, String, String)>,
+    State(state): State<AppState>,
+) -> Response {
+    let (Ok(message_id), Ok(attachment_id)) =
+        (Uuid::parse_str(&id), Uuid::parse_str(&attachment_id))
+    else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let attachment = match state
+        .db
+        .get_attachment(&local, message_id, attachment_id)
+        .await
+    {
+        Ok(Some(a)) => a,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            error!("db get_attachment error: {:?}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let quarantined = state.db.message_quarantined(message_id).await.unwrap_or_else(|e| {
+        error!("db message_quarantined error: {:?}", e);
+        true
+    });
+
+    // anything else, e.g. text/html or image/svg+xml, could run script on our origin;
+    // files from quarantined messages are never opened in the browser
+    let kind = match attachment.disposition.as_deref() {
+        Some("attachment") => "attachment",
+        _ if is_raster_image(&attachment.content_type) && !quarantined => "inline",
+        _ => "attachment",
+    };
+    let disposition = match &attachment.filename {
+        Some(name) => content_disposition(kind, name),
+        None => kind.to_string(),
+    };
+
+    (
+        [
+            (header::CONTENT_TYPE, attachment.content_type),
+            (header::CONTENT_DISPOSITION, disposition),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+        ],
+        attachment.data,
+    )
+        .into_response()
+}
+
+fn display_address(a: &MailAddress) -> String {
+    match (&a.name, &a.address) {
+        (Some(name), Some(addr)) => format!("{} <{}>", name, addr),
+        (None, Some(addr)) => addr.clone(),
+        (Some(name), None) => name.clone(),
+        (None, None) => "<unknown>".into(),
+    }
+}
+
+/// `segment` percent-encoded for a URL path; quoted local parts may hold any
+/// printable character, including `/`, `"` and non-ASCII text.
+fn path_segment(segment: &str) -> String {
+    let mut out = String::with_capacity(segment.len());
+    for b in segment.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+fn content_disposition(kind: &str, filename: &str) -> String {
+    // header values must be visible ASCII, so drop anything else from the filename
+    let safe: String = filename
+        .chars()
+        .filter(|c| (c.is_ascii_graphic() || *c == ' ') && *c != '"' && *c != '\\')
+        .collect();
+    format!("{}; filename=\"{}\"", kind, safe)
+}
+
+/* ---------- Message API ---------- */
+
+/// Messages of a mailbox, newest first, narrowed by the same `?q=`, `?header=` and
+/// `?quarantined=` parameters as the inbox page.
+async fn list_messages(
+    Path(local): Path<String>,
+    Query(query): Query<InboxQuery>,
+    State(state): State<AppState>,
+) -> Response {
+    match state.db.mailbox_exists(&local).await {
+        Ok(true) => {}
+        Ok(false) => return json_error(StatusCode::NOT_FOUND, "mailbox not found"),
+        Err(e) => {
+            error!("db mailbox_exists error: {:?}", e);
+            return json_error(StatusCode::INTERNAL_SERVER_ERROR, "database error");
+        }
+    }
+
+    match state.db.list_messages(&local, &query.filter()).await {
+        Ok(messages) => Json(serde_json::json!({
+            "messages": messages.iter().map(message_summary).collect::<Vec<_>>(),
+        }))
+        .into_response(),
+        Err(e) => {
+            error!("db list_messages error: {:?}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "database error")
+        }
+    }
+}
+
+fn message_summary(m: &Message) -> serde_json::Value {
+    serde_json::json!({
+        "id": m.id,
+        "from_addr": m.from_addr,
+        "to_addr": m.to_addr,
+        "header_from": m.header_from,
+        "subject": m.subject,
+        "preview": html::preview(&m.body_text, 140),
+        "quarantined": m.quarantined,
+        "received_at": m.received_at,
+    })
+}
+
+#[derive(Deserialize)]
+pub struct HeadersQuery {
+    pub name: Option<String>,
+}
+
+/// All stored headers of a message in order, optionally only those named `?name=`.
+async fn message_headers(
+    Path((local, id)): Path<(String, String)>,
+    Query(query): Query<HeadersQuery>,
+    State(state): State<AppState>,
+) -> Response {
+    let Ok(uuid) = Uuid::parse_str(&id) else {
+        return json_error(StatusCode::NOT_FOUND, "message not found");
+    };
+
+    let message = match state.db.get_message(&local, uuid).await {
+        Ok(Some(m)) => m,
+        Ok(None) => return json_error(StatusCode::NOT_FOUND, "message not found"),
+        Err(e) => {
+            error!("db get_message error: {:?}", e);
+            return json_error(StatusCode::INTERNAL_SERVER_ERROR, "database error");
+        }
+    };
+
+    let headers: Vec<_> = message
+        .headers
+        .into_iter()
+        .filter(|h| {
+            query
+                .name
+                .as_deref()
+                .is_none_or(|n| h.name.eq_ignore_ascii_case(n))
+        })
+        .collect();
+
+    Json(headers).into_response()
+}
+
+/// Envelope and header senders/recipients of a message, kept apart.
+async fn message_addresses(
+    Path((local, id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Response {
+    let Ok(uuid) = Uuid::parse_str(&id) else {
+        return json_error(StatusCode::NOT_FOUND, "message not found");
+    };
+
+    match state.db.get_message(&local, uuid).await {
+        Ok(Some(m)) => Json(serde_json::json!({
+            "envelope": {
+                "from": m.from_addr,
+                "to": m.envelope_to,
+                "delivered_to": m.to_addr,
+            },
+            "header": {
+                "from": m.header_from,
+                "to": m.header_to,
+                "cc": m.header_cc,
+                "reply_to": m.header_reply_to,
+            },
+            "bcc": m.bcc,
+        }))
+        .into_response(),
+        Ok(None) => json_error(StatusCode::NOT_FOUND, "message not found"),
+        Err(e) => {
+            error!("db get_message error: {:?}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "database error")
+        }
+    }
+}
+
+/// SMTP connection details recorded when the message was received.
+async fn message_connection(
+    Path((local, id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Response {
+    let Ok(uuid) = Uuid::parse_str(&id) else {
+        return json_error(StatusCode::NOT_FOUND, "message not found");
+    };
+
+    match state.db.get_message(&local, uuid).await {
+        Ok(Some(m)) => Json(m.connection).into_response(),
+        Ok(None) => json_error(StatusCode::NOT_FOUND, "message not found"),
+        Err(e) => {
+            error!("db get_message error: {:?}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "database error")
+        }
+    }
+}
+
+/// SPF and other sender authentication results recorded on receipt.
+async fn message_auth(
+    Path((local, id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Response {
+    let Ok(uuid) = Uuid::parse_str(&id) else {
+        return json_error(StatusCode::NOT_FOUND, "message not found");
+    };
+
+    match state.db.get_message(&local, uuid).await {
+        Ok(Some(m)) => Json(m.auth).into_response(),
+        Ok(None) => json_error(StatusCode::NOT_FOUND, "message not found"),
+        Err(e) => {
+            error!("db get_message error: {:?}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "database error")
+        }
+    }
+}
+
+/// Spam score and the rules that contributed to it.
+async fn message_spam(
+    Path((local, id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Response {
+    let Ok(uuid) = Uuid::parse_str(&id) else {
+        return json_error(StatusCode::NOT_FOUND, "message not found");
+    };
+
+    match state.db.get_message(&local, uuid).await {
+        Ok(Some(m)) => Json(m.spam).into_response(),
+        Ok(None) => json_error(StatusCode::NOT_FOUND, "message not found"),
+        Err(e) => {
+            error!("db get_message error: {:?}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "database error")
+        }
+    }
+}
+
+/// List a MIME tree depth-first with each node's nesting level, for the template.
+fn flatten_mime(node: &mime::MimeNode, depth: usize, out: &mut Vec<serde_json::Value>) {
+    out.push(serde_json::json!({
+        "depth": depth,
+        "path": node.path,
+        "content_type": node.content_type,
+        "charset": node.charset,
+        "transfer_encoding": node.transfer_encoding,
+        "disposition": node.disposition,
+        "filename": node.filename,
+        "size": node.size,
+        "raw_size": node.raw_size,
+        "multipart": node.content_type.starts_with("multipart/"),
+    }));
+    for child in &node.children {
+        flatten_mime(child, depth + 1, out);
+    }
+}
+
+async fn message_mime(
+    Path((local, id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Response {
+    let Ok(uuid) = Uuid::parse_str(&id) else {
+        return json_error(StatusCode::NOT_FOUND, "message not found");
+    };
+
+    let message = match state.db.get_message(&local, uuid).await {
+        Ok(Some(m)) => m,
+        Ok(None) => return json_error(StatusCode::NOT_FOUND, "message not found"),
+        Err(e) => {
+            error!("db get_message error: {:?}", e);
+            return json_error(StatusCode::INTERNAL_SERVER_ERROR, "database error");
+        }
+    };
+
+    let source = match state.db.message_source(message.id).await {
+        Ok(Some(source)) => source,
+        Ok(None) => return json_error(StatusCode::NOT_FOUND, "message not found"),
+        Err(e) => {
+            error!("db message_source error: {:?}", e);
+            return json_error(StatusCode::INTERNAL_SERVER_ERROR, "database error");
+        }
+    };
+    match MessageParser::default().parse(&source) {
+        Some(parsed) => Json(mime::mime_tree(&parsed)).into_response(),
+        None => json_error(StatusCode::UNPROCESSABLE_ENTITY, "message could not be parsed"),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PartQuery {
+    /// Return the part exactly as transmitted (headers and transfer encoding included).
+    #[serde(default)]
+    pub raw: bool,
+}
+
+async fn download_part(
+    Path((local, id, path)): Path<(String, String, String)>,
+    Query(query): Query<PartQuery>,
+    State(state): State<AppState>,
+) -> Response {
+    let Ok(uuid) = Uuid::parse_str(&id) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let message = match state.db.get_message(&local, uuid).await {
+        Ok(Some(m)) => m,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            error!("db get_message error: {:?}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let source = match state.db.message_source(message.id).await {
+        Ok(Some(source)) => source,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            error!("db message_source error: {:?}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let Some(parsed) = MessageParser::default().parse(&source) else {
+        return StatusCode::UNPROCESSABLE_ENTITY.into_response();
+    };
+    let Some(part) = mime::find_part(&parsed, &path) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    if query.raw {
+        // 8-bit and binary parts are sent without a charset rather than mislabelled
+        let content_type = match std::str::from_utf8(&part.raw) {
+            Ok(_) => "text/plain; charset=utf-8",
+            Err(_) => "text/plain",
+        };
+        return (
+            [
+                (header::CONTENT_TYPE, content_type.to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("inline; filename=\"part-{}.txt\"", path),
+                ),
+                (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+            ],
+            part.raw,
+        )
+            .into_response();
+    }
+
+    // decoded text parts are always UTF-8 after parsing
+    let content_type = if part.content_type.starts_with("text/") {
+        format!("{}; charset=utf-8", part.content_type)
+    } else {
+        part.content_type
+    };
+    let filename = part.filename.unwrap_or_else(|| format!("part-{}", path));
+
+    (
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CONTENT_DISPOSITION, content_disposition("attachment", &filename)),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+        ],
+        part.decoded,
+    )
+        .into_response()
+}
+
+/* ---------- Extraction API ---------- */
+
+fn json_error(status: StatusCode, message: impl Into<String>) -> Response {
+    (status, Json(serde_json::json!({ "error": message.into() }))).into_response()
+}
+
+async fn message_extractions(state: &AppState, local: &str, id: &str, kind: &str) -> Response {
+    let Ok(uuid) = Uuid::parse_str(id) else {
+        return json_error(StatusCode::NOT_FOUND, "message not found");
+    };
+
+    match state.db.get_message(local, uuid).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return json_error(StatusCode::NOT_FOUND, "message not found"),
+        Err(e) => {
+            error!("db get_message error: {:?}", e);
+            return json_error(StatusCode::INTERNAL_SERVER_ERROR, "database error");
+        }
+    }
+
+    match state.db.list_extractions(local, uuid, kind).await {
+        Ok(found) => Json(found).into_response(),
+        Err(e) => {
+            error!("db list_extractions error: {:?}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "database error")
+        }
+    }
+}
+
+async fn message_codes(
+    Path((local, id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Response {
+    message_extractions(&state, &local, &id, extract::KIND_CODE).await
+}
+
+async fn message_links(
+    Path((local, id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Response {
+    message_extractions(&state, &local, &id, extract::KIND_LINK).await
+}
+
+/// The code from the most recently received message that contained one.
+async fn latest_code(Path(local): Path<String>, State(state): State<AppState>) -> Response {
+    match state.db.latest_extraction(&local, extract::KIND_CODE).await {
+        Ok(Some(found)) => Json(serde_json::json!({
+            "code": found.value,
+            "message_id": found.message_id,
+            "rule": found.rule,
+            "created_at": found.created_at,
+        }))
+        .into_response(),
+        Ok(None) => json_error(StatusCode::NOT_FOUND, "no code found"),
+        Err(e) => {
+            error!("db latest_extraction error: {:?}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "database error")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RuleForm {
+    pub kind: String,
+    pub name: Option<String>,
+    pub pattern: String,
+}
+
+async fn list_rules(Path(local): Path<String>, State(state): State<AppState>) -> Response {
+    let mailbox = match state.db.get_mailbox_by_local(&local).await {
+        Ok(Some(m)) => m,
+        Ok(None) => return json_error(StatusCode::NOT_FOUND, "mailbox not found"),
+        Err(e) => {
+            error!("db get_mailbox_by_local error: {:?}", e);
+            return json_error(StatusCode::INTERNAL_SERVER_ERROR, "database error");
+        }
+    };
+
+    match state.db.list_extraction_rules(mailbox.id).await {
+        Ok(rules) => Json(rules).into_response(),
+        Err(e) => {
+            error!("db list_extraction_rules error: {:?}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "database error")
+        }
+    }
+}
+
+async fn create_rule(
+    Path(local): Path<String>,
+    State(state): State<AppState>,
+    Json(form): Json<RuleForm>,
+) -> Response {
+    if let Err(e) = extract::validate_rule(&form.kind, &form.pattern) {
+        return json_error(StatusCode::BAD_REQUEST, e);
+    }
+
+    let mailbox = match state.db.get_mailbox_by_local(&local).await {
+        Ok(Some(m)) => m,
+        Ok(None) => return json_error(StatusCode::NOT_FOUND, "mailbox not found"),
+        Err(e) => {
+            error!("db get_mailbox_by_local error: {:?}", e);
+            return json_error(StatusCode::INTERNAL_SERVER_ERROR, "database error");
+        }
+    };
+
+    let name = form.name.unwrap_or_else(|| form.kind.clone());
+    match state
+        .db
+        .create_extraction_rule(mailbox.id, &form.kind, &name, &form.pattern)
+        .await
+    {
+        Ok(rule) => (StatusCode::CREATED, Json(rule)).into_response(),
+        Err(e) => {
+            error!("db create_extraction_rule error: {:?}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "database error")
+        }
+    }
+}
+
+async fn delete_rule(
+    Path((local, rule_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Response {
+    let Ok(rule_id) = Uuid::parse_str(&rule_id) else {
+        return json_error(StatusCode::NOT_FOUND, "rule not found");
+    };
+
+    let mailbox = match state.db.get_mailbox_by_local(&local).await {
+        Ok(Some(m)) => m,
+        Ok(None) => return json_error(StatusCode::NOT_FOUND, "mailbox not found"),
+        Err(e) => {
+            error!("db get_mailbox_by_local error: {:?}", e);
+            return json_error(StatusCode::INTERNAL_SERVER_ERROR, "database error");
+        }
+    };
+
+    match state.db.delete_extraction_rule(mailbox.id, rule_id).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => json_error(StatusCode::NOT_FOUND, "rule not found"),
+        Err(e) => {
+            error!("db delete_extraction_rule error: {:?}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "database error")
+        }
+    }
+}
+
+/* ---------- Session transcripts ---------- */
+
+#[derive(Deserialize)]
+pub struct TranscriptSettings {
+    pub enabled: bool,
+}
+
+/// Turn transcript recording on or off for a mailbox.
+async fn set_transcript_recording(
+    Path(local): Path<String>,
+    State(state): State<AppState>,
+    Json(settings): Json<TranscriptSettings>,
+) -> Response {
+    match state.db.set_record_transcripts(&local, settings.enabled).await {
+        Ok(true) => Json(serde_json::json!({ "enabled": settings.enabled })).into_response(),
+        Ok(false) => json_error(StatusCode::NOT_FOUND, "mailbox not found"),
+        Err(e) => {
+            error!("db set_record_transcripts error: {:?}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "database error")
+        }
+    }
+}
+
+fn transcript_summary(t: &SessionTranscript) -> serde_json::Value {
+    serde_json::json!({
+        "id": t.id,
+        "session_id": t.session_id,
+        "client_ip": t.client_ip,
+        "helo": t.helo,
+        "outcome": t.outcome,
+        "started_at": t.started_at,
+        "ended_at": t.ended_at,
+        "lines": t.lines.len(),
+    })
+}
+
+async fn list_transcripts(Path(local): Path<String>, State(state): State<AppState>) -> Response {
+    let mailbox = match state.db.get_mailbox_by_local(&local).await {
+        Ok(Some(m)) => m,
+        Ok(None) => return json_error(StatusCode::NOT_FOUND, "mailbox not found"),
+        Err(e) => {
+            error!("db get_mailbox_by_local error: {:?}", e);
+            return json_error(StatusCode::INTERNAL_SERVER_ERROR, "database error");
+        }
+    };
+
+    match state.db.list_transcripts(&local).await {
+        Ok(found) => Json(serde_json::json!({
+            "enabled": mailbox.record_transcripts,
+            "transcripts": found.iter().map(transcript_summary).collect::<Vec<_>>(),
+        }))
+        .into_response(),
+        Err(e) => {
+            error!("db list_transcripts error: {:?}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "database error")
+        }
+    }
+}
+
+/// A full transcript, looked up by its id or the SMTP session id, with the messages it produced.
+async fn get_transcript(
+    Path((local, transcript_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Response {
+    let transcript = match state.db.get_transcript(&local, &transcript_id).await {
+        Ok(Some(t)) => t,
+        Ok(None) => return json_error(StatusCode::NOT_FOUND, "transcript not found"),
+        Err(e) => {
+            error!("db get_transcript error: {:?}", e);
+            return json_error(StatusCode::INTERNAL_SERVER_ERROR, "database error");
+        }
+    };
+
+    let messages = state
+        .db
+        .session_message_ids(&local, &transcript.session_id)
+        .await
+        .unwrap_or_else(|e| {
+            error!("db session_message_ids error: {:?}", e);
+            vec![]
+        });
+
+    Json(serde_json::json!({
+        "transcript": transcript,
+        "messages": messages,
+    }))
+    .into_response()
+}
+
+async fn view_transcripts(
+    Path(local): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Html<String>, Redirect> {
+    render_transcripts(&state, &local, None).await
+}
+
+async fn view_transcript(
+    Path((local, transcript_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Result<Html<String>, Redirect> {
+    render_transcripts(&state, &local, Some(&transcript_id)).await
+}
+
+/// The transcript list of a mailbox, with one transcript expanded when `selected` is given.
+async fn render_transcripts(
+    state: &AppState,
+    local: &str,
+    selected: Option<&str>,
+) -> Result<Html<String>, Redirect> {
+    let mailbox = match state.db.get_mailbox_by_local(local).await {
+        Ok(Some(m)) => m,
+        _ => return Err(Redirect::to("/")),
+    };
+
+    let transcripts = state.db.list_transcripts(local).await.unwrap_or_else(|e| {
+        error!("db list_transcripts error: {:?}", e);
+        vec![]
+    });
+
+    let mut ctx = Context::new();
+    ctx.insert("domain", &state.domain);
+    ctx.insert("local", local);
+    ctx.insert("enabled", &mailbox.record_transcripts);
+    ctx.insert(
+        "transcripts",
+        &transcripts.iter().map(transcript_summary).collect::<Vec<_>>(),
+    );
+
+    if let Some(selected) = selected {
+        let transcript = match state.db.get_transcript(local, selected).await {
+            Ok(Some(t)) => t,
+            _ => {
+                return Err(Redirect::to(&format!(
+                    "/inbox/{}/transcripts",
+                    path_segment(local)
+                )))
+            }
+        };
+        let messages = state
+            .db
+            .session_message_ids(local, &transcript.session_id)
+            .await
+            .unwrap_or_default();
+        ctx.insert("transcript", &transcript);
+        ctx.insert("messages", &messages);
+    }
+
+    let rendered = state.templates.render("transcripts.html", &ctx).map_err(|e| {
+        error!("render transcripts template: {:?}", e);
+        Redirect::to(&format!("/inbox/{}", path_segment(local)))
+    })?;
+
+    Ok(Html(rendered))
+}
+
+/* ---------- DMARC and TLS reports ---------- */
+
+/// DMARC and TLS-RPT reports received by a mailbox, summarized per domain.
+async fn list_reports(Path(local): Path<String>, State(state): State<AppState>) -> Response {
+    match state.db.get_mailbox_by_local(&local).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return json_error(StatusCode::NOT_FOUND, "mailbox not found"),
+        Err(e) => {
+            error!("db get_mailbox_by_local error: {:?}", e);
+            return json_error(StatusCode::INTERNAL_SERVER_ERROR, "database error");
+        }
+    }
+
+    let result = async {
+        let dmarc = state.db.dmarc_summary(&local).await?;
+        let tls = state.db.tls_summary(&local).await?;
+        let reports = state.db.list_reports(&local).await?;
+        anyhow::Ok((dmarc, tls, reports))
+    }
+    .await;
+
+    match result {
+        Ok((dmarc, tls, reports)) => Json(serde_json::json!({
+            "dmarc": dmarc,
+            "tls": tls,
+            "reports": reports,
+        }))
+        .into_response(),
+        Err(e) => {
+            error!("db report summary error: {:?}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "database error")
+        }
+    }
+}
+
+fn pass_rate(passed: i64, total: i64) -> String {
+    if total == 0 {
+        return "-".to_string();
+    }
+    format!("{:.1}%", passed as f64 * 100.0 / total as f64)
+}
+
+async fn view_reports(
+    Path(local): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Html<String>, Redirect> {
+    if !matches!(state.db.get_mailbox_by_local(&local).await, Ok(Some(_))) {
+        return Err(Redirect::to("/"));
+    }
+
+    let dmarc = state.db.dmarc_summary(&local).await.unwrap_or_else(|e| {
+        error!("db dmarc_summary error: {:?}", e);
+        vec![]
+    });
+    let tls = state.db.tls_summary(&local).await.unwrap_or_else(|e| {
+        error!("db tls_summary error: {:?}", e);
+        vec![]
+    });
+    let reports = state.db.list_reports(&local).await.unwrap_or_else(|e| {
+        error!("db list_reports error: {:?}", e);
+        vec![]
+    });
+
+    let dmarc: Vec<_> = dmarc
+        .iter()
+        .map(|d| {
+            serde_json::json!({
+                "domain": d.domain,
+                "reports": d.reports,
+                "messages": d.messages,
+                "passed": d.passed,
+                "failed": d.failed,
+                "pass_rate": pass_rate(d.passed, d.messages),
+                "period": format!(
+                    "{} – {}",
+                    d.first_seen.format("%Y-%m-%d"),
+                    d.last_seen.format("%Y-%m-%d")
+                ),
+                "sources": d.sources.iter().map(|s| serde_json::json!({
+                    "source_ip": s.source_ip,
+                    "messages": s.messages,
+                    "passed": s.passed,
+                    "failed": s.failed,
+                    "pass_rate": pass_rate(s.passed, s.messages),
+                })).collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+    let tls: Vec<_> = tls
+        .iter()
+        .map(|t| {
+            serde_json::json!({
+                "policy_domain": t.policy_domain,
+                "reports": t.reports,
+                "successful": t.successful,
+                "failed": t.failed,
+                "success_rate": pass_rate(t.successful, t.successful + t.failed),
+                "failures": t.failures,
+            })
+        })
+        .collect();
+    let reports: Vec<_> = reports
+        .iter()
+        .map(|r| {
+            serde_json::json!({
+                "message_id": r.message_id,
+                "kind": if r.kind == "dmarc" { "DMARC" } else { "TLS-RPT" },
+                "org_name": r.org_name,
+                "report_id": r.report_id,
+                "domain": r.domain,
+                "period": format!(
+                    "{} – {}",
+                    r.date_begin.format("%Y-%m-%d %H:%M"),
+                    r.date_end.format("%Y-%m-%d %H:%M")
+                ),
+            })
+        })
+        .collect();
+
+    let mut ctx = Context::new();
+    ctx.insert("domain", &state.domain);
+    ctx.insert("local", &local);
+    ctx.insert("dmarc", &dmarc);
+    ctx.insert("tls", &tls);
+    ctx.insert("reports", &reports);
+
+    let rendered = state.templates.render("reports.html", &ctx).map_err(|e| {
+        error!("render reports template: {:?}", e);
+        Redirect::to(&format!("/inbox/{}", path_segment(&local)))
+    })?;
+
+    Ok(Html(rendered))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::{insert_message, remove_mailbox, test_db};
+
+    #[test]
+    fn templates_escape_values() {
+        let mut tera = load_templates().unwrap();
+        tera.add_raw_template("probe.html", "<pre>{{ raw }}</pre>")
+            .unwrap();
+        let mut ctx = Context::new();
+        ctx.insert("raw", "<script>alert(1)</script>");
+        assert_eq!(
+            tera.render("probe.html", &ctx).unwrap(),
+            "<pre>&lt;script&gt;alert(1)&lt;&#x2F;script&gt;</pre>"
+        );
+    }
+
+    #[test]
+    fn mailbox_names_are_inert_in_pages() {
+        let local = "\"<img src=x onerror=alert(1)>\"/'";
+        let mut ctx = Context::new();
+        ctx.insert("domain", "tempmail.local");
+        ctx.insert("local", local);
+        ctx.insert("enabled", &false);
+        ctx.insert("transcripts", &Vec::<serde_json::Value>::new());
+        let page = load_templates()
+            .unwrap()
+            .render("transcripts.html", &ctx)
+            .unwrap();
+
+        assert!(!page.contains("<img"));
+        assert!(page.contains("&lt;img src=x onerror=alert(1)&gt;"));
+        assert!(page
+            .contains("href=\"/inbox/%22%3Cimg%20src%3Dx%20onerror%3Dalert%281%29%3E%22%2F%27\""));
+        assert!(page.contains(
+            "<body data-local=\"&quot;&lt;img src=x onerror=alert(1)&gt;&quot;&#x2F;&#x27;\">"
+        ));
+        assert_eq!(
+            path_segment(local),
+            "%22%3Cimg%20src%3Dx%20onerror%3Dalert%281%29%3E%22%2F%27"
+        );
+        assert_eq!(path_segment("bücher"), "b%C3%BCcher");
+    }
+
+    #[tokio::test]
+    async fn api_lists_messages_filtered_by_header() {
+        let Some(db) = test_db().await else {
+            return;
+        };
+        let local = format!("api{}", &Uuid::new_v4().simple().to_string()[..8]);
+        let mailbox = db.create_mailbox(&local, None).await.unwrap();
+        let spring =
+            insert_message(&db, mailbox.id, "spring", &[("X-Campaign-Id", "spring-24")]).await;
+        insert_message(&db, mailbox.id, "autumn", &[("x-campaign-id", "autumn-24")]).await;
+        insert_message(&db, mailbox.id, "plain", &[]).await;
+        let state = AppState {
+            db: db.clone(),
+            domain: "tempmail.test".to_string(),
+            templates: Arc::new(load_templates().unwrap()),
+        };
+
+        let subjects = |header: Option<&str>| {
+            let query = InboxQuery {
+                q: None,
+                header: header.map(str::to_string),
+                quarantined: false,
+            };
+            let response = list_messages(Path(local.clone()), Query(query), State(state.clone()));
+            async move {
+                let response = response.await;
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                json["messages"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|m| (m["id"].as_str().unwrap().to_string(), m["subject"].clone()))
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        let found = subjects(Some("X-Campaign-Id: spring-24")).await;
+        assert_eq!(found, [(spring.to_string(), "spring".into())]);
+        assert_eq!(subjects(Some("x-campaign-id")).await.len(), 2);
+        assert_eq!(subjects(None).await.len(), 3);
+        assert!(subjects(Some("X-Campaign-Id:summer")).await.is_empty());
+
+        let missing = list_messages(
+            Path("no-such-mailbox".to_string()),
+            Query(InboxQuery {
+                q: None,
+                header: None,
+                quarantined: false,
+            }),
+            State(state),
+        )
+        .await;
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+
+        remove_mailbox(&db, mailbox.id).await;
+    }
+
+    #[test]
+    fn only_raster_images_are_inline() {
+        assert!(is_raster_image("image/png"));
+        assert!(is_raster_image("IMAGE/JPEG; name=a.jpg"));
+        assert!(!is_raster_image("image/svg+xml"));
+        assert!(!is_raster_image("text/html"));
+    }
+}
@@ -0,0 +1,284 @@
+//! Connection, message rate and per-session limits for the SMTP listener.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::env_or;
+use crate::metrics;
+
+/// Rate windows are kept for this many keys before idle ones are swept.
+const SWEEP_THRESHOLD: usize = 1024;
+
+/// Limits read from the environment; 0 disables a count or rate limit.
+#[derive(Debug, Clone)]
+pub struct LimitsConfig {
+    /// Concurrent connections across all clients (`SMTP_MAX_CONNECTIONS`).
+    pub max_connections: usize,
+    /// Concurrent connections from one IP (`SMTP_MAX_CONNECTIONS_PER_IP`); see [`client_key`].
+    pub max_connections_per_ip: usize,
+    /// Transactions per minute from one IP (`SMTP_MESSAGES_PER_MINUTE_PER_IP`); see [`client_key`].
+    pub messages_per_ip: usize,
+    /// Messages per minute to one mailbox (`SMTP_MESSAGES_PER_MINUTE_PER_MAILBOX`).
+    pub messages_per_mailbox: usize,
+    /// How long to wait for the next command line (`SMTP_COMMAND_TIMEOUT_SECS`,
+    /// RFC 5321 §4.5.3.2.7).
+    pub command_timeout: Duration,
+    /// How long to wait for each line of message data (`SMTP_DATA_TIMEOUT_SECS`,
+    /// RFC 5321 §4.5.3.2.5).
+    pub data_timeout: Duration,
+    /// Longest a connection may stay open (`SMTP_MAX_SESSION_SECS`).
+    pub max_session: Duration,
+    /// Commands accepted per connection (`SMTP_MAX_COMMANDS`).
+    pub max_commands: usize,
+    /// 5xx replies after which the client is disconnected (`SMTP_MAX_ERRORS`).
+    pub max_errors: usize,
+}
+
+impl LimitsConfig {
+    pub fn from_env() -> Self {
+        Self {
+            max_connections: env_or("SMTP_MAX_CONNECTIONS", 500),
+            max_connections_per_ip: env_or("SMTP_MAX_CONNECTIONS_PER_IP", 50),
+            messages_per_ip: env_or("SMTP_MESSAGES_PER_MINUTE_PER_IP", 300),
+            messages_per_mailbox: env_or("SMTP_MESSAGES_PER_MINUTE_PER_MAILBOX", 120),
+            command_timeout: Duration::from_secs(env_or("SMTP_COMMAND_TIMEOUT_SECS", 300)),
+            data_timeout: Duration::from_secs(env_or("SMTP_DATA_TIMEOUT_SECS", 180)),
+            max_session: Duration::from_secs(env_or("SMTP_MAX_SESSION_SECS", 1800)),
+            max_commands: env_or("SMTP_MAX_COMMANDS", 1000),
+            max_errors: env_or("SMTP_MAX_ERRORS", 20),
+        }
+    }
+}
+
+/// Why a connection was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    Global,
+    PerIp,
+}
+
+/// The address per-IP limits count against: an IPv4 address itself, or the /64 of
+/// an IPv6 one, since a single host is routinely handed a whole /64 to rotate through.
+fn client_key(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            IpAddr::V6(Ipv6Addr::new(s[0], s[1], s[2], s[3], 0, 0, 0, 0))
+        }
+        v4 => v4,
+    }
+}
+
+/// Shared limiter state for every connection.
+pub struct Limiter {
+    config: LimitsConfig,
+    connections: Mutex<Connections>,
+    ip_rate: RateWindow,
+    mailbox_rate: RateWindow,
+}
+
+#[derive(Default)]
+struct Connections {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+impl Limiter {
+    pub fn new(config: LimitsConfig) -> Arc<Self> {
+        Arc::new(Self {
+            ip_rate: RateWindow::new(config.messages_per_ip),
+            mailbox_rate: RateWindow::new(config.messages_per_mailbox),
+            connections: Mutex::new(Connections::default()),
+            config,
+        })
+    }
+
+    /// Reserve a connection slot; the slot is released when the guard drops. This
+    /// happens on accept, before the client address is known, so a flood of
+    /// connections cannot pile up while their PROXY headers are awaited.
+    pub fn admit(self: &Arc<Self>) -> Result<ConnectionGuard, Refusal> {
+        let mut connections = self.connections.lock().unwrap();
+        if self.config.max_connections > 0 && connections.total >= self.config.max_connections {
+            metrics::CONNECTIONS_REFUSED_GLOBAL.inc();
+            return Err(Refusal::Global);
+        }
+        connections.total += 1;
+        metrics::CONNECTIONS_TOTAL.inc();
+        metrics::CONNECTIONS_ACTIVE.inc();
+        Ok(ConnectionGuard {
+            limiter: self.clone(),
+            ip: None,
+        })
+    }
+
+    /// Count a new transaction from `ip`; false once the per-minute limit is used up.
+    pub fn allow_message_from(&self, ip: IpAddr) -> bool {
+        let allowed = self.ip_rate.hit(&client_key(ip).to_string());
+        if !allowed {
+            metrics::MESSAGES_LIMITED_IP.inc();
+        }
+        allowed
+    }
+
+    /// Count a message for the mailbox `local`; false once the per-minute limit is used up.
+    pub fn allow_message_to(&self, local: &str) -> bool {
+        let allowed = self.mailbox_rate.hit(&local.to_lowercase());
+        if !allowed {
+            metrics::MESSAGES_LIMITED_MAILBOX.inc();
+        }
+        allowed
+    }
+}
+
+pub struct ConnectionGuard {
+    limiter: Arc<Limiter>,
+    /// The [`client_key`] counted by `identify`.
+    ip: Option<IpAddr>,
+}
+
+impl ConnectionGuard {
+    /// Count the connection against the per-IP limit of the client `ip`.
+    pub fn identify(&mut self, ip: IpAddr) -> Result<(), Refusal> {
+        let ip = client_key(ip);
+        let limiter = self.limiter.clone();
+        let mut connections = limiter.connections.lock().unwrap();
+        let open = connections.per_ip.entry(ip).or_default();
+        if limiter.config.max_connections_per_ip > 0
+            && *open >= limiter.config.max_connections_per_ip
+        {
+            metrics::CONNECTIONS_REFUSED_IP.inc();
+            return Err(Refusal::PerIp);
+        }
+        *open += 1;
+        self.ip = Some(ip);
+        Ok(())
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut connections = self.limiter.connections.lock().unwrap();
+        connections.total -= 1;
+        if let Some(ip) = self.ip {
+            if let Some(open) = connections.per_ip.get_mut(&ip) {
+                *open -= 1;
+                if *open == 0 {
+                    connections.per_ip.remove(&ip);
+                }
+            }
+        }
+        metrics::CONNECTIONS_ACTIVE.dec();
+    }
+}
+
+/// Sliding one-minute window of events per key.
+struct RateWindow {
+    limit: usize,
+    hits: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl RateWindow {
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            hits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn hit(&self, key: &str) -> bool {
+        if self.limit == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let mut hits = self.hits.lock().unwrap();
+        if hits.len() > SWEEP_THRESHOLD {
+            hits.retain(|_, times| {
+                times
+                    .back()
+                    .is_some_and(|t| now.duration_since(*t) < Self::WINDOW)
+            });
+        }
+
+        let times = hits.entry(key.to_string()).or_default();
+        while times
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= Self::WINDOW)
+        {
+            times.pop_front();
+        }
+        if times.len() >= self.limit {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(max_connections: usize, per_ip: usize, messages_per_ip: usize) -> Arc<Limiter> {
+        Limiter::new(LimitsConfig {
+            max_connections,
+            max_connections_per_ip: per_ip,
+            messages_per_ip,
+            messages_per_mailbox: 0,
+            ..LimitsConfig::from_env()
+        })
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn client_keys() {
+        assert_eq!(client_key(ip("192.0.2.7")), ip("192.0.2.7"));
+        assert_eq!(client_key(ip("::ffff:192.0.2.7")), ip("192.0.2.7"));
+        assert_eq!(client_key(ip("2001:db8:1:2:3:4:5:6")), ip("2001:db8:1:2::"));
+    }
+
+    #[test]
+    fn ipv6_connections_count_per_64() {
+        let limiter = limiter(0, 2, 0);
+        let mut first = limiter.admit().unwrap();
+        first.identify(ip("2001:db8::1")).unwrap();
+        let mut second = limiter.admit().unwrap();
+        second.identify(ip("2001:db8::ffff:2")).unwrap();
+
+        let mut third = limiter.admit().unwrap();
+        assert_eq!(third.identify(ip("2001:db8::3")), Err(Refusal::PerIp));
+        third.identify(ip("2001:db8:0:1::3")).unwrap();
+
+        drop(first);
+        let mut fourth = limiter.admit().unwrap();
+        fourth.identify(ip("2001:db8::4")).unwrap();
+    }
+
+    #[test]
+    fn global_limit_applies_before_the_client_is_known() {
+        let limiter = limiter(1, 0, 0);
+        let guard = limiter.admit().unwrap();
+        assert!(matches!(limiter.admit(), Err(Refusal::Global)));
+        drop(guard);
+        assert!(limiter.admit().is_ok());
+    }
+
+    #[test]
+    fn message_rate_counts_per_64() {
+        let limiter = limiter(0, 0, 2);
+        assert!(limiter.allow_message_from(ip("2001:db8::1")));
+        assert!(limiter.allow_message_from(ip("2001:db8::2")));
+        assert!(!limiter.allow_message_from(ip("2001:db8::3")));
+        assert!(limiter.allow_message_from(ip("2001:db8:0:1::1")));
+        assert!(limiter.allow_message_from(ip("192.0.2.1")));
+        assert!(limiter.allow_message_from(ip("192.0.2.2")));
+        assert!(limiter.allow_message_from(ip("192.0.2.1")));
+        assert!(!limiter.allow_message_from(ip("192.0.2.1")));
+    }
+}
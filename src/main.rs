@@ -1,6 +1,27 @@
+mod address;
+mod arc;
+mod clamd;
+mod command;
+mod config;
 mod db;
+mod dkim;
+mod dmarc;
+mod dns;
+mod dnsbl;
+mod extract;
+mod greylist;
+mod html;
 mod http;
+mod limits;
+mod metrics;
+mod milter;
+mod mime;
+mod proxy;
+mod reports;
 mod smtp;
+mod spam;
+mod spf;
+mod transcript;
 
 use anyhow::Result;
 use std::net::SocketAddr;
@@ -33,17 +54,19 @@ async fn main() -> Result<()> {
     db.run_migrations().await?;
     tracing::info!("Database connected and migrations applied");
 
+    let resolver = dns::from_env()?;
+
     // Clone db for both servers
     let smtp_db = db.clone();
     let http_db = db.clone();
 
     // Start SMTP server
     let smtp_addr: SocketAddr = "0.0.0.0:2525".parse()?;
-    let smtp_domain_clone = smtp_domain.clone();
+    let smtp_config = smtp::SmtpConfig::from_env(smtp_domain.clone());
     
     tracing::info!("Starting SMTP server on {}", smtp_addr);
     let smtp_handle = task::spawn(async move {
-        if let Err(e) = smtp::start_server(smtp_addr, smtp_domain_clone, smtp_db).await {
+        if let Err(e) = smtp::start_server(smtp_addr, smtp_config, smtp_db, resolver).await {
             tracing::error!("SMTP server error: {}", e);
         }
     });
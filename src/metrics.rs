@@ -0,0 +1,86 @@
+//! Process-wide SMTP counters, exposed in the Prometheus text format on `/metrics`.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    kind: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    const fn new(name: &'static str, kind: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            kind,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Only meaningful for gauges.
+    pub fn dec(&self) {
+        self.value.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub static CONNECTIONS_TOTAL: Counter = Counter::new(
+    "smtp_connections_total",
+    "counter",
+    "SMTP connections accepted.",
+);
+pub static CONNECTIONS_ACTIVE: Counter = Counter::new(
+    "smtp_connections_active",
+    "gauge",
+    "SMTP connections currently open.",
+);
+pub static CONNECTIONS_REFUSED_GLOBAL: Counter = Counter::new(
+    "smtp_connections_refused_global_limit_total",
+    "counter",
+    "Connections refused because the server-wide limit was reached.",
+);
+pub static CONNECTIONS_REFUSED_IP: Counter = Counter::new(
+    "smtp_connections_refused_ip_limit_total",
+    "counter",
+    "Connections refused because the client IP had too many open.",
+);
+pub static MESSAGES_LIMITED_IP: Counter = Counter::new(
+    "smtp_messages_rate_limited_ip_total",
+    "counter",
+    "Transactions deferred by the per-IP message rate limit.",
+);
+pub static MESSAGES_LIMITED_MAILBOX: Counter = Counter::new(
+    "smtp_messages_rate_limited_mailbox_total",
+    "counter",
+    "Recipients deferred by the per-mailbox message rate limit.",
+);
+
+static ALL: &[&Counter] = &[
+    &CONNECTIONS_TOTAL,
+    &CONNECTIONS_ACTIVE,
+    &CONNECTIONS_REFUSED_GLOBAL,
+    &CONNECTIONS_REFUSED_IP,
+    &MESSAGES_LIMITED_IP,
+    &MESSAGES_LIMITED_MAILBOX,
+];
+
+pub fn render() -> String {
+    let mut out = String::new();
+    for counter in ALL {
+        let _ = writeln!(out, "# HELP {} {}", counter.name, counter.help);
+        let _ = writeln!(out, "# TYPE {} {}", counter.name, counter.kind);
+        let _ = writeln!(
+            out,
+            "{} {}",
+            counter.name,
+            counter.value.load(Ordering::Relaxed)
+        );
+    }
+    out
+}
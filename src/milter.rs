@@ -0,0 +1,1151 @@
+//! Client side of the Sendmail milter protocol (version 6).
+//!
+//! Every SMTP connection opens one socket per filter listed in `MILTERS`
+//! and reports the connection, HELO, envelope, headers and body to it. Filters
+//! can accept, reject, tempfail or discard at any stage, and at end of message
+//! add, insert or change headers and ask for quarantine.
+
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::config::env_or;
+use crate::dkim;
+
+const VERSION: u32 = 6;
+/// Largest BODY chunk the protocol allows.
+const MAX_BODY_CHUNK: usize = 65535;
+/// Replies above this size are treated as a broken filter.
+const MAX_REPLY: usize = 1024 * 1024;
+
+// actions we let filters take (SMFIF_*)
+const ADD_HEADERS: u32 = 0x01;
+const CHANGE_HEADERS: u32 = 0x10;
+const QUARANTINE: u32 = 0x20;
+
+// protocol steps a filter may opt out of (SMFIP_*)
+const NO_CONNECT: u32 = 0x01;
+const NO_HELO: u32 = 0x02;
+const NO_MAIL: u32 = 0x04;
+const NO_RCPT: u32 = 0x08;
+const NO_BODY: u32 = 0x10;
+const NO_HEADERS: u32 = 0x20;
+const NO_EOH: u32 = 0x40;
+const NR_HEADER: u32 = 0x80;
+const NO_DATA: u32 = 0x200;
+const SKIP: u32 = 0x400;
+const NR_CONNECT: u32 = 0x1000;
+const NR_HELO: u32 = 0x2000;
+const NR_MAIL: u32 = 0x4000;
+const NR_RCPT: u32 = 0x8000;
+const NR_DATA: u32 = 0x10000;
+const NR_EOH: u32 = 0x40000;
+const NR_BODY: u32 = 0x80000;
+const HEADER_LEADING_SPACE: u32 = 0x100000;
+const OFFERED_PROTOCOL: u32 = NO_CONNECT
+    | NO_HELO
+    | NO_MAIL
+    | NO_RCPT
+    | NO_BODY
+    | NO_HEADERS
+    | NO_EOH
+    | NR_HEADER
+    | NO_DATA
+    | SKIP
+    | NR_CONNECT
+    | NR_HELO
+    | NR_MAIL
+    | NR_RCPT
+    | NR_DATA
+    | NR_EOH
+    | NR_BODY
+    | HEADER_LEADING_SPACE;
+
+#[derive(Debug, Clone)]
+pub enum MilterAddress {
+    Tcp(String),
+    Unix(String),
+}
+
+impl MilterAddress {
+    /// `inet:port@host`, `inet:host:port`, `host:port`, `unix:/path`, `local:/path` or `/path`.
+    fn parse(value: &str) -> Self {
+        if let Some(path) = value
+            .strip_prefix("unix:")
+            .or_else(|| value.strip_prefix("local:"))
+        {
+            return MilterAddress::Unix(path.to_string());
+        }
+        if value.starts_with('/') {
+            return MilterAddress::Unix(value.to_string());
+        }
+        let inet = value
+            .strip_prefix("inet:")
+            .or_else(|| value.strip_prefix("inet6:"))
+            .unwrap_or(value);
+        match inet.split_once('@') {
+            Some((port, host)) => MilterAddress::Tcp(format!("{}:{}", host, port)),
+            None => MilterAddress::Tcp(inet.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MilterConfig {
+    /// Filters in the order they are consulted (`MILTERS`, comma-separated).
+    pub filters: Vec<(String, MilterAddress)>,
+    /// Answer 451/421 when a filter fails instead of carrying on without it
+    /// (`MILTER_ON_ERROR=tempfail`).
+    pub tempfail_on_error: bool,
+    /// Limit for connecting and for each reply (`MILTER_TIMEOUT_SECS`).
+    pub timeout: Duration,
+}
+
+impl MilterConfig {
+    pub fn from_env() -> Self {
+        Self {
+            // not env_list: socket paths are case-sensitive
+            filters: std::env::var("MILTERS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|spec| !spec.is_empty())
+                .map(|spec| (spec.to_string(), MilterAddress::parse(spec)))
+                .collect(),
+            tempfail_on_error: env_or("MILTER_ON_ERROR", String::from("accept"))
+                .eq_ignore_ascii_case("tempfail"),
+            timeout: Duration::from_secs(env_or("MILTER_TIMEOUT_SECS", 10)),
+        }
+    }
+}
+
+/// What the filters decided about a connection, command or message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Continue,
+    /// Refuse with this SMTP reply (5xx).
+    Reject(String),
+    /// Refuse with this SMTP reply (4xx).
+    TempFail(String),
+    /// Claim delivery but drop the message.
+    Discard,
+}
+
+/// A header modification requested at end of message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderChange {
+    Add {
+        name: String,
+        value: String,
+    },
+    /// Insert at this position in the header (0 is the top).
+    Insert {
+        index: usize,
+        name: String,
+        value: String,
+    },
+    /// Replace the `index`-th (1-based) header of this name; an empty value deletes it.
+    Change {
+        index: usize,
+        name: String,
+        value: String,
+    },
+}
+
+/// Outcome of the message stages (headers, body, end of message).
+#[derive(Debug, Default)]
+pub struct MessageResult {
+    pub verdict: Option<Verdict>,
+    pub header_changes: Vec<HeaderChange>,
+    /// Reason given by a filter that asked for quarantine.
+    pub quarantine: Option<String>,
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> Stream for T {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Active,
+    /// The filter accepted the connection; it is not consulted again.
+    Done,
+    /// The filter accepted the current message; it is consulted again on the next one.
+    MessageDone,
+}
+
+struct Filter {
+    name: String,
+    stream: Box<dyn Stream>,
+    actions: u32,
+    protocol: u32,
+    state: State,
+}
+
+/// The filters of one SMTP connection.
+pub struct Milters {
+    filters: Vec<Filter>,
+    tempfail_on_error: bool,
+    timeout: Duration,
+    /// A message was announced with MAIL and has not been completed or aborted.
+    in_message: bool,
+    /// A filter could not be reached and failures are not ignored.
+    unavailable: bool,
+}
+
+/// Details reported at connect and in macros.
+pub struct ConnectInfo<'a> {
+    pub hostname: &'a str,
+    pub client_ip: IpAddr,
+    pub client_port: u16,
+    pub session_id: &'a str,
+    pub server_name: &'a str,
+}
+
+enum Reply {
+    Accept,
+    Continue,
+    Discard,
+    Reject(String),
+    TempFail(String),
+    Skip,
+}
+
+const REJECT_REPLY: &str = "550 5.7.1 Command rejected";
+const TEMPFAIL_REPLY: &str = "451 4.7.1 Service unavailable - try again later";
+
+fn reject_reply(text: Option<String>) -> Reply {
+    Reply::Reject(text.unwrap_or_else(|| REJECT_REPLY.to_string()))
+}
+
+fn tempfail_reply(text: Option<String>) -> Reply {
+    Reply::TempFail(text.unwrap_or_else(|| TEMPFAIL_REPLY.to_string()))
+}
+
+impl Milters {
+    /// Open and negotiate with every configured filter.
+    pub async fn connect(config: &MilterConfig) -> Self {
+        let mut milters = Milters {
+            filters: Vec::new(),
+            tempfail_on_error: config.tempfail_on_error,
+            timeout: config.timeout,
+            in_message: false,
+            unavailable: false,
+        };
+        for (name, address) in &config.filters {
+            match tokio::time::timeout(config.timeout, open(address)).await {
+                Ok(Ok(stream)) => {
+                    let mut filter = Filter {
+                        name: name.clone(),
+                        stream,
+                        actions: 0,
+                        protocol: 0,
+                        state: State::Active,
+                    };
+                    match tokio::time::timeout(config.timeout, filter.negotiate()).await {
+                        Ok(Ok(())) => milters.filters.push(filter),
+                        Ok(Err(e)) => milters.failed(name, &e.to_string()),
+                        Err(_) => milters.failed(name, "negotiation timed out"),
+                    }
+                }
+                Ok(Err(e)) => milters.failed(name, &e.to_string()),
+                Err(_) => milters.failed(name, "connect timed out"),
+            }
+        }
+        milters
+    }
+
+    /// Filters that could not be reached only count when failures are not ignored.
+    fn failed(&mut self, name: &str, error: &str) {
+        tracing::warn!("Milter {} unavailable: {}", name, error);
+        self.unavailable |= self.tempfail_on_error;
+    }
+
+    pub async fn connect_stage(&mut self, info: &ConnectInfo<'_>) -> Verdict {
+        let family = if info.client_ip.is_ipv4() { b'4' } else { b'6' };
+        let mut data = cstr(info.hostname);
+        data.push(family);
+        data.extend_from_slice(&info.client_port.to_be_bytes());
+        data.extend_from_slice(&cstr(&info.client_ip.to_string()));
+
+        let macros = macro_data(
+            b'C',
+            &[
+                ("j", info.server_name),
+                ("{daemon_name}", "tempmail"),
+                ("{client_addr}", &info.client_ip.to_string()),
+                ("{client_name}", info.hostname),
+                ("i", info.session_id),
+            ],
+        );
+        self.stage(b'C', &data, Some(&macros), NO_CONNECT, NR_CONNECT, true)
+            .await
+    }
+
+    pub async fn helo(&mut self, helo: &str) -> Verdict {
+        self.stage(b'H', &cstr(helo), None, NO_HELO, NR_HELO, true)
+            .await
+    }
+
+    /// Start a message; a message still in progress is aborted first.
+    pub async fn mail(&mut self, from: &str, args: &[&str]) -> Verdict {
+        self.abort().await;
+        self.in_message = true;
+        let mut data = cstr(&format!("<{}>", from));
+        for arg in args {
+            data.extend_from_slice(&cstr(arg));
+        }
+        let macros = macro_data(b'M', &[("{mail_addr}", from)]);
+        let verdict = self
+            .stage(b'M', &data, Some(&macros), NO_MAIL, NR_MAIL, false)
+            .await;
+        if verdict != Verdict::Continue {
+            self.abort().await;
+        }
+        verdict
+    }
+
+    pub async fn rcpt(&mut self, to: &str) -> Verdict {
+        let macros = macro_data(b'R', &[("{rcpt_addr}", to)]);
+        self.stage(
+            b'R',
+            &cstr(&format!("<{}>", to)),
+            Some(&macros),
+            NO_RCPT,
+            NR_RCPT,
+            false,
+        )
+        .await
+    }
+
+    /// Send DATA, the headers, the body and end of message, collecting modifications.
+    pub async fn message(&mut self, data: &[u8]) -> MessageResult {
+        let mut result = MessageResult::default();
+        if self.unavailable {
+            result.verdict = Some(Verdict::TempFail(TEMPFAIL_REPLY.to_string()));
+            return result;
+        }
+        if self.filters.is_empty() {
+            return result;
+        }
+        let message = dkim::to_crlf(data);
+        let (headers, body) = dkim::split_message(&message);
+        let fields = dkim::parse_fields(headers);
+
+        for i in 0..self.filters.len() {
+            if self.filters[i].state != State::Active {
+                continue;
+            }
+            let outcome = tokio::time::timeout(
+                // a generous limit for the whole message, each reply has its own
+                self.timeout * 4,
+                self.filters[i].message(&fields, body, &mut result),
+            )
+            .await;
+            let reply = match outcome {
+                Ok(Ok(reply)) => reply,
+                Ok(Err(e)) => self.filter_error(i, &e.to_string()),
+                Err(_) => self.filter_error(i, "timed out"),
+            };
+            if let Some(verdict) = self.verdict(i, reply, false) {
+                result.verdict = Some(verdict);
+                break;
+            }
+        }
+
+        self.in_message = false;
+        for filter in &mut self.filters {
+            if filter.state == State::MessageDone {
+                filter.state = State::Active;
+            }
+        }
+        result
+    }
+
+    /// Tell filters the current message will not be completed (RSET, new MAIL, HELO).
+    pub async fn abort(&mut self) {
+        if !self.in_message {
+            return;
+        }
+        self.in_message = false;
+        for filter in &mut self.filters {
+            if filter.state == State::Active {
+                let _ = filter.send(b'A', &[]).await;
+            }
+            if filter.state == State::MessageDone {
+                filter.state = State::Active;
+            }
+        }
+    }
+
+    pub async fn quit(&mut self) {
+        for filter in &mut self.filters {
+            let _ = filter.send(b'Q', &[]).await;
+        }
+        self.filters.clear();
+    }
+
+    /// Run one command through every active filter until one of them decides.
+    async fn stage(
+        &mut self,
+        command: u8,
+        data: &[u8],
+        macros: Option<&[u8]>,
+        skip_flag: u32,
+        no_reply_flag: u32,
+        connection_level: bool,
+    ) -> Verdict {
+        if self.unavailable {
+            return Verdict::TempFail(TEMPFAIL_REPLY.to_string());
+        }
+        for i in 0..self.filters.len() {
+            let filter = &mut self.filters[i];
+            if filter.state != State::Active || filter.protocol & skip_flag != 0 {
+                continue;
+            }
+            let expect_reply = filter.protocol & no_reply_flag == 0;
+            let outcome = tokio::time::timeout(self.timeout, async {
+                if let Some(macros) = macros {
+                    filter.send(b'D', macros).await?;
+                }
+                filter.send(command, data).await?;
+                if expect_reply {
+                    filter.read_reply(None).await
+                } else {
+                    Ok(Reply::Continue)
+                }
+            })
+            .await;
+            let reply = match outcome {
+                Ok(Ok(reply)) => reply,
+                Ok(Err(e)) => self.filter_error(i, &e.to_string()),
+                Err(_) => self.filter_error(i, "timed out"),
+            };
+            if let Some(verdict) = self.verdict(i, reply, connection_level) {
+                return verdict;
+            }
+        }
+        Verdict::Continue
+    }
+
+    fn filter_error(&mut self, i: usize, error: &str) -> Reply {
+        let filter = &mut self.filters[i];
+        tracing::warn!("Milter {} failed: {}", filter.name, error);
+        filter.state = State::Done;
+        if self.tempfail_on_error {
+            tempfail_reply(None)
+        } else {
+            Reply::Continue
+        }
+    }
+
+    /// Apply a filter's reply; `Some` ends the stage.
+    fn verdict(&mut self, i: usize, reply: Reply, connection_level: bool) -> Option<Verdict> {
+        let filter = &mut self.filters[i];
+        match reply {
+            Reply::Continue | Reply::Skip => None,
+            Reply::Accept => {
+                filter.state = if connection_level {
+                    State::Done
+                } else {
+                    State::MessageDone
+                };
+                None
+            }
+            Reply::Discard => {
+                tracing::info!("Milter {} discarded the message", filter.name);
+                Some(Verdict::Discard)
+            }
+            Reply::Reject(text) => {
+                tracing::info!("Milter {} rejected: {}", filter.name, text);
+                Some(Verdict::Reject(text))
+            }
+            Reply::TempFail(text) => {
+                tracing::info!("Milter {} tempfailed: {}", filter.name, text);
+                Some(Verdict::TempFail(text))
+            }
+        }
+    }
+}
+
+async fn open(address: &MilterAddress) -> std::io::Result<Box<dyn Stream>> {
+    Ok(match address {
+        MilterAddress::Tcp(addr) => Box::new(TcpStream::connect(addr).await?),
+        #[cfg(unix)]
+        MilterAddress::Unix(path) => Box::new(tokio::net::UnixStream::connect(path).await?),
+        #[cfg(not(unix))]
+        MilterAddress::Unix(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "unix sockets are not supported on this platform",
+            ))
+        }
+    })
+}
+
+impl Filter {
+    async fn send(&mut self, command: u8, data: &[u8]) -> std::io::Result<()> {
+        let mut packet = Vec::with_capacity(data.len() + 5);
+        packet.extend_from_slice(&(data.len() as u32 + 1).to_be_bytes());
+        packet.push(command);
+        packet.extend_from_slice(data);
+        self.stream.write_all(&packet).await?;
+        self.stream.flush().await
+    }
+
+    async fn read_packet(&mut self) -> std::io::Result<(u8, Vec<u8>)> {
+        let mut len = [0u8; 4];
+        self.stream.read_exact(&mut len).await?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len == 0 || len > MAX_REPLY {
+            return Err(invalid(format!("bad packet length {}", len)));
+        }
+        let mut packet = vec![0u8; len];
+        self.stream.read_exact(&mut packet).await?;
+        let command = packet.remove(0);
+        Ok((command, packet))
+    }
+
+    async fn negotiate(&mut self) -> std::io::Result<()> {
+        let mut data = Vec::with_capacity(12);
+        data.extend_from_slice(&VERSION.to_be_bytes());
+        data.extend_from_slice(&(ADD_HEADERS | CHANGE_HEADERS | QUARANTINE).to_be_bytes());
+        data.extend_from_slice(&OFFERED_PROTOCOL.to_be_bytes());
+        self.send(b'O', &data).await?;
+
+        let (command, reply) = self.read_packet().await?;
+        if command != b'O' || reply.len() < 12 {
+            return Err(invalid("unexpected option negotiation reply".to_string()));
+        }
+        let word =
+            |i: usize| u32::from_be_bytes([reply[i], reply[i + 1], reply[i + 2], reply[i + 3]]);
+        if word(0) < 2 {
+            return Err(invalid(format!("unsupported milter version {}", word(0))));
+        }
+        self.actions = word(4) & (ADD_HEADERS | CHANGE_HEADERS | QUARANTINE);
+        self.protocol = word(8) & OFFERED_PROTOCOL;
+        Ok(())
+    }
+
+    /// Read until a reply that ends the step; modifications are only valid at end of message.
+    async fn read_reply(
+        &mut self,
+        mut result: Option<&mut MessageResult>,
+    ) -> std::io::Result<Reply> {
+        loop {
+            let (command, data) = self.read_packet().await?;
+            match command {
+                b'a' => return Ok(Reply::Accept),
+                b'c' => return Ok(Reply::Continue),
+                b'd' => return Ok(Reply::Discard),
+                b'r' => return Ok(reject_reply(None)),
+                b't' => return Ok(tempfail_reply(None)),
+                b's' => return Ok(Reply::Skip),
+                b'y' => {
+                    let text = strings(&data).into_iter().next().unwrap_or_default();
+                    let text = text.trim().replace(['\r', '\n'], " ");
+                    return Ok(match text.as_bytes().first() {
+                        Some(b'4') => tempfail_reply(Some(text)),
+                        Some(b'5') => reject_reply(Some(text)),
+                        _ => {
+                            tracing::warn!(
+                                "Milter {} sent invalid reply code {:?}",
+                                self.name,
+                                text
+                            );
+                            tempfail_reply(None)
+                        }
+                    });
+                }
+                b'p' => continue,
+                b'h' | b'i' | b'm' | b'q' => {
+                    let Some(result) = result.as_deref_mut() else {
+                        return Err(invalid("modification outside end of message".to_string()));
+                    };
+                    self.modification(command, &data, result);
+                }
+                other => {
+                    tracing::debug!(
+                        "Milter {} sent unsupported reply {:?}",
+                        self.name,
+                        other as char
+                    );
+                }
+            }
+        }
+    }
+
+    fn modification(&self, command: u8, data: &[u8], result: &mut MessageResult) {
+        let index = || {
+            data.get(..4)
+                .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize)
+                .unwrap_or(0)
+        };
+        let allowed = match command {
+            b'h' | b'i' => self.actions & ADD_HEADERS != 0,
+            b'm' => self.actions & CHANGE_HEADERS != 0,
+            _ => self.actions & QUARANTINE != 0,
+        };
+        if !allowed {
+            tracing::warn!(
+                "Milter {} took action {:?} it did not negotiate",
+                self.name,
+                command as char
+            );
+            return;
+        }
+
+        match command {
+            b'h' => {
+                if let [name, value, ..] = strings(data).as_slice() {
+                    result.header_changes.push(HeaderChange::Add {
+                        name: name.clone(),
+                        value: value.clone(),
+                    });
+                }
+            }
+            b'i' | b'm' => {
+                if let [name, value, ..] = strings(data.get(4..).unwrap_or_default()).as_slice() {
+                    let (name, value) = (name.clone(), value.clone());
+                    result.header_changes.push(if command == b'i' {
+                        HeaderChange::Insert {
+                            index: index(),
+                            name,
+                            value,
+                        }
+                    } else {
+                        HeaderChange::Change {
+                            index: index(),
+                            name,
+                            value,
+                        }
+                    });
+                }
+            }
+            _ => {
+                let reason = strings(data).into_iter().next().unwrap_or_default();
+                result.quarantine = Some(format!("milter {}: {}", self.name, reason));
+            }
+        }
+    }
+
+    async fn message(
+        &mut self,
+        fields: &[dkim::Field<'_>],
+        body: &[u8],
+        result: &mut MessageResult,
+    ) -> std::io::Result<Reply> {
+        if self.protocol & NO_DATA == 0 {
+            self.send(b'T', &[]).await?;
+            if let Some(reply) = self.step_reply(NR_DATA).await? {
+                return Ok(reply);
+            }
+        }
+
+        if self.protocol & NO_HEADERS == 0 {
+            for field in fields {
+                let value = field.value().replace("\r\n", "\n");
+                let value = if self.protocol & HEADER_LEADING_SPACE != 0 {
+                    value.as_str()
+                } else {
+                    value.strip_prefix(' ').unwrap_or(&value)
+                };
+                let mut data = cstr(field.name);
+                data.extend_from_slice(&cstr(value));
+                self.send(b'L', &data).await?;
+                if let Some(reply) = self.step_reply(NR_HEADER).await? {
+                    return Ok(reply);
+                }
+            }
+        }
+
+        if self.protocol & NO_EOH == 0 {
+            self.send(b'N', &[]).await?;
+            if let Some(reply) = self.step_reply(NR_EOH).await? {
+                return Ok(reply);
+            }
+        }
+
+        if self.protocol & NO_BODY == 0 {
+            for chunk in body.chunks(MAX_BODY_CHUNK) {
+                self.send(b'B', chunk).await?;
+                match self.step_reply(NR_BODY).await? {
+                    Some(Reply::Skip) => break,
+                    Some(reply) => return Ok(reply),
+                    None => {}
+                }
+            }
+        }
+
+        self.send(b'E', &[]).await?;
+        self.read_reply(Some(result)).await
+    }
+
+    /// The reply to a message step, or `None` to go on with the next step.
+    async fn step_reply(&mut self, no_reply_flag: u32) -> std::io::Result<Option<Reply>> {
+        if self.protocol & no_reply_flag != 0 {
+            return Ok(None);
+        }
+        Ok(match self.read_reply(None).await? {
+            Reply::Continue => None,
+            reply => Some(reply),
+        })
+    }
+}
+
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn cstr(value: &str) -> Vec<u8> {
+    let mut data = value.as_bytes().to_vec();
+    data.push(0);
+    data
+}
+
+fn strings(data: &[u8]) -> Vec<String> {
+    data.split(|&b| b == 0)
+        .map(|s| String::from_utf8_lossy(s).to_string())
+        .collect()
+}
+
+fn macro_data(stage: u8, macros: &[(&str, &str)]) -> Vec<u8> {
+    let mut data = vec![stage];
+    for (name, value) in macros {
+        data.extend_from_slice(&cstr(name));
+        data.extend_from_slice(&cstr(value));
+    }
+    data
+}
+
+/// Apply the header changes a filter asked for to the raw message.
+pub fn apply_header_changes(data: &[u8], changes: &[HeaderChange]) -> Vec<u8> {
+    if changes.is_empty() {
+        return data.to_vec();
+    }
+    let message = dkim::to_crlf(data);
+    let (headers, body) = dkim::split_message(&message);
+    let mut fields: Vec<(String, Vec<u8>)> = dkim::parse_fields(headers)
+        .iter()
+        .map(|f| (f.name.to_string(), f.text.to_vec()))
+        .collect();
+    if fields.is_empty() {
+        return data.to_vec();
+    }
+
+    let format_field = |name: &str, value: &str| {
+        let value = value.replace("\r\n", "\n").replace('\n', "\r\n");
+        let text = if value.starts_with([' ', '\t']) {
+            format!("{}:{}", name, value)
+        } else {
+            format!("{}: {}", name, value)
+        };
+        (name.to_string(), text.into_bytes())
+    };
+    for change in changes {
+        match change {
+            HeaderChange::Add { name, value } => fields.push(format_field(name, value)),
+            HeaderChange::Insert { index, name, value } => {
+                let index = (*index).min(fields.len());
+                fields.insert(index, format_field(name, value));
+            }
+            HeaderChange::Change { index, name, value } => {
+                let position = fields
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, (n, _))| n.eq_ignore_ascii_case(name))
+                    .nth(index.saturating_sub(1))
+                    .map(|(i, _)| i);
+                match (position, value.is_empty()) {
+                    (Some(i), true) => {
+                        fields.remove(i);
+                    }
+                    (Some(i), false) => fields[i] = format_field(name, value),
+                    // changing a header that is not there adds it
+                    (None, false) => fields.push(format_field(name, value)),
+                    (None, true) => {}
+                }
+            }
+        }
+    }
+
+    let mut out = Vec::with_capacity(message.len());
+    for (_, text) in &fields {
+        out.extend_from_slice(text);
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b"\r\n");
+    out.extend_from_slice(body);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    type Packets = Vec<(u8, Vec<u8>)>;
+
+    /// The options a filter answers with: version 6, these actions and protocol flags.
+    fn options(actions: u32, protocol: u32) -> Vec<u8> {
+        let mut reply = vec![b'O'];
+        reply.extend_from_slice(&VERSION.to_be_bytes());
+        reply.extend_from_slice(&actions.to_be_bytes());
+        reply.extend_from_slice(&protocol.to_be_bytes());
+        reply
+    }
+
+    fn packet(command: u8, fields: &[&[u8]]) -> Vec<u8> {
+        let mut packet = vec![command];
+        for field in fields {
+            packet.extend_from_slice(field);
+        }
+        packet
+    }
+
+    /// A filter stub that answers each packet it receives with the packets
+    /// `reply` returns for it (command byte first). Returns a config pointing
+    /// at it and the packets it received, up to QUIT or end of connection.
+    async fn stub<F>(reply: F) -> (MilterConfig, tokio::task::JoinHandle<Packets>)
+    where
+        F: Fn(u8, &[u8]) -> Vec<Vec<u8>> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut received = Vec::new();
+            while let Ok(len) = stream.read_u32().await {
+                let mut data = vec![0u8; len as usize];
+                stream.read_exact(&mut data).await.unwrap();
+                let command = data.remove(0);
+                // macros, abort and quit never get a reply
+                let answers = match command {
+                    b'D' | b'A' | b'Q' => Vec::new(),
+                    _ => reply(command, &data),
+                };
+                for answer in answers {
+                    stream.write_u32(answer.len() as u32).await.unwrap();
+                    stream.write_all(&answer).await.unwrap();
+                }
+                received.push((command, data));
+                if command == b'Q' {
+                    break;
+                }
+            }
+            received
+        });
+        let config = MilterConfig {
+            filters: vec![("stub".to_string(), MilterAddress::Tcp(address))],
+            tempfail_on_error: true,
+            timeout: Duration::from_secs(5),
+        };
+        (config, handle)
+    }
+
+    fn commands(received: &Packets) -> String {
+        received
+            .iter()
+            .map(|(command, _)| *command as char)
+            .collect()
+    }
+
+    fn info() -> ConnectInfo<'static> {
+        ConnectInfo {
+            hostname: "client.example",
+            client_ip: "192.0.2.1".parse().unwrap(),
+            client_port: 40000,
+            session_id: "session",
+            server_name: "mx.example",
+        }
+    }
+
+    const MESSAGE: &[u8] = b"From: a@example.com\r\nSubject: hi\r\n\r\nbody\r\n";
+
+    #[test]
+    fn parses_addresses() {
+        assert!(matches!(MilterAddress::parse("inet:8891@localhost"),
+            MilterAddress::Tcp(a) if a == "localhost:8891"));
+        assert!(matches!(MilterAddress::parse("127.0.0.1:8891"),
+            MilterAddress::Tcp(a) if a == "127.0.0.1:8891"));
+        assert!(matches!(MilterAddress::parse("unix:/run/milter.sock"),
+            MilterAddress::Unix(p) if p == "/run/milter.sock"));
+        assert!(matches!(MilterAddress::parse("/run/milter.sock"),
+            MilterAddress::Unix(p) if p == "/run/milter.sock"));
+    }
+
+    #[tokio::test]
+    async fn negotiates_and_reports_every_stage() {
+        let (config, handle) = stub(|command, _| match command {
+            b'O' => vec![options(ADD_HEADERS, 0)],
+            _ => vec![vec![b'c']],
+        })
+        .await;
+        let mut milters = Milters::connect(&config).await;
+        assert_eq!(milters.connect_stage(&info()).await, Verdict::Continue);
+        assert_eq!(milters.helo("client.example").await, Verdict::Continue);
+        assert_eq!(
+            milters.mail("a@example.com", &["SIZE=10"]).await,
+            Verdict::Continue
+        );
+        assert_eq!(milters.rcpt("b@example.com").await, Verdict::Continue);
+        let result = milters.message(MESSAGE).await;
+        assert_eq!(result.verdict, None);
+        assert!(result.header_changes.is_empty());
+        milters.quit().await;
+
+        let received = handle.await.unwrap();
+        assert_eq!(commands(&received), "ODCHDMDRTLLNBEQ");
+        let word = |data: &[u8], i: usize| u32::from_be_bytes(data[i..i + 4].try_into().unwrap());
+        let offer = &received[0].1;
+        assert_eq!(word(offer, 0), VERSION);
+        assert_eq!(word(offer, 4), ADD_HEADERS | CHANGE_HEADERS | QUARANTINE);
+        assert_eq!(word(offer, 8), OFFERED_PROTOCOL);
+        assert_eq!(received[2].1, b"client.example\x004\x9c\x40192.0.2.1\0");
+        assert_eq!(received[5].1, b"<a@example.com>\0SIZE=10\0");
+        assert_eq!(received[9].1, b"From\0a@example.com\0");
+        assert_eq!(received[10].1, b"Subject\0hi\0");
+        assert_eq!(received[12].1, b"body\r\n");
+    }
+
+    #[tokio::test]
+    async fn protocol_flags_skip_steps_and_replies() {
+        let protocol =
+            NO_CONNECT | NO_HELO | NR_MAIL | NR_RCPT | NO_DATA | NR_HEADER | NO_EOH | NO_BODY;
+        let (config, handle) = stub(move |command, _| match command {
+            b'O' => vec![options(0, protocol)],
+            b'E' => vec![vec![b'a']],
+            _ => vec![],
+        })
+        .await;
+        let mut milters = Milters::connect(&config).await;
+        assert_eq!(milters.connect_stage(&info()).await, Verdict::Continue);
+        assert_eq!(milters.helo("client.example").await, Verdict::Continue);
+        assert_eq!(milters.mail("a@example.com", &[]).await, Verdict::Continue);
+        assert_eq!(milters.rcpt("b@example.com").await, Verdict::Continue);
+        assert_eq!(milters.message(MESSAGE).await.verdict, None);
+        milters.quit().await;
+
+        // no connect, HELO, DATA, end of headers or body; nothing waits for a reply but EOM
+        assert_eq!(commands(&handle.await.unwrap()), "ODMDRLLEQ");
+    }
+
+    #[tokio::test]
+    async fn replies_become_verdicts() {
+        let (config, _handle) = stub(|command, data| match command {
+            b'O' => vec![options(0, 0)],
+            b'R' if data.starts_with(b"<reject@") => {
+                vec![packet(b'y', &[b"550 5.7.1 no such user\r\n\0"])]
+            }
+            b'R' if data.starts_with(b"<later@") => {
+                vec![packet(b'y', &[b"451 4.7.1 try later\0"])]
+            }
+            b'R' if data.starts_with(b"<bad@") => vec![packet(b'y', &[b"250 fine\0"])],
+            b'R' if data.starts_with(b"<r@") => vec![vec![b'r']],
+            b'R' if data.starts_with(b"<t@") => vec![vec![b't']],
+            // progress packets are ignored until the real reply
+            b'E' => vec![vec![b'p'], vec![b'd']],
+            _ => vec![vec![b'c']],
+        })
+        .await;
+        let mut milters = Milters::connect(&config).await;
+        assert_eq!(milters.mail("a@example.com", &[]).await, Verdict::Continue);
+        assert_eq!(
+            milters.rcpt("reject@example.com").await,
+            Verdict::Reject("550 5.7.1 no such user".to_string())
+        );
+        assert_eq!(
+            milters.rcpt("later@example.com").await,
+            Verdict::TempFail("451 4.7.1 try later".to_string())
+        );
+        assert_eq!(
+            milters.rcpt("bad@example.com").await,
+            Verdict::TempFail(TEMPFAIL_REPLY.to_string())
+        );
+        assert_eq!(
+            milters.rcpt("r@example.com").await,
+            Verdict::Reject(REJECT_REPLY.to_string())
+        );
+        assert_eq!(
+            milters.rcpt("t@example.com").await,
+            Verdict::TempFail(TEMPFAIL_REPLY.to_string())
+        );
+        assert_eq!(milters.rcpt("ok@example.com").await, Verdict::Continue);
+        assert_eq!(
+            milters.message(MESSAGE).await.verdict,
+            Some(Verdict::Discard)
+        );
+    }
+
+    #[tokio::test]
+    async fn accept_ends_the_message_and_skip_ends_the_body() {
+        let (config, handle) = stub(|command, data| match command {
+            b'O' => vec![options(0, SKIP)],
+            b'M' if data.starts_with(b"<accept@") => vec![vec![b'a']],
+            b'B' => vec![vec![b's']],
+            _ => vec![vec![b'c']],
+        })
+        .await;
+        let mut milters = Milters::connect(&config).await;
+        // an accepted message is not reported any further
+        assert_eq!(
+            milters.mail("accept@example.com", &[]).await,
+            Verdict::Continue
+        );
+        assert_eq!(milters.rcpt("b@example.com").await, Verdict::Continue);
+        assert_eq!(milters.message(MESSAGE).await.verdict, None);
+        // the next one is
+        assert_eq!(milters.mail("a@example.com", &[]).await, Verdict::Continue);
+        let mut body = MESSAGE.to_vec();
+        body.resize(body.len() + MAX_BODY_CHUNK * 2, b'x');
+        assert_eq!(milters.message(&body).await.verdict, None);
+        milters.quit().await;
+
+        // one body chunk of three, then end of message
+        assert_eq!(commands(&handle.await.unwrap()), "ODMDMTLLNBEQ");
+    }
+
+    #[tokio::test]
+    async fn collects_modifications_at_end_of_message() {
+        let (config, _handle) = stub(|command, _| match command {
+            b'O' => vec![options(ADD_HEADERS | QUARANTINE, 0)],
+            b'E' => vec![
+                packet(b'h', &[b"X-Spam\0yes\0"]),
+                packet(b'i', &[&0u32.to_be_bytes(), b"X-First\0 1\0"]),
+                // CHANGE_HEADERS was not negotiated
+                packet(b'm', &[&1u32.to_be_bytes(), b"Subject\0\0"]),
+                packet(b'q', &[b"looks bad\0"]),
+                vec![b'c'],
+            ],
+            _ => vec![vec![b'c']],
+        })
+        .await;
+        let mut milters = Milters::connect(&config).await;
+        assert_eq!(milters.mail("a@example.com", &[]).await, Verdict::Continue);
+        let result = milters.message(MESSAGE).await;
+        assert_eq!(result.verdict, None);
+        assert_eq!(
+            result.header_changes,
+            vec![
+                HeaderChange::Add {
+                    name: "X-Spam".to_string(),
+                    value: "yes".to_string()
+                },
+                HeaderChange::Insert {
+                    index: 0,
+                    name: "X-First".to_string(),
+                    value: " 1".to_string()
+                },
+            ]
+        );
+        assert_eq!(result.quarantine.as_deref(), Some("milter stub: looks bad"));
+    }
+
+    #[tokio::test]
+    async fn modifications_before_end_of_message_break_the_filter() {
+        let (config, _handle) = stub(|command, _| match command {
+            b'O' => vec![options(ADD_HEADERS, 0)],
+            b'M' => vec![packet(b'h', &[b"X-Spam\0yes\0"])],
+            _ => vec![vec![b'c']],
+        })
+        .await;
+        let mut milters = Milters::connect(&config).await;
+        assert_eq!(
+            milters.mail("a@example.com", &[]).await,
+            Verdict::TempFail(TEMPFAIL_REPLY.to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn unreachable_filter_follows_the_error_policy() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let mut config = MilterConfig {
+            filters: vec![("gone".to_string(), MilterAddress::Tcp(address))],
+            tempfail_on_error: true,
+            timeout: Duration::from_secs(5),
+        };
+        let mut milters = Milters::connect(&config).await;
+        assert!(matches!(
+            milters.helo("client.example").await,
+            Verdict::TempFail(_)
+        ));
+        assert!(matches!(
+            milters.message(MESSAGE).await.verdict,
+            Some(Verdict::TempFail(_))
+        ));
+
+        config.tempfail_on_error = false;
+        let mut milters = Milters::connect(&config).await;
+        assert_eq!(milters.helo("client.example").await, Verdict::Continue);
+        assert_eq!(milters.message(MESSAGE).await.verdict, None);
+    }
+
+    #[tokio::test]
+    async fn oversized_packets_are_refused() {
+        let (config, _handle) = stub(|command, _| match command {
+            b'O' => vec![options(0, 0)],
+            _ => vec![vec![0u8; MAX_REPLY + 1]],
+        })
+        .await;
+        let mut milters = Milters::connect(&config).await;
+        assert!(matches!(
+            milters.helo("client.example").await,
+            Verdict::TempFail(_)
+        ));
+    }
+
+    fn change(index: usize, name: &str, value: &str) -> HeaderChange {
+        HeaderChange::Change {
+            index,
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    const HEADERS: &[u8] = b"Received: one\r\nReceived: two\r\nSubject: hi\r\n\r\nbody\r\n";
+
+    #[test]
+    fn adds_and_inserts_headers() {
+        let changes = [
+            HeaderChange::Add {
+                name: "X-Spam".to_string(),
+                value: "yes".to_string(),
+            },
+            HeaderChange::Insert {
+                index: 1,
+                name: "X-Inserted".to_string(),
+                value: "a\n\tb".to_string(),
+            },
+            HeaderChange::Insert {
+                index: 99,
+                name: "X-Last".to_string(),
+                value: "\tz".to_string(),
+            },
+        ];
+        assert_eq!(
+            apply_header_changes(HEADERS, &changes),
+            b"Received: one\r\nX-Inserted: a\r\n\tb\r\nReceived: two\r\nSubject: hi\r\n\
+              X-Spam: yes\r\nX-Last:\tz\r\n\r\nbody\r\n"
+        );
+    }
+
+    #[test]
+    fn changes_and_deletes_the_nth_header() {
+        assert_eq!(
+            apply_header_changes(HEADERS, &[change(2, "received", "changed")]),
+            b"Received: one\r\nreceived: changed\r\nSubject: hi\r\n\r\nbody\r\n"
+        );
+        assert_eq!(
+            apply_header_changes(HEADERS, &[change(1, "Received", "")]),
+            b"Received: two\r\nSubject: hi\r\n\r\nbody\r\n"
+        );
+        // a missing header is added, deleting one is a no-op
+        assert_eq!(
+            apply_header_changes(HEADERS, &[change(3, "Received", ""), change(1, "To", "b")]),
+            b"Received: one\r\nReceived: two\r\nSubject: hi\r\nTo: b\r\n\r\nbody\r\n"
+        );
+        assert_eq!(apply_header_changes(b"raw\n", &[]), b"raw\n");
+    }
+}
@@ -0,0 +1,466 @@
+use mail_parser::{Address, Message, MessagePart, MimeHeaders, PartType};
+use serde::Serialize;
+
+use crate::db::{MailAddress, MessageHeader, NewAttachment};
+
+/// Collect every non-body part (attachments and inline resources) of a parsed message.
+pub fn extract_attachments(message: &Message) -> Vec<NewAttachment> {
+    message
+        .attachments()
+        .map(|part| {
+            NewAttachment {
+                content_id: part.content_id().map(normalize_content_id),
+                filename: part.attachment_name().map(|s| s.to_string()),
+                content_type: if part.content_type().is_some() {
+                    content_type_of(part)
+                } else {
+                    "application/octet-stream".to_string()
+                },
+                disposition: part
+                    .content_disposition()
+                    .map(|cd| cd.ctype().to_lowercase()),
+                data: part.contents().to_vec(),
+            }
+        })
+        .collect()
+}
+
+/// All top-level headers in their original order, duplicates included, with
+/// folded values joined onto one line.
+pub fn collect_headers(message: &Message) -> Vec<MessageHeader> {
+    let raw = message.raw_message.as_ref();
+
+    message.parts[0]
+        .headers
+        .iter()
+        .map(|header| {
+            let name = raw
+                .get(header.offset_field..header.offset_start)
+                .and_then(|b| std::str::from_utf8(b).ok())
+                .map(|n| n.trim_end().trim_end_matches(':').trim().to_string())
+                .filter(|n| !n.is_empty())
+                .unwrap_or_else(|| header.name.as_str().to_string());
+            let value = raw
+                .get(header.offset_start..header.offset_end)
+                .map(|b| unfold(&String::from_utf8_lossy(b)))
+                .unwrap_or_default();
+            MessageHeader { name, value }
+        })
+        .collect()
+}
+
+/// Flatten an address header (list or groups) into name/address pairs.
+pub fn addresses(address: Option<&Address>) -> Vec<MailAddress> {
+    address
+        .map(|a| {
+            a.iter()
+                .map(|addr| MailAddress {
+                    name: addr
+                        .name
+                        .as_ref()
+                        .map(|n| n.trim().to_string())
+                        .filter(|n| !n.is_empty()),
+                    address: addr.address.as_ref().map(|a| a.trim().to_lowercase()),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Undo header folding (RFC 5322 §2.2.3): line breaks followed by whitespace become a single space.
+pub fn unfold(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut lines = value.split('\n');
+    if let Some(first) = lines.next() {
+        out.push_str(first.trim_end_matches('\r').trim());
+    }
+    for line in lines {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() {
+            continue;
+        }
+        out.push(' ');
+        out.push_str(line.trim());
+    }
+    out.trim().to_string()
+}
+
+/// Strip the angle brackets and surrounding whitespace from a Content-ID value.
+pub fn normalize_content_id(id: &str) -> String {
+    id.trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .trim()
+        .to_string()
+}
+
+/// Replace `cid:` URLs in an HTML body with whatever `resolve` returns for the
+/// referenced Content-ID. References that cannot be resolved are left untouched.
+pub fn rewrite_cid_references<F>(html: &str, resolve: F) -> String
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let lower = html.to_ascii_lowercase();
+    let mut out = String::with_capacity(html.len());
+    let mut pos = 0;
+
+    while let Some(found) = lower[pos..].find("cid:") {
+        let start = pos + found;
+        let value_start = start + 4;
+        let value_end = html[value_start..]
+            .find(|c: char| c == '"' || c == '\'' || c == ')' || c == '>' || c.is_whitespace())
+            .map(|i| value_start + i)
+            .unwrap_or(html.len());
+
+        let cid = percent_decode(&html[value_start..value_end]);
+        out.push_str(&html[pos..start]);
+        match resolve(&normalize_content_id(&cid)) {
+            Some(url) => out.push_str(&url),
+            None => out.push_str(&html[start..value_end]),
+        }
+        pos = value_end;
+    }
+
+    out.push_str(&html[pos..]);
+    out
+}
+
+/// Decode `%XX` escapes as allowed in `cid:` URLs (RFC 2392).
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+            if let Ok(b) = u8::from_str_radix(hex, 16) {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// One node of a message's MIME structure.
+///
+/// Paths number children from 1 at each level (`1`, `1.2`, …); the root is
+/// `0`, and an attached `message/rfc822` has its embedded root as child `1`.
+#[derive(Debug, Clone, Serialize)]
+pub struct MimeNode {
+    pub path: String,
+    pub content_type: String,
+    pub charset: Option<String>,
+    pub transfer_encoding: Option<String>,
+    pub disposition: Option<String>,
+    pub filename: Option<String>,
+    pub content_id: Option<String>,
+    /// Size of the decoded body in bytes.
+    pub size: usize,
+    /// Size of the part as transmitted, headers included.
+    pub raw_size: usize,
+    pub children: Vec<MimeNode>,
+}
+
+/// A single part selected by path, both decoded and as transmitted.
+pub struct PartData {
+    pub content_type: String,
+    pub filename: Option<String>,
+    pub decoded: Vec<u8>,
+    pub raw: Vec<u8>,
+}
+
+/// The part tree of `message`, which must be parsed from the bytes as received
+/// ([`crate::db::Db::message_source`]) for binary parts to keep their content.
+pub fn mime_tree(message: &Message) -> MimeNode {
+    mime_node(message, &message.parts[0], "0".to_string())
+}
+
+fn mime_node(message: &Message, part: &MessagePart, path: String) -> MimeNode {
+    let child_path = |n: usize| {
+        if path == "0" {
+            n.to_string()
+        } else {
+            format!("{}.{}", path, n)
+        }
+    };
+
+    let children = match &part.body {
+        PartType::Multipart(ids) => ids
+            .iter()
+            .enumerate()
+            .filter_map(|(i, id)| {
+                message
+                    .parts
+                    .get(*id)
+                    .map(|child| mime_node(message, child, child_path(i + 1)))
+            })
+            .collect(),
+        PartType::Message(inner) => vec![mime_node(inner, &inner.parts[0], child_path(1))],
+        _ => vec![],
+    };
+
+    MimeNode {
+        content_type: content_type_of(part),
+        charset: part
+            .content_type()
+            .and_then(|ct| ct.attribute("charset"))
+            .map(|c| c.to_lowercase()),
+        transfer_encoding: part.content_transfer_encoding().map(|e| e.to_lowercase()),
+        disposition: part.content_disposition().map(|cd| cd.ctype().to_lowercase()),
+        filename: part.attachment_name().map(|s| s.to_string()),
+        content_id: part.content_id().map(normalize_content_id),
+        size: match &part.body {
+            PartType::Multipart(_) => 0,
+            PartType::Message(_) => part.offset_end.saturating_sub(part.offset_body),
+            _ => part.contents().len(),
+        },
+        raw_size: part.offset_end.saturating_sub(part.offset_header),
+        path,
+        children,
+    }
+}
+
+/// Look up a part by the path used in [`MimeNode`].
+pub fn find_part(message: &Message, path: &str) -> Option<PartData> {
+    let mut msg = message;
+    let mut part = &message.parts[0];
+
+    if path != "0" {
+        for segment in path.split('.') {
+            let n: usize = segment.parse().ok()?;
+            match &part.body {
+                PartType::Multipart(ids) => {
+                    part = msg.parts.get(*ids.get(n.checked_sub(1)?)?)?;
+                }
+                PartType::Message(inner) if n == 1 => {
+                    msg = inner;
+                    part = &inner.parts[0];
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    // part offsets index the raw buffer of the message level that owns the part
+    let decoded = match &part.body {
+        PartType::Message(_) => msg
+            .raw_message
+            .get(part.offset_body..part.offset_end)
+            .unwrap_or_default()
+            .to_vec(),
+        _ => part.contents().to_vec(),
+    };
+
+    Some(PartData {
+        content_type: content_type_of(part),
+        filename: part.attachment_name().map(|s| s.to_string()),
+        decoded,
+        raw: msg
+            .raw_message
+            .get(part.offset_header..part.offset_end)
+            .unwrap_or_default()
+            .to_vec(),
+    })
+}
+
+fn content_type_of(part: &MessagePart) -> String {
+    part.content_type()
+        .map(|ct| match ct.subtype() {
+            Some(sub) => format!("{}/{}", ct.ctype(), sub),
+            None => ct.ctype().to_string(),
+        })
+        .unwrap_or_else(|| "text/plain".to_string())
+        .to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mail_parser::MessageParser;
+
+    const MESSAGE: &[u8] = b"From: alice@example.com\r\n\
+To: bob@example.com\r\n\
+Subject: nested\r\n\
+MIME-Version: 1.0\r\n\
+Content-Type: multipart/mixed; boundary=\"outer\"\r\n\
+\r\n\
+--outer\r\n\
+Content-Type: multipart/related; boundary=\"related\"\r\n\
+\r\n\
+--related\r\n\
+Content-Type: multipart/alternative; boundary=\"alt\"\r\n\
+\r\n\
+--alt\r\n\
+Content-Type: text/plain; charset=UTF-8\r\n\
+\r\n\
+Hello\r\n\
+--alt\r\n\
+Content-Type: text/html; charset=ISO-8859-1\r\n\
+Content-Transfer-Encoding: quoted-printable\r\n\
+\r\n\
+<p>Caf=E9 <img src=3D\"cid:logo@example.com\"></p>\r\n\
+--alt--\r\n\
+\r\n\
+--related\r\n\
+Content-Type: image/png\r\n\
+Content-Transfer-Encoding: base64\r\n\
+Content-ID: <logo@example.com>\r\n\
+Content-Disposition: inline\r\n\
+\r\n\
+iVBORw0KGgo=\r\n\
+--related--\r\n\
+\r\n\
+--outer\r\n\
+Content-Type: application/octet-stream; name=\"data.bin\"\r\n\
+Content-Transfer-Encoding: base64\r\n\
+Content-Disposition: attachment; filename=\"data.bin\"\r\n\
+\r\n\
+AP8A/w==\r\n\
+--outer\r\n\
+Content-Type: message/rfc822\r\n\
+\r\n\
+From: carol@example.com\r\n\
+Subject: forwarded\r\n\
+Content-Type: multipart/mixed; boundary=\"inner\"\r\n\
+\r\n\
+--inner\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+Inner text\r\n\
+--inner\r\n\
+Content-Type: text/csv; name=\"list.csv\"\r\n\
+Content-Disposition: attachment; filename=\"list.csv\"\r\n\
+\r\n\
+a,b\r\n\
+--inner--\r\n\
+\r\n\
+--outer--\r\n";
+
+    fn parse() -> Message<'static> {
+        MessageParser::default().parse(MESSAGE).unwrap()
+    }
+
+    /// `path content-type` for every node, depth first.
+    fn flatten(node: &MimeNode, out: &mut Vec<String>) {
+        out.push(format!("{} {}", node.path, node.content_type));
+        for child in &node.children {
+            flatten(child, out);
+        }
+    }
+
+    #[test]
+    fn numbers_parts_by_path() {
+        let tree = mime_tree(&parse());
+        let mut paths = Vec::new();
+        flatten(&tree, &mut paths);
+        assert_eq!(
+            paths,
+            [
+                "0 multipart/mixed",
+                "1 multipart/related",
+                "1.1 multipart/alternative",
+                "1.1.1 text/plain",
+                "1.1.2 text/html",
+                "1.2 image/png",
+                "2 application/octet-stream",
+                "3 message/rfc822",
+                "3.1 multipart/mixed",
+                "3.1.1 text/plain",
+                "3.1.2 text/csv",
+            ]
+        );
+
+        let html = &tree.children[0].children[0].children[1];
+        assert_eq!(html.charset.as_deref(), Some("iso-8859-1"));
+        assert_eq!(html.transfer_encoding.as_deref(), Some("quoted-printable"));
+
+        let image = &tree.children[0].children[1];
+        assert_eq!(image.content_id.as_deref(), Some("logo@example.com"));
+        assert_eq!(image.disposition.as_deref(), Some("inline"));
+        assert_eq!(image.size, 8);
+
+        let attachment = &tree.children[1];
+        assert_eq!(attachment.filename.as_deref(), Some("data.bin"));
+        assert_eq!(attachment.disposition.as_deref(), Some("attachment"));
+        assert_eq!(attachment.size, 4);
+        assert!(attachment.raw_size > attachment.size);
+        assert_eq!(tree.size, 0);
+    }
+
+    #[test]
+    fn finds_parts_by_path() {
+        let message = parse();
+
+        let html = find_part(&message, "1.1.2").unwrap();
+        assert_eq!(html.content_type, "text/html");
+        assert_eq!(
+            html.decoded,
+            "<p>Caf\u{e9} <img src=\"cid:logo@example.com\"></p>".as_bytes()
+        );
+        assert!(html.raw.starts_with(b"Content-Type: text/html"));
+        assert!(html.raw.ends_with(b"src=3D\"cid:logo@example.com\"></p>"));
+
+        let attachment = find_part(&message, "2").unwrap();
+        assert_eq!(attachment.filename.as_deref(), Some("data.bin"));
+        assert_eq!(attachment.decoded, [0x00, 0xff, 0x00, 0xff]);
+
+        // an attached message is its source, and its parts are reached through it
+        let forwarded = find_part(&message, "3").unwrap();
+        assert!(forwarded
+            .decoded
+            .starts_with(b"From: carol@example.com\r\n"));
+        let inner = find_part(&message, "3.1.2").unwrap();
+        assert_eq!(inner.filename.as_deref(), Some("list.csv"));
+        assert_eq!(inner.decoded, b"a,b");
+        assert_eq!(
+            find_part(&message, "3.1").unwrap().content_type,
+            "multipart/mixed"
+        );
+
+        assert_eq!(
+            find_part(&message, "0").unwrap().content_type,
+            "multipart/mixed"
+        );
+        for missing in ["4", "0.1", "1.3", "1.2.1", "3.2", "x", "", "1..1"] {
+            assert!(find_part(&message, missing).is_none(), "{:?}", missing);
+        }
+    }
+
+    #[test]
+    fn rewrites_cid_references() {
+        let resolve = |cid: &str| {
+            (cid == "logo@example.com").then(|| "/api/m/1/cid/logo%40example.com".to_string())
+        };
+        let html = "<img src=\"cid:logo@example.com\"><img src='CID:logo%40example.com'>\
+                    <div style=\"background:url(cid:logo@example.com)\"></div>\
+                    <img src=\"cid:missing@example.com\"> cid:logo@example.com";
+        assert_eq!(
+            rewrite_cid_references(html, resolve),
+            "<img src=\"/api/m/1/cid/logo%40example.com\"><img src='/api/m/1/cid/logo%40example.com'>\
+             <div style=\"background:url(/api/m/1/cid/logo%40example.com)\"></div>\
+             <img src=\"cid:missing@example.com\"> /api/m/1/cid/logo%40example.com"
+        );
+        assert_eq!(
+            rewrite_cid_references("no references", resolve),
+            "no references"
+        );
+    }
+
+    #[test]
+    fn normalizes_content_ids() {
+        assert_eq!(
+            normalize_content_id(" <part1@example.com> "),
+            "part1@example.com"
+        );
+        assert_eq!(
+            normalize_content_id("part1@example.com"),
+            "part1@example.com"
+        );
+    }
+}
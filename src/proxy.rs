@@ -0,0 +1,284 @@
+//! PROXY protocol v1 and v2 headers sent by load balancers such as HAProxy, so
+//! that the listeners see the real client address instead of the balancer's.
+
+use anyhow::{anyhow, bail, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::TcpStream;
+
+use crate::config::{env_list, env_or};
+use crate::spf;
+
+/// Balancers send the header right away; a peer that does not is dropped.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest v1 header, including the CRLF.
+const V1_MAX_LEN: usize = 107;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    /// Expect a PROXY header on every connection (`SMTP_PROXY_PROTOCOL`, `HTTP_PROXY_PROTOCOL`).
+    pub enabled: bool,
+    /// Only peers in these networks may send a header; everyone else is treated as
+    /// connecting directly (`PROXY_TRUSTED_NETWORKS`). Empty trusts no one, since any
+    /// client could otherwise claim any address.
+    pub trusted: Vec<(IpAddr, u8)>,
+}
+
+impl ProxyConfig {
+    /// Settings for the listener named `listener` (`SMTP` or `HTTP`).
+    pub fn from_env(listener: &str) -> Self {
+        let config = Self {
+            enabled: env_or(&format!("{}_PROXY_PROTOCOL", listener), false),
+            trusted: env_list("PROXY_TRUSTED_NETWORKS")
+                .iter()
+                .filter_map(|entry| {
+                    let network = parse_network(entry);
+                    if network.is_none() {
+                        tracing::warn!("Ignoring invalid PROXY_TRUSTED_NETWORKS entry {:?}", entry);
+                    }
+                    network
+                })
+                .collect(),
+        };
+        if config.enabled && config.trusted.is_empty() {
+            tracing::warn!(
+                "{}_PROXY_PROTOCOL is set but PROXY_TRUSTED_NETWORKS is empty; \
+                 PROXY headers will not be accepted from any peer",
+                listener
+            );
+        }
+        config
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.trusted
+            .iter()
+            .any(|(network, prefix)| spf::in_network(ip, *network, *prefix))
+    }
+}
+
+fn parse_network(value: &str) -> Option<(IpAddr, u8)> {
+    let (addr, prefix) = value.split_once('/').unwrap_or((value, ""));
+    let ip: IpAddr = addr.parse().ok()?;
+    let max = if ip.is_ipv4() { 32 } else { 128 };
+    let prefix = if prefix.is_empty() {
+        max
+    } else {
+        prefix.parse().ok().filter(|p| *p <= max)?
+    };
+    Some((ip, prefix))
+}
+
+/// The address of the client behind `peer`. When the listener expects PROXY
+/// headers, the header is consumed from `stream` so the application protocol
+/// starts right after it.
+pub async fn client_address(
+    config: &ProxyConfig,
+    stream: &mut TcpStream,
+    peer: SocketAddr,
+) -> Result<SocketAddr> {
+    if !config.enabled || !config.trusts(peer.ip().to_canonical()) {
+        return Ok(peer);
+    }
+    let source = tokio::time::timeout(HEADER_TIMEOUT, read_header(stream))
+        .await
+        .map_err(|_| anyhow!("timed out waiting for PROXY header"))??;
+    // LOCAL connections (health checks) and unknown protocols carry no client address
+    Ok(source.unwrap_or(peer))
+}
+
+async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+    if &start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start).await
+    } else {
+        bail!("connection did not start with a PROXY header")
+    }
+}
+
+/// `PROXY TCP4 <src> <dst> <sport> <dport>\r\n`, or `PROXY UNKNOWN ...\r\n`.
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S, start: &[u8]) -> Result<Option<SocketAddr>> {
+    let mut header = start.to_vec();
+    while !header.ends_with(b"\r\n") {
+        if header.len() >= V1_MAX_LEN {
+            bail!("PROXY v1 header is too long");
+        }
+        header.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&header[..header.len() - 2])
+        .map_err(|_| anyhow!("PROXY v1 header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.get(1).copied() {
+        Some("UNKNOWN") => Ok(None),
+        Some("TCP4") | Some("TCP6") if fields.len() == 6 => {
+            let ip: IpAddr = fields[2]
+                .parse()
+                .map_err(|_| anyhow!("invalid PROXY v1 source address {:?}", fields[2]))?;
+            let port: u16 = fields[4]
+                .parse()
+                .map_err(|_| anyhow!("invalid PROXY v1 source port {:?}", fields[4]))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => bail!("malformed PROXY v1 header {:?}", line),
+    }
+}
+
+/// Binary header: signature, version/command, family/transport, length, addresses.
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let len = stream.read_u16().await? as usize;
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+
+    if version_command >> 4 != 2 {
+        bail!(
+            "unsupported PROXY protocol version {}",
+            version_command >> 4
+        );
+    }
+    match version_command & 0x0f {
+        0x0 => return Ok(None),
+        0x1 => {}
+        command => bail!("unknown PROXY v2 command {}", command),
+    }
+
+    // the address block is followed by optional TLVs, which are skipped
+    match family >> 4 {
+        0x1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        0x2 if body.len() >= 36 => {
+            let octets: [u8; 16] = body[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(octets)),
+                port,
+            )))
+        }
+        // AF_UNSPEC and AF_UNIX have no usable client address
+        0x0 | 0x3 => Ok(None),
+        _ => bail!("malformed PROXY v2 address block"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_listed_networks_are_trusted() {
+        let mut config = ProxyConfig {
+            enabled: true,
+            trusted: Vec::new(),
+        };
+        assert!(!config.trusts("10.1.2.3".parse().unwrap()));
+
+        config.trusted = vec![parse_network("10.0.0.0/8").unwrap()];
+        assert!(config.trusts("10.1.2.3".parse().unwrap()));
+        assert!(!config.trusts("192.0.2.1".parse().unwrap()));
+    }
+
+    /// Parse the header at the start of `input`; returns the source and what follows it.
+    async fn parse(input: &[u8]) -> Result<(Option<SocketAddr>, Vec<u8>)> {
+        let mut stream = input;
+        let source = read_header(&mut stream).await?;
+        Ok((source, stream.to_vec()))
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header.extend_from_slice(b"EHLO");
+        header
+    }
+
+    #[tokio::test]
+    async fn reads_v1_headers() {
+        let (source, rest) = parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 40000 25\r\nEHLO")
+            .await
+            .unwrap();
+        assert_eq!(source, Some("192.0.2.1:40000".parse().unwrap()));
+        assert_eq!(rest, b"EHLO");
+
+        let (source, rest) = parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 40000 25\r\nEHLO")
+            .await
+            .unwrap();
+        assert_eq!(source, Some("[2001:db8::1]:40000".parse().unwrap()));
+        assert_eq!(rest, b"EHLO");
+
+        let (source, rest) = parse(b"PROXY UNKNOWN\r\nEHLO").await.unwrap();
+        assert_eq!(source, None);
+        assert_eq!(rest, b"EHLO");
+
+        assert!(parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 40000\r\n")
+            .await
+            .is_err());
+        assert!(parse(b"PROXY TCP4 not-an-ip 198.51.100.1 40000 25\r\n")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn long_v1_header_is_refused() {
+        let mut header = b"PROXY UNKNOWN ".to_vec();
+        header.resize(V1_MAX_LEN - 2, b'x');
+        header.extend_from_slice(b"\r\n");
+        assert!(parse(&header).await.is_ok());
+
+        header.insert(20, b'x');
+        let error = parse(&header).await.unwrap_err();
+        assert!(error.to_string().contains("too long"));
+    }
+
+    #[tokio::test]
+    async fn reads_v2_headers() {
+        // LOCAL: a health check from the balancer itself
+        let (source, rest) = parse(&v2(0x0, 0x00, &[])).await.unwrap();
+        assert_eq!(source, None);
+        assert_eq!(rest, b"EHLO");
+
+        let mut ipv4 = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        ipv4.extend_from_slice(&40000u16.to_be_bytes());
+        ipv4.extend_from_slice(&25u16.to_be_bytes());
+        // a TLV after the addresses is skipped
+        ipv4.extend_from_slice(&[0x04, 0x00, 0x01, 0x00]);
+        let (source, rest) = parse(&v2(0x1, 0x11, &ipv4)).await.unwrap();
+        assert_eq!(source, Some("192.0.2.1:40000".parse().unwrap()));
+        assert_eq!(rest, b"EHLO");
+
+        let mut ipv6 = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        ipv6.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6.extend_from_slice(&40000u16.to_be_bytes());
+        ipv6.extend_from_slice(&25u16.to_be_bytes());
+        let (source, rest) = parse(&v2(0x1, 0x21, &ipv6)).await.unwrap();
+        assert_eq!(source, Some("[2001:db8::1]:40000".parse().unwrap()));
+        assert_eq!(rest, b"EHLO");
+
+        // an address block too short for its family
+        assert!(parse(&v2(0x1, 0x11, &ipv4[..8])).await.is_err());
+        assert!(parse(&v2(0x2, 0x11, &ipv4)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn bad_signature_is_refused() {
+        let mut header = v2(0x1, 0x11, &[0; 12]);
+        header[5] = b'x';
+        assert!(parse(&header).await.is_err());
+        assert!(parse(b"EHLO client.example\r\n").await.is_err());
+        assert!(parse(b"PROXY").await.is_err());
+    }
+}
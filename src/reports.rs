@@ -0,0 +1,519 @@
+//! DMARC aggregate (RFC 7489 appendix C) and SMTP TLS (RFC 8460) reports
+//! delivered as attachments: zipped or gzipped XML, and gzipped or plain JSON.
+
+use chrono::{DateTime, TimeZone, Utc};
+use flate2::read::GzDecoder;
+use std::io::{Cursor, Read};
+
+use crate::db::{NewAttachment, NewDmarcRecord, NewDmarcReport, NewTlsPolicy, NewTlsReport};
+
+/// Largest decompressed report accepted; guards against zip bombs.
+const MAX_REPORT_BYTES: u64 = 20 * 1024 * 1024;
+
+pub enum Report {
+    Dmarc(NewDmarcReport),
+    Tls(NewTlsReport),
+}
+
+/// Parse every attachment that looks like a DMARC or TLS report.
+pub fn find_reports(attachments: &[NewAttachment]) -> Vec<Report> {
+    attachments
+        .iter()
+        .filter_map(|attachment| {
+            let content = unpack(attachment)?;
+            match parse(&content) {
+                Ok(report) => Some(report),
+                Err(e) => {
+                    tracing::debug!(
+                        "Ignoring report attachment {:?}: {}",
+                        attachment.filename,
+                        e
+                    );
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// The report document inside an attachment, or `None` if it is not a report container.
+fn unpack(attachment: &NewAttachment) -> Option<Vec<u8>> {
+    let content_type = attachment.content_type.to_ascii_lowercase();
+    let filename = attachment
+        .filename
+        .as_deref()
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    let data = attachment.data.as_slice();
+
+    let zipped = content_type.contains("zip") && !content_type.contains("gzip")
+        || filename.ends_with(".zip");
+    let gzipped = content_type.contains("gzip") || filename.ends_with(".gz");
+    let plain = matches!(
+        content_type.as_str(),
+        "text/xml" | "application/xml" | "application/json" | "application/tlsrpt+json"
+    ) || filename.ends_with(".xml")
+        || filename.ends_with(".json");
+
+    let result = if zipped {
+        unzip(data)
+    } else if gzipped {
+        read_limited(GzDecoder::new(data))
+    } else if plain {
+        Ok(data.to_vec())
+    } else {
+        return None;
+    };
+
+    match result {
+        Ok(content) => Some(content),
+        Err(e) => {
+            tracing::debug!("Failed to unpack {:?}: {}", attachment.filename, e);
+            None
+        }
+    }
+}
+
+fn read_limited(reader: impl Read) -> Result<Vec<u8>, String> {
+    let mut content = Vec::new();
+    reader
+        .take(MAX_REPORT_BYTES + 1)
+        .read_to_end(&mut content)
+        .map_err(|e| e.to_string())?;
+    if content.len() as u64 > MAX_REPORT_BYTES {
+        return Err("report is too large".to_string());
+    }
+    Ok(content)
+}
+
+/// Reporters put a single XML file in the archive.
+fn unzip(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data)).map_err(|e| e.to_string())?;
+    for i in 0..archive.len() {
+        let file = archive.by_index(i).map_err(|e| e.to_string())?;
+        if file.is_file() {
+            return read_limited(file);
+        }
+    }
+    Err("empty archive".to_string())
+}
+
+fn parse(content: &[u8]) -> Result<Report, String> {
+    let text = std::str::from_utf8(content).map_err(|_| "report is not UTF-8".to_string())?;
+    let text = text.trim_start_matches('\u{feff}').trim_start();
+    if text.starts_with('<') {
+        parse_dmarc(text).map(Report::Dmarc)
+    } else if text.starts_with('{') {
+        parse_tls(text).map(Report::Tls)
+    } else {
+        Err("unrecognized report format".to_string())
+    }
+}
+
+fn child<'a, 'i>(node: roxmltree::Node<'a, 'i>, name: &str) -> Option<roxmltree::Node<'a, 'i>> {
+    node.children().find(|n| n.has_tag_name(name))
+}
+
+/// Trimmed text of the element at `path` below `node`.
+fn text_at(node: roxmltree::Node, path: &[&str]) -> Option<String> {
+    let mut node = node;
+    for name in path {
+        node = child(node, name)?;
+    }
+    node.text()
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(str::to_string)
+}
+
+fn timestamp(value: Option<String>) -> Result<DateTime<Utc>, String> {
+    value
+        .and_then(|v| v.parse::<i64>().ok())
+        .and_then(|secs| Utc.timestamp_opt(secs, 0).single())
+        .ok_or_else(|| "invalid date_range".to_string())
+}
+
+fn parse_dmarc(text: &str) -> Result<NewDmarcReport, String> {
+    let document = roxmltree::Document::parse(text).map_err(|e| e.to_string())?;
+    let feedback = document.root_element();
+    if !feedback.has_tag_name("feedback") {
+        return Err("root element is not <feedback>".to_string());
+    }
+
+    let metadata = child(feedback, "report_metadata").ok_or("missing report_metadata")?;
+    let published = child(feedback, "policy_published").ok_or("missing policy_published")?;
+
+    let records = feedback
+        .children()
+        .filter(|n| n.has_tag_name("record"))
+        .filter_map(|record| {
+            let row = child(record, "row")?;
+            Some(NewDmarcRecord {
+                source_ip: text_at(row, &["source_ip"])?,
+                count: text_at(row, &["count"])?.parse().ok()?,
+                disposition: text_at(row, &["policy_evaluated", "disposition"]),
+                dkim: text_at(row, &["policy_evaluated", "dkim"]),
+                spf: text_at(row, &["policy_evaluated", "spf"]),
+                header_from: text_at(record, &["identifiers", "header_from"]),
+                envelope_from: text_at(record, &["identifiers", "envelope_from"]),
+            })
+        })
+        .collect();
+
+    Ok(NewDmarcReport {
+        org_name: text_at(metadata, &["org_name"]).ok_or("missing org_name")?,
+        report_id: text_at(metadata, &["report_id"]).ok_or("missing report_id")?,
+        email: text_at(metadata, &["email"]),
+        domain: text_at(published, &["domain"])
+            .ok_or("missing policy domain")?
+            .to_ascii_lowercase(),
+        policy: text_at(published, &["p"]),
+        date_begin: timestamp(text_at(metadata, &["date_range", "begin"]))?,
+        date_end: timestamp(text_at(metadata, &["date_range", "end"]))?,
+        records,
+    })
+}
+
+fn parse_tls(text: &str) -> Result<NewTlsReport, String> {
+    let json: serde_json::Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    let string = |value: &serde_json::Value, key: &str| {
+        value.get(key).and_then(|v| v.as_str()).map(str::to_string)
+    };
+    let datetime = |key: &str| {
+        json.get("date-range")
+            .and_then(|range| range.get(key))
+            .and_then(|v| v.as_str())
+            .and_then(|v| DateTime::parse_from_rfc3339(v).ok())
+            .map(|dt| dt.with_timezone(&Utc))
+            .ok_or_else(|| format!("invalid date-range {}", key))
+    };
+
+    let policies = json
+        .get("policies")
+        .and_then(|p| p.as_array())
+        .ok_or("missing policies")?
+        .iter()
+        .filter_map(|entry| {
+            let policy = entry.get("policy")?;
+            let summary = entry.get("summary");
+            let count = |key: &str| {
+                summary
+                    .and_then(|s| s.get(key))
+                    .and_then(|v| v.as_i64())
+                    .unwrap_or(0)
+            };
+            Some(NewTlsPolicy {
+                policy_type: string(policy, "policy-type")?,
+                policy_domain: string(policy, "policy-domain")?.to_ascii_lowercase(),
+                successful: count("total-successful-session-count"),
+                failed: count("total-failure-session-count"),
+                failure_details: entry
+                    .get("failure-details")
+                    .filter(|d| d.is_array())
+                    .cloned()
+                    .unwrap_or_else(|| serde_json::json!([])),
+            })
+        })
+        .collect();
+
+    Ok(NewTlsReport {
+        org_name: string(&json, "organization-name").ok_or("missing organization-name")?,
+        report_id: string(&json, "report-id").ok_or("missing report-id")?,
+        contact: string(&json, "contact-info"),
+        date_begin: datetime("start-datetime")?,
+        date_end: datetime("end-datetime")?,
+        policies,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    /// As sent by Google: no namespace, a record per source.
+    const GOOGLE: &str = r#"<?xml version="1.0" encoding="UTF-8" ?>
+<feedback>
+  <report_metadata>
+    <org_name>google.com</org_name>
+    <email>noreply-dmarc-support@google.com</email>
+    <report_id>12345678901234567890</report_id>
+    <date_range>
+      <begin>1700000000</begin>
+      <end>1700086399</end>
+    </date_range>
+  </report_metadata>
+  <policy_published>
+    <domain>Example.COM</domain>
+    <adkim>r</adkim>
+    <aspf>r</aspf>
+    <p>reject</p>
+    <sp>reject</sp>
+    <pct>100</pct>
+  </policy_published>
+  <record>
+    <row>
+      <source_ip>192.0.2.10</source_ip>
+      <count>12</count>
+      <policy_evaluated>
+        <disposition>none</disposition>
+        <dkim>pass</dkim>
+        <spf>pass</spf>
+      </policy_evaluated>
+    </row>
+    <identifiers>
+      <header_from>example.com</header_from>
+    </identifiers>
+  </record>
+  <record>
+    <row>
+      <source_ip>198.51.100.7</source_ip>
+      <count>3</count>
+      <policy_evaluated>
+        <disposition>reject</disposition>
+        <dkim>fail</dkim>
+        <spf>fail</spf>
+      </policy_evaluated>
+    </row>
+    <identifiers>
+      <envelope_from>spoof.example</envelope_from>
+      <header_from>example.com</header_from>
+    </identifiers>
+  </record>
+</feedback>
+"#;
+
+    /// As sent by Microsoft: a byte order mark and namespaced elements.
+    const MICROSOFT: &str = "\u{feff}<?xml version=\"1.0\"?>\
+<feedback xmlns:xsd=\"http://www.w3.org/2001/XMLSchema\" xmlns=\"urn:ietf:params:xml:ns:dmarc-2.0\">\
+<report_metadata><org_name>Enterprise Outlook</org_name>\
+<email>dmarcreport@microsoft.com</email><report_id>a1b2c3</report_id>\
+<date_range><begin>1700006400</begin><end>1700092800</end></date_range></report_metadata>\
+<policy_published><domain>example.com</domain><p>quarantine</p></policy_published>\
+<record><row><source_ip>2001:db8::25</source_ip><count>1</count>\
+<policy_evaluated><disposition>none</disposition><dkim>pass</dkim><spf>fail</spf></policy_evaluated>\
+</row><identifiers><header_from>example.com</header_from></identifiers></record>\
+</feedback>";
+
+    /// RFC 8460 appendix B.
+    const TLS_RPT: &str = r#"{
+  "organization-name": "Company-X",
+  "date-range": {
+    "start-datetime": "2016-04-01T00:00:00Z",
+    "end-datetime": "2016-04-01T23:59:59Z"
+  },
+  "contact-info": "sts-reporting@company-x.example",
+  "report-id": "5065427c-23d3-47ca-b6e0-946ea0e8c4be",
+  "policies": [{
+    "policy": {
+      "policy-type": "sts",
+      "policy-string": ["version: STSv1", "mode: testing",
+            "mx: *.mail.company-y.example", "max_age: 86400"],
+      "policy-domain": "Company-Y.example",
+      "mx-host": ["*.mail.company-y.example"]
+    },
+    "summary": {
+      "total-successful-session-count": 5326,
+      "total-failure-session-count": 303
+    },
+    "failure-details": [{
+      "result-type": "certificate-expired",
+      "sending-mta-ip": "2001:db8:abcd:0012::1",
+      "receiving-mx-hostname": "mx1.mail.company-y.example",
+      "failed-session-count": 100
+    }]
+  }]
+}"#;
+
+    fn attachment(filename: &str, content_type: &str, data: Vec<u8>) -> NewAttachment {
+        NewAttachment {
+            content_id: None,
+            filename: Some(filename.to_string()),
+            content_type: content_type.to_string(),
+            disposition: Some("attachment".to_string()),
+            data,
+        }
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zip(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        writer.start_file(name, options).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn dmarc(reports: Vec<Report>) -> NewDmarcReport {
+        let mut reports = reports.into_iter();
+        match (reports.next(), reports.next()) {
+            (Some(Report::Dmarc(report)), None) => report,
+            _ => panic!("expected one DMARC report"),
+        }
+    }
+
+    #[test]
+    fn reads_zipped_and_gzipped_aggregate_reports() {
+        let name = "google.com!example.com!1700000000!1700086399";
+        for attachment in [
+            attachment(
+                &format!("{}.zip", name),
+                "application/zip",
+                zip(&format!("{}.xml", name), GOOGLE.as_bytes()),
+            ),
+            attachment(
+                &format!("{}.xml.gz", name),
+                "application/gzip",
+                gzip(GOOGLE.as_bytes()),
+            ),
+            // some reporters only get the file name right
+            attachment(
+                &format!("{}.zip", name),
+                "application/octet-stream",
+                zip("report.xml", GOOGLE.as_bytes()),
+            ),
+        ] {
+            let report = dmarc(find_reports(&[attachment]));
+            assert_eq!(report.org_name, "google.com");
+            assert_eq!(report.report_id, "12345678901234567890");
+            assert_eq!(
+                report.email.as_deref(),
+                Some("noreply-dmarc-support@google.com")
+            );
+            assert_eq!(report.domain, "example.com");
+            assert_eq!(report.policy.as_deref(), Some("reject"));
+            assert_eq!(report.date_begin.timestamp(), 1700000000);
+            assert_eq!(report.date_end.timestamp(), 1700086399);
+
+            let records: Vec<_> = report
+                .records
+                .iter()
+                .map(|r| {
+                    (
+                        r.source_ip.as_str(),
+                        r.count,
+                        r.disposition.as_deref(),
+                        r.dkim.as_deref(),
+                        r.spf.as_deref(),
+                    )
+                })
+                .collect();
+            assert_eq!(
+                records,
+                [
+                    ("192.0.2.10", 12, Some("none"), Some("pass"), Some("pass")),
+                    (
+                        "198.51.100.7",
+                        3,
+                        Some("reject"),
+                        Some("fail"),
+                        Some("fail")
+                    ),
+                ]
+            );
+            assert_eq!(report.records[0].envelope_from, None);
+            assert_eq!(
+                report.records[1].envelope_from.as_deref(),
+                Some("spoof.example")
+            );
+            assert_eq!(
+                report.records[1].header_from.as_deref(),
+                Some("example.com")
+            );
+        }
+    }
+
+    #[test]
+    fn reads_namespaced_reports_with_a_byte_order_mark() {
+        let data = zip("report.xml", MICROSOFT.as_bytes());
+        let report = dmarc(find_reports(&[attachment(
+            "report.zip",
+            "application/zip",
+            data,
+        )]));
+        assert_eq!(report.org_name, "Enterprise Outlook");
+        assert_eq!(report.policy.as_deref(), Some("quarantine"));
+        assert_eq!(report.records.len(), 1);
+        assert_eq!(report.records[0].source_ip, "2001:db8::25");
+    }
+
+    #[test]
+    fn reads_tls_reports() {
+        let gzipped = attachment(
+            "company-x.example!company-y.example!1459468800!1459555199!001.json.gz",
+            "application/tlsrpt+gzip",
+            gzip(TLS_RPT.as_bytes()),
+        );
+        let plain = attachment(
+            "report.json",
+            "application/tlsrpt+json",
+            TLS_RPT.as_bytes().to_vec(),
+        );
+        for attachment in [gzipped, plain] {
+            let reports = find_reports(&[attachment]);
+            let [Report::Tls(report)] = reports.as_slice() else {
+                panic!("expected one TLS report");
+            };
+            assert_eq!(report.org_name, "Company-X");
+            assert_eq!(report.report_id, "5065427c-23d3-47ca-b6e0-946ea0e8c4be");
+            assert_eq!(
+                report.contact.as_deref(),
+                Some("sts-reporting@company-x.example")
+            );
+            assert_eq!(report.date_begin.to_rfc3339(), "2016-04-01T00:00:00+00:00");
+            assert_eq!(report.date_end.to_rfc3339(), "2016-04-01T23:59:59+00:00");
+
+            let [policy] = report.policies.as_slice() else {
+                panic!("expected one policy");
+            };
+            assert_eq!(policy.policy_type, "sts");
+            assert_eq!(policy.policy_domain, "company-y.example");
+            assert_eq!((policy.successful, policy.failed), (5326, 303));
+            assert_eq!(
+                policy.failure_details[0]["result-type"],
+                "certificate-expired"
+            );
+        }
+    }
+
+    #[test]
+    fn ignores_other_attachments_and_broken_reports() {
+        let attachments = [
+            attachment("photo.jpg", "image/jpeg", vec![0xff, 0xd8, 0xff]),
+            attachment("notes.xml", "text/xml", b"<notes/>".to_vec()),
+            attachment("broken.zip", "application/zip", b"PK not really".to_vec()),
+            attachment(
+                "broken.json",
+                "application/json",
+                br#"{"organization-name": "x"}"#.to_vec(),
+            ),
+            attachment("empty.xml.gz", "application/gzip", gzip(b"")),
+        ];
+        assert!(find_reports(&attachments).is_empty());
+    }
+
+    #[test]
+    fn refuses_decompression_bombs() {
+        let mut content = GOOGLE.as_bytes().to_vec();
+        content.resize(MAX_REPORT_BYTES as usize + 1, b' ');
+
+        let gzipped = attachment("bomb.xml.gz", "application/gzip", gzip(&content));
+        assert!(gzipped.data.len() < 100 * 1024);
+        assert!(unpack(&gzipped).is_none());
+
+        let zipped = attachment("bomb.zip", "application/zip", zip("bomb.xml", &content));
+        assert!(unpack(&zipped).is_none());
+
+        content.truncate(MAX_REPORT_BYTES as usize);
+        let gzipped = attachment("big.xml.gz", "application/gzip", gzip(&content));
+        assert_eq!(find_reports(&[gzipped]).len(), 1);
+    }
+}
@@ -1,23 +1,124 @@
-use crate::db::Db;
+use crate::address;
+use crate::clamd::{self, ClamdConfig, InfectedAction, ScanResult};
+use crate::command::{self, Body, Command};
+use crate::db::{
+    AuthResults, ConnectionInfo, Db, MailAddress, MessageHeader, NewMessage, SessionTranscript,
+};
+use crate::dns::{self, Resolver};
+use crate::dnsbl::{self, DnsblAction, DnsblConfig};
+use crate::greylist::{self, Decision, GreylistConfig};
+use crate::limits::{Limiter, LimitsConfig, Refusal};
+use crate::milter::{self, MilterConfig, Milters, Verdict};
+use crate::proxy::{self, ProxyConfig};
+use crate::reports::{self, Report};
+use crate::spam::{self, SpamConfig};
+use crate::spf::{self, SpfCheck};
+use crate::transcript::{ReplyWriter, Transcript, TranscriptConfig};
+use crate::{arc, dkim, dmarc, extract, html, mime};
 use anyhow::{Context, Result};
+use chrono::Utc;
 use mail_parser::MessageParser;
-use std::net::SocketAddr;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Instant;
+use uuid::Uuid;
 
-pub async fn start_server(addr: SocketAddr, domain: String, db: Db) -> Result<()> {
+/// Largest message accepted, as advertised with the SIZE extension.
+const MAX_MESSAGE_SIZE: usize = 10485760;
+
+/// Longest command line: 512 octets (RFC 5321 §4.5.3.1.4) with room for the
+/// parameters of the advertised extensions, such as ORCPT and ENVID.
+const MAX_COMMAND_LINE: usize = 2048;
+
+/// Longest text line (RFC 5321 §4.5.3.1.6). Longer lines are tolerated in a message
+/// within SIZE; this only bounds what is read while skipping an oversized one.
+const MAX_TEXT_LINE: usize = 1000;
+
+/// Settings for the SMTP listener, read from the environment at startup.
+#[derive(Debug, Clone)]
+pub struct SmtpConfig {
+    pub domain: String,
+    pub transcripts: TranscriptConfig,
+    pub spam: SpamConfig,
+    pub clamd: ClamdConfig,
+    pub milter: MilterConfig,
+    pub greylist: GreylistConfig,
+    pub limits: LimitsConfig,
+    pub dnsbl: DnsblConfig,
+    pub proxy: ProxyConfig,
+}
+
+impl SmtpConfig {
+    pub fn from_env(domain: String) -> Self {
+        Self {
+            // recipients are compared in ASCII form, so an IDN domain is configured as punycode
+            domain: address::normalize_domain(&domain).unwrap_or(domain),
+            transcripts: TranscriptConfig::from_env(),
+            spam: SpamConfig::from_env(),
+            clamd: ClamdConfig::from_env(),
+            milter: MilterConfig::from_env(),
+            greylist: GreylistConfig::from_env(),
+            limits: LimitsConfig::from_env(),
+            dnsbl: DnsblConfig::from_env(),
+            proxy: ProxyConfig::from_env("SMTP"),
+        }
+    }
+}
+
+pub async fn start_server(
+    addr: SocketAddr,
+    config: SmtpConfig,
+    db: Db,
+    resolver: Arc<dyn Resolver>,
+) -> Result<()> {
     let listener = TcpListener::bind(addr).await?;
     tracing::info!("SMTP server listening on {}", addr);
+    let limiter = Limiter::new(config.limits.clone());
+    let config = Arc::new(config);
 
     loop {
         match listener.accept().await {
-            Ok((stream, peer)) => {
-                let domain = domain.clone();
+            Ok((mut stream, balancer)) => {
+                let mut guard = match limiter.admit() {
+                    Ok(guard) => guard,
+                    Err(refusal) => {
+                        tracing::warn!("Refusing {}: {:?} connection limit", balancer, refusal);
+                        // the reply fits in the socket buffer, so no task is needed to send it
+                        if let Ok(mut stream) = stream.into_std() {
+                            let reply = refusal_reply(&config.domain, refusal);
+                            let _ = std::io::Write::write(&mut stream, reply.as_bytes());
+                        }
+                        continue;
+                    }
+                };
+                let config = config.clone();
                 let db = db.clone();
+                let resolver = resolver.clone();
+                let limiter = limiter.clone();
                 tokio::spawn(async move {
-                    if let Err(e) = handle_connection(stream, peer, &domain, db).await {
+                    let peer = proxy::client_address(&config.proxy, &mut stream, balancer).await;
+                    let peer = match peer {
+                        Ok(peer) => peer,
+                        Err(e) => {
+                            tracing::warn!("Dropping connection from {}: {}", balancer, e);
+                            return;
+                        }
+                    };
+                    if let Err(refusal) = guard.identify(peer.ip().to_canonical()) {
+                        tracing::warn!("Refusing {}: {:?} connection limit", peer, refusal);
+                        refuse_connection(stream, &config.domain, refusal).await;
+                        return;
+                    }
+                    let result =
+                        handle_connection(stream, peer, &config, db, resolver, &limiter).await;
+                    if let Err(e) = result {
                         tracing::error!("Connection error from {}: {}", peer, e);
                     }
+                    drop(guard);
                 });
             }
             Err(e) => {
@@ -27,76 +128,466 @@ pub async fn start_server(addr: SocketAddr, domain: String, db: Db) -> Result<()
     }
 }
 
+/// Greet a client over its connection limit with 421 and hang up.
+async fn refuse_connection(mut stream: TcpStream, domain: &str, refusal: Refusal) {
+    let _ = stream.write_all(refusal_reply(domain, refusal).as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+fn refusal_reply(domain: &str, refusal: Refusal) -> String {
+    match refusal {
+        Refusal::Global => {
+            format!("421 4.7.0 {} Too many connections, try again later\r\n", domain)
+        }
+        Refusal::PerIp => format!(
+            "421 4.7.0 {} Too many connections from your address, try again later\r\n",
+            domain
+        ),
+    }
+}
+
+/// Where a session is in the command sequence of RFC 5321 §4.1.4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Waiting for HELO or EHLO.
+    Connected,
+    /// Greeted, no mail transaction in progress.
+    Greeted,
+    /// MAIL accepted, waiting for recipients.
+    Mail,
+    /// At least one recipient accepted; DATA or BDAT may follow.
+    Rcpt,
+    /// BDAT chunks received, waiting for more or for BDAT LAST.
+    Chunking,
+}
+
+impl State {
+    /// The 503 reply for a command that is not allowed in this state.
+    fn refusal(self, command: &Command) -> Option<&'static str> {
+        let needs_helo = "503 5.5.1 Send HELO or EHLO first";
+        let needs_mail = "503 5.5.1 Need MAIL command first";
+        let needs_rcpt = "503 5.5.1 Need RCPT command first";
+        match (command, self) {
+            (
+                Command::Mail { .. } | Command::Rcpt { .. } | Command::Data | Command::Bdat { .. },
+                State::Connected,
+            ) => Some(needs_helo),
+            (Command::Mail { .. }, State::Greeted) => None,
+            (Command::Mail { .. }, _) => Some("503 5.5.1 Sender already specified"),
+            (Command::Rcpt { .. }, State::Greeted) => Some(needs_mail),
+            (Command::Rcpt { .. }, State::Chunking) => {
+                Some("503 5.5.1 Recipients must precede BDAT")
+            }
+            (Command::Data | Command::Bdat { .. }, State::Greeted) => Some(needs_mail),
+            (Command::Data | Command::Bdat { .. }, State::Mail) => Some(needs_rcpt),
+            (Command::Data, State::Chunking) => Some("503 5.5.1 DATA not allowed after BDAT"),
+            _ => None,
+        }
+    }
+}
+
+/// State of one SMTP connection.
+struct Session {
+    connection: ConnectionInfo,
+    client_ip: IpAddr,
+    state: State,
+    /// The client greeted with EHLO rather than HELO.
+    esmtp: bool,
+    /// Sender of the current transaction; empty for the null sender.
+    mail_from: Option<String>,
+    rcpt_to: Vec<String>,
+    /// SPF result for the current transaction's sender.
+    spf: Option<SpfCheck>,
+    /// Every recipient in our domain addressed during the connection, across
+    /// transactions, including those refused at RCPT; transcripts are kept for them.
+    addressed_recipients: Vec<String>,
+    /// Number of messages stored during the connection.
+    delivered: usize,
+    milters: Milters,
+    /// A milter asked to discard the current message.
+    discard: bool,
+    /// A milter asked to quarantine the current message.
+    quarantine: bool,
+    /// The sender declared BODY=BINARYMIME, so the content must come with BDAT.
+    binarymime: bool,
+    /// The sender asked for SMTPUTF8, allowing UTF-8 addresses and headers.
+    smtputf8: bool,
+}
+
+impl Session {
+    fn reset(&mut self) {
+        self.mail_from = None;
+        self.rcpt_to.clear();
+        self.spf = None;
+        self.discard = false;
+        self.quarantine = false;
+        self.binarymime = false;
+        self.smtputf8 = false;
+        // the greeting survives the end of a transaction and RSET (RFC 5321 §4.1.1.5)
+        if self.state != State::Connected {
+            self.state = State::Greeted;
+        }
+    }
+}
+
+type Reader = BufReader<OwnedReadHalf>;
+type Writer = ReplyWriter<OwnedWriteHalf>;
+
 async fn handle_connection(
     stream: TcpStream,
-    _peer: SocketAddr,
-    domain: &str,
+    peer: SocketAddr,
+    config: &SmtpConfig,
     db: Db,
+    resolver: Arc<dyn Resolver>,
+    limiter: &Limiter,
 ) -> Result<()> {
-    let (reader, mut writer) = stream.into_split();
+    let started_at = Utc::now();
+    let (reader, writer) = stream.into_split();
     let mut reader = BufReader::new(reader);
-    let mut line = String::new();
+    let mut writer = ReplyWriter::new(writer, Transcript::new(config.transcripts.data_bytes));
+
+    let client_ip = peer.ip().to_canonical();
+    let mut session = Session {
+        connection: ConnectionInfo {
+            session_id: new_session_id(),
+            client_ip: client_ip.to_string(),
+            client_port: peer.port(),
+            reverse_dns: dns::verified_reverse(resolver.as_ref(), client_ip).await,
+            helo: None,
+            // the listener is plain TCP; there is no STARTTLS yet
+            tls: None,
+            dnsbl: dnsbl::lookup(resolver.as_ref(), &config.dnsbl, client_ip).await,
+        },
+        client_ip,
+        state: State::Connected,
+        esmtp: false,
+        mail_from: None,
+        rcpt_to: Vec::new(),
+        spf: None,
+        addressed_recipients: Vec::new(),
+        delivered: 0,
+        milters: Milters::connect(&config.milter).await,
+        discard: false,
+        quarantine: false,
+        binarymime: false,
+        smtputf8: false,
+    };
+    tracing::debug!(
+        "Session {} from {} ({})",
+        session.connection.session_id,
+        peer,
+        session.connection.reverse_dns.as_deref().unwrap_or("unknown")
+    );
+
+    let result = run_session(
+        &mut reader,
+        &mut writer,
+        &mut session,
+        config,
+        &db,
+        resolver.as_ref(),
+        limiter,
+    )
+    .await;
+    if let Err(e) = &result {
+        writer.transcript.note(&format!("connection error: {}", e));
+    }
+    session.milters.quit().await;
+
+    if let Err(e) = save_transcript(&db, config, &session, writer.transcript, started_at).await {
+        tracing::error!("Failed to save transcript for {}: {}", session.connection.session_id, e);
+    }
+
+    result
+}
+
+async fn run_session(
+    reader: &mut Reader,
+    writer: &mut Writer,
+    session: &mut Session,
+    config: &SmtpConfig,
+    db: &Db,
+    resolver: &dyn Resolver,
+    limiter: &Limiter,
+) -> Result<()> {
+    let domain = config.domain.as_str();
+    let mut line = Vec::new();
+
+    for listing in &session.connection.dnsbl {
+        writer.transcript.note(&format!(
+            "client listed on {} ({})",
+            listing.zone,
+            listing.codes.join(", ")
+        ));
+    }
+    if let Some(listing) = session
+        .connection
+        .dnsbl
+        .iter()
+        .find(|l| l.action == DnsblAction::Reject)
+    {
+        tracing::info!("Rejecting {}: listed on {}", session.client_ip, listing.zone);
+        let reply = format!(
+            "554 5.7.1 Service unavailable; client [{}] blocked using {}{}\r\n",
+            session.client_ip,
+            listing.zone,
+            listing.reason.as_deref().map(|r| format!(": {}", r)).unwrap_or_default()
+        );
+        writer.write_all(reply.as_bytes()).await?;
+        return Ok(());
+    }
+
+    let verdict = session
+        .milters
+        .connect_stage(&milter::ConnectInfo {
+            hostname: session
+                .connection
+                .reverse_dns
+                .as_deref()
+                .unwrap_or(&session.connection.client_ip),
+            client_ip: session.client_ip,
+            client_port: session.connection.client_port,
+            session_id: &session.connection.session_id,
+            server_name: domain,
+        })
+        .await;
+    match verdict {
+        Verdict::Reject(_) => {
+            writer.transcript.note("connection rejected by milter");
+            writer.write_all(b"554 5.7.1 Access denied\r\n").await?;
+            return Ok(());
+        }
+        Verdict::TempFail(_) => {
+            writer.transcript.note("connection tempfailed by milter");
+            writer
+                .write_all(format!("421 4.3.2 {} Service not available\r\n", domain).as_bytes())
+                .await?;
+            return Ok(());
+        }
+        Verdict::Continue | Verdict::Discard => {}
+    }
 
     // Send greeting
     writer
         .write_all(format!("220 {} ESMTP Temporary Mail Server\r\n", domain).as_bytes())
         .await?;
 
-    let mut mail_from = String::new();
-    let mut rcpt_to = Vec::new();
     let mut data_buffer = Vec::new();
+    let limits = &config.limits;
+    let deadline = Instant::now() + limits.max_session;
+    let mut commands = 0;
 
     loop {
+        if limits.max_errors > 0 && writer.errors >= limits.max_errors {
+            writer.transcript.note("too many errors");
+            let reply = format!("421 4.7.0 {} Too many errors, closing connection\r\n", domain);
+            writer.write_all(reply.as_bytes()).await?;
+            break;
+        }
+
         line.clear();
-        let bytes_read = reader.read_line(&mut line).await?;
-        
+        let read =
+            read_line(reader, &mut line, MAX_COMMAND_LINE, limits.command_timeout, deadline)
+                .await?;
+        let bytes_read = match read {
+            Read::Line(n) => n,
+            Read::TooLong => {
+                writer.transcript.client(&String::from_utf8_lossy(&line));
+                writer.write_all(b"500 5.5.2 Line too long\r\n").await?;
+                if line.get(..4).is_some_and(|verb| verb.eq_ignore_ascii_case(b"BDAT")) {
+                    break;
+                }
+                continue;
+            }
+            stalled => {
+                close_stalled(writer, domain, stalled).await?;
+                break;
+            }
+        };
+
         if bytes_read == 0 {
+            writer.transcript.note("connection closed by client");
+            break;
+        }
+
+        commands += 1;
+        if limits.max_commands > 0 && commands > limits.max_commands {
+            writer.transcript.note("too many commands");
+            let reply = format!("421 4.7.0 {} Too many commands, closing connection\r\n", domain);
+            writer.write_all(reply.as_bytes()).await?;
             break;
         }
 
-        let command = line.trim();
-        tracing::debug!("Received: {}", command);
+        writer.transcript.client(&String::from_utf8_lossy(&line));
+        let Ok(raw) = std::str::from_utf8(&line) else {
+            writer.write_all(b"500 5.5.2 Syntax error, command is not valid UTF-8\r\n").await?;
+            continue;
+        };
+        let raw = raw.trim();
+        tracing::debug!("Received: {}", raw);
+        let parsed = match command::parse(raw, session.esmtp) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                writer.write_all(format!("{}\r\n", e).as_bytes()).await?;
+                // after a malformed BDAT the chunk boundary is unknown, so the rest of
+                // the connection cannot be trusted
+                if raw.get(..4).is_some_and(|verb| verb.eq_ignore_ascii_case("BDAT")) {
+                    break;
+                }
+                continue;
+            }
+        };
 
-        let parts: Vec<&str> = command.splitn(2, ' ').collect();
-        let cmd = parts[0].to_uppercase();
+        // a refused BDAT still has to consume its chunk below
+        let refusal = session.state.refusal(&parsed);
+        if let Some(reply) = refusal.filter(|_| !matches!(parsed, Command::Bdat { .. })) {
+            writer.write_all(format!("{}\r\n", reply).as_bytes()).await?;
+            continue;
+        }
 
-        match cmd.as_str() {
-            "HELO" | "EHLO" => {
+        match parsed {
+            Command::Helo(helo) | Command::Ehlo(helo) => {
+                session.esmtp = raw[..4].eq_ignore_ascii_case("EHLO");
+                session.connection.helo = Some(helo);
+                session.reset();
+                session.milters.abort().await;
+                let helo = session.connection.helo.clone().unwrap_or_default();
+                if let Some(reply) = milter_refusal(session.milters.helo(&helo).await, session) {
+                    writer.write_all(format!("{}\r\n", reply).as_bytes()).await?;
+                    continue;
+                }
+                session.state = State::Greeted;
+                if !session.esmtp {
+                    writer
+                        .write_all(format!("250 {} Hello\r\n", domain).as_bytes())
+                        .await?;
+                    continue;
+                }
                 writer
                     .write_all(format!("250-{} Hello\r\n", domain).as_bytes())
                     .await?;
-                writer.write_all(b"250-SIZE 10485760\r\n").await?;
+                writer
+                    .write_all(format!("250-SIZE {}\r\n", MAX_MESSAGE_SIZE).as_bytes())
+                    .await?;
                 writer.write_all(b"250-8BITMIME\r\n").await?;
+                writer.write_all(b"250-CHUNKING\r\n").await?;
+                writer.write_all(b"250-BINARYMIME\r\n").await?;
+                writer.write_all(b"250-SMTPUTF8\r\n").await?;
+                writer.write_all(b"250-DSN\r\n").await?;
+                writer.write_all(b"250-ENHANCEDSTATUSCODES\r\n").await?;
                 writer.write_all(b"250 PIPELINING\r\n").await?;
             }
-            "MAIL" => {
-                if let Some(from) = extract_email(command) {
-                    mail_from = from;
-                    writer.write_all(b"250 OK\r\n").await?;
-                } else {
-                    writer.write_all(b"501 Syntax error\r\n").await?;
+            Command::Mail { from, params } => {
+                if params.size.is_some_and(|size| size > MAX_MESSAGE_SIZE) {
+                    writer
+                        .write_all(b"552 5.3.4 Message size exceeds fixed maximum message size\r\n")
+                        .await?;
+                    continue;
+                }
+                let smtputf8 = params.smtputf8;
+                if !from.is_ascii() && !smtputf8 {
+                    writer
+                        .write_all(b"553 5.6.7 Non-ASCII sender address requires SMTPUTF8\r\n")
+                        .await?;
+                    continue;
+                }
+                if !limiter.allow_message_from(session.client_ip) {
+                    writer.transcript.note("per-IP message rate limit reached");
+                    let reply = "451 4.7.1 Too many messages from your address, try again later";
+                    writer.write_all(format!("{}\r\n", reply).as_bytes()).await?;
+                    continue;
+                }
+                let spf = spf::check(
+                    resolver,
+                    session.client_ip,
+                    &from,
+                    session.connection.helo.as_deref(),
+                )
+                .await;
+                tracing::debug!(
+                    "SPF {} for {} from {}",
+                    spf.result.as_str(),
+                    spf.identity,
+                    session.client_ip
+                );
+                session.discard = false;
+                session.quarantine = false;
+                // milters get the parameters as the client wrote them
+                let args: Vec<&str> = raw
+                    .rsplit_once('>')
+                    .map(|(_, rest)| rest.split_whitespace().collect())
+                    .unwrap_or_default();
+                let verdict = session.milters.mail(&from, &args).await;
+                if let Some(reply) = milter_refusal(verdict, session) {
+                    writer.write_all(format!("{}\r\n", reply).as_bytes()).await?;
+                    continue;
                 }
+                session.mail_from = Some(from);
+                session.spf = Some(spf);
+                session.binarymime = params.body == Some(Body::BinaryMime);
+                session.smtputf8 = smtputf8;
+                session.state = State::Mail;
+                writer.write_all(b"250 2.1.0 Sender OK\r\n").await?;
             }
-            "RCPT" => {
-                if let Some(to) = extract_email(command) {
-                    // Check if mailbox exists or domain matches
-                    let local = to.split('@').next().unwrap_or("");
-                    if to.ends_with(&format!("@{}", domain)) {
-                        rcpt_to.push(to);
-                        writer.write_all(b"250 OK\r\n").await?;
-                    } else {
-                        writer
-                            .write_all(b"550 Mailbox unavailable\r\n")
-                            .await?;
+            Command::Rcpt { to, .. } => {
+                // RFC 5321 §4.5.1: mail to postmaster must be accepted
+                let to = if to == "postmaster" { format!("postmaster@{}", domain) } else { to };
+                if !to.is_ascii() && !session.smtputf8 {
+                    writer
+                        .write_all(b"553 5.6.7 Non-ASCII recipient address requires SMTPUTF8\r\n")
+                        .await?;
+                    continue;
+                }
+                // Check if domain matches
+                if to.ends_with(&format!("@{}", domain)) {
+                    // recorded before the checks so a refused sender can still read the dialogue
+                    if !session.addressed_recipients.contains(&to) {
+                        session.addressed_recipients.push(to.clone());
                     }
+                    let sender = session.mail_from.as_deref().unwrap_or_default();
+                    match greylist::check(db, &config.greylist, session.client_ip, sender, &to)
+                        .await
+                    {
+                        Ok(Decision::Defer(seconds)) => {
+                            writer.transcript.note(&format!("recipient {} greylisted", to));
+                            let reply = format!(
+                                "451 4.7.1 Greylisted, please try again in {} seconds\r\n",
+                                seconds
+                            );
+                            writer.write_all(reply.as_bytes()).await?;
+                            continue;
+                        }
+                        Ok(Decision::Pass) => {}
+                        // never hold mail back because the greylist is unavailable
+                        Err(e) => tracing::warn!("Greylist check failed: {}", e),
+                    }
+                    // a quoted local part may contain '@'; the domain follows the last one
+                    let local = to.rsplit_once('@').map_or(to.as_str(), |(local, _)| local);
+                    if !limiter.allow_message_to(local) {
+                        writer.transcript.note(&format!("message rate limit reached for {}", to));
+                        let reply = "451 4.7.1 Too many messages for this mailbox, try again later";
+                        writer.write_all(format!("{}\r\n", reply).as_bytes()).await?;
+                        continue;
+                    }
+                    let verdict = session.milters.rcpt(&to).await;
+                    if let Some(reply) = milter_refusal(verdict, session) {
+                        writer.write_all(format!("{}\r\n", reply).as_bytes()).await?;
+                        continue;
+                    }
+                    session.rcpt_to.push(to);
+                    session.state = State::Rcpt;
+                    writer.write_all(b"250 2.1.5 Recipient OK\r\n").await?;
                 } else {
-                    writer.write_all(b"501 Syntax error\r\n").await?;
+                    writer
+                        .write_all(b"550 5.1.1 Mailbox unavailable\r\n")
+                        .await?;
                 }
             }
-            "DATA" => {
-                if mail_from.is_empty() || rcpt_to.is_empty() {
+            Command::Data => {
+                if session.binarymime {
+                    // binary content cannot be sent as dot-stuffed lines (RFC 3030 §3)
                     writer
-                        .write_all(b"503 Bad sequence of commands\r\n")
+                        .write_all(b"503 5.5.1 BODY=BINARYMIME requires BDAT\r\n")
                         .await?;
                     continue;
                 }
@@ -106,89 +597,786 @@ async fn handle_connection(
                     .await?;
 
                 data_buffer.clear();
+                let mut oversized = false;
                 loop {
                     line.clear();
-                    reader.read_line(&mut line).await?;
+                    // past SIZE the rest of the message is only read to find its end;
+                    // a line may carry a stuffed dot and CRLF beyond the content
+                    let max = if oversized {
+                        MAX_TEXT_LINE
+                    } else {
+                        MAX_MESSAGE_SIZE - data_buffer.len() + 3
+                    };
+                    match read_line(reader, &mut line, max, limits.data_timeout, deadline).await? {
+                        Read::Line(0) => {
+                            writer.transcript.note("connection closed by client during DATA");
+                            return Ok(());
+                        }
+                        Read::Line(_) => {}
+                        Read::TooLong => {
+                            oversized = true;
+                            data_buffer.clear();
+                            continue;
+                        }
+                        stalled => {
+                            close_stalled(writer, domain, stalled).await?;
+                            return Ok(());
+                        }
+                    }
 
-                    if line == ".\r\n" || line == ".\n" {
+                    if line == b".\r\n" || line == b".\n" {
+                        writer.transcript.end_data();
+                        writer.transcript.client(".\r\n");
                         break;
                     }
+                    if oversized {
+                        continue;
+                    }
+
+                    writer.transcript.data(&line);
+                    // undo dot-stuffing (RFC 5321 §4.5.2)
+                    let content = line.strip_prefix(b".").unwrap_or(&line);
+                    if data_buffer.len() + content.len() > MAX_MESSAGE_SIZE {
+                        oversized = true;
+                        data_buffer.clear();
+                        continue;
+                    }
+                    data_buffer.extend_from_slice(content);
+                }
 
-                    data_buffer.extend_from_slice(line.as_bytes());
+                if oversized {
+                    writer.transcript.note("message exceeds SIZE");
+                    writer
+                        .write_all(b"552 5.3.4 Message size exceeds fixed maximum message size\r\n")
+                        .await?;
+                    session.reset();
+                    continue;
                 }
 
-                // Process the email
-                match process_email(&db, &mail_from, &rcpt_to, &data_buffer, domain).await {
-                    Ok(_) => {
-                        writer.write_all(b"250 OK: Message accepted\r\n").await?;
+                let data = std::mem::take(&mut data_buffer);
+                finish_message(writer, session, data, config, db, resolver).await?;
+            }
+            Command::Bdat { size, last } => {
+                // the chunk follows the command whether or not it is accepted
+                let in_sequence = refusal.is_none();
+                if session.state != State::Chunking {
+                    data_buffer.clear();
+                }
+                let before = data_buffer.len();
+                let fits = chunk_fits(before, size);
+                let keep = (in_sequence && fits).then_some(&mut data_buffer);
+                match read_chunk(reader, size, keep, limits.data_timeout, deadline).await? {
+                    Read::Line(n) if n < size => {
+                        writer.transcript.note("connection closed by client during BDAT");
+                        return Ok(());
                     }
-                    Err(e) => {
-                        tracing::error!("Failed to process email: {}", e);
-                        writer
-                            .write_all(b"451 Temporary failure\r\n")
-                            .await?;
+                    Read::Line(_) => {}
+                    stalled => {
+                        close_stalled(writer, domain, stalled).await?;
+                        return Ok(());
                     }
                 }
 
-                mail_from.clear();
-                rcpt_to.clear();
+                if let Some(reply) = refusal {
+                    writer.write_all(format!("{}\r\n", reply).as_bytes()).await?;
+                    continue;
+                }
+                if !fits {
+                    writer
+                        .write_all(b"552 5.3.4 Message size exceeds fixed maximum message size\r\n")
+                        .await?;
+                    session.reset();
+                    data_buffer.clear();
+                    continue;
+                }
+                writer.transcript.data(&data_buffer[before..]);
+                if !last {
+                    session.state = State::Chunking;
+                    writer
+                        .write_all(format!("250 2.0.0 {} octets received\r\n", size).as_bytes())
+                        .await?;
+                    continue;
+                }
+
+                writer.transcript.end_data();
+                let data = std::mem::take(&mut data_buffer);
+                finish_message(writer, session, data, config, db, resolver).await?;
             }
-            "RSET" => {
-                mail_from.clear();
-                rcpt_to.clear();
+            Command::Rset => {
+                session.reset();
+                session.milters.abort().await;
                 data_buffer.clear();
-                writer.write_all(b"250 OK\r\n").await?;
+                writer.write_all(b"250 2.0.0 OK\r\n").await?;
             }
-            "QUIT" => {
-                writer.write_all(b"221 Bye\r\n").await?;
+            Command::Quit => {
+                writer.write_all(b"221 2.0.0 Bye\r\n").await?;
                 break;
             }
-            "NOOP" => {
-                writer.write_all(b"250 OK\r\n").await?;
+            Command::Noop => {
+                writer.write_all(b"250 2.0.0 OK\r\n").await?;
             }
-            _ => {
-                writer
-                    .write_all(b"502 Command not implemented\r\n")
-                    .await?;
+            // every address at the domain accepts mail, so confirming one would only
+            // help address harvesting (RFC 5321 §3.5.3, §7.3)
+            Command::Vrfy(_) => {
+                let reply = "252 2.5.2 Cannot VRFY user, but will accept message and attempt delivery";
+                writer.write_all(format!("{}\r\n", reply).as_bytes()).await?;
+            }
+            Command::Expn(_) => {
+                writer.write_all(b"502 5.5.1 EXPN not available\r\n").await?;
             }
+            Command::Help(topic) => {
+                writer.write_all(help(topic.as_deref()).as_bytes()).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Filter and store a complete message received with DATA or BDAT, and reply to it.
+async fn finish_message(
+    writer: &mut Writer,
+    session: &mut Session,
+    mut data: Vec<u8>,
+    config: &SmtpConfig,
+    db: &Db,
+    resolver: &dyn Resolver,
+) -> Result<()> {
+    let filtered = session.milters.message(&data).await;
+    match filtered.verdict {
+        Some(Verdict::Reject(reply)) | Some(Verdict::TempFail(reply)) => {
+            writer.transcript.note("message refused by milter");
+            writer.write_all(format!("{}\r\n", reply).as_bytes()).await?;
+            session.reset();
+            return Ok(());
+        }
+        Some(Verdict::Discard) => session.discard = true,
+        Some(Verdict::Continue) | None => {}
+    }
+    if session.discard {
+        writer.transcript.note("message discarded by milter");
+        writer.write_all(b"250 2.0.0 OK: Message accepted\r\n").await?;
+        session.reset();
+        return Ok(());
+    }
+    if let Some(reason) = &filtered.quarantine {
+        tracing::info!("Quarantining message from session {}: {}", session.connection.session_id, reason);
+        session.quarantine = true;
+    }
+    if !filtered.header_changes.is_empty() {
+        data = milter::apply_header_changes(&data, &filtered.header_changes);
+    }
+
+    // Process the email
+    match process_email(db, resolver, session, &data, config).await {
+        Ok(Delivery::Stored) => {
+            session.delivered += 1;
+            writer.write_all(b"250 2.0.0 OK: Message accepted\r\n").await?;
+        }
+        Ok(Delivery::Refused(reply)) => {
+            writer.write_all(format!("{}\r\n", reply).as_bytes()).await?;
+        }
+        Err(e) => {
+            tracing::error!("Failed to process email: {}", e);
+            writer
+                .write_all(b"451 4.3.0 Temporary failure\r\n")
+                .await?;
         }
     }
 
+    session.reset();
     Ok(())
 }
 
-fn extract_email(command: &str) -> Option<String> {
-    let start = command.find('<')?;
-    let end = command.find('>')?;
-    Some(command[start + 1..end].to_lowercase())
+enum Read {
+    /// Bytes read; a line of 0 bytes means the client hung up.
+    Line(usize),
+    /// The line was longer than allowed; it was read to its end but not kept whole.
+    TooLong,
+    /// Nothing complete arrived within the timeout.
+    Idle,
+    /// The session reached its maximum duration.
+    Expired,
 }
 
-async fn process_email(
+/// Read one line of at most `max` octets, giving up after `timeout` or at the session
+/// `deadline`, whichever comes first. A client trickling bytes still has to finish the
+/// line in time.
+async fn read_line(
+    reader: &mut Reader,
+    line: &mut Vec<u8>,
+    max: usize,
+    timeout: std::time::Duration,
+    deadline: Instant,
+) -> Result<Read> {
+    let expires = deadline.min(Instant::now() + timeout);
+    match tokio::time::timeout_at(expires, read_limited(reader, line, max)).await {
+        Ok(read) => read,
+        Err(_) if expires == deadline => Ok(Read::Expired),
+        Err(_) => Ok(Read::Idle),
+    }
+}
+
+/// Read up to and including LF. Past `max` octets the rest of the line is skipped
+/// rather than buffered, so `line` keeps only its beginning.
+async fn read_limited<R>(reader: &mut R, line: &mut Vec<u8>, max: usize) -> Result<Read>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut too_long = false;
+    loop {
+        let buf = reader.fill_buf().await?;
+        if buf.is_empty() {
+            break;
+        }
+        let (n, end) = match buf.iter().position(|&b| b == b'\n') {
+            Some(i) => (i + 1, true),
+            None => (buf.len(), false),
+        };
+        if too_long || line.len() + n > max {
+            let keep = max.saturating_sub(line.len()).min(n);
+            line.extend_from_slice(&buf[..keep]);
+            too_long = true;
+        } else {
+            line.extend_from_slice(&buf[..n]);
+        }
+        reader.consume(n);
+        if end {
+            break;
+        }
+    }
+    Ok(if too_long { Read::TooLong } else { Read::Line(line.len()) })
+}
+
+/// Whether a BDAT chunk of `size` octets still fits after `received` octets.
+/// The size comes from the client, so it may be anywhere up to `usize::MAX`.
+fn chunk_fits(received: usize, size: usize) -> bool {
+    size <= MAX_MESSAGE_SIZE.saturating_sub(received)
+}
+
+/// Read a BDAT chunk of exactly `size` bytes, appending it to `keep` or discarding it.
+/// Each read has to make progress within `timeout`; returns the number of bytes read,
+/// which is short only when the client hung up.
+async fn read_chunk(
+    reader: &mut Reader,
+    size: usize,
+    mut keep: Option<&mut Vec<u8>>,
+    timeout: std::time::Duration,
+    deadline: Instant,
+) -> Result<Read> {
+    let mut buf = vec![0u8; size.min(64 * 1024)];
+    let mut read = 0;
+    while read < size {
+        let want = (size - read).min(buf.len());
+        let expires = deadline.min(Instant::now() + timeout);
+        let n = match tokio::time::timeout_at(expires, reader.read(&mut buf[..want])).await {
+            Ok(n) => n?,
+            Err(_) if expires == deadline => return Ok(Read::Expired),
+            Err(_) => return Ok(Read::Idle),
+        };
+        if n == 0 {
+            break;
+        }
+        if let Some(keep) = keep.as_deref_mut() {
+            keep.extend_from_slice(&buf[..n]);
+        }
+        read += n;
+    }
+    Ok(Read::Line(read))
+}
+
+/// Tell a client that timed out why it is being disconnected (RFC 5321 §4.5.3.2).
+async fn close_stalled(writer: &mut Writer, domain: &str, read: Read) -> Result<()> {
+    let (note, reply) = match read {
+        Read::Expired => ("session time limit reached", "Session time limit exceeded"),
+        _ => ("timed out waiting for client", "Timeout exceeded"),
+    };
+    writer.transcript.note(note);
+    writer
+        .write_all(format!("421 4.4.2 {} {}, closing connection\r\n", domain, reply).as_bytes())
+        .await?;
+    Ok(())
+}
+
+/// Commands and their syntax, as listed by HELP.
+const HELP_TOPICS: &[(&str, &str)] = &[
+    ("HELO", "HELO <domain>"),
+    ("EHLO", "EHLO <domain>"),
+    (
+        "MAIL",
+        "MAIL FROM:<address> [SIZE=<n>] [BODY=7BIT|8BITMIME|BINARYMIME] [SMTPUTF8] \
+         [RET=FULL|HDRS] [ENVID=<id>]",
+    ),
+    ("RCPT", "RCPT TO:<address> [NOTIFY=<list>] [ORCPT=<type>;<address>]"),
+    ("DATA", "DATA"),
+    ("BDAT", "BDAT <size> [LAST]"),
+    ("RSET", "RSET"),
+    ("NOOP", "NOOP"),
+    ("QUIT", "QUIT"),
+    ("VRFY", "VRFY <address>"),
+    ("EXPN", "EXPN <list>"),
+    ("HELP", "HELP [<command>]"),
+];
+
+/// The reply to HELP: every command, or the syntax of one (RFC 5321 §4.1.1.8).
+fn help(topic: Option<&str>) -> String {
+    let Some(topic) = topic else {
+        let commands: Vec<&str> = HELP_TOPICS.iter().map(|(command, _)| *command).collect();
+        return format!(
+            "214-2.0.0 Commands supported:\r\n\
+             214-2.0.0 {}\r\n\
+             214 2.0.0 HELP <command> for details\r\n",
+            commands.join(" ")
+        );
+    };
+    match HELP_TOPICS.iter().find(|(command, _)| command.eq_ignore_ascii_case(topic)) {
+        Some((_, syntax)) => format!("214 2.0.0 {}\r\n", syntax),
+        None => "504 5.5.4 HELP topic not recognized\r\n".to_string(),
+    }
+}
+
+/// The reply for a command a milter refused; a discard only takes effect at end of DATA.
+fn milter_refusal(verdict: Verdict, session: &mut Session) -> Option<String> {
+    match verdict {
+        Verdict::Continue => None,
+        Verdict::Discard => {
+            session.discard = true;
+            None
+        }
+        Verdict::Reject(reply) | Verdict::TempFail(reply) => Some(reply),
+    }
+}
+
+/// Keep the transcript if the domain or one of the addressed mailboxes asked for it.
+async fn save_transcript(
     db: &Db,
-    from: &str,
-    recipients: &[String],
-    raw_data: &[u8],
-    domain: &str,
+    config: &SmtpConfig,
+    session: &Session,
+    transcript: Transcript,
+    started_at: chrono::DateTime<Utc>,
 ) -> Result<()> {
-    let raw_email = String::from_utf8_lossy(raw_data).to_string();
-    
+    let mut mailbox_ids = Vec::new();
+
+    for recipient in &session.addressed_recipients {
+        let (local, domain) = recipient.rsplit_once('@').unwrap_or((recipient, ""));
+        let domain_wide = config.transcripts.domains.iter().any(|d| d.eq_ignore_ascii_case(domain));
+        let mailbox = match db.get_mailbox_by_local(local).await? {
+            Some(mb) if mb.record_transcripts || domain_wide => mb,
+            // sessions to a recording domain are kept even if nothing was delivered
+            None if domain_wide => db.create_mailbox(local, None).await?,
+            _ => continue,
+        };
+        mailbox_ids.push(mailbox.id);
+    }
+
+    if mailbox_ids.is_empty() {
+        return Ok(());
+    }
+
+    db.create_transcript(
+        &SessionTranscript {
+            id: Uuid::nil(),
+            session_id: session.connection.session_id.clone(),
+            client_ip: session.connection.client_ip.clone(),
+            helo: session.connection.helo.clone(),
+            outcome: if session.delivered > 0 { "delivered" } else { "failed" }.to_string(),
+            started_at,
+            ended_at: Utc::now(),
+            lines: transcript.into_lines(),
+        },
+        &mailbox_ids,
+    )
+    .await?;
+
+    db.prune_transcripts(
+        config.transcripts.retention_days,
+        config.transcripts.max_per_mailbox,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Random identifier for a connection, shown in the `Received:` header and logs.
+fn new_session_id() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(12)
+        .map(|c| (c as char).to_ascii_uppercase())
+        .collect()
+}
+
+/// Build the trace header (RFC 5321 §4.4) recording how we received the message.
+fn received_header(session: &Session, domain: &str) -> String {
+    let conn = &session.connection;
+    let ip_literal = match conn.client_ip.parse::<IpAddr>() {
+        Ok(IpAddr::V6(v6)) => format!("IPv6:{}", v6),
+        _ => conn.client_ip.clone(),
+    };
+    let helo = conn.helo.as_deref().unwrap_or("unknown");
+    let rdns = conn.reverse_dns.as_deref().unwrap_or("unknown");
+    // RFC 3848 and RFC 6531 §3.7.3 protocol names
+    let protocol = match (session.esmtp, session.smtputf8, conn.tls.is_some()) {
+        (false, _, _) => "SMTP",
+        (true, false, false) => "ESMTP",
+        (true, false, true) => "ESMTPS",
+        (true, true, false) => "UTF8SMTP",
+        (true, true, true) => "UTF8SMTPS",
+    };
+    let tls = conn
+        .tls
+        .as_ref()
+        .map(|t| format!(" ({} {})", t.protocol, t.cipher))
+        .unwrap_or_default();
+    // only name the recipient when there is exactly one, so others are not disclosed
+    let for_clause = match session.rcpt_to.as_slice() {
+        [only] => format!("\r\n\tfor <{}>", only),
+        _ => String::new(),
+    };
+
+    format!(
+        "Received: from {} ({} [{}])\r\n\tby {} (tempmail_rs) with {}{} id {}{};\r\n\t{}\r\n",
+        helo,
+        rdns,
+        ip_literal,
+        domain,
+        protocol,
+        tls,
+        conn.session_id,
+        for_clause,
+        Utc::now().to_rfc2822()
+    )
+}
+
+/// Postgres text and JSONB cannot hold NUL characters, which binary (BINARYMIME) content,
+/// decoded headers and `&#0;` in HTML may all produce.
+fn text_column(text: String) -> String {
+    if text.contains('\0') {
+        text.replace('\0', "\u{fffd}")
+    } else {
+        text
+    }
+}
+
+/// Run DKIM, DMARC and ARC over the message as received and combine them with the session's SPF result.
+async fn authenticate(resolver: &dyn Resolver, session: &Session, data: &[u8]) -> AuthResults {
+    let dkim = dkim::verify(resolver, data).await;
+    for check in &dkim {
+        tracing::debug!(
+            "DKIM {} for d={} s={}",
+            check.result.as_str(),
+            check.domain.as_deref().unwrap_or("?"),
+            check.selector.as_deref().unwrap_or("?")
+        );
+    }
+
+    let mut from_domains: Vec<String> = Vec::new();
+    if let Some(headers) = MessageParser::default().parse_headers(data) {
+        for address in mime::addresses(headers.from()) {
+            let domain = address
+                .address
+                .as_deref()
+                .and_then(|a| a.rsplit_once('@'))
+                .map(|(_, d)| d.to_string());
+            if let Some(domain) = domain.filter(|d| !from_domains.contains(d)) {
+                from_domains.push(domain);
+            }
+        }
+    }
+
+    let dmarc = dmarc::check(resolver, &from_domains, session.spf.as_ref(), &dkim).await;
+    tracing::debug!(
+        "DMARC {} for {}",
+        dmarc.result.as_str(),
+        dmarc.from_domain.as_deref().unwrap_or("?")
+    );
+
+    let arc = arc::verify(resolver, data).await;
+    if arc.result != arc::ArcResult::None {
+        tracing::debug!("ARC {} with {} instances", arc.result.as_str(), arc.instances);
+    }
+
+    AuthResults {
+        spf: session.spf.clone(),
+        dkim,
+        dmarc: Some(dmarc),
+        arc: Some(arc),
+    }
+}
+
+/// `Authentication-Results:` header (RFC 8601) summarizing SPF, DKIM and DMARC.
+fn authentication_results(domain: &str, auth: &AuthResults) -> String {
+    let mut results = Vec::new();
+
+    match &auth.spf {
+        Some(spf) => {
+            let property = if spf.scope == "helo" { "smtp.helo" } else { "smtp.mailfrom" };
+            let identity = if spf.scope == "helo" { &spf.domain } else { &spf.identity };
+            results.push(format!(
+                "spf={}{} {}={}",
+                spf.result.as_str(),
+                comment(spf.mechanism.as_deref().or(spf.reason.as_deref())),
+                property,
+                result_value(identity)
+            ));
+        }
+        None => results.push("spf=none".to_string()),
+    }
+
+    if auth.dkim.is_empty() {
+        results.push("dkim=none".to_string());
+    }
+    for sig in &auth.dkim {
+        let mut result = format!("dkim={}{}", sig.result.as_str(), comment(sig.reason.as_deref()));
+        if let Some(d) = &sig.domain {
+            result.push_str(&format!(" header.d={}", result_value(d)));
+        }
+        if let Some(s) = &sig.selector {
+            result.push_str(&format!(" header.s={}", result_value(s)));
+        }
+        if let Some(b) = &sig.header_b {
+            result.push_str(&format!(" header.b={}", result_value(b)));
+        }
+        results.push(result);
+    }
+
+    match &auth.dmarc {
+        Some(dmarc) => {
+            let policy = dmarc.policy.as_ref().map(|p| format!("p={}", p));
+            let mut result = format!(
+                "dmarc={}{}",
+                dmarc.result.as_str(),
+                comment(policy.as_deref().or(dmarc.reason.as_deref()))
+            );
+            if let Some(from) = &dmarc.from_domain {
+                result.push_str(&format!(" header.from={}", result_value(from)));
+            }
+            results.push(result);
+        }
+        None => results.push("dmarc=none".to_string()),
+    }
+
+    match &auth.arc {
+        Some(arc) if arc.result != arc::ArcResult::None => {
+            let detail = match (&arc.reason, arc.oldest_pass) {
+                (Some(reason), _) => reason.clone(),
+                (None, Some(oldest)) => format!("i={} oldest-pass={}", arc.instances, oldest),
+                (None, None) => format!("i={}", arc.instances),
+            };
+            results.push(format!("arc={}{}", arc.result.as_str(), comment(Some(&detail))));
+        }
+        _ => results.push("arc=none".to_string()),
+    }
+
+    format!(
+        "Authentication-Results: {};\r\n\t{}\r\n",
+        domain,
+        results.join(";\r\n\t")
+    )
+}
+
+/// Remove `Authentication-Results:` headers that claim to come from us; they can only
+/// be forged (RFC 8601 §5). Results from other authentication servers are kept.
+fn strip_authentication_results(data: &[u8], domain: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut pos = 0;
+    let mut dropping = false;
+    while pos < data.len() {
+        let end = data[pos..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(data.len(), |i| pos + i + 1);
+        let line = &data[pos..end];
+        if line == b"\r\n" || line == b"\n" {
+            break;
+        }
+        if !line.starts_with(b" ") && !line.starts_with(b"\t") {
+            dropping = authserv_id(line).is_some_and(|id| id.eq_ignore_ascii_case(domain));
+        }
+        if !dropping {
+            out.extend_from_slice(line);
+        }
+        pos = end;
+    }
+    out.extend_from_slice(&data[pos..]);
+    out
+}
+
+/// The authserv-id of an `Authentication-Results:` header line, if it is one.
+fn authserv_id(line: &[u8]) -> Option<String> {
+    let line = String::from_utf8_lossy(line);
+    let (name, value) = line.split_once(':')?;
+    if !name.trim_end().eq_ignore_ascii_case("Authentication-Results") {
+        return None;
+    }
+    let mut value = value.trim_start();
+    // skip leading comments, e.g. `(mx.example) example.com; ...`
+    while let Some(rest) = value.strip_prefix('(') {
+        value = rest.split_once(')').map_or("", |(_, rest)| rest).trim_start();
+    }
+    let end = value.find(|c: char| c == ';' || c.is_whitespace()).unwrap_or(value.len());
+    Some(value[..end].to_string())
+}
+
+/// ` (text)` with characters that would end the comment removed.
+fn comment(text: Option<&str>) -> String {
+    match text {
+        Some(t) if !t.is_empty() => format!(
+            " ({})",
+            t.chars()
+                .filter(|c| !matches!(c, '(' | ')' | '\\' | '\r' | '\n'))
+                .collect::<String>()
+        ),
+        _ => String::new(),
+    }
+}
+
+/// A property value, quoted when it is not a plain token (RFC 8601 §2.2).
+fn result_value(value: &str) -> String {
+    let token = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`{|}~@".contains(c));
+    if token {
+        value.to_string()
+    } else {
+        format!(
+            "\"{}\"",
+            value
+                .chars()
+                .filter(|c| !c.is_control())
+                .collect::<String>()
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+        )
+    }
+}
+
+/// What became of a message handed over with DATA.
+enum Delivery {
+    Stored,
+    /// Refused with this SMTP reply.
+    Refused(String),
+}
+
+async fn process_email(
+    db: &Db,
+    resolver: &dyn Resolver,
+    session: &Session,
+    data: &[u8],
+    config: &SmtpConfig,
+) -> Result<Delivery> {
+    let domain = config.domain.as_str();
+    let from = session.mail_from.as_deref().unwrap_or_default();
+    let recipients = &session.rcpt_to;
+
+    let scan = match &config.clamd.address {
+        Some(address) => Some(clamd::scan(address, config.clamd.timeout, data).await),
+        None => None,
+    };
+    let mut quarantined = session.quarantine;
+    if let Some(verdict) = &scan {
+        tracing::debug!(
+            "Virus scan {} for session {}{}",
+            verdict.result.as_str(),
+            session.connection.session_id,
+            verdict.reason.as_deref().map(|r| format!(": {}", r)).unwrap_or_default()
+        );
+        match verdict.result {
+            ScanResult::Clean => {}
+            ScanResult::Infected => match config.clamd.action {
+                InfectedAction::Reject => {
+                    return Ok(Delivery::Refused(format!(
+                        "554 5.7.1 Message rejected: infected with {}",
+                        verdict.signature.as_deref().unwrap_or("malware")
+                    )));
+                }
+                InfectedAction::Quarantine => quarantined = true,
+            },
+            ScanResult::Error if !config.clamd.accept_on_error => {
+                anyhow::bail!("virus scan failed: {}", verdict.reason.as_deref().unwrap_or(""));
+            }
+            ScanResult::Error => {}
+        }
+    }
+
+    let auth = authenticate(resolver, session, data).await;
+
+    let mut raw_data = received_header(session, domain).into_bytes();
+    raw_data.extend_from_slice(authentication_results(domain, &auth).as_bytes());
+    raw_data.extend_from_slice(&strip_authentication_results(data, domain));
+    let raw_data = raw_data.as_slice();
+    let raw_email = text_column(String::from_utf8_lossy(raw_data).to_string());
+
     // Parse email
     let parser = MessageParser::default();
     let message = parser
         .parse(raw_data)
         .context("Failed to parse email")?;
 
-    let subject = message
-        .subject()
-        .unwrap_or("(No Subject)")
-        .to_string();
-
-    let body_text = message
-        .body_text(0)
-        .map(|s| s.to_string())
-        .unwrap_or_else(|| "(No text body)".to_string());
+    let subject = text_column(message.subject().unwrap_or("(No Subject)").to_string());
 
-    let body_html = message.body_html(0).map(|s| s.to_string());
+    // mail-parser converts between text and HTML bodies when one is missing;
+    // only keep a real text/html part and do the HTML-to-text conversion
+    // ourselves so links and tables survive
+    let body_html = message
+        .html_part(0)
+        .filter(|part| part.is_text_html())
+        .and_then(|part| part.text_contents())
+        .map(|s| text_column(s.to_string()));
+    let has_plain_text = message
+        .text_part(0)
+        .map(|part| !part.is_text_html())
+        .unwrap_or(false);
+    let body_text = match (&body_html, has_plain_text) {
+        (Some(html_body), false) => text_column(html::html_to_text(html_body)),
+        _ => message
+            .body_text(0)
+            .map(|s| text_column(s.to_string()))
+            .unwrap_or_default(),
+    };
+    let mut attachments = mime::extract_attachments(&message);
+    for attachment in &mut attachments {
+        attachment.filename = attachment.filename.take().map(text_column);
+        attachment.content_id = attachment.content_id.take().map(text_column);
+    }
+    // decoded header text (encoded words, RFC 2231) can carry NUL as well, which
+    // JSONB rejects just like text columns
+    let headers: Vec<MessageHeader> = mime::collect_headers(&message)
+        .into_iter()
+        .map(|header| MessageHeader {
+            name: text_column(header.name),
+            value: text_column(header.value),
+        })
+        .collect();
+    let addresses = |address| -> Vec<MailAddress> {
+        mime::addresses(address)
+            .into_iter()
+            .map(|a| MailAddress {
+                name: a.name.map(text_column),
+                address: a.address.map(text_column),
+            })
+            .collect()
+    };
+    let header_from = addresses(message.from()).into_iter().next();
+    let header_to = addresses(message.to());
+    let header_cc = addresses(message.cc());
+    let header_reply_to = addresses(message.reply_to());
+    let reports = reports::find_reports(&attachments);
+    let spam = spam::score(
+        &config.spam,
+        &spam::Input {
+            headers: &headers,
+            from: header_from.as_ref(),
+            reply_to: &header_reply_to,
+            subject: &subject,
+            body_text: &body_text,
+            body_html: body_html.as_deref(),
+            has_plain_text,
+            auth: &auth,
+            dnsbl: &session.connection.dnsbl,
+        },
+    );
 
     // Store message for each recipient
     for recipient in recipients {
@@ -196,7 +1384,12 @@ async fn process_email(
             continue;
         }
 
-        let local = recipient.split('@').next().unwrap_or("");
+        let local = recipient.rsplit_once('@').map_or("", |(local, _)| local);
+        let bcc = !header_to
+            .iter()
+            .chain(&header_cc)
+            .filter_map(|a| a.address.as_deref())
+            .any(|a| address::normalize(a).as_deref() == Some(recipient.as_str()));
         
         // Get or create mailbox
         let mailbox = match db.get_mailbox_by_local(local).await? {
@@ -207,19 +1400,329 @@ async fn process_email(
         };
 
         // Store message
-        db.create_message(
-            mailbox.id,
-            Some(from),
-            recipient,
-            &subject,
-            &body_text,
-            body_html.as_deref(),
-            &raw_email,
-        )
-        .await?;
+        let stored = db
+            .create_message(&NewMessage {
+                mailbox_id: mailbox.id,
+                from_addr: Some(from).filter(|f| !f.is_empty()),
+                to_addr: recipient,
+                envelope_to: recipients,
+                header_from: header_from.as_ref(),
+                header_to: &header_to,
+                header_cc: &header_cc,
+                header_reply_to: &header_reply_to,
+                bcc,
+                subject: &subject,
+                body_text: &body_text,
+                body_html: body_html.as_deref(),
+                raw: &raw_email,
+                raw_bytes: Some(raw_data).filter(|data| *data != raw_email.as_bytes()),
+                headers: &headers,
+                connection: &session.connection,
+                auth: &auth,
+                spam: &spam,
+                scan: scan.as_ref(),
+                quarantined,
+            })
+            .await?;
+
+        for attachment in &attachments {
+            db.create_attachment(stored.id, attachment).await?;
+        }
+
+        let rules = db.list_extraction_rules(mailbox.id).await?;
+        for found in extract::extract(&subject, &body_text, body_html.as_deref(), &rules) {
+            db.create_extraction(stored.id, &found).await?;
+        }
+
+        for report in &reports {
+            match report {
+                Report::Dmarc(report) => {
+                    db.create_dmarc_report(stored.id, mailbox.id, report).await?;
+                }
+                Report::Tls(report) => {
+                    db.create_tls_report(stored.id, mailbox.id, report).await?;
+                }
+            }
+        }
 
         tracing::info!("Email stored for {}: {}", recipient, subject);
     }
 
-    Ok(())
+    Ok(Delivery::Stored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::{remove_mailbox, test_db};
+    use crate::db::MessageFilter;
+    use crate::dns::ZoneResolver;
+
+    /// Run one SMTP session against the listener's handler and return every reply.
+    async fn session(db: &Db, config: &SmtpConfig, dialogue: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap());
+        let (accepted, client) = tokio::join!(listener.accept(), client);
+        let (stream, peer) = accepted.unwrap();
+        let mut client = client.unwrap();
+        client.write_all(dialogue.as_bytes()).await.unwrap();
+
+        let resolver: Arc<dyn Resolver> = Arc::new(ZoneResolver::parse("").unwrap());
+        let limiter = Limiter::new(config.limits.clone());
+        handle_connection(stream, peer, config, db.clone(), resolver, &limiter)
+            .await
+            .unwrap();
+
+        let mut replies = String::new();
+        client.read_to_string(&mut replies).await.unwrap();
+        replies
+    }
+
+    #[tokio::test]
+    async fn nul_characters_are_stored_as_replacement_characters() {
+        let Some(db) = test_db().await else {
+            return;
+        };
+        let config = SmtpConfig::from_env("tempmail.test".to_string());
+        let local = format!("nul{}", &Uuid::new_v4().simple().to_string()[..8]);
+
+        // "a\0b" as an encoded word, and an HTML-only body with a NUL reference
+        let replies = session(
+            &db,
+            &config,
+            &format!(
+                "EHLO client.test\r\n\
+                 MAIL FROM:<sender@example.test>\r\n\
+                 RCPT TO:<{}@tempmail.test>\r\n\
+                 DATA\r\n\
+                 From: =?utf-8?B?YQBi?= <sender@example.test>\r\n\
+                 Subject: =?utf-8?B?YQBi?=\r\n\
+                 X-Note: =?utf-8?B?YQBi?=\r\n\
+                 Content-Type: text/html\r\n\r\n\
+                 <p>x&#0;y</p>\r\n.\r\n\
+                 QUIT\r\n",
+                local
+            ),
+        )
+        .await;
+        assert!(replies.contains("250 2.0.0 OK"), "{}", replies);
+
+        let mailbox = db.get_mailbox_by_local(&local).await.unwrap().unwrap();
+        let messages = db
+            .list_messages(&local, &MessageFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(messages[0].subject, "a\u{fffd}b");
+        assert_eq!(messages[0].body_text, "x\u{fffd}y");
+        assert_eq!(
+            messages[0].header_from.as_ref().unwrap().name.as_deref(),
+            Some("a\u{fffd}b")
+        );
+
+        remove_mailbox(&db, mailbox.id).await;
+    }
+
+    #[tokio::test]
+    async fn quoted_at_sign_is_delivered_to_its_own_mailbox() {
+        let Some(db) = test_db().await else {
+            return;
+        };
+        let config = SmtpConfig::from_env("tempmail.test".to_string());
+        let tag = &Uuid::new_v4().simple().to_string()[..8];
+        let local = format!("\"{}@evil\"", tag);
+
+        let replies = session(
+            &db,
+            &config,
+            &format!(
+                "EHLO client.test\r\n\
+                 MAIL FROM:<sender@example.test>\r\n\
+                 RCPT TO:<{}@tempmail.test>\r\n\
+                 DATA\r\n\
+                 Subject: quoted\r\n\r\nhello\r\n.\r\n\
+                 QUIT\r\n",
+                local
+            ),
+        )
+        .await;
+        assert!(replies.contains("250 2.0.0 OK"), "{}", replies);
+
+        let mailbox = db.get_mailbox_by_local(&local).await.unwrap().unwrap();
+        assert_eq!(mailbox.local, local);
+        let messages = db
+            .list_messages(&mailbox.local, &MessageFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].to_addr, format!("{}@tempmail.test", local));
+        assert!(db
+            .get_mailbox_by_local(&format!("\"{}", tag))
+            .await
+            .unwrap()
+            .is_none());
+
+        remove_mailbox(&db, mailbox.id).await;
+    }
+
+    #[test]
+    fn forged_authentication_results_are_removed() {
+        let data = b"Authentication-Results: tempmail.local;\r\n\tspf=pass\r\n\
+Authentication-Results: (forged) TEMPMAIL.LOCAL; dkim=pass\r\n\
+Authentication-Results: mx.example.com; spf=fail\r\n\
+Subject: hi\r\n\
+\r\n\
+Authentication-Results: tempmail.local; body\r\n";
+        let stripped = strip_authentication_results(data, "tempmail.local");
+        assert_eq!(
+            String::from_utf8(stripped).unwrap(),
+            "Authentication-Results: mx.example.com; spf=fail\r\n\
+Subject: hi\r\n\
+\r\n\
+Authentication-Results: tempmail.local; body\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn long_lines_are_skipped_not_buffered() {
+        let mut input: &[u8] = b"NOOP\r\nMAIL FROM:<aaaaaaaaaa>\r\n\xff\xfe8bit\r\npartial";
+        let mut line = Vec::new();
+
+        assert!(matches!(
+            read_limited(&mut input, &mut line, 16).await.unwrap(),
+            Read::Line(6)
+        ));
+        assert_eq!(line, b"NOOP\r\n");
+
+        line.clear();
+        let read = read_limited(&mut input, &mut line, 16).await.unwrap();
+        assert!(matches!(read, Read::TooLong));
+        assert_eq!(line, b"MAIL FROM:<aaaaa");
+
+        line.clear();
+        assert!(matches!(
+            read_limited(&mut input, &mut line, 16).await.unwrap(),
+            Read::Line(8)
+        ));
+        assert_eq!(line, b"\xff\xfe8bit\r\n");
+
+        line.clear();
+        assert!(matches!(
+            read_limited(&mut input, &mut line, 16).await.unwrap(),
+            Read::Line(7)
+        ));
+        line.clear();
+        assert!(matches!(
+            read_limited(&mut input, &mut line, 16).await.unwrap(),
+            Read::Line(0)
+        ));
+    }
+
+    #[test]
+    fn oversized_chunk_does_not_overflow() {
+        assert!(chunk_fits(0, MAX_MESSAGE_SIZE));
+        assert!(chunk_fits(10, MAX_MESSAGE_SIZE - 10));
+        assert!(!chunk_fits(10, MAX_MESSAGE_SIZE - 9));
+        assert!(!chunk_fits(10, usize::MAX));
+    }
+
+    #[test]
+    fn commands_follow_the_transaction_sequence() {
+        let refusal =
+            |state: State, line: &str| state.refusal(&command::parse(line, true).unwrap());
+        let mail = "MAIL FROM:<a@example.test>";
+        let rcpt = "RCPT TO:<b@example.test>";
+        let bdat = "BDAT 10";
+
+        for line in [mail, rcpt, "DATA", bdat] {
+            assert_eq!(
+                refusal(State::Connected, line),
+                Some("503 5.5.1 Send HELO or EHLO first"),
+                "{}",
+                line
+            );
+        }
+        for line in [
+            "EHLO client.test",
+            "RSET",
+            "NOOP",
+            "HELP",
+            "VRFY bob",
+            "QUIT",
+        ] {
+            for state in [
+                State::Connected,
+                State::Greeted,
+                State::Mail,
+                State::Rcpt,
+                State::Chunking,
+            ] {
+                assert_eq!(refusal(state, line), None, "{} in {:?}", line, state);
+            }
+        }
+
+        assert_eq!(refusal(State::Greeted, mail), None);
+        assert_eq!(
+            refusal(State::Greeted, rcpt),
+            Some("503 5.5.1 Need MAIL command first")
+        );
+        assert_eq!(
+            refusal(State::Greeted, "DATA"),
+            Some("503 5.5.1 Need MAIL command first")
+        );
+        assert_eq!(
+            refusal(State::Greeted, bdat),
+            Some("503 5.5.1 Need MAIL command first")
+        );
+
+        assert_eq!(
+            refusal(State::Mail, mail),
+            Some("503 5.5.1 Sender already specified")
+        );
+        assert_eq!(refusal(State::Mail, rcpt), None);
+        assert_eq!(
+            refusal(State::Mail, "DATA"),
+            Some("503 5.5.1 Need RCPT command first")
+        );
+        assert_eq!(
+            refusal(State::Mail, bdat),
+            Some("503 5.5.1 Need RCPT command first")
+        );
+
+        assert_eq!(
+            refusal(State::Rcpt, mail),
+            Some("503 5.5.1 Sender already specified")
+        );
+        assert_eq!(refusal(State::Rcpt, rcpt), None);
+        assert_eq!(refusal(State::Rcpt, "DATA"), None);
+        assert_eq!(refusal(State::Rcpt, bdat), None);
+
+        assert_eq!(
+            refusal(State::Chunking, mail),
+            Some("503 5.5.1 Sender already specified")
+        );
+        assert_eq!(
+            refusal(State::Chunking, rcpt),
+            Some("503 5.5.1 Recipients must precede BDAT")
+        );
+        assert_eq!(
+            refusal(State::Chunking, "DATA"),
+            Some("503 5.5.1 DATA not allowed after BDAT")
+        );
+        assert_eq!(refusal(State::Chunking, "BDAT 0 LAST"), None);
+    }
+
+    #[test]
+    fn help_lists_commands_and_their_syntax() {
+        let all = help(None);
+        assert!(all.starts_with("214-2.0.0 Commands supported:\r\n"));
+        assert!(all.contains("HELO EHLO MAIL RCPT DATA BDAT RSET NOOP QUIT VRFY EXPN HELP\r\n"));
+        assert!(all.ends_with("214 2.0.0 HELP <command> for details\r\n"));
+
+        assert_eq!(help(Some("bdat")), "214 2.0.0 BDAT <size> [LAST]\r\n");
+        assert!(help(Some("MAIL")).starts_with("214 2.0.0 MAIL FROM:<address> [SIZE=<n>]"));
+        assert_eq!(
+            help(Some("STARTTLS")),
+            "504 5.5.4 HELP topic not recognized\r\n"
+        );
+    }
 }
@@ -0,0 +1,697 @@
+//! Rules-based spam scoring run on every received message.
+//!
+//! Rules use a small SpamAssassin-like format, one directive per line:
+//!
+//! ```text
+//! header   NAME  Subject =~ /free money/i   # also !~, exists:Header, missing:Header
+//! body     NAME  /click here/i              # subject and text body
+//! uri      NAME  /^https?:\/\/bit\.ly\//i   # every link in the message
+//! eval     NAME  spf_fail                   # a built-in check, see `Check`
+//! score    NAME  1.5                        # 0 disables a rule; defaults to 1.0
+//! describe NAME  Text shown with the hit
+//! ```
+//!
+//! A `#` at the start of a line or after whitespace starts a comment; write `\#`
+//! for a literal one.
+//!
+//! The built-in rules below are always loaded first; `SPAM_RULES_FILE` can add
+//! rules and override the tests, scores and descriptions of built-in ones.
+
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::arc::ArcResult;
+use crate::config::env_or;
+use crate::db::{AuthResults, MailAddress, MessageHeader};
+use crate::dkim::DkimResult;
+use crate::dmarc::{self, DmarcResult};
+use crate::dnsbl::DnsblListing;
+use crate::extract;
+use crate::spf::SpfResult;
+
+const DEFAULT_RULES: &str = r#"
+header   MISSING_DATE        missing:Date
+score    MISSING_DATE        1.0
+describe MISSING_DATE        Missing Date header
+
+header   MISSING_MID         missing:Message-ID
+score    MISSING_MID         0.5
+describe MISSING_MID         Missing Message-ID header
+
+header   MISSING_SUBJECT     missing:Subject
+score    MISSING_SUBJECT     1.0
+describe MISSING_SUBJECT     Missing Subject header
+
+eval     SUBJ_ALL_CAPS       subject_all_caps
+score    SUBJ_ALL_CAPS       1.5
+describe SUBJ_ALL_CAPS       Subject is all capitals
+
+header   SUBJ_EXCESS_PUNCT   Subject =~ /[!?]{3,}/
+score    SUBJ_EXCESS_PUNCT   1.0
+describe SUBJ_EXCESS_PUNCT   Subject has repeated ! or ?
+
+header   SUBJ_FREE           Subject =~ /\bfree\b/i
+score    SUBJ_FREE           0.5
+describe SUBJ_FREE           Subject mentions something free
+
+header   SUBJ_MONEY          Subject =~ /[$€£]\s?\d/
+score    SUBJ_MONEY          0.8
+describe SUBJ_MONEY          Subject mentions an amount of money
+
+header   TO_UNDISCLOSED      To =~ /undisclosed.recipients/i
+score    TO_UNDISCLOSED      1.0
+describe TO_UNDISCLOSED      Sent to undisclosed recipients
+
+header   FROM_NUMERIC_LOCAL  From =~ /\d{6,}@/
+score    FROM_NUMERIC_LOCAL  0.8
+describe FROM_NUMERIC_LOCAL  From address has a long numeric local part
+
+eval     REPLY_TO_DIFFERS    reply_to_differs
+score    REPLY_TO_DIFFERS    0.5
+describe REPLY_TO_DIFFERS    Reply-To domain differs from the From domain
+
+eval     HTML_ONLY           html_only
+score    HTML_ONLY           0.7
+describe HTML_ONLY           HTML body without a plain text alternative
+
+eval     EMPTY_BODY          empty_body
+score    EMPTY_BODY          1.0
+describe EMPTY_BODY          Message has no body text
+
+body     BODY_CLICK_HERE     /\bclick here\b/i
+score    BODY_CLICK_HERE     0.5
+describe BODY_CLICK_HERE     Asks to "click here"
+
+body     BODY_URGENCY        /\b(act now|limited time|expires? today|urgent response)\b/i
+score    BODY_URGENCY        1.0
+describe BODY_URGENCY        Creates a sense of urgency
+
+body     BODY_GUARANTEE      /\b100\s?% (free|guaranteed|satisfied)\b/i
+score    BODY_GUARANTEE      1.5
+describe BODY_GUARANTEE      Promises a 100% guarantee
+
+body     BODY_WINNER         /\b(you have|you've) (won|been selected)\b/i
+score    BODY_WINNER         2.0
+describe BODY_WINNER         Claims the recipient has won something
+
+body     BODY_EASY_MONEY     /\b(make money|extra (cash|income)|cash bonus|work from home)\b/i
+score    BODY_EASY_MONEY     1.5
+describe BODY_EASY_MONEY     Offers easy money
+
+body     BODY_PHARMA         /\b(viagra|cialis|pharmacy online)\b/i
+score    BODY_PHARMA         3.0
+describe BODY_PHARMA         Mentions pharmaceuticals
+
+body     BODY_VERIFY_ACCOUNT /\bverify your (account|password|identity)\b/i
+score    BODY_VERIFY_ACCOUNT 1.5
+describe BODY_VERIFY_ACCOUNT Asks to verify an account
+
+uri      URI_IP_HOST         /^https?:\/\/\d{1,3}(\.\d{1,3}){3}([:\/?#]|$)/i
+score    URI_IP_HOST         2.0
+describe URI_IP_HOST         Links to a bare IP address
+
+uri      URI_SHORTENER       /^https?:\/\/(bit\.ly|tinyurl\.com|goo\.gl|t\.co|ow\.ly|is\.gd|buff\.ly|cutt\.ly)\//i
+score    URI_SHORTENER       1.0
+describe URI_SHORTENER       Uses a URL shortener
+
+uri      URI_SUSPICIOUS_TLD  /^https?:\/\/[^\/?#]+\.(xyz|top|click|loan|work|gq|tk)([:\/?#]|$)/i
+score    URI_SUSPICIOUS_TLD  1.0
+describe URI_SUSPICIOUS_TLD  Links to a TLD common in spam
+
+eval     URI_TEXT_MISMATCH   link_text_mismatch
+score    URI_TEXT_MISMATCH   2.0
+describe URI_TEXT_MISMATCH   Link text shows a different domain than the link target
+
+eval     SPF_PASS            spf_pass
+score    SPF_PASS            -0.1
+describe SPF_PASS            SPF passed
+
+eval     SPF_FAIL            spf_fail
+score    SPF_FAIL            1.5
+describe SPF_FAIL            SPF failed
+
+eval     SPF_SOFTFAIL        spf_softfail
+score    SPF_SOFTFAIL        0.7
+describe SPF_SOFTFAIL        SPF soft-failed
+
+eval     DKIM_VALID          dkim_pass
+score    DKIM_VALID          -0.1
+describe DKIM_VALID          Has a valid DKIM signature
+
+eval     DKIM_INVALID        dkim_fail
+score    DKIM_INVALID        0.5
+describe DKIM_INVALID        Has a DKIM signature that does not verify
+
+eval     DKIM_NONE           dkim_none
+score    DKIM_NONE           0.3
+describe DKIM_NONE           Not DKIM signed
+
+eval     DMARC_PASS          dmarc_pass
+score    DMARC_PASS          -0.5
+describe DMARC_PASS          DMARC passed
+
+eval     DMARC_FAIL          dmarc_fail
+score    DMARC_FAIL          2.0
+describe DMARC_FAIL          DMARC failed
+
+eval     ARC_FAIL            arc_fail
+score    ARC_FAIL            0.5
+describe ARC_FAIL            ARC chain did not validate
+
+eval     RCVD_IN_DNSBL       dnsbl_listed
+score    RCVD_IN_DNSBL       2.5
+describe RCVD_IN_DNSBL       Client is listed on a DNS blocklist
+"#;
+
+/// Scoring result stored with a message.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpamReport {
+    pub score: f64,
+    /// Score at which the message counts as spam (`SPAM_THRESHOLD`).
+    pub threshold: f64,
+    pub is_spam: bool,
+    /// Rules that matched, in rule order.
+    pub rules: Vec<SpamHit>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpamHit {
+    pub name: String,
+    pub score: f64,
+    pub description: Option<String>,
+}
+
+/// Built-in checks available to `eval` rules.
+#[derive(Debug, Clone, Copy)]
+enum Check {
+    SpfPass,
+    SpfFail,
+    SpfSoftFail,
+    DkimPass,
+    DkimFail,
+    DkimNone,
+    DmarcPass,
+    DmarcFail,
+    ArcFail,
+    DnsblListed,
+    SubjectAllCaps,
+    ReplyToDiffers,
+    HtmlOnly,
+    EmptyBody,
+    LinkTextMismatch,
+}
+
+impl Check {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "spf_pass" => Check::SpfPass,
+            "spf_fail" => Check::SpfFail,
+            "spf_softfail" => Check::SpfSoftFail,
+            "dkim_pass" => Check::DkimPass,
+            "dkim_fail" => Check::DkimFail,
+            "dkim_none" => Check::DkimNone,
+            "dmarc_pass" => Check::DmarcPass,
+            "dmarc_fail" => Check::DmarcFail,
+            "arc_fail" => Check::ArcFail,
+            "dnsbl_listed" => Check::DnsblListed,
+            "subject_all_caps" => Check::SubjectAllCaps,
+            "reply_to_differs" => Check::ReplyToDiffers,
+            "html_only" => Check::HtmlOnly,
+            "empty_body" => Check::EmptyBody,
+            "link_text_mismatch" => Check::LinkTextMismatch,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Test {
+    /// `ALL` matches against every header line.
+    Header {
+        name: String,
+        regex: Regex,
+        negate: bool,
+    },
+    Exists {
+        name: String,
+        negate: bool,
+    },
+    Body(Regex),
+    Uri(Regex),
+    Eval(Check),
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    name: String,
+    test: Test,
+    score: f64,
+    description: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SpamConfig {
+    pub threshold: f64,
+    rules: Vec<Rule>,
+}
+
+impl SpamConfig {
+    pub fn from_env() -> Self {
+        let mut definitions = Definitions::default();
+        definitions.parse(DEFAULT_RULES, "built-in rules");
+
+        if let Ok(path) = std::env::var("SPAM_RULES_FILE") {
+            if !path.is_empty() {
+                match std::fs::read_to_string(&path) {
+                    Ok(contents) => definitions.parse(&contents, &path),
+                    Err(e) => tracing::warn!("Failed to read spam rules {}: {}", path, e),
+                }
+            }
+        }
+
+        Self {
+            threshold: env_or("SPAM_THRESHOLD", 5.0),
+            rules: definitions.into_rules(),
+        }
+    }
+}
+
+/// Rule directives collected from all sources; later definitions win.
+#[derive(Default)]
+struct Definitions {
+    tests: Vec<(String, Test)>,
+    scores: HashMap<String, f64>,
+    descriptions: HashMap<String, String>,
+}
+
+impl Definitions {
+    fn parse(&mut self, contents: &str, source: &str) {
+        for (i, line) in contents.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Err(e) = self.parse_line(line) {
+                tracing::warn!("{}:{}: {}", source, i + 1, e);
+            }
+        }
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<(), String> {
+        let (directive, rest) = split_word(line);
+        let (name, rest) = split_word(rest);
+        let name = name.to_string();
+        if name.is_empty() {
+            return Err("missing rule name".to_string());
+        }
+        if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("invalid rule name {}", name));
+        }
+
+        let test = match directive {
+            "header" => parse_header_test(rest)?,
+            "body" => Test::Body(parse_regex(rest)?),
+            "uri" => Test::Uri(parse_regex(rest)?),
+            "eval" => Test::Eval(Check::parse(rest).ok_or_else(|| format!("unknown check {}", rest))?),
+            "score" => {
+                let score = rest.parse().map_err(|_| format!("invalid score {}", rest))?;
+                self.scores.insert(name, score);
+                return Ok(());
+            }
+            "describe" => {
+                self.descriptions.insert(name, rest.replace("\\#", "#"));
+                return Ok(());
+            }
+            other => return Err(format!("unknown directive {}", other)),
+        };
+
+        match self.tests.iter_mut().find(|(n, _)| *n == name) {
+            Some(existing) => existing.1 = test,
+            None => self.tests.push((name, test)),
+        }
+        Ok(())
+    }
+
+    fn into_rules(mut self) -> Vec<Rule> {
+        self.tests
+            .into_iter()
+            .map(|(name, test)| Rule {
+                score: self.scores.get(&name).copied().unwrap_or(1.0),
+                description: self.descriptions.remove(&name),
+                name,
+                test,
+            })
+            .filter(|rule| rule.score != 0.0)
+            .collect()
+    }
+}
+
+/// The line up to a comment: a `#` at the start or after whitespace, unless escaped.
+fn strip_comment(line: &str) -> &str {
+    let mut prev = ' ';
+    for (i, c) in line.char_indices() {
+        if c == '#' && prev.is_whitespace() {
+            return &line[..i];
+        }
+        prev = c;
+    }
+    line
+}
+
+/// The first word of `line` and the trimmed remainder.
+fn split_word(line: &str) -> (&str, &str) {
+    match line.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim()),
+        None => (line, ""),
+    }
+}
+
+/// `/pattern/flags` with the flags `i`, `m`, `s` and `x`.
+fn parse_regex(value: &str) -> Result<Regex, String> {
+    let body = value
+        .strip_prefix('/')
+        .ok_or("expected /pattern/flags")?;
+    let end = body.rfind('/').ok_or("unterminated pattern")?;
+    let mut builder = RegexBuilder::new(&body[..end]);
+    for flag in body[end + 1..].chars() {
+        match flag {
+            'i' => builder.case_insensitive(true),
+            'm' => builder.multi_line(true),
+            's' => builder.dot_matches_new_line(true),
+            'x' => builder.ignore_whitespace(true),
+            other => return Err(format!("unknown regex flag {}", other)),
+        };
+    }
+    builder.build().map_err(|e| e.to_string())
+}
+
+fn parse_header_test(value: &str) -> Result<Test, String> {
+    if let Some(name) = value.strip_prefix("exists:") {
+        return Ok(Test::Exists {
+            name: name.trim().to_string(),
+            negate: false,
+        });
+    }
+    if let Some(name) = value.strip_prefix("missing:") {
+        return Ok(Test::Exists {
+            name: name.trim().to_string(),
+            negate: true,
+        });
+    }
+
+    let (name, rest) = value
+        .split_once(char::is_whitespace)
+        .ok_or("expected <Header> =~ /pattern/")?;
+    let rest = rest.trim_start();
+    let negate = if rest.starts_with("=~") {
+        false
+    } else if rest.starts_with("!~") {
+        true
+    } else {
+        return Err("expected =~ or !~".to_string());
+    };
+    Ok(Test::Header {
+        name: name.trim_end_matches(':').to_string(),
+        regex: parse_regex(rest[2..].trim())?,
+        negate,
+    })
+}
+
+/// What the rules are run against.
+pub struct Input<'a> {
+    pub headers: &'a [MessageHeader],
+    pub from: Option<&'a MailAddress>,
+    pub reply_to: &'a [MailAddress],
+    pub subject: &'a str,
+    pub body_text: &'a str,
+    pub body_html: Option<&'a str>,
+    /// The message has a text/plain body part of its own.
+    pub has_plain_text: bool,
+    pub auth: &'a AuthResults,
+    /// Blocklists that list the connecting client.
+    pub dnsbl: &'a [DnsblListing],
+}
+
+/// Score a message; the result is stored with every delivered copy.
+pub fn score(config: &SpamConfig, input: &Input) -> SpamReport {
+    let body = format!("{}\n{}", input.subject, input.body_text);
+    let links = extract::find_links(input.body_text, input.body_html);
+
+    let rules: Vec<SpamHit> = config
+        .rules
+        .iter()
+        .filter(|rule| match &rule.test {
+            Test::Header {
+                name,
+                regex,
+                negate,
+            } => {
+                let matched = header_values(input.headers, name).any(|v| regex.is_match(&v));
+                matched != *negate
+            }
+            Test::Exists { name, negate } => {
+                let exists = input.headers.iter().any(|h| h.name.eq_ignore_ascii_case(name));
+                exists != *negate
+            }
+            Test::Body(regex) => regex.is_match(&body),
+            Test::Uri(regex) => links.iter().any(|l| regex.is_match(&l.url)),
+            Test::Eval(check) => eval(*check, input, &links),
+        })
+        .map(|rule| SpamHit {
+            name: rule.name.clone(),
+            score: rule.score,
+            description: rule.description.clone(),
+        })
+        .collect();
+
+    let score = (rules.iter().map(|r| r.score).sum::<f64>() * 100.0).round() / 100.0;
+    SpamReport {
+        score,
+        threshold: config.threshold,
+        is_spam: score >= config.threshold,
+        rules,
+    }
+}
+
+fn header_values<'a>(
+    headers: &'a [MessageHeader],
+    name: &'a str,
+) -> impl Iterator<Item = String> + 'a {
+    headers.iter().filter_map(move |h| {
+        if name == "ALL" {
+            Some(format!("{}: {}", h.name, h.value))
+        } else if h.name.eq_ignore_ascii_case(name) {
+            Some(h.value.clone())
+        } else {
+            None
+        }
+    })
+}
+
+fn eval(check: Check, input: &Input, links: &[extract::FoundLink]) -> bool {
+    let auth = input.auth;
+    let spf = auth.spf.as_ref().map(|s| s.result);
+    let dmarc = auth.dmarc.as_ref().map(|d| d.result);
+
+    match check {
+        Check::SpfPass => spf == Some(SpfResult::Pass),
+        Check::SpfFail => spf == Some(SpfResult::Fail),
+        Check::SpfSoftFail => spf == Some(SpfResult::SoftFail),
+        Check::DkimPass => auth.dkim.iter().any(|d| d.result == DkimResult::Pass),
+        Check::DkimFail => {
+            !auth.dkim.is_empty() && auth.dkim.iter().all(|d| d.result != DkimResult::Pass)
+        }
+        Check::DkimNone => auth.dkim.is_empty(),
+        Check::DmarcPass => dmarc == Some(DmarcResult::Pass),
+        Check::DmarcFail => dmarc == Some(DmarcResult::Fail),
+        Check::ArcFail => auth.arc.as_ref().is_some_and(|a| a.result == ArcResult::Fail),
+        Check::DnsblListed => !input.dnsbl.is_empty(),
+        Check::SubjectAllCaps => {
+            let letters: Vec<char> = input.subject.chars().filter(|c| c.is_alphabetic()).collect();
+            letters.len() >= 10 && letters.iter().all(|c| !c.is_lowercase())
+        }
+        Check::ReplyToDiffers => {
+            let from = input.from.and_then(address_domain);
+            from.is_some_and(|from| {
+                input
+                    .reply_to
+                    .iter()
+                    .filter_map(address_domain)
+                    .any(|d| dmarc::organizational_domain(&d) != dmarc::organizational_domain(&from))
+            })
+        }
+        Check::HtmlOnly => input.body_html.is_some() && !input.has_plain_text,
+        Check::EmptyBody => input.body_text.trim().is_empty(),
+        Check::LinkTextMismatch => links.iter().any(|link| {
+            let shown = link.text.as_deref().and_then(displayed_host);
+            match (shown, url_host(&link.url)) {
+                (Some(shown), Some(target)) => {
+                    dmarc::organizational_domain(&shown) != dmarc::organizational_domain(&target)
+                }
+                _ => false,
+            }
+        }),
+    }
+}
+
+fn address_domain(address: &MailAddress) -> Option<String> {
+    address
+        .address
+        .as_deref()
+        .and_then(|a| a.rsplit_once('@'))
+        .map(|(_, domain)| domain.to_ascii_lowercase())
+}
+
+fn url_host(url: &str) -> Option<String> {
+    let rest = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority.rsplit_once('@').map(|(_, h)| h).unwrap_or(authority);
+    let host = host.split(':').next()?.trim_end_matches('.').to_ascii_lowercase();
+    (host.contains('.') && !host.is_empty()).then_some(host)
+}
+
+/// The host a link's text claims to go to, when the text looks like a URL or domain.
+fn displayed_host(text: &str) -> Option<String> {
+    let text = text.trim();
+    if text.contains(char::is_whitespace) {
+        return None;
+    }
+    let lower = text.to_ascii_lowercase();
+    let looks_like_url = lower.starts_with("http://")
+        || lower.starts_with("https://")
+        || lower.starts_with("www.");
+    if !looks_like_url {
+        return None;
+    }
+    url_host(&lower)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spf::SpfCheck;
+
+    fn rules(contents: &str) -> Vec<Rule> {
+        let mut definitions = Definitions::default();
+        definitions.parse(contents, "test");
+        definitions.into_rules()
+    }
+
+    fn header(name: &str, value: &str) -> MessageHeader {
+        MessageHeader {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    fn hits(rules: Vec<Rule>, input: &Input) -> Vec<String> {
+        let config = SpamConfig {
+            threshold: 5.0,
+            rules,
+        };
+        score(&config, input).rules.into_iter().map(|hit| hit.name).collect()
+    }
+
+    fn input<'a>(headers: &'a [MessageHeader], auth: &'a AuthResults) -> Input<'a> {
+        Input {
+            headers,
+            from: None,
+            reply_to: &[],
+            subject: "Hello",
+            body_text: "Some text",
+            body_html: None,
+            has_plain_text: true,
+            auth,
+            dnsbl: &[],
+        }
+    }
+
+    #[test]
+    fn trailing_comments_are_ignored() {
+        let rules = rules(
+            r#"
+            # a whole-line comment
+            header   FREE  Subject =~ /free money/i   # also !~, exists:Header
+            score    FREE  1.5                        # 0 disables a rule
+            describe FREE  Offers \#1 deal           # shown with the hit
+            body     ISSUE /issue#\d+/
+            "#,
+        );
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].score, 1.5);
+        assert_eq!(rules[0].description.as_deref(), Some("Offers #1 deal"));
+        assert!(matches!(&rules[1].test, Test::Body(regex) if regex.is_match("see issue#42")));
+    }
+
+    #[test]
+    fn invalid_rules_are_skipped() {
+        let rules = rules(
+            "header BAD Subject =~ /x/q\nscore ZERO 0\neval ZERO spf_fail\neval UNKNOWN nope",
+        );
+        assert!(rules.is_empty());
+    }
+
+    #[test]
+    fn header_and_body_rules() {
+        let rules = rules(
+            r#"
+            header FREE     Subject =~ /free money/i
+            header NO_DATE  missing:Date
+            header HAS_LIST exists:List-Id
+            body   TEXT     /some text/i
+            "#,
+        );
+        let headers = [header("Subject", "FREE MONEY now")];
+        let auth = AuthResults::default();
+        assert_eq!(hits(rules, &input(&headers, &auth)), ["FREE", "NO_DATE", "TEXT"]);
+    }
+
+    #[test]
+    fn eval_checks() {
+        let rules = rules(
+            r#"
+            eval SPF_FAIL     spf_fail
+            eval DKIM_NONE    dkim_none
+            eval DNSBL        dnsbl_listed
+            eval CAPS         subject_all_caps
+            eval REPLY_TO     reply_to_differs
+            eval HTML_ONLY    html_only
+            eval EMPTY        empty_body
+            eval MISMATCH     link_text_mismatch
+            "#,
+        );
+        let auth = AuthResults {
+            spf: Some(SpfCheck {
+                result: SpfResult::Fail,
+                scope: "mailfrom".to_string(),
+                identity: "a@example.com".to_string(),
+                domain: "example.com".to_string(),
+                mechanism: Some("-all".to_string()),
+                reason: None,
+            }),
+            ..Default::default()
+        };
+        let from = MailAddress {
+            name: None,
+            address: Some("a@mail.example.com".to_string()),
+        };
+        let reply_to = [MailAddress {
+            name: None,
+            address: Some("b@other.example".to_string()),
+        }];
+        let input = Input {
+            from: Some(&from),
+            reply_to: &reply_to,
+            subject: "URGENT ACTION REQUIRED",
+            body_html: Some(r#"<a href="http://evil.example/login">https://www.bank.example</a>"#),
+            has_plain_text: false,
+            ..input(&[], &auth)
+        };
+        assert_eq!(
+            hits(rules, &input),
+            ["SPF_FAIL", "DKIM_NONE", "CAPS", "REPLY_TO", "HTML_ONLY", "MISMATCH"]
+        );
+    }
+}
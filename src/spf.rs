@@ -0,0 +1,824 @@
+//! Sender Policy Framework evaluation (RFC 7208).
+
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+
+use crate::dns::{DnsError, Resolver};
+
+/// Mechanisms and modifiers that query DNS, per evaluation (§4.6.4).
+const MAX_DNS_LOOKUPS: usize = 10;
+/// Lookups answering NXDOMAIN or no records, per evaluation (§4.6.4).
+const MAX_VOID_LOOKUPS: usize = 2;
+/// Names considered from a single MX or PTR answer (§4.6.4).
+const MAX_NAMES: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpfResult {
+    None,
+    Neutral,
+    Pass,
+    Fail,
+    SoftFail,
+    TempError,
+    PermError,
+}
+
+impl SpfResult {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SpfResult::None => "none",
+            SpfResult::Neutral => "neutral",
+            SpfResult::Pass => "pass",
+            SpfResult::Fail => "fail",
+            SpfResult::SoftFail => "softfail",
+            SpfResult::TempError => "temperror",
+            SpfResult::PermError => "permerror",
+        }
+    }
+}
+
+/// Outcome of the SPF check of a message's sender.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpfCheck {
+    pub result: SpfResult,
+    /// `mailfrom`, or `helo` when the reverse-path was null.
+    pub scope: String,
+    /// The checked identity (`postmaster@<helo>` for the helo scope).
+    pub identity: String,
+    pub domain: String,
+    /// The directive that decided the result, e.g. `-all` or `ip4:192.0.2.0/24`.
+    pub mechanism: Option<String>,
+    /// What went wrong for error results, or the domain's explanation for `fail`.
+    pub reason: Option<String>,
+}
+
+/// Check the envelope sender, falling back to the HELO name for a null reverse-path.
+pub async fn check(
+    resolver: &dyn Resolver,
+    ip: IpAddr,
+    mail_from: &str,
+    helo: Option<&str>,
+) -> SpfCheck {
+    let helo = helo.unwrap_or("").trim_end_matches('.');
+    let (scope, identity) = if mail_from.is_empty() {
+        ("helo", format!("postmaster@{}", helo))
+    } else if !mail_from.contains('@') {
+        ("mailfrom", format!("postmaster@{}", mail_from))
+    } else {
+        ("mailfrom", mail_from.to_string())
+    };
+    let domain = identity
+        .rsplit_once('@')
+        .map(|(_, d)| d.trim_end_matches('.').to_ascii_lowercase())
+        .unwrap_or_default();
+
+    let mut check = SpfCheck {
+        result: SpfResult::None,
+        scope: scope.to_string(),
+        identity: identity.clone(),
+        domain: domain.clone(),
+        mechanism: None,
+        reason: None,
+    };
+
+    if !is_valid_domain(&domain) {
+        check.reason = Some("no valid domain to check".to_string());
+        return check;
+    }
+
+    let mut eval = Evaluation {
+        resolver,
+        ip,
+        sender: identity,
+        helo: helo.to_string(),
+        lookups: 0,
+        void_lookups: 0,
+    };
+    let verdict = eval.check_host(domain).await;
+    check.result = verdict.result;
+    check.mechanism = verdict.mechanism;
+    check.reason = verdict.reason;
+    check
+}
+
+/// A domain that has at least two labels and fits in DNS (§4.3).
+fn is_valid_domain(domain: &str) -> bool {
+    !domain.is_empty()
+        && domain.len() <= 253
+        && domain.contains('.')
+        && domain
+            .split('.')
+            .all(|label| !label.is_empty() && label.len() <= 63)
+}
+
+struct Verdict {
+    result: SpfResult,
+    mechanism: Option<String>,
+    reason: Option<String>,
+}
+
+impl Verdict {
+    fn new(result: SpfResult) -> Self {
+        Self {
+            result,
+            mechanism: None,
+            reason: None,
+        }
+    }
+
+    fn error(result: SpfResult, reason: impl Into<String>) -> Self {
+        Self {
+            result,
+            mechanism: None,
+            reason: Some(reason.into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Qualifier {
+    Pass,
+    Fail,
+    SoftFail,
+    Neutral,
+}
+
+impl Qualifier {
+    fn result(self) -> SpfResult {
+        match self {
+            Qualifier::Pass => SpfResult::Pass,
+            Qualifier::Fail => SpfResult::Fail,
+            Qualifier::SoftFail => SpfResult::SoftFail,
+            Qualifier::Neutral => SpfResult::Neutral,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Mechanism {
+    All,
+    Include(String),
+    A(Option<String>, Cidr),
+    Mx(Option<String>, Cidr),
+    Ptr(Option<String>),
+    Ip(IpAddr, u8),
+    Exists(String),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Cidr {
+    v4: u8,
+    v6: u8,
+}
+
+impl Default for Cidr {
+    fn default() -> Self {
+        Self { v4: 32, v6: 128 }
+    }
+}
+
+struct Directive {
+    qualifier: Qualifier,
+    mechanism: Mechanism,
+    /// The term as written in the record.
+    text: String,
+}
+
+struct Record {
+    directives: Vec<Directive>,
+    redirect: Option<String>,
+    exp: Option<String>,
+}
+
+enum Error {
+    Temp(String),
+    Perm(String),
+}
+
+impl From<Error> for Verdict {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Temp(reason) => Verdict::error(SpfResult::TempError, reason),
+            Error::Perm(reason) => Verdict::error(SpfResult::PermError, reason),
+        }
+    }
+}
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+struct Evaluation<'a> {
+    resolver: &'a dyn Resolver,
+    ip: IpAddr,
+    /// `<local>@<domain>` of the identity being checked.
+    sender: String,
+    helo: String,
+    lookups: usize,
+    void_lookups: usize,
+}
+
+impl<'a> Evaluation<'a> {
+    /// The `check_host()` function of §4; recursive for include and redirect.
+    fn check_host(&mut self, domain: String) -> BoxFuture<'_, Verdict> {
+        Box::pin(async move {
+            match self.evaluate(&domain).await {
+                Ok(verdict) => verdict,
+                Err(e) => e.into(),
+            }
+        })
+    }
+
+    async fn evaluate(&mut self, domain: &str) -> Result<Verdict, Error> {
+        let Some(text) = self.fetch_record(domain).await? else {
+            return Ok(Verdict::error(
+                SpfResult::None,
+                format!("no SPF record for {}", domain),
+            ));
+        };
+        let record = parse_record(&text).map_err(Error::Perm)?;
+
+        for directive in &record.directives {
+            if self.matches(&directive.mechanism, domain).await? {
+                let result = directive.qualifier.result();
+                let reason = match (result, &record.exp) {
+                    (SpfResult::Fail, Some(exp)) => self.explanation(exp, domain).await,
+                    _ => None,
+                };
+                return Ok(Verdict {
+                    result,
+                    mechanism: Some(directive.text.clone()),
+                    reason,
+                });
+            }
+        }
+
+        if let Some(redirect) = &record.redirect {
+            self.count_lookup()?;
+            let target = self.expand_domain(redirect, domain)?;
+            let verdict = self.check_host(target.clone()).await;
+            if verdict.result == SpfResult::None {
+                return Err(Error::Perm(format!("redirect to {} has no SPF record", target)));
+            }
+            return Ok(Verdict {
+                mechanism: verdict
+                    .mechanism
+                    .or_else(|| Some(format!("redirect={}", redirect))),
+                ..verdict
+            });
+        }
+
+        Ok(Verdict {
+            mechanism: Some("default".to_string()),
+            ..Verdict::new(SpfResult::Neutral)
+        })
+    }
+
+    /// The single `v=spf1` TXT record of `domain`, if any (§4.5).
+    async fn fetch_record(&self, domain: &str) -> Result<Option<String>, Error> {
+        let records = match self.resolver.txt(domain).await {
+            Ok(records) => records,
+            Err(DnsError::NotFound) => return Ok(None),
+            Err(DnsError::Temporary(e)) => return Err(Error::Temp(e)),
+        };
+
+        let mut spf = records.into_iter().filter(|r| {
+            let lower = r.to_ascii_lowercase();
+            lower == "v=spf1" || lower.starts_with("v=spf1 ")
+        });
+        match (spf.next(), spf.next()) {
+            (None, _) => Ok(None),
+            (Some(record), None) => Ok(Some(record)),
+            (Some(_), Some(_)) => Err(Error::Perm(format!("multiple SPF records for {}", domain))),
+        }
+    }
+
+    fn count_lookup(&mut self) -> Result<(), Error> {
+        self.lookups += 1;
+        if self.lookups > MAX_DNS_LOOKUPS {
+            return Err(Error::Perm(format!(
+                "more than {} DNS lookups",
+                MAX_DNS_LOOKUPS
+            )));
+        }
+        Ok(())
+    }
+
+    fn count_void(&mut self) -> Result<(), Error> {
+        self.void_lookups += 1;
+        if self.void_lookups > MAX_VOID_LOOKUPS {
+            return Err(Error::Perm(format!(
+                "more than {} void DNS lookups",
+                MAX_VOID_LOOKUPS
+            )));
+        }
+        Ok(())
+    }
+
+    async fn matches(&mut self, mechanism: &Mechanism, domain: &str) -> Result<bool, Error> {
+        match mechanism {
+            Mechanism::All => Ok(true),
+            Mechanism::Ip(network, prefix) => Ok(in_network(self.ip, *network, *prefix)),
+            Mechanism::Include(spec) => {
+                self.count_lookup()?;
+                let target = self.expand_domain(spec, domain)?;
+                let verdict = self.check_host(target.clone()).await;
+                match verdict.result {
+                    SpfResult::Pass => Ok(true),
+                    SpfResult::Fail | SpfResult::SoftFail | SpfResult::Neutral => Ok(false),
+                    SpfResult::TempError => Err(Error::Temp(
+                        verdict.reason.unwrap_or_else(|| format!("include:{}", target)),
+                    )),
+                    SpfResult::PermError | SpfResult::None => Err(Error::Perm(
+                        verdict
+                            .reason
+                            .unwrap_or_else(|| format!("include:{} failed", target)),
+                    )),
+                }
+            }
+            Mechanism::A(spec, cidr) => {
+                self.count_lookup()?;
+                let target = self.target(spec.as_deref(), domain)?;
+                let ips = self.addresses(&target).await?;
+                Ok(ips.iter().any(|ip| self.in_cidr(*ip, *cidr)))
+            }
+            Mechanism::Mx(spec, cidr) => {
+                self.count_lookup()?;
+                let target = self.target(spec.as_deref(), domain)?;
+                let exchanges = match self.resolver.mx(&target).await {
+                    Ok(names) => names,
+                    Err(DnsError::NotFound) => {
+                        self.count_void()?;
+                        return Ok(false);
+                    }
+                    Err(DnsError::Temporary(e)) => return Err(Error::Temp(e)),
+                };
+                if exchanges.len() > MAX_NAMES {
+                    return Err(Error::Perm(format!("more than {} MX names for {}", MAX_NAMES, target)));
+                }
+                for exchange in exchanges {
+                    let ips = self.addresses(&exchange).await?;
+                    if ips.iter().any(|ip| self.in_cidr(*ip, *cidr)) {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Mechanism::Ptr(spec) => {
+                self.count_lookup()?;
+                let target = self.target(spec.as_deref(), domain)?;
+                let names = match self.resolver.ptr(self.ip).await {
+                    Ok(names) => names,
+                    Err(DnsError::NotFound) => {
+                        self.count_void()?;
+                        return Ok(false);
+                    }
+                    // a failing PTR lookup just means no match (§5.5)
+                    Err(DnsError::Temporary(_)) => return Ok(false),
+                };
+                for name in names.into_iter().take(MAX_NAMES) {
+                    let name = name.trim_end_matches('.').to_ascii_lowercase();
+                    if name != target && !name.ends_with(&format!(".{}", target)) {
+                        continue;
+                    }
+                    if let Ok(ips) = self.resolver.ips(&name).await {
+                        if ips.contains(&self.ip) {
+                            return Ok(true);
+                        }
+                    }
+                }
+                Ok(false)
+            }
+            Mechanism::Exists(spec) => {
+                self.count_lookup()?;
+                let target = self.expand_domain(spec, domain)?;
+                match self.resolver.a(&target).await {
+                    Ok(ips) => Ok(!ips.is_empty()),
+                    Err(DnsError::NotFound) => {
+                        self.count_void()?;
+                        Ok(false)
+                    }
+                    Err(DnsError::Temporary(e)) => Err(Error::Temp(e)),
+                }
+            }
+        }
+    }
+
+    fn target(&self, spec: Option<&str>, domain: &str) -> Result<String, Error> {
+        match spec {
+            Some(spec) => self.expand_domain(spec, domain),
+            None => Ok(domain.to_string()),
+        }
+    }
+
+    /// A or AAAA records of `name`, whichever matches the client's address family.
+    async fn addresses(&mut self, name: &str) -> Result<Vec<IpAddr>, Error> {
+        let found = match self.ip {
+            IpAddr::V4(_) => self
+                .resolver
+                .a(name)
+                .await
+                .map(|v| v.into_iter().map(IpAddr::V4).collect::<Vec<_>>()),
+            IpAddr::V6(_) => self
+                .resolver
+                .aaaa(name)
+                .await
+                .map(|v| v.into_iter().map(IpAddr::V6).collect::<Vec<_>>()),
+        };
+        match found {
+            Ok(ips) if !ips.is_empty() => Ok(ips),
+            Ok(_) | Err(DnsError::NotFound) => {
+                self.count_void()?;
+                Ok(Vec::new())
+            }
+            Err(DnsError::Temporary(e)) => Err(Error::Temp(e)),
+        }
+    }
+
+    fn in_cidr(&self, network: IpAddr, cidr: Cidr) -> bool {
+        let prefix = match network {
+            IpAddr::V4(_) => cidr.v4,
+            IpAddr::V6(_) => cidr.v6,
+        };
+        in_network(self.ip, network, prefix)
+    }
+
+    /// The `exp=` explanation string; failures to fetch it are ignored (§6.2).
+    async fn explanation(&self, spec: &str, domain: &str) -> Option<String> {
+        let target = self.expand_domain(spec, domain).ok()?;
+        let records = self.resolver.txt(&target).await.ok()?;
+        if records.len() != 1 {
+            return None;
+        }
+        self.expand(&records[0], domain, true).ok()
+    }
+
+    /// Expand a domain-spec and shorten it to fit in DNS (§7.3).
+    fn expand_domain(&self, spec: &str, domain: &str) -> Result<String, Error> {
+        let mut expanded = self
+            .expand(spec, domain, false)?
+            .trim_end_matches('.')
+            .to_ascii_lowercase();
+        while expanded.len() > 253 {
+            match expanded.split_once('.') {
+                Some((_, rest)) => expanded = rest.to_string(),
+                None => return Err(Error::Perm("expanded domain too long".to_string())),
+            }
+        }
+        if expanded.is_empty() {
+            return Err(Error::Perm(format!("empty domain from {}", spec)));
+        }
+        Ok(expanded)
+    }
+
+    /// Macro expansion (§7); `exp` allows the explanation-only letters c, r and t.
+    fn expand(&self, spec: &str, domain: &str, exp: bool) -> Result<String, Error> {
+        let bad = || Error::Perm(format!("invalid macro in {}", spec));
+        let mut out = String::new();
+        let mut chars = spec.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('%') => out.push('%'),
+                Some('_') => out.push(' '),
+                Some('-') => out.push_str("%20"),
+                Some('{') => {
+                    let letter = chars.next().ok_or_else(bad)?;
+                    let mut digits = String::new();
+                    while let Some(d) = chars.peek().filter(|d| d.is_ascii_digit()) {
+                        digits.push(*d);
+                        chars.next();
+                    }
+                    let reverse = chars.next_if(|r| r.eq_ignore_ascii_case(&'r')).is_some();
+                    let mut delimiters = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(d) if ".-+,/_=".contains(d) => delimiters.push(d),
+                            _ => return Err(bad()),
+                        }
+                    }
+
+                    let value = self.macro_value(letter, domain, exp).ok_or_else(bad)?;
+                    let keep = match digits.as_str() {
+                        "" => None,
+                        n => Some(n.parse::<usize>().ok().filter(|n| *n > 0).ok_or_else(bad)?),
+                    };
+                    let transformed = transform(&value, &delimiters, keep, reverse);
+                    if letter.is_ascii_uppercase() {
+                        out.push_str(&url_escape(&transformed));
+                    } else {
+                        out.push_str(&transformed);
+                    }
+                }
+                _ => return Err(bad()),
+            }
+        }
+
+        Ok(out)
+    }
+
+    fn macro_value(&self, letter: char, domain: &str, exp: bool) -> Option<String> {
+        let (local, sender_domain) = self.sender.rsplit_once('@')?;
+        Some(match letter.to_ascii_lowercase() {
+            's' => self.sender.clone(),
+            'l' => local.to_string(),
+            'o' => sender_domain.to_string(),
+            'd' => domain.to_string(),
+            'i' => match self.ip {
+                IpAddr::V4(v4) => v4.to_string(),
+                IpAddr::V6(v6) => v6
+                    .octets()
+                    .iter()
+                    .flat_map(|b| [b >> 4, b & 0x0f])
+                    .map(|n| format!("{:x}", n))
+                    .collect::<Vec<_>>()
+                    .join("."),
+            },
+            // validated names are expensive and discouraged (§7.3); "unknown" is allowed
+            'p' => "unknown".to_string(),
+            'v' => match self.ip {
+                IpAddr::V4(_) => "in-addr".to_string(),
+                IpAddr::V6(_) => "ip6".to_string(),
+            },
+            'h' => self.helo.clone(),
+            'c' if exp => self.ip.to_string(),
+            'r' if exp => "unknown".to_string(),
+            't' if exp => chrono::Utc::now().timestamp().to_string(),
+            _ => return None,
+        })
+    }
+}
+
+/// Split on the delimiters, optionally reverse, keep the rightmost parts and rejoin with dots.
+fn transform(value: &str, delimiters: &str, keep: Option<usize>, reverse: bool) -> String {
+    if delimiters.is_empty() && keep.is_none() && !reverse {
+        return value.to_string();
+    }
+    let delimiters = if delimiters.is_empty() { "." } else { delimiters };
+    let mut parts: Vec<&str> = value.split(|c| delimiters.contains(c)).collect();
+    if reverse {
+        parts.reverse();
+    }
+    if let Some(n) = keep {
+        if parts.len() > n {
+            parts.drain(..parts.len() - n);
+        }
+    }
+    parts.join(".")
+}
+
+fn url_escape(value: &str) -> String {
+    let mut out = String::new();
+    for b in value.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+pub(crate) fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+fn parse_record(text: &str) -> Result<Record, String> {
+    let mut record = Record {
+        directives: Vec::new(),
+        redirect: None,
+        exp: None,
+    };
+
+    for term in text.split_ascii_whitespace().skip(1) {
+        if let Some((name, value)) = modifier(term) {
+            let slot = match name.to_ascii_lowercase().as_str() {
+                "redirect" => &mut record.redirect,
+                "exp" => &mut record.exp,
+                // unknown modifiers are ignored (§6)
+                _ => continue,
+            };
+            if slot.is_some() {
+                return Err(format!("duplicate {} modifier", name));
+            }
+            *slot = Some(value.to_string());
+            continue;
+        }
+
+        let (qualifier, rest) = match term.chars().next() {
+            Some('+') => (Qualifier::Pass, &term[1..]),
+            Some('-') => (Qualifier::Fail, &term[1..]),
+            Some('~') => (Qualifier::SoftFail, &term[1..]),
+            Some('?') => (Qualifier::Neutral, &term[1..]),
+            _ => (Qualifier::Pass, term),
+        };
+        record.directives.push(Directive {
+            qualifier,
+            mechanism: parse_mechanism(rest).ok_or_else(|| format!("invalid term '{}'", term))?,
+            text: term.to_string(),
+        });
+    }
+
+    // redirect is ignored when the record has an "all" mechanism (§6.1)
+    if record
+        .directives
+        .iter()
+        .any(|d| matches!(d.mechanism, Mechanism::All))
+    {
+        record.redirect = None;
+    }
+
+    Ok(record)
+}
+
+/// `name=value` terms; a `:` or `/` before the `=` means it is a mechanism.
+fn modifier(term: &str) -> Option<(&str, &str)> {
+    let (name, value) = term.split_once('=')?;
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+    valid.then_some((name, value))
+}
+
+fn parse_mechanism(term: &str) -> Option<Mechanism> {
+    let split = term.find([':', '/']).unwrap_or(term.len());
+    let (name, rest) = term.split_at(split);
+    let (spec, cidr) = split_cidr(rest.strip_prefix(':').unwrap_or(rest));
+    let spec = spec.filter(|_| rest.starts_with(':'));
+    if rest.starts_with(':') && spec.is_none() {
+        return None;
+    }
+
+    match name.to_ascii_lowercase().as_str() {
+        "all" if rest.is_empty() => Some(Mechanism::All),
+        "include" => Some(Mechanism::Include(spec?)).filter(|_| cidr.is_empty()),
+        "exists" => Some(Mechanism::Exists(spec?)).filter(|_| cidr.is_empty()),
+        "ptr" if cidr.is_empty() => Some(Mechanism::Ptr(spec)),
+        "a" => Some(Mechanism::A(spec, parse_dual_cidr(cidr)?)),
+        "mx" => Some(Mechanism::Mx(spec, parse_dual_cidr(cidr)?)),
+        "ip4" => {
+            let (addr, prefix) = split_prefix(rest.strip_prefix(':')?);
+            let addr: std::net::Ipv4Addr = addr.parse().ok()?;
+            let prefix = parse_prefix(prefix, 32)?;
+            Some(Mechanism::Ip(IpAddr::V4(addr), prefix))
+        }
+        "ip6" => {
+            let (addr, prefix) = split_prefix(rest.strip_prefix(':')?);
+            let addr: std::net::Ipv6Addr = addr.parse().ok()?;
+            let prefix = parse_prefix(prefix, 128)?;
+            Some(Mechanism::Ip(IpAddr::V6(addr), prefix))
+        }
+        _ => None,
+    }
+}
+
+/// Separate a trailing `/n` or `//n` from a domain-spec, ignoring `/` inside macros.
+fn split_cidr(value: &str) -> (Option<String>, &str) {
+    let mut depth = 0;
+    for (i, c) in value.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            '/' if depth == 0 => {
+                let spec = &value[..i];
+                return ((!spec.is_empty()).then(|| spec.to_string()), &value[i..]);
+            }
+            _ => {}
+        }
+    }
+    ((!value.is_empty()).then(|| value.to_string()), "")
+}
+
+fn split_prefix(value: &str) -> (&str, Option<&str>) {
+    match value.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (value, None),
+    }
+}
+
+fn parse_prefix(prefix: Option<&str>, max: u8) -> Option<u8> {
+    match prefix {
+        None => Some(max),
+        Some(p) if !p.is_empty() && p.len() <= 3 && p.bytes().all(|b| b.is_ascii_digit()) => {
+            p.parse().ok().filter(|n| *n <= max)
+        }
+        _ => None,
+    }
+}
+
+/// `/v4`, `//v6` or `/v4//v6` (§5.6).
+fn parse_dual_cidr(cidr: &str) -> Option<Cidr> {
+    let mut out = Cidr::default();
+    if cidr.is_empty() {
+        return Some(out);
+    }
+    let (v4, v6) = match cidr.split_once("//") {
+        Some((v4, v6)) => (v4, Some(v6)),
+        None => (cidr, None),
+    };
+    if !v4.is_empty() {
+        out.v4 = parse_prefix(Some(v4.strip_prefix('/')?), 32)?;
+    }
+    if let Some(v6) = v6 {
+        out.v6 = parse_prefix(Some(v6), 128)?;
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::ZoneResolver;
+
+    const ZONE: &str = r#"
+pass.test.                         TXT "v=spf1 ip4:192.0.2.0/24 -all"
+fail.test.                         TXT "v=spf1 ip4:198.51.100.0/24 -all"
+fail.test.                         TXT "some other record"
+soft.test.                         TXT "v=spf1 ~all"
+inc.test.                          TXT "v=spf1 include:pass.test -all"
+mx.test.                           TXT "v=spf1 mx/24 -all"
+mx.test.                           MX  10 mail.mx.test.
+mail.mx.test.                      A   192.0.2.200
+redir.test.                        TXT "v=spf1 redirect=pass.test"
+exists.test.                       TXT "v=spf1 exists:%{ir}.%{l1r-}.allow.exists.test -all"
+1.2.0.192.bob.allow.exists.test.   A   127.0.0.2
+exp.test.                          TXT "v=spf1 -all exp=explain.%{d}"
+explain.exp.test.                  TXT "%{i} is not allowed to send for %{d}"
+multi.test.                        TXT "v=spf1 -all"
+multi.test.                        TXT "v=spf1 +all"
+loop.test.                         TXT "v=spf1 include:loop.test -all"
+bad.test.                          TXT "v=spf1 ip4:192.0.2.1/40 -all"
+helo.test.                         TXT "v=spf1 ip4:192.0.2.1 -all"
+"#;
+
+    async fn result(sender: &str) -> SpfCheck {
+        let zone = ZoneResolver::parse(ZONE).unwrap();
+        check(&zone, "192.0.2.1".parse().unwrap(), sender, Some("helo.test")).await
+    }
+
+    #[tokio::test]
+    async fn evaluates_mechanisms() {
+        for (domain, expected) in [
+            ("pass.test", SpfResult::Pass),
+            ("fail.test", SpfResult::Fail),
+            ("soft.test", SpfResult::SoftFail),
+            ("inc.test", SpfResult::Pass),
+            ("mx.test", SpfResult::Pass),
+            ("redir.test", SpfResult::Pass),
+            ("exists.test", SpfResult::Pass),
+            ("none.test", SpfResult::None),
+        ] {
+            let spf = result(&format!("bob@{}", domain)).await;
+            assert_eq!(spf.result, expected, "{}", domain);
+        }
+        assert_eq!(result("alice@exists.test").await.result, SpfResult::Fail);
+    }
+
+    #[tokio::test]
+    async fn broken_records_are_permanent_errors() {
+        for domain in ["multi.test", "loop.test", "bad.test"] {
+            let spf = result(&format!("bob@{}", domain)).await;
+            assert_eq!(spf.result, SpfResult::PermError, "{}", domain);
+        }
+    }
+
+    #[tokio::test]
+    async fn fail_carries_the_explanation() {
+        let spf = result("bob@exp.test").await;
+        assert_eq!(spf.result, SpfResult::Fail);
+        assert_eq!(spf.mechanism.as_deref(), Some("-all"));
+        assert_eq!(spf.reason.as_deref(), Some("192.0.2.1 is not allowed to send for exp.test"));
+    }
+
+    #[tokio::test]
+    async fn null_sender_checks_helo() {
+        let spf = result("").await;
+        assert_eq!(spf.scope, "helo");
+        assert_eq!(spf.identity, "postmaster@helo.test");
+        assert_eq!(spf.result, SpfResult::Pass);
+    }
+
+    #[test]
+    fn networks() {
+        let ip = "192.0.2.77".parse().unwrap();
+        assert!(in_network(ip, "192.0.2.0".parse().unwrap(), 24));
+        assert!(!in_network(ip, "192.0.3.0".parse().unwrap(), 24));
+        assert!(in_network("2001:db8::1".parse().unwrap(), "2001:db8::".parse().unwrap(), 32));
+    }
+}
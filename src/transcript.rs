@@ -0,0 +1,218 @@
+//! Recording of SMTP command/response dialogues for debugging failed deliveries.
+
+use std::time::Instant;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::config::{env_list, env_or};
+use crate::db::TranscriptLine;
+
+/// Stop recording after this many lines so a chatty client cannot grow a session unbounded.
+const MAX_LINES: usize = 2000;
+
+#[derive(Debug, Clone)]
+pub struct TranscriptConfig {
+    /// Domains whose sessions are always recorded (`TRANSCRIPT_DOMAINS`).
+    pub domains: Vec<String>,
+    /// Transcripts older than this are deleted (`TRANSCRIPT_RETENTION_DAYS`).
+    pub retention_days: i64,
+    /// At most this many transcripts are kept per mailbox (`TRANSCRIPT_MAX_PER_MAILBOX`).
+    pub max_per_mailbox: i64,
+    /// DATA content beyond this many bytes is left out (`TRANSCRIPT_DATA_BYTES`).
+    pub data_bytes: usize,
+}
+
+impl TranscriptConfig {
+    pub fn from_env() -> Self {
+        Self {
+            domains: env_list("TRANSCRIPT_DOMAINS"),
+            retention_days: env_or("TRANSCRIPT_RETENTION_DAYS", 7),
+            max_per_mailbox: env_or("TRANSCRIPT_MAX_PER_MAILBOX", 50),
+            data_bytes: env_or("TRANSCRIPT_DATA_BYTES", 2048),
+        }
+    }
+}
+
+/// Collects the dialogue of one session in memory until we know whether to keep it.
+pub struct Transcript {
+    started: Instant,
+    lines: Vec<TranscriptLine>,
+    data_limit: usize,
+    data_seen: usize,
+}
+
+impl Transcript {
+    pub fn new(data_limit: usize) -> Self {
+        Self {
+            started: Instant::now(),
+            lines: Vec::new(),
+            data_limit,
+            data_seen: 0,
+        }
+    }
+
+    fn push(&mut self, direction: &str, text: &str) {
+        if self.lines.len() >= MAX_LINES {
+            return;
+        }
+        self.lines.push(TranscriptLine {
+            at_ms: self.started.elapsed().as_millis() as u64,
+            direction: direction.to_string(),
+            // stored as JSONB, which cannot hold NUL; binary DATA and BDAT content may carry it
+            text: text
+                .trim_end_matches(['\r', '\n'])
+                .replace('\0', "\u{fffd}"),
+        });
+    }
+
+    /// A command line sent by the client.
+    pub fn client(&mut self, line: &str) {
+        self.push("C", line);
+    }
+
+    /// One or more reply lines sent by us.
+    pub fn server(&mut self, reply: &[u8]) {
+        for line in String::from_utf8_lossy(reply).lines() {
+            self.push("S", line);
+        }
+    }
+
+    /// A line of message content; only the first `data_limit` bytes are kept.
+    pub fn data(&mut self, line: &[u8]) {
+        let before = self.data_seen;
+        self.data_seen += line.len();
+        if before < self.data_limit {
+            let keep = (self.data_limit - before).min(line.len());
+            self.push("C", &String::from_utf8_lossy(&line[..keep]));
+        }
+    }
+
+    /// Close the DATA section, noting how much content was left out.
+    pub fn end_data(&mut self) {
+        if self.data_seen > self.data_limit {
+            let note = format!(
+                "[... {} bytes of message content not recorded ...]",
+                self.data_seen - self.data_limit
+            );
+            self.push("-", &note);
+        }
+        self.data_seen = 0;
+    }
+
+    /// Note something that is neither a command nor a reply, like a dropped connection.
+    pub fn note(&mut self, text: &str) {
+        self.push("-", text);
+    }
+
+    pub fn into_lines(self) -> Vec<TranscriptLine> {
+        self.lines
+    }
+}
+
+/// Wraps the write half of a connection so every reply also lands in the transcript.
+pub struct ReplyWriter<W> {
+    inner: W,
+    pub transcript: Transcript,
+    /// Number of 5xx replies sent so far.
+    pub errors: usize,
+}
+
+impl<W: AsyncWrite + Unpin> ReplyWriter<W> {
+    pub fn new(inner: W, transcript: Transcript) -> Self {
+        Self {
+            inner,
+            transcript,
+            errors: 0,
+        }
+    }
+
+    pub async fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        if buf.first() == Some(&b'5') {
+            self.errors += 1;
+        }
+        self.transcript.server(buf);
+        self.inner.write_all(buf).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(transcript: Transcript) -> Vec<(String, String)> {
+        transcript
+            .into_lines()
+            .into_iter()
+            .map(|l| (l.direction, l.text))
+            .collect()
+    }
+
+    #[test]
+    fn records_both_directions() {
+        let mut transcript = Transcript::new(100);
+        transcript.client("EHLO client.test\r\n");
+        transcript.server(b"250-tempmail.test Hello\r\n250 SIZE 100\r\n");
+        transcript.note("connection closed");
+        assert_eq!(
+            texts(transcript),
+            [
+                ("C".into(), "EHLO client.test".into()),
+                ("S".into(), "250-tempmail.test Hello".into()),
+                ("S".into(), "250 SIZE 100".into()),
+                ("-".into(), "connection closed".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn data_is_cut_at_the_limit() {
+        let mut transcript = Transcript::new(10);
+        transcript.data(b"Subject: x\r\n");
+        transcript.data(b"more\r\n");
+        transcript.end_data();
+        transcript.data(b"short\r\n");
+        transcript.end_data();
+        assert_eq!(
+            texts(transcript),
+            [
+                ("C".into(), "Subject: x".into()),
+                (
+                    "-".into(),
+                    "[... 8 bytes of message content not recorded ...]".into()
+                ),
+                ("C".into(), "short".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn binary_data_has_no_nul() {
+        let mut transcript = Transcript::new(100);
+        transcript.data(b"a\0b\xff\r\n");
+        assert_eq!(texts(transcript)[0].1, "a\u{fffd}b\u{fffd}");
+    }
+
+    #[test]
+    fn line_count_is_capped() {
+        let mut transcript = Transcript::new(0);
+        for _ in 0..MAX_LINES + 10 {
+            transcript.client("NOOP");
+        }
+        transcript.note("dropped");
+        assert_eq!(transcript.into_lines().len(), MAX_LINES);
+    }
+
+    #[tokio::test]
+    async fn reply_writer_counts_errors() {
+        let mut writer = ReplyWriter::new(Vec::new(), Transcript::new(0));
+        writer.write_all(b"250 OK\r\n").await.unwrap();
+        writer.write_all(b"550 5.1.1 No\r\n").await.unwrap();
+        writer.write_all(b"451 4.7.1 Later\r\n").await.unwrap();
+        writer.write_all(b"500 5.5.2 What\r\n").await.unwrap();
+        assert_eq!(writer.errors, 2);
+        assert_eq!(
+            writer.inner,
+            b"250 OK\r\n550 5.1.1 No\r\n451 4.7.1 Later\r\n500 5.5.2 What\r\n"
+        );
+        assert_eq!(writer.transcript.into_lines().len(), 4);
+    }
+}